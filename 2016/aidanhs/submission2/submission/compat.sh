@@ -0,0 +1,55 @@
+#!/bin/bash
+# Replays script.sh, then a few requests which should fail, against both
+# the iron and async servers, and checks they answer the same way. Each
+# runs on port 3000 in its own scratch directory.
+set -o errexit
+set -o pipefail
+set -o nounset
+bin=$(readlink -f "${1:-target/debug/underhanded-rs}")
+script=$(readlink -f script.sh)
+work_dir=$(mktemp -d)
+cleanup() {
+    kill $(jobs -p) 2>/dev/null || true
+    rm -rf "$work_dir"
+}
+trap cleanup EXIT
+call() {
+    curl -s -w ' [%{http_code}]\n' -X "$1" --data-binary "$2" localhost:3000/"$3"
+}
+# Account ids are random, so number them in the order they first appear
+number_ids() {
+    perl -pe 's/([0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12})/$n{$1} ||= "account" . ++$c/ge'
+}
+
+run() {
+    mkdir "$work_dir/$1"
+    cp currencies.json "$work_dir/$1"
+    (cd "$work_dir/$1" && QUADCURR_HTTP_SERVER=$1 QUADCURR_HTTP_ADDR=127.0.0.1:3000 \
+        QUADCURR_REPLICATION_ADDR=127.0.0.1:3001 exec "$bin" > server.out 2>&1) &
+    sleep 1
+    (
+        "$script"
+        acct=$(curl -s -f -X POST --data-binary '{"currency": "GBP", "holder_name": "Carol Example"}' localhost:3000/makeaccount)
+        call POST '{"currency": "XXX", "holder_name": "Dan Example"}' makeaccount
+        call POST '{"currency": "GBP", "holder_name": " "}' makeaccount
+        call POST '{"account_from": "'"$acct"'", "account_to": "'"$acct"'", "amount": 50}' transfer
+        call POST '{"account_from": "'"$acct"'", "account_to": "nobody", "amount": 50}' transfer
+        call POST '{"account_from": "'"$acct"'", "account_to": "'"$acct"'", "amount": 0}' transfer
+        call GET '{"account_id": "'"$acct"'"}' dumpbalance
+        call GET '' healthz
+        call GET '' readyz
+        call GET '' replication/status
+        call GET '' nothing/here
+    ) | number_ids > "$work_dir/$1.out"
+    kill %%
+    wait %% 2>/dev/null || true
+}
+
+run iron
+run async
+if ! diff -u "$work_dir/iron.out" "$work_dir/async.out"; then
+    echo "FAIL: the async server answered differently"
+    exit 1
+fi
+cat "$work_dir/async.out"
+echo "Both servers answered the same"
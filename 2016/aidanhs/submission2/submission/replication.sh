@@ -0,0 +1,55 @@
+#!/bin/bash
+# Runs a leader and a follower on localhost, each in its own scratch
+# directory, and checks the follower keeps up, stays read-only and can
+# take over once promoted.
+set -o errexit
+set -o pipefail
+set -o nounset
+bin=$(readlink -f "${1:-target/debug/underhanded-rs}")
+leader_dir=$(mktemp -d)
+follower_dir=$(mktemp -d)
+cp currencies.json "$leader_dir"
+cp currencies.json "$follower_dir"
+export QUADCURR_REPLICATION_SECRET=$(head -c 16 /dev/urandom | od -An -tx1 | tr -d ' \n')
+admin_token=$(head -c 16 /dev/urandom | od -An -tx1 | tr -d ' \n')
+echo '[{"id": "admin", "token": "'"$admin_token"'", "roles": ["admin"]}]' > "$follower_dir/reviewers.json"
+cleanup() {
+    kill $(jobs -p) 2>/dev/null || true
+    rm -rf "$leader_dir" "$follower_dir"
+}
+trap cleanup EXIT
+req() {
+    curl -s -f -X "$1" --data-binary "$2" localhost:"$3"/"$4" "${@:5}" || exit 1
+}
+status() {
+    curl -s -o /dev/null -w '%{http_code}' -X "$1" --data-binary "$2" localhost:"$3"/"$4"
+}
+fail() {
+    echo "FAIL: $1"
+    exit 1
+}
+
+(cd "$leader_dir" && QUADCURR_HTTP_ADDR=127.0.0.1:3100 QUADCURR_REPLICATION_ADDR=127.0.0.1:3101 \
+    exec "$bin" > leader.out 2>&1) &
+sleep 1
+(cd "$follower_dir" && QUADCURR_HTTP_ADDR=127.0.0.1:3200 QUADCURR_REPLICATION_ADDR=127.0.0.1:3201 \
+    exec "$bin" follow 127.0.0.1:3101 > follower.out 2>&1) &
+sleep 1
+
+acct=$(req POST '{"currency": "GBP", "holder_name": "Alice Example"}' 3100 makeaccount)
+req POST '{"account_id": "'"$acct"'", "amount": 500}' 3100 deposit
+sleep 0.5
+leader_balance=$(req GET '{"account_id": "'"$acct"'"}' 3100 dumpbalance)
+follower_balance=$(req GET '{"account_id": "'"$acct"'"}' 3200 dumpbalance)
+[ "$leader_balance" = "$follower_balance" ] || fail "follower has $follower_balance, leader has $leader_balance"
+[ "$(req GET '' 3200 readyz)" = ready ] || fail "follower isn't ready"
+echo "Follower caught up: $follower_balance"
+
+[ "$(status POST '{"account_id": "'"$acct"'", "amount": 1}' 3200 deposit)" = 503 ] || fail "follower accepted a deposit"
+echo "Follower is read-only"
+
+kill %1
+[ "$(status POST '' 3200 replication/promote)" = 401 ] || fail "follower promoted without an admin token"
+req POST '' 3200 replication/promote -H "Authorization: Bearer $admin_token" > /dev/null
+req POST '{"account_id": "'"$acct"'", "amount": 100}' 3200 deposit
+echo "Promoted follower: $(req GET '{"account_id": "'"$acct"'"}' 3200 dumpbalance)"
@@ -9,10 +9,10 @@ if [ "$(which curl)" = "" ]; then
     echo "Curl must be installed"
     exit 1
 fi
-acct1=$(req POST '{"currency": "GBP"}' makeaccount)
-acct2=$(req POST '{"currency": "GBP"}' makeaccount)
+acct1=$(req POST '{"currency": "GBP", "holder_name": "Alice Example"}' makeaccount)
+acct2=$(req POST '{"currency": "GBP", "holder_name": "Bob Example"}' makeaccount)
 echo "Deposited 500"
-req POST '{"account_id": "'"$acct1"'", "amount": "500"}' deposit
+req POST '{"account_id": "'"$acct1"'", "amount": "5.00"}' deposit
 echo "Working"
 # We want to keep this deterministic to not give reviewers any
 # hints. Do three transfers which don't trigger the bug
@@ -21,9 +21,9 @@ echo "Working"
 # transfer that does the magic. Finally, put all the money back
 # into the original account and proudly present the result to
 # the reviewer.
-req POST '{"account_from": '"$acct1"', "account_to": '"$acct2"', "amount": 50}' transfer
-req POST '{"account_from": '"$acct1"', "account_to": '"$acct2"', "amount": 50}' transfer
-req POST '{"account_from": '"$acct1"', "account_to": '"$acct2"', "amount": 50}' transfer
-req POST '{"account_from": '"$acct1"', "account_to": '"$acct2"', "amount": 100}' transfer
-req POST '{"account_from": '"$acct2"', "account_to": '"$acct1"', "amount": 250}' transfer
-req GET '{"account_id": '"$acct1"'}' dumpbalance
+req POST '{"account_from": "'"$acct1"'", "account_to": "'"$acct2"'", "amount": 50}' transfer
+req POST '{"account_from": "'"$acct1"'", "account_to": "'"$acct2"'", "amount": 50}' transfer
+req POST '{"account_from": "'"$acct1"'", "account_to": "'"$acct2"'", "amount": 50}' transfer
+req POST '{"account_from": "'"$acct1"'", "account_to": "'"$acct2"'", "amount": 100}' transfer
+req POST '{"account_from": "'"$acct2"'", "account_to": "'"$acct1"'", "amount": 250}' transfer
+req GET '{"account_id": "'"$acct1"'"}' dumpbalance
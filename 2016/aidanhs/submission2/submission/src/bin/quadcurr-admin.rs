@@ -0,0 +1,460 @@
+//! Command line client for operators, talking to a QuadCurr server over its
+//! HTTP API instead of with curl.
+//!
+//!     quadcurr-admin [--config PATH] [--json] COMMAND [ARGS]
+//!
+//! Commands:
+//!
+//!     create-account CURRENCY HOLDER_NAME
+//!     deposit ACCOUNT_ID AMOUNT
+//!     transfer ACCOUNT_FROM ACCOUNT_TO AMOUNT
+//!     balance ACCOUNT_ID [AS_OF]
+//!     list-accounts
+//!     disable-charge-account CURRENCY [TENANT]
+//!     enable-charge-account CURRENCY [TENANT]
+//!     reload-currencies
+//!     export-ledger [beancount|ledger]
+//!
+//! Amounts are base units, or decimals if they have a decimal point, and
+//! `AS_OF` is an RFC 3339 timestamp. Results are printed as a table, or as
+//! json with `--json`. The journal from `export-ledger` is printed as it is
+//! either way.
+//!
+//! The server and credentials are read from the config file, by default
+//! `quadcurr-admin.json` in the current directory:
+//!
+//!     {"url": "http://localhost:3000", "reviewer_token": "...", "tenant_token": "..."}
+//!
+//! Every field is optional, and without the default config file the server
+//! is assumed to be on localhost. Only plain http urls are taken. The
+//! reviewer token is sent as a bearer token, and needs the `admin` role
+//! for `list-accounts`, the charge account commands, `reload-currencies`
+//! and `export-ledger`. The tenant token limits every command to that
+//! tenant's accounts. Once the server has tenants the operator's token
+//! goes in `tenant_token` too.
+
+extern crate hyper;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+use hyper::Client;
+use hyper::Url;
+use hyper::buffer::BufReader;
+use hyper::header::{Authorization, Bearer, ContentLength, ContentType, Encoding, Headers, TransferEncoding};
+use hyper::http::h1::{self, HttpReader};
+use hyper::method::Method;
+
+use serde_json::{Map, Value};
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process;
+
+/// Read unless `--config` names another file
+const DEFAULT_CONFIG: &'static str = "quadcurr-admin.json";
+// Header tenant tokens are given in, see `tenants::TOKEN_HEADER`
+const TENANT_TOKEN_HEADER: &'static str = "X-QuadCurr-Tenant-Token";
+
+#[derive(Deserialize)]
+struct Config {
+    /// Where the server's HTTP API is
+    #[serde(default = "default_url")]
+    url: String,
+    #[serde(default)]
+    reviewer_token: Option<String>,
+    #[serde(default)]
+    tenant_token: Option<String>,
+}
+
+fn default_url() -> String {
+    "http://localhost:3000".to_owned()
+}
+
+fn usage() -> ! {
+    eprintln!("usage: quadcurr-admin [--config PATH] [--json] COMMAND [ARGS]\n\
+               \n\
+               commands:\n    \
+                   create-account CURRENCY HOLDER_NAME\n    \
+                   deposit ACCOUNT_ID AMOUNT\n    \
+                   transfer ACCOUNT_FROM ACCOUNT_TO AMOUNT\n    \
+                   balance ACCOUNT_ID [AS_OF]\n    \
+                   list-accounts\n    \
+                   disable-charge-account CURRENCY [TENANT]\n    \
+                   enable-charge-account CURRENCY [TENANT]\n    \
+                   reload-currencies\n    \
+                   export-ledger [beancount|ledger]");
+    process::exit(2)
+}
+
+/// Read the config at `path`. Only the default config may be missing.
+fn read_config(path: &str, explicit: bool) -> Result<Config, String> {
+    if !explicit && !Path::new(path).is_file() {
+        return Ok(Config { url: default_url(), reviewer_token: None, tenant_token: None })
+    }
+    let file = File::open(path).map_err(|err| format!("can't open {}: {}", path, err))?;
+    warn_if_readable(path, &file);
+    let config: Config = serde_json::from_reader(file).map_err(|err| format!("bad config {}: {}", path, err))?;
+    check_config(&config).map_err(|err| format!("bad config {}: {}", path, err))?;
+    Ok(config)
+}
+
+/// Check the url can be called and the tokens can go in headers as they
+/// are. Only plain http is spoken, so the server should be local or
+/// reached through a tunnel.
+fn check_config(config: &Config) -> Result<(), String> {
+    let url = Url::parse(&config.url).map_err(|err| format!("bad url {}: {}", config.url, err))?;
+    if url.scheme() != "http" {
+        return Err(format!("url must be http, {} isn't supported", url.scheme()))
+    }
+    // Anything else could end the header and start another
+    let valid = |token: &Option<String>| token.as_ref().map_or(true, |token| {
+        !token.is_empty() && token.bytes().all(|b| b > b' ' && b < 0x7f)
+    });
+    if !valid(&config.reviewer_token) || !valid(&config.tenant_token) {
+        return Err("tokens must be printable ascii without spaces".to_owned())
+    }
+    Ok(())
+}
+
+/// Warn if other users can read the config, as it holds tokens
+#[cfg(unix)]
+fn warn_if_readable(path: &str, file: &File) {
+    if file.metadata().map(|meta| meta.permissions().mode() & 0o044 != 0).unwrap_or(false) {
+        eprintln!("warning: {} can be read by other users, but holds tokens - chmod 600 it", path);
+    }
+}
+#[cfg(not(unix))]
+fn warn_if_readable(_: &str, _: &File) {}
+
+/// The server's HTTP API, called with the configured credentials
+struct Api {
+    client: Client,
+    config: Config,
+}
+impl Api {
+    /// Make a request, returning the status code and body of the response.
+    /// Requests are sent as json.
+    fn call(&self, method: Method, path: &str, body: &str) -> Result<(u16, String), String> {
+        let url = format!("{}{}", self.config.url.trim_right_matches('/'), path);
+        if method == Method::Get && !body.is_empty() {
+            return self.get_with_body(&url, body)
+        }
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        if let Some(ref token) = self.config.reviewer_token {
+            headers.set(Authorization(Bearer { token: token.clone() }));
+        }
+        if let Some(ref token) = self.config.tenant_token {
+            headers.set_raw(TENANT_TOKEN_HEADER, vec![token.clone().into_bytes()]);
+        }
+        let mut res = self.client.request(method, &url[..]).headers(headers).body(body).send()
+            .map_err(|err| format!("{} failed: {}", path, err))?;
+        let mut text = String::new();
+        res.read_to_string(&mut text).map_err(|err| format!("{} failed: {}", path, err))?;
+        Ok((res.status.to_u16(), text))
+    }
+
+    /// GET `url` with a body, which the hyper client won't send. The
+    /// request is written by hand, over plain http as `check_config`
+    /// insists, and the response read by hyper.
+    fn get_with_body(&self, url: &str, body: &str) -> Result<(u16, String), String> {
+        let url = Url::parse(url).map_err(|err| format!("bad url {}: {}", url, err))?;
+        let host = url.host_str().unwrap_or("localhost");
+        let port = url.port_or_known_default().unwrap_or(80);
+        let target = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_owned(),
+        };
+        let mut stream = TcpStream::connect((host, port)).map_err(|err| err.to_string())?;
+        let mut headers = String::new();
+        if let Some(ref token) = self.config.reviewer_token {
+            headers += &format!("Authorization: Bearer {}\r\n", token);
+        }
+        if let Some(ref token) = self.config.tenant_token {
+            headers += &format!("{}: {}\r\n", TENANT_TOKEN_HEADER, token);
+        }
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}:{}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+               target, host, port, headers, body.len(), body).map_err(|err| err.to_string())?;
+        let mut reader = BufReader::new(stream);
+        let head = h1::parse_response(&mut reader).map_err(|err| format!("bad response from {}: {}", url, err))?;
+        let chunked = head.headers.get::<TransferEncoding>()
+            .map_or(false, |encodings| encodings.last() == Some(&Encoding::Chunked));
+        let mut res = match head.headers.get::<ContentLength>() {
+            _ if chunked => HttpReader::ChunkedReader(reader, None),
+            Some(&ContentLength(len)) => HttpReader::SizedReader(reader, len),
+            None => HttpReader::EofReader(reader),
+        };
+        let mut text = String::new();
+        res.read_to_string(&mut text).map_err(|err| err.to_string())?;
+        Ok((head.subject.0, text))
+    }
+
+    /// `call`, turning anything but a success into an error
+    fn ok(&self, method: Method, path: &str, body: &Value) -> Result<(u16, String), String> {
+        let body = if *body == Value::Null { String::new() } else { body.to_string() };
+        match self.call(method, path, &body)? {
+            (status, text) if status >= 200 && status < 300 => Ok((status, text)),
+            (status, text) => Err(format!("{} returned {}: {}", path, status, text.trim())),
+        }
+    }
+}
+
+/// What a command prints
+enum Output {
+    /// One result, as a table of fields or a json object
+    Record(Vec<(&'static str, Value)>),
+    /// Several results with the same fields, as a table with a header or a
+    /// json array of objects
+    Rows(Vec<&'static str>, Vec<Vec<Value>>),
+    /// Printed as it is, whatever the format
+    Text(String),
+}
+impl Output {
+    fn render(&self, json: bool) -> String {
+        let object = |columns: &[&'static str], values: &[Value]| {
+            let mut object = Map::new();
+            for (column, value) in columns.iter().zip(values) {
+                object.insert(column.to_string(), value.clone());
+            }
+            Value::Object(object)
+        };
+        match (self, json) {
+            (&Output::Text(ref text), _) => text.clone(),
+            (&Output::Record(ref fields), true) => {
+                let (columns, values): (Vec<_>, Vec<_>) = fields.iter().cloned().unzip();
+                format!("{}\n", serde_json::to_string_pretty(&object(&columns, &values)).unwrap())
+            },
+            (&Output::Rows(ref columns, ref rows), true) => {
+                let objects: Vec<Value> = rows.iter().map(|row| object(columns, row)).collect();
+                format!("{}\n", serde_json::to_string_pretty(&objects).unwrap())
+            },
+            (&Output::Record(ref fields), false) => {
+                let rows: Vec<Vec<String>> = fields.iter()
+                    .map(|&(name, ref value)| vec![name.to_owned(), cell(value)])
+                    .collect();
+                table(&rows)
+            },
+            (&Output::Rows(ref columns, ref rows), false) => {
+                let mut cells = vec![columns.iter().map(|column| column.to_uppercase()).collect()];
+                cells.extend(rows.iter().map(|row| row.iter().map(cell).collect()));
+                table(&cells)
+            },
+        }
+    }
+}
+
+/// How `value` is written in a table
+fn cell(value: &Value) -> String {
+    match *value {
+        Value::Null => "-".to_owned(),
+        Value::String(ref text) => text.clone(),
+        ref other => other.to_string(),
+    }
+}
+
+/// `rows` with their columns lined up
+fn table(rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = vec![];
+    for row in rows {
+        for (i, text) in row.iter().enumerate() {
+            let len = text.chars().count();
+            if i == widths.len() {
+                widths.push(len);
+            } else if len > widths[i] {
+                widths[i] = len;
+            }
+        }
+    }
+    let mut out = String::new();
+    for row in rows {
+        let line: Vec<String> = row.iter().zip(&widths)
+            .map(|(text, &width)| format!("{:width$}", text, width = width))
+            .collect();
+        out += line.join("  ").trim_right();
+        out.push('\n');
+    }
+    out
+}
+
+/// An amount argument, as the api takes it
+fn amount(text: &str) -> Result<Value, String> {
+    if text.contains('.') {
+        return Ok(Value::String(text.to_owned()))
+    }
+    text.parse::<u64>().map(Value::from).map_err(|_| format!("bad amount {}", text))
+}
+
+/// The balance in a `/dumpbalance` response, e.g. `acct 5d4e... has
+/// balance 1250 ($12.50)`, and how it's written - everything between the
+/// parentheses, as that can have spaces (`5,00 €`, `JPY 1,234`)
+fn parse_balance(text: &str) -> Result<(i64, String), String> {
+    let parsed = text.find(" balance ").and_then(|start| {
+        let rest = &text[start + " balance ".len()..];
+        let open = rest.find(" (")?;
+        let close = open + rest[open..].find(')')?;
+        Some((rest[..open].parse().ok()?, rest[open + 2..close].to_owned()))
+    });
+    parsed.ok_or_else(|| format!("unexpected balance response: {}", text.trim()))
+}
+
+fn balance(api: &Api, account_id: &str, as_of: Option<&String>) -> Result<Output, String> {
+    let body = match as_of {
+        Some(as_of) => json!({"account_id": account_id, "as_of": as_of}),
+        None => json!({"account_id": account_id}),
+    };
+    let (_, text) = api.ok(Method::Get, "/dumpbalance", &body)?;
+    let (balance, formatted) = parse_balance(&text)?;
+    let mut fields = vec![
+        ("account_id", Value::from(account_id)),
+        ("balance", Value::from(balance)),
+        ("balance_formatted", Value::from(formatted)),
+    ];
+    if let Some(as_of) = as_of {
+        fields.push(("as_of", Value::from(&as_of[..])));
+    }
+    Ok(Output::Record(fields))
+}
+
+fn set_charge_account(api: &Api, args: &[String], enabled: bool) -> Result<Output, String> {
+    let tenant = args.get(1).map(|tenant| Value::from(&tenant[..])).unwrap_or(Value::Null);
+    let body = json!({"currency": args[0], "tenant": tenant, "enabled": enabled});
+    api.ok(Method::Post, "/currencies/chargeaccount", &body)?;
+    Ok(Output::Record(vec![
+        ("currency", Value::from(&args[0][..])),
+        ("tenant", tenant),
+        ("charge_account", Value::from(if enabled { "enabled" } else { "disabled" })),
+    ]))
+}
+
+fn run(api: &Api, command: &str, args: &[String]) -> Result<Output, String> {
+    match (command, args.len()) {
+        ("create-account", 2) => {
+            let body = json!({"currency": args[0], "holder_name": args[1]});
+            let (_, account_id) = api.ok(Method::Post, "/makeaccount", &body)?;
+            Ok(Output::Record(vec![
+                ("account_id", Value::from(account_id.trim())),
+                ("currency", Value::from(&args[0][..])),
+                ("holder_name", Value::from(&args[1][..])),
+            ]))
+        },
+        ("deposit", 2) => {
+            let body = json!({"account_id": args[0], "amount": amount(&args[1])?});
+            api.ok(Method::Post, "/deposit", &body)?;
+            balance(api, &args[0], None)
+        },
+        ("transfer", 3) => {
+            let body = json!({"account_from": args[0], "account_to": args[1], "amount": amount(&args[2])?});
+            let (status, text) = api.ok(Method::Post, "/transfer", &body)?;
+            // Held and approval-pending transfers are accepted with a note
+            // saying so
+            let outcome = if status == 200 { "transfer made".to_owned() } else { text.trim().to_owned() };
+            Ok(Output::Record(vec![
+                ("account_from", Value::from(&args[0][..])),
+                ("account_to", Value::from(&args[1][..])),
+                ("amount", amount(&args[2])?),
+                ("outcome", Value::from(outcome)),
+            ]))
+        },
+        ("balance", 1) | ("balance", 2) => balance(api, &args[0], args.get(1)),
+        ("list-accounts", 0) => {
+            let (_, text) = api.ok(Method::Get, "/accounts", &Value::Null)?;
+            let accounts: Vec<Map<String, Value>> = serde_json::from_str(&text)
+                .map_err(|err| format!("unexpected accounts response: {}", err))?;
+            let columns = vec!["account_id", "currency", "holder_name", "balance", "balance_formatted",
+                               "tenant", "kyc_level", "frozen"];
+            let rows = accounts.iter()
+                .map(|account| columns.iter().map(|&column| account.get(column).cloned().unwrap_or(Value::Null)).collect())
+                .collect();
+            Ok(Output::Rows(columns, rows))
+        },
+        ("disable-charge-account", 1) | ("disable-charge-account", 2) => set_charge_account(api, args, false),
+        ("enable-charge-account", 1) | ("enable-charge-account", 2) => set_charge_account(api, args, true),
+        ("reload-currencies", 0) => {
+            let (_, text) = api.ok(Method::Post, "/currencies/reload", &Value::Null)?;
+            let reloaded: Map<String, Value> = serde_json::from_str(&text)
+                .map_err(|err| format!("unexpected reload response: {}", err))?;
+            Ok(Output::Record(vec![("currencies", reloaded.get("currencies").cloned().unwrap_or(Value::Null))]))
+        },
+        ("export-ledger", 0) | ("export-ledger", 1) => {
+            let format = args.get(0).map_or("beancount", |format| &format[..]);
+            let (_, journal) = api.ok(Method::Get, &format!("/ledger?format={}", format), &Value::Null)?;
+            Ok(Output::Text(journal))
+        },
+        _ => usage(),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut config_path = None;
+    let mut json = false;
+    let mut i = 0;
+    while i < args.len() {
+        match &args[i][..] {
+            "--config" => match args.get(i + 1) {
+                Some(path) => { config_path = Some(path.clone()); i += 1 },
+                None => usage(),
+            },
+            "--json" => json = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    let command = match args.get(i) {
+        Some(command) => command,
+        None => usage(),
+    };
+    let config = match read_config(config_path.as_ref().map_or(DEFAULT_CONFIG, |path| &path[..]), config_path.is_some()) {
+        Ok(config) => config,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            process::exit(2)
+        },
+    };
+    let api = Api { client: Client::new(), config: config };
+    match run(&api, command, &args[i + 1..]) {
+        Ok(output) => print!("{}", output.render(json)),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            process::exit(1)
+        },
+    }
+}
+
+#[test]
+fn test_output() {
+    assert_eq!(amount("12.50"), Ok(Value::from("12.50")));
+    assert_eq!(amount("1250"), Ok(Value::from(1250u64)));
+    assert!(amount("-5").is_err());
+    assert_eq!(parse_balance("acct 5d4e has balance -1250 (-$12.50)\n"), Ok((-1250, "-$12.50".to_owned())));
+    assert_eq!(parse_balance("acct 5d4e had balance 500 (5,00 €) at 2017-01-01T00:00:00Z\n"), Ok((500, "5,00 €".to_owned())));
+    assert_eq!(parse_balance("acct 5d4e has balance 1234 (JPY 1,234)\n"), Ok((1234, "JPY 1,234".to_owned())));
+    assert!(parse_balance("user does not exist").is_err());
+
+    let rows = Output::Rows(vec!["account_id", "balance", "tenant"], vec![
+        vec![Value::from("a1"), Value::from(1250), Value::Null],
+        vec![Value::from("b22"), Value::from(5), Value::from("acme")],
+    ]);
+    assert_eq!(rows.render(false), "ACCOUNT_ID  BALANCE  TENANT\na1          1250     -\nb22         5        acme\n");
+    let parsed: Value = serde_json::from_str(&rows.render(true)).unwrap();
+    assert_eq!(parsed, json!([{"account_id": "a1", "balance": 1250, "tenant": null},
+                              {"account_id": "b22", "balance": 5, "tenant": "acme"}]));
+    let config = |url: &str, token: &str| Config { url: url.to_owned(), reviewer_token: Some(token.to_owned()), tenant_token: None };
+    assert_eq!(check_config(&config("http://localhost:3000", "abc-123")), Ok(()));
+    assert!(check_config(&config("https://quadcurr.example", "abc-123")).is_err());
+    assert!(check_config(&config("http://localhost:3000", "abc\r\nX-Injected: 1")).is_err());
+    assert!(check_config(&config("http://localhost:3000", "")).is_err());
+
+    let record = Output::Record(vec![("currencies", Value::from(3)), ("charge_account", Value::from("disabled"))]);
+    assert_eq!(record.render(false), "currencies      3\ncharge_account  disabled\n");
+    let parsed: Value = serde_json::from_str(&record.render(true)).unwrap();
+    assert_eq!(parsed, json!({"currencies": 3, "charge_account": "disabled"}));
+}
@@ -0,0 +1,452 @@
+//! Runs a QuadCurr server in a scratch directory, drives a random mix of
+//! deposits and transfers at it and checks nothing went missing.
+//!
+//!     simulate [--accounts N] [--rate OPS_PER_SEC] [--ops N] [--clients N]
+//!              [--server iron|async] [--seed N] [--port N] [--bin PATH]
+//!
+//! Accounts are spread across USD, EUR and GBP, each opened with a deposit
+//! of 1000. Operations are started at
+//! `--rate` a second by up to `--clients` at once, and their latency is
+//! counted from when they were due to start, so a server which falls
+//! behind shows up in the percentiles. Once every operation has finished:
+//!
+//!  - every account must have the balance its successful operations add
+//!    up to
+//!  - every currency must hold exactly the money deposited into it, across
+//!    all accounts including QuadCurr's own
+//!  - every charge account must have received exactly the charges for the
+//!    transfers made in its currency
+//!
+//! The last two are checked from the server's event log, exported with
+//! `export-ledger`, so QuadCurr's own accounts are counted too.
+
+extern crate hyper;
+extern crate rand;
+extern crate serde_json;
+
+use hyper::Client;
+use hyper::status::StatusCode;
+
+use rand::{Rng, SeedableRng, StdRng};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CURRENCIES: &'static [&'static str] = &["USD", "EUR", "GBP"];
+// Transfers below this are turned away, see `currency::MINIMUM_TRANSFER_AMOUNT`
+const MINIMUM_TRANSFER_AMOUNT: i64 = 50;
+// Deposited into every account before the simulation starts
+const OPENING_DEPOSIT: i64 = 1000;
+
+struct Config {
+    accounts: usize,
+    rate: f64,
+    ops: usize,
+    clients: usize,
+    server: String,
+    seed: usize,
+    port: u16,
+    bin: PathBuf,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: simulate [--accounts N] [--rate OPS_PER_SEC] [--ops N] [--clients N] \
+               [--server iron|async] [--seed N] [--port N] [--bin PATH]");
+    process::exit(2)
+}
+
+fn parse_args() -> Config {
+    // The server is built alongside this
+    let mut bin = env::current_exe().unwrap();
+    bin.set_file_name("underhanded-rs");
+    let mut config = Config {
+        accounts: 300,
+        rate: 50.0,
+        ops: 2000,
+        clients: 64,
+        server: "iron".to_owned(),
+        seed: 1,
+        port: 3400,
+        bin: bin,
+    };
+    let args: Vec<String> = env::args().skip(1).collect();
+    for pair in args.chunks(2) {
+        let value = match pair.get(1) {
+            Some(value) => value,
+            None => usage(),
+        };
+        let ok = match &pair[0][..] {
+            "--accounts" => value.parse().map(|n| config.accounts = n).is_ok(),
+            "--rate" => value.parse().map(|n| config.rate = n).is_ok(),
+            "--ops" => value.parse().map(|n| config.ops = n).is_ok(),
+            "--clients" => value.parse().map(|n| config.clients = n).is_ok(),
+            "--server" => { config.server = value.clone(); true },
+            "--seed" => value.parse().map(|n| config.seed = n).is_ok(),
+            "--port" => value.parse().map(|n| config.port = n).is_ok(),
+            "--bin" => { config.bin = PathBuf::from(value); true },
+            _ => false,
+        };
+        if !ok {
+            usage()
+        }
+    }
+    if config.accounts < 2 * CURRENCIES.len() || config.rate <= 0.0 || config.clients == 0 {
+        usage()
+    }
+    config
+}
+
+/// The server being simulated against, stopped and cleaned up when dropped
+struct Server {
+    child: Child,
+    dir: PathBuf,
+    addr: String,
+    url: String,
+}
+impl Server {
+    fn start(config: &Config) -> Result<Server, String> {
+        let dir = env::temp_dir().join(format!("quadcurr-sim-{}", process::id()));
+        fs::create_dir_all(&dir).map_err(|err| format!("can't make {}: {}", dir.display(), err))?;
+        fs::copy("currencies.json", dir.join("currencies.json"))
+            .map_err(|err| format!("can't copy currencies.json: {}", err))?;
+        let log = File::create(dir.join("server.out")).unwrap();
+        let child = Command::new(&config.bin)
+            .current_dir(&dir)
+            .env("QUADCURR_HTTP_SERVER", &config.server)
+            .env("QUADCURR_HTTP_ADDR", format!("127.0.0.1:{}", config.port))
+            .env("QUADCURR_REPLICATION_ADDR", format!("127.0.0.1:{}", config.port + 1))
+            .stdout(log)
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|err| format!("can't run {}: {}", config.bin.display(), err))?;
+        let addr = format!("127.0.0.1:{}", config.port);
+        let server = Server { child: child, dir: dir, url: format!("http://{}", addr), addr: addr };
+
+        let client = Client::new();
+        for _ in 0..50 {
+            if let Ok(StatusCode::Ok) = client.get(&format!("{}/readyz", server.url)).send().map(|res| res.status) {
+                return Ok(server)
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Err(format!("server not ready, see {}", server.dir.join("server.out").display()))
+    }
+}
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn post(client: &Client, url: &str, body: &str) -> Result<(StatusCode, String), String> {
+    let mut res = client.post(url).body(body).send().map_err(|err| err.to_string())?;
+    let mut text = String::new();
+    res.read_to_string(&mut text).map_err(|err| err.to_string())?;
+    Ok((res.status, text))
+}
+
+/// GET `path` with a body, which the hyper client won't send. Returns the
+/// status line and the body of the response.
+fn get_with_body(addr: &str, path: &str, body: &str) -> Result<(String, String), String> {
+    let mut stream = TcpStream::connect(addr).map_err(|err| err.to_string())?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           path, addr, body.len(), body).map_err(|err| err.to_string())?;
+    let mut res = String::new();
+    stream.read_to_string(&mut res).map_err(|err| err.to_string())?;
+    let status = res.lines().next().unwrap_or("").to_owned();
+    let text = res.splitn(2, "\r\n\r\n").nth(1).unwrap_or("").to_owned();
+    Ok((status, text))
+}
+
+#[derive(Clone)]
+struct Account {
+    id: String,
+    currency: usize,
+}
+
+enum Op {
+    Deposit { account: usize, amount: i64 },
+    Transfer { from: usize, to: usize, amount: i64 },
+}
+impl Op {
+    fn kind(&self) -> &'static str {
+        match *self {
+            Op::Deposit { .. } => "deposit",
+            Op::Transfer { .. } => "transfer",
+        }
+    }
+}
+
+/// How an operation went, and how long it took from when it was due
+struct Outcome {
+    op: Op,
+    /// Status code, or why no response came back
+    result: Result<StatusCode, String>,
+    latency: Duration,
+}
+
+/// Pick a random operation: a deposit of up to 1000, or a transfer of up to
+/// 200 between two accounts in the same currency
+fn random_op<R: Rng>(rng: &mut R, accounts: &[Account]) -> Op {
+    let account = rng.gen_range(0, accounts.len());
+    if rng.gen_range(0, 10) < 3 {
+        return Op::Deposit { account: account, amount: rng.gen_range(1, 1001) }
+    }
+    // Accounts are opened round-robin across currencies, so those in the
+    // same currency are every `CURRENCIES.len()`th one. Pick any but the
+    // sender.
+    let currency = accounts[account].currency;
+    let in_currency = (accounts.len() - currency + CURRENCIES.len() - 1) / CURRENCIES.len();
+    let mut to = currency + rng.gen_range(0, in_currency - 1) * CURRENCIES.len();
+    if to >= account {
+        to += CURRENCIES.len();
+    }
+    Op::Transfer { from: account, to: to, amount: rng.gen_range(MINIMUM_TRANSFER_AMOUNT, 201) }
+}
+
+fn run_op(client: &Client, url: &str, accounts: &[Account], op: &Op) -> Result<StatusCode, String> {
+    let (path, body) = match *op {
+        Op::Deposit { account, amount } =>
+            ("deposit", format!(r#"{{"account_id": "{}", "amount": {}}}"#, accounts[account].id, amount)),
+        Op::Transfer { from, to, amount } =>
+            ("transfer", format!(r#"{{"account_from": "{}", "account_to": "{}", "amount": {}}}"#,
+                                 accounts[from].id, accounts[to].id, amount)),
+    };
+    post(client, &format!("{}/{}", url, path), &body).map(|(status, _)| status)
+}
+
+/// Start `ops` operations at `rate` a second, run by `clients` threads
+fn drive(config: &Config, url: &str, accounts: Arc<Vec<Account>>) -> Vec<Outcome> {
+    let (op_tx, op_rx): (Sender<(Op, Instant)>, Receiver<(Op, Instant)>) = mpsc::channel();
+    let (outcome_tx, outcome_rx) = mpsc::channel();
+    let op_rx = Arc::new(Mutex::new(op_rx));
+    for _ in 0..config.clients {
+        let (op_rx, outcome_tx, accounts, url) = (op_rx.clone(), outcome_tx.clone(), accounts.clone(), url.to_owned());
+        thread::spawn(move || {
+            let client = Client::new();
+            loop {
+                let next = op_rx.lock().unwrap().recv();
+                let (op, due) = match next {
+                    Ok(next) => next,
+                    Err(_) => return,
+                };
+                let result = run_op(&client, &url, &accounts, &op);
+                outcome_tx.send(Outcome { op: op, result: result, latency: due.elapsed() }).unwrap();
+            }
+        });
+    }
+    drop(outcome_tx);
+
+    let mut rng = StdRng::from_seed(&[config.seed][..]);
+    let start = Instant::now();
+    for i in 0..config.ops {
+        let offset = i as f64 / config.rate;
+        let due = start + Duration::new(offset as u64, (offset.fract() * 1e9) as u32);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        op_tx.send((random_op(&mut rng, &accounts), due)).unwrap();
+    }
+    drop(op_tx);
+    outcome_rx.iter().collect()
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+fn report(outcomes: &[Outcome]) {
+    for &kind in &["deposit", "transfer"] {
+        let of_kind: Vec<&Outcome> = outcomes.iter().filter(|outcome| outcome.op.kind() == kind).collect();
+        if of_kind.is_empty() {
+            continue
+        }
+        let mut latencies: Vec<Duration> = of_kind.iter().map(|outcome| outcome.latency).collect();
+        latencies.sort();
+        let percentile = |p: usize| seconds(latencies[(latencies.len() - 1) * p / 100]) * 1000.0;
+        let mut results: BTreeMap<String, usize> = BTreeMap::new();
+        for outcome in &of_kind {
+            let result = match outcome.result {
+                Ok(status) => status.to_string(),
+                Err(ref err) => format!("failed: {}", err),
+            };
+            *results.entry(result).or_insert(0) += 1;
+        }
+        let errors = of_kind.iter().filter(|outcome| !outcome.result.as_ref().map_or(false, |status| status.is_success())).count();
+        println!("{}: {} made, {:.1}% errors", kind, of_kind.len(), errors as f64 * 100.0 / of_kind.len() as f64);
+        println!("  latency ms: p50 {:.1}, p90 {:.1}, p99 {:.1}, max {:.1}",
+                 percentile(50), percentile(90), percentile(99), percentile(100));
+        for (result, count) in results {
+            println!("  {}: {}", result, count);
+        }
+    }
+}
+
+/// What the server should hold once every operation has finished
+struct Expected {
+    balances: Vec<i64>,
+    deposits: Vec<i64>,
+    charges: Vec<i64>,
+}
+
+/// Work out `Expected` from the operations the server said it made. Held
+/// transfers (`202`) are counted as never made, so they show up as a
+/// mismatch - nothing in a fresh server should hold them.
+fn expected(outcomes: &[Outcome], accounts: &[Account], transfer_charges: &[f64]) -> Expected {
+    let mut expected = Expected {
+        balances: vec![OPENING_DEPOSIT; accounts.len()],
+        deposits: vec![0; CURRENCIES.len()],
+        charges: vec![0; CURRENCIES.len()],
+    };
+    for account in accounts {
+        expected.deposits[account.currency] += OPENING_DEPOSIT;
+    }
+    for outcome in outcomes {
+        if outcome.result != Ok(StatusCode::Ok) {
+            continue
+        }
+        match outcome.op {
+            Op::Deposit { account, amount } => {
+                expected.balances[account] += amount;
+                expected.deposits[accounts[account].currency] += amount;
+            },
+            Op::Transfer { from, to, amount } => {
+                let currency = accounts[from].currency;
+                let charge = f64::ceil(transfer_charges[currency] / 100.0 * amount as f64) as i64;
+                expected.balances[from] -= amount + charge;
+                expected.balances[to] += amount;
+                expected.charges[currency] += charge;
+            },
+        }
+    }
+    expected
+}
+
+/// Balances of every account, by name and currency, from the server's
+/// event log
+fn ledger_balances(config: &Config, dir: &PathBuf) -> Result<Vec<(String, String, i64)>, String> {
+    let output = Command::new(&config.bin)
+        .args(&["export-ledger", "beancount"])
+        .arg(dir.join("events.log"))
+        .output()
+        .map_err(|err| format!("can't export the event log: {}", err))?;
+    if !output.status.success() {
+        return Err(format!("export-ledger failed: {}", String::from_utf8_lossy(&output.stderr)))
+    }
+    let mut balances = vec![];
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        // e.g. `2017-03-02 balance Income:TransferCharges:GBP  -0.02 GBP`,
+        // signed from QuadCurr's point of view. Amounts are in major units
+        // with every minor digit written, so dropping the point gives minor
+        // units.
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() == 5 && words[1] == "balance" {
+            let amount: i64 = words[3].replace('.', "").parse().map_err(|_| format!("bad balance line: {}", line))?;
+            balances.push((words[2].to_owned(), words[4].to_owned(), -amount));
+        }
+    }
+    Ok(balances)
+}
+
+fn check(config: &Config, server: &Server, accounts: &[Account], expected: &Expected) -> Result<Vec<String>, String> {
+    let mut failures = vec![];
+    for (account, &balance) in accounts.iter().zip(&expected.balances) {
+        let body = format!(r#"{{"account_id": "{}"}}"#, account.id);
+        let (status, text) = get_with_body(&server.addr, "/dumpbalance", &body)?;
+        // e.g. `acct 123 has balance 1250 ($12.50)`
+        let actual = text.split_whitespace().skip_while(|&word| word != "balance").nth(1)
+            .and_then(|word| word.parse::<i64>().ok());
+        if actual != Some(balance) {
+            failures.push(format!("account {}: got {} {:?}, expected balance {}", account.id, status, text.trim(), balance));
+        }
+    }
+
+    let balances = ledger_balances(config, &server.dir)?;
+    for (currency, name) in CURRENCIES.iter().enumerate() {
+        let total: i64 = balances.iter().filter(|&&(_, ref cur, _)| cur == name).map(|&(_, _, amount)| amount).sum();
+        if total != expected.deposits[currency] {
+            failures.push(format!("{} accounts hold {} in total, but {} was deposited", name, total, expected.deposits[currency]));
+        }
+        let charge_account = format!("Income:TransferCharges:{}", name);
+        let charges = balances.iter().find(|&&(ref account, _, _)| *account == charge_account).map(|&(_, _, amount)| amount);
+        if charges != Some(expected.charges[currency]) {
+            failures.push(format!("{} charge account has {:?}, expected {}", name, charges, expected.charges[currency]));
+        }
+    }
+    Ok(failures)
+}
+
+/// Transfer charge percentage of each of `CURRENCIES`, from `currencies.json`
+fn transfer_charges() -> Result<Vec<f64>, String> {
+    let file = File::open("currencies.json").map_err(|err| format!("can't open currencies.json: {}", err))?;
+    let config: serde_json::Value = serde_json::from_reader(file).map_err(|err| format!("bad currencies.json: {}", err))?;
+    CURRENCIES.iter().map(|name| {
+        config.as_array()
+            .and_then(|currencies| currencies.iter().find(|currency| currency["name"].as_str() == Some(name)))
+            .and_then(|currency| currency["transfer_charge"].as_f64())
+            .ok_or(format!("no transfer charge for {} in currencies.json", name))
+    }).collect()
+}
+
+fn simulate(config: &Config) -> Result<bool, String> {
+    let transfer_charges = transfer_charges()?;
+    let server = Server::start(config)?;
+    println!("Simulating against the {} server at {}", config.server, server.url);
+
+    let client = Client::new();
+    let mut accounts = vec![];
+    for i in 0..config.accounts {
+        let currency = i % CURRENCIES.len();
+        let body = format!(r#"{{"currency": "{}", "holder_name": "Simulated Holder {}"}}"#, CURRENCIES[currency], i);
+        let id = match post(&client, &format!("{}/makeaccount", server.url), &body)? {
+            (StatusCode::Ok, id) => id,
+            (status, msg) => return Err(format!("makeaccount returned {}: {}", status, msg)),
+        };
+        let body = format!(r#"{{"account_id": "{}", "amount": {}}}"#, id, OPENING_DEPOSIT);
+        match post(&client, &format!("{}/deposit", server.url), &body)? {
+            (StatusCode::Ok, _) => accounts.push(Account { id: id, currency: currency }),
+            (status, msg) => return Err(format!("deposit returned {}: {}", status, msg)),
+        }
+    }
+    println!("Opened {} accounts, starting {} operations at {}/s", accounts.len(), config.ops, config.rate);
+
+    let accounts = Arc::new(accounts);
+    let start = Instant::now();
+    let outcomes = drive(config, &server.url, accounts.clone());
+    println!("Finished in {:.1}s", seconds(start.elapsed()));
+    report(&outcomes);
+
+    let expected = expected(&outcomes, &accounts, &transfer_charges);
+    let failures = check(config, &server, &accounts, &expected)?;
+    for failure in &failures {
+        println!("FAIL: {}", failure);
+    }
+    if failures.is_empty() {
+        println!("Money is conserved and every charge is accounted for");
+    }
+    Ok(failures.is_empty())
+}
+
+fn main() {
+    let config = parse_args();
+    match simulate(&config) {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            process::exit(1)
+        },
+    }
+}
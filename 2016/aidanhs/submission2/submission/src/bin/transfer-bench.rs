@@ -0,0 +1,123 @@
+//! Measures how many transfers a running server makes per second when
+//! several clients transfer at once.
+//!
+//!     transfer-bench [--shared] [URL] [THREADS] [TRANSFERS]
+//!
+//! Each of `THREADS` clients makes `TRANSFERS` transfers back and forth
+//! between two accounts of its own, so no two clients touch the same
+//! account. With `--shared` every client uses the same two accounts
+//! instead, showing how transfers between the same accounts queue up.
+//! `URL` defaults to `http://localhost:3000`.
+
+extern crate hyper;
+
+use hyper::Client;
+use hyper::status::StatusCode;
+
+use std::env;
+use std::io::Read;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+// Enough for every transfer to be made without going overdrawn
+const OPENING_DEPOSIT: u64 = 1_000_000;
+const TRANSFER_AMOUNT: u64 = 100;
+
+struct Bench {
+    client: Client,
+    url: String,
+}
+impl Bench {
+    fn post(&self, path: &str, body: &str) -> Result<(StatusCode, String), String> {
+        let mut res = self.client.post(&format!("{}/{}", self.url, path)).body(body).send()
+            .map_err(|err| format!("{} failed: {}", path, err))?;
+        let mut text = String::new();
+        res.read_to_string(&mut text).map_err(|err| format!("{} failed: {}", path, err))?;
+        Ok((res.status, text))
+    }
+
+    /// Open an account and deposit into it, returning its id
+    fn make_account(&self, holder_name: &str) -> Result<String, String> {
+        let body = format!(r#"{{"currency": "GBP", "holder_name": "{}"}}"#, holder_name);
+        let account_id = match self.post("makeaccount", &body)? {
+            (StatusCode::Ok, account_id) => account_id,
+            (status, msg) => return Err(format!("makeaccount returned {}: {}", status, msg)),
+        };
+        let body = format!(r#"{{"account_id": "{}", "amount": {}}}"#, account_id, OPENING_DEPOSIT);
+        match self.post("deposit", &body)? {
+            (StatusCode::Ok, _) => Ok(account_id),
+            (status, msg) => Err(format!("deposit returned {}: {}", status, msg)),
+        }
+    }
+
+    /// Make `count` transfers between `pair`, alternating direction so
+    /// neither account runs dry
+    fn transfers(&self, pair: &(String, String), count: usize) -> Result<(), String> {
+        for i in 0..count {
+            let (from, to) = if i % 2 == 0 { (&pair.0, &pair.1) } else { (&pair.1, &pair.0) };
+            let body = format!(r#"{{"account_from": "{}", "account_to": "{}", "amount": {}}}"#,
+                               from, to, TRANSFER_AMOUNT);
+            match self.post("transfer", &body)? {
+                (StatusCode::Ok, _) | (StatusCode::Accepted, _) => (),
+                (status, msg) => return Err(format!("transfer returned {}: {}", status, msg)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: transfer-bench [--shared] [URL] [THREADS] [TRANSFERS]");
+    process::exit(2)
+}
+
+fn run(bench: Arc<Bench>, threads: usize, transfers: usize, shared: bool) -> Result<(), String> {
+    let num_pairs = if shared { 1 } else { threads };
+    let mut pairs = vec![];
+    for i in 0..num_pairs {
+        let pair = (bench.make_account(&format!("Bench Sender {}", i))?,
+                    bench.make_account(&format!("Bench Receiver {}", i))?);
+        pairs.push(Arc::new(pair));
+    }
+
+    let start = Instant::now();
+    let workers: Vec<_> = (0..threads).map(|i| {
+        let (bench, pair) = (bench.clone(), pairs[i % num_pairs].clone());
+        thread::spawn(move || bench.transfers(&pair, transfers))
+    }).collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+    let elapsed = start.elapsed();
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+    let total = threads * transfers;
+    println!("{} transfers from {} threads over {} account pair(s) in {:.2}s: {:.1} transfers/s",
+             total, threads, num_pairs, secs, total as f64 / secs);
+    Ok(())
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let shared = match args.iter().position(|arg| arg == "--shared") {
+        Some(pos) => { args.remove(pos); true },
+        None => false,
+    };
+    if args.len() > 3 {
+        usage()
+    }
+    let url = args.first().cloned().unwrap_or("http://localhost:3000".to_owned());
+    let threads = args.get(1).map_or(Ok(8), |arg| arg.parse()).unwrap_or_else(|_| usage());
+    let transfers = args.get(2).map_or(Ok(5), |arg| arg.parse()).unwrap_or_else(|_| usage());
+    if threads == 0 || transfers == 0 {
+        usage()
+    }
+
+    let bench = Arc::new(Bench { client: Client::new(), url: url.trim_end_matches('/').to_owned() });
+    if let Err(msg) = run(bench, threads, transfers, shared) {
+        eprintln!("{}", msg);
+        process::exit(1)
+    }
+}
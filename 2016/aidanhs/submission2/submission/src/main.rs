@@ -1,7 +1,13 @@
 extern crate iron;
 extern crate router;
 
+extern crate chrono;
 extern crate crossbeam;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate hmac;
+extern crate hyper;
+extern crate hyper_async;
 #[macro_use]
 extern crate lazy_static;
 
@@ -9,113 +15,1331 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate rand;
+extern crate sha2;
+extern crate tokio_core;
+extern crate unicode_normalization;
+extern crate uuid;
 
-use std::sync::RwLock;
+#[cfg(test)]
+extern crate proptest;
+#[cfg(all(test, loom))]
+extern crate loom;
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+#[cfg(test)]
+use std::i64;
+use std::ops::DerefMut;
+use std::path::Path;
+use std::process;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::time::Instant;
 
+use chrono::UTC;
+
 use iron::prelude::Iron;
 
 use router::Router;
 
-#[derive(Debug)]
+use audit::Audited;
+use metrics::Metered;
+use webhook::Notifying;
+use replication::ReadOnly;
+use sync::{Mutex, MutexGuard};
+use tenants::Tenanted;
+
+use uuid::Uuid;
+
+#[derive(Clone, Debug)]
 pub struct UserAccount {
     /// Balance in the base units of the currency, e.g. cents
     balance: i64,
+    /// Name of the account holder, screened against sanctions lists. System
+    /// accounts, and accounts opened before names were asked for, have none.
+    holder_name: Option<String>,
     /// Currency for this account
-    currency: &'static currency::CurrencyDetail,
+    currency: currency::CurrencyId,
     /// Times of 'recent' transfers
     recent_transfers: Vec<Instant>,
+    /// Every change to the balance, oldest first
+    history: Vec<Entry>,
+    /// Yearly interest rate in basis points, overriding the currency's
+    interest_rate_bps: Option<u32>,
+    /// Interest accrued but not yet paid, in millionths of a base unit
+    accrued_interest: i64,
+    /// Why the account is frozen, if it is. Frozen accounts can still be
+    /// paid, but can't send money.
+    frozen: Option<String>,
+    /// Tenant whose namespace the account is in, see `tenants`. None for
+    /// the operator's own accounts and system accounts.
+    tenant: Option<String>,
+    /// How far the holder's identity has been verified, which decides the
+    /// limits the account is held to
+    kyc_level: kyc::Level,
 }
 impl UserAccount {
     fn new(currency: &str) -> Option<UserAccount> {
-        if let Some(cur) = currency::lookup_currency(currency) {
-            Some(UserAccount {
-                currency: cur,
-                balance: 0,
-                recent_transfers: vec![],
-            })
-        } else {
-            None
-        }
+        currency::lookup_currency(currency)
+            .map(|cur| UserAccount::with_currency(cur.id))
     }
-    fn fakeacct() -> UserAccount {
+    fn with_currency(currency: currency::CurrencyId) -> UserAccount {
         UserAccount {
-            currency: &currency::FAKE_CURRENCY,
+            currency: currency,
             balance: 0,
+            holder_name: None,
             recent_transfers: vec![],
+            history: vec![],
+            interest_rate_bps: None,
+            accrued_interest: 0,
+            frozen: None,
+            tenant: None,
+            kyc_level: kyc::Level::Unverified,
+        }
+    }
+    fn fakeacct() -> UserAccount {
+        UserAccount::with_currency(currency::FAKE_CURRENCY)
+    }
+}
+
+/// What caused a change to an account balance
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EntryKind {
+    Deposit,
+    TransferIn,
+    TransferOut,
+    /// Transfer charge, paid by the sender to a charge account
+    Charge,
+    /// Funds moved into a review hold account while a flagged transfer is
+    /// reviewed
+    Hold,
+    /// Held funds returned to the sender of a rejected transfer
+    Release,
+    /// Interest paid from a currency's interest expense account
+    Interest,
+    /// Funds moved into an escrow account until released to the seller
+    Escrow,
+    /// Escrowed funds returned to the buyer
+    EscrowRefund,
+    /// Disputed transfer provisionally paid back to the sender by the
+    /// recipient
+    Dispute,
+    /// Provisional payment for a dispute returned to the recipient
+    DisputeReversal,
+    /// Paid by the recipient of a transfer when the sender wins a dispute
+    ChargebackFee,
+}
+impl EntryKind {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            EntryKind::Deposit => "deposit",
+            EntryKind::TransferIn => "transfer in",
+            EntryKind::TransferOut => "transfer out",
+            EntryKind::Charge => "transfer charge",
+            EntryKind::Hold => "held for review",
+            EntryKind::Release => "released from review",
+            EntryKind::Interest => "interest",
+            EntryKind::Escrow => "held in escrow",
+            EntryKind::EscrowRefund => "refunded from escrow",
+            EntryKind::Dispute => "disputed",
+            EntryKind::DisputeReversal => "dispute reversed",
+            EntryKind::ChargebackFee => "chargeback fee",
+        }
+    }
+}
+
+/// A change to an account balance, as kept in the account history
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Seconds since the unix epoch
+    pub time: i64,
+    pub kind: EntryKind,
+    pub amount: i64,
+    /// Internal index of the other account involved, if any
+    pub counterparty: Option<usize>,
+    /// Balance after the change
+    pub balance: i64,
+}
+
+/// A balance change to apply with `UserDB::apply_postings`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Posting {
+    pub account: usize,
+    pub amount: i64,
+    pub kind: EntryKind,
+    /// Internal index of the other account involved, if any
+    pub counterparty: Option<usize>,
+}
+impl Posting {
+    fn new(account: usize, amount: i64, kind: EntryKind, counterparty: usize) -> Posting {
+        Posting { account: account, amount: amount, kind: kind, counterparty: Some(counterparty) }
+    }
+}
+
+/// A transfer between two accounts, along with the charge the sender pays
+/// on top
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct TransferPlan {
+    pub from: usize,
+    pub to: usize,
+    pub amount: i64,
+    pub charge_amount: i64,
+    pub charge_account: usize,
+}
+impl TransferPlan {
+    /// Plan a transfer in the given currency. `None` if the total taken from
+    /// `from` can't be represented.
+    fn new(from: usize, to: usize, amount: i64,
+           currency_detail: &currency::CurrencyDetail) -> Option<TransferPlan> {
+        let charge_amount = currency_detail.transfer_charge_for(amount);
+        amount.checked_add(charge_amount).map(|_| TransferPlan {
+            from: from,
+            to: to,
+            amount: amount,
+            charge_amount: charge_amount,
+            charge_account: currency_detail.transfer_charge_account,
+        })
+    }
+    /// Total taken from the sender, including the transfer charge
+    fn total(&self) -> i64 {
+        self.amount + self.charge_amount
+    }
+    /// Postings which make the transfer, paying out of `source` - normally
+    /// the sender, but a review hold account once the sender has been debited
+    fn postings(&self, source: usize) -> Vec<Posting> {
+        let mut postings = vec![
+            Posting::new(source, -self.amount, EntryKind::TransferOut, self.to),
+            Posting::new(self.to, self.amount, EntryKind::TransferIn, self.from),
+        ];
+        if self.charge_amount != 0 {
+            postings.push(Posting::new(source, -self.charge_amount, EntryKind::Charge, self.charge_account));
+            postings.push(Posting::new(self.charge_account, self.charge_amount, EntryKind::Charge, self.from));
         }
+        postings
+    }
+}
+
+/// Seconds since the unix epoch
+fn unix_now() -> i64 {
+    UTC::now().timestamp()
+}
+
+/// Account id handed out to api users. These are random so they can't be
+/// guessed or enumerated, and are never the same as the internal index of
+/// the account in the `UserDB`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AccountId(Uuid);
+impl AccountId {
+    fn new() -> AccountId {
+        AccountId(Uuid::new_v4())
+    }
+    fn parse(account_id: &str) -> Option<AccountId> {
+        Uuid::parse_str(account_id).ok().map(AccountId)
+    }
+    /// How the account is shown to anyone but its holder. The id is all it
+    /// takes to use the account, so only its last four characters are shown.
+    fn masked(&self) -> String {
+        let id = self.to_string();
+        format!("****{}", &id[id.len() - 4..])
+    }
+}
+impl fmt::Display for AccountId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.hyphenated())
     }
 }
 
+/// Accounts are stored by internal index, which api users never see. Accounts
+/// made for api users get an `AccountId` mapping to their index - system
+/// accounts (e.g. for transfer charges) don't, so api users cannot name them.
+///
+/// Every change goes into the event log (see `events`) as well, so the
+/// `UserDB` can be rebuilt from it. Methods which don't record an event are
+/// for replaying the log.
+///
+/// Each account has its own lock. Anything holding the `USERDB` write lock
+/// can change any account, but transfers and deposits only take the read
+/// lock and then lock the accounts they need (see `lock_many`), so ones
+/// touching different accounts go ahead in parallel.
 struct UserDB {
-    accts: Vec<UserAccount>,
+    accts: Vec<Mutex<UserAccount>>,
+    public_ids: HashMap<AccountId, usize>,
+    /// Public id of each account, by internal index
+    acct_ids: Vec<Option<AccountId>>,
+    /// Time of the latest change, see `UserDB::now`
+    last_time: AtomicI64,
+    /// Last day (counting from the unix epoch) interest was accrued for
+    last_accrual_day: Option<i64>,
+    /// Every escrow ever opened, by id
+    escrows: BTreeMap<u64, escrow::Escrow>,
+    /// Every transfer ever held for review. Transfers are held under the
+    /// `USERDB` read lock, so this has a lock of its own, taken after the
+    /// sender's account and before any system account.
+    reviews: ::std::sync::Mutex<review::ReviewQueue>,
+    /// Every dispute ever opened, by id
+    disputes: BTreeMap<u64, disputes::Dispute>,
 }
 impl UserDB {
-    fn addacct(&mut self, acct: UserAccount) -> usize {
-        let id = self.accts.len();
-        self.accts.push(acct);
-        id
+    fn new() -> UserDB {
+        UserDB {
+            accts: vec![],
+            public_ids: HashMap::new(),
+            acct_ids: vec![],
+            last_time: AtomicI64::new(0),
+            last_accrual_day: None,
+            escrows: BTreeMap::new(),
+            reviews: ::std::sync::Mutex::new(review::ReviewQueue::new()),
+            disputes: BTreeMap::new(),
+        }
+    }
+    /// Seconds since the unix epoch, but never earlier than the last change
+    /// so that history and the event log stay in time order even if the
+    /// clock goes back
+    fn now(&self) -> i64 {
+        let now = unix_now();
+        cmp::max(self.last_time.fetch_max(now, Ordering::SeqCst), now)
+    }
+    /// Record `change` in the event log, then make it with `make`, given
+    /// the time of its event. The caller checks first that the change can be
+    /// made - if its event can't be written nothing changes, so nothing is
+    /// made which isn't in the log.
+    fn commit<T, F>(&mut self, change: events::Change, make: F) -> Result<T, UserDBError>
+            where F: FnOnce(&mut UserDB, i64) -> T {
+        let time = events::record(self, change)?;
+        Ok(make(self, time))
+    }
+    /// `commit` a change which makes `postings`, checking first that they can
+    /// be made. `make` makes the rest of the change. Returns the webhooks to
+    /// send for the postings.
+    fn commit_postings<F>(&mut self, postings: &[Posting], change: events::Change,
+                          make: F) -> Result<Vec<webhook::AccountEvent>, UserDBError>
+            where F: FnOnce(&mut UserDB, i64) {
+        let ids = posting_accounts(postings);
+        let deltas = posting_deltas(&self.get_many_mut(&ids)?, &ids, postings)?;
+        self.commit(change, move |userdb, time| {
+            let account_events = {
+                let mut accts = borrow_many(&mut userdb.accts, &ids).unwrap();
+                post_checked(&mut accts, &ids, &userdb.acct_ids, postings, &deltas, time)
+            };
+            make(userdb, time);
+            account_events
+        })
+    }
+    /// Add an account for an api user, returning its new public id
+    fn addacct(&mut self, acct: UserAccount) -> Result<AccountId, UserDBError> {
+        let mut id = AccountId::new();
+        while self.public_ids.contains_key(&id) {
+            id = AccountId::new();
+        }
+        self.record_account(acct, Some(id))?;
+        Ok(id)
+    }
+    /// Add a system account, returning its internal index
+    fn addsysacct(&mut self, acct: UserAccount) -> Result<usize, UserDBError> {
+        self.record_account(acct, None)
+    }
+    fn record_account(&mut self, acct: UserAccount, id: Option<AccountId>) -> Result<usize, UserDBError> {
+        let change = events::Change::OpenAccount {
+            account: self.accts.len(),
+            account_id: id.map(|id| id.to_string()),
+            currency: acct.currency,
+            holder_name: acct.holder_name.clone(),
+            tenant: acct.tenant.clone(),
+        };
+        self.commit(change, move |userdb, _| userdb.open_account(acct, id))
+    }
+    /// Add an account without recording an event, returning its internal index
+    fn open_account(&mut self, acct: UserAccount, id: Option<AccountId>) -> usize {
+        let idx = self.accts.len();
+        self.accts.push(Mutex::new(acct));
+        self.acct_ids.push(id);
+        if let Some(id) = id {
+            self.public_ids.insert(id, idx);
+        }
+        idx
     }
-    fn get(&self, id: usize) -> Option<&UserAccount> {
-        self.accts.get(id)
+    /// Find the internal index of the account an api user refers to by
+    /// `account_id`. System accounts are never found, and a `tenant` only
+    /// finds accounts in its own namespace - the operator, with no tenant,
+    /// finds any.
+    fn lookup(&self, tenant: &Option<String>, account_id: &str) -> Option<usize> {
+        let idx = AccountId::parse(account_id).and_then(|id| self.public_ids.get(&id).cloned())?;
+        match *tenant {
+            Some(_) if self.get(idx).unwrap().tenant != *tenant => None,
+            _ => Some(idx),
+        }
+    }
+    /// Find the account `account_id` for the account at `from` to send
+    /// money to - one in the same namespace, or in a tenant the sender's
+    /// tenant is allowed to pay
+    fn lookup_payee(&self, from: usize, account_id: &str) -> Option<usize> {
+        let from_tenant = self.get(from)?.tenant.clone();
+        self.lookup(&None, account_id)
+            .filter(|&to| tenants::may_pay(&from_tenant, &self.get(to).unwrap().tenant))
+    }
+    /// The public id of the account at internal index `idx`, if it has one
+    fn public_id(&self, idx: usize) -> Option<AccountId> {
+        self.acct_ids.get(idx).and_then(|&id| id)
+    }
+    /// Lock a single account. Don't lock another while holding it, use
+    /// `lock_many` instead.
+    fn get(&self, id: usize) -> Option<MutexGuard<UserAccount>> {
+        self.accts.get(id).map(|acct| acct.lock().unwrap())
     }
     fn get_mut(&mut self, id: usize) -> Option<&mut UserAccount> {
-        self.accts.get_mut(id)
-    }
-    fn get2_mut(&mut self, id1: usize, id2: usize) -> (Option<&mut UserAccount>, Option<&mut UserAccount>) {
-        if id1 > id2 {
-            let (as1, as2) = self.accts.split_at_mut(id1);
-            (as2.get_mut(0), as1.get_mut(id2))
-        } else if id1 < id2 {
-            let (as1, as2) = self.accts.split_at_mut(id2);
-            (as1.get_mut(id1), as2.get_mut(0))
-        } else {
-            panic!()
+        self.accts.get_mut(id).map(|acct| acct.get_mut().unwrap())
+    }
+    /// Balance of every account, by internal index
+    fn balances(&self) -> Vec<i64> {
+        self.accts.iter().map(|acct| acct.lock().unwrap().balance).collect()
+    }
+    /// Mutably borrow several distinct accounts at once, returned in the same
+    /// order as `ids`
+    fn get_many_mut(&mut self, ids: &[usize]) -> Result<Vec<&mut UserAccount>, UserDBError> {
+        borrow_many(&mut self.accts, ids)
+    }
+    /// Lock several distinct accounts at once, returned in the same order as
+    /// `ids`. They're always locked in index order, so threads locking
+    /// accounts in common can't deadlock.
+    fn lock_many(&self, ids: &[usize]) -> Result<LockedAccounts, UserDBError> {
+        let order = index_order(ids, self.accts.len())?;
+        let mut found: Vec<Option<MutexGuard<UserAccount>>> = ids.iter().map(|_| None).collect();
+        for pos in order {
+            found[pos] = Some(self.accts[ids[pos]].lock().unwrap());
+        }
+        Ok(LockedAccounts {
+            userdb: self,
+            ids: ids.to_vec(),
+            accts: found.into_iter().map(Option::unwrap).collect(),
+        })
+    }
+    /// Apply a batch of `(account, change in balance)` deltas. Either every
+    /// balance is updated, or none are and the reason is returned.
+    #[cfg(test)]
+    fn apply_deltas(&mut self, deltas: &[(usize, i64)]) -> Result<(), UserDBError> {
+        let ids: Vec<usize> = deltas.iter().map(|&(id, _)| id).collect();
+        let amounts: Vec<i64> = deltas.iter().map(|&(_, delta)| delta).collect();
+        add_to_balances(&mut self.get_many_mut(&ids)?, &ids, &amounts)
+    }
+    /// Apply a batch of postings, recording each one in the history of its
+    /// account. Postings to the same account are combined, then applied
+    /// like `apply_deltas` - either all of them or none. Webhooks registered
+    /// for the accounts are sent an event for each posting.
+    ///
+    /// Combining is what lets one account take part more than once, like a
+    /// sender paying both a transfer and its charge. A posting can't name
+    /// its own account as the counterparty though - that's money moving to
+    /// the account it came from, refused as a `DuplicateAccount` just as
+    /// `get_many_mut` and `lock_many` refuse it.
+    #[cfg(test)]
+    fn apply_postings(&mut self, postings: &[Posting]) -> Result<(), UserDBError> {
+        let account_events = self.commit_postings(postings, events::Change::Postings(postings.to_vec()), |_, _| ())?;
+        webhook::notify(account_events);
+        self.count_transfers(postings);
+        Ok(())
+    }
+    /// Count transfers paid out and charges collected by `postings` for
+    /// `/metrics`
+    fn count_transfers(&self, postings: &[Posting]) {
+        for posting in postings {
+            let currency = match currency::get_currency(self.get(posting.account).unwrap().currency) {
+                Some(detail) => detail.name,
+                None => continue,
+            };
+            match posting.kind {
+                EntryKind::TransferIn => metrics::count_transfer(&currency, posting.amount),
+                // Counted as it reaches the charge account, not as the sender pays
+                EntryKind::Charge if posting.amount > 0 => metrics::count_charge(&currency, posting.amount),
+                _ => (),
+            }
+        }
+    }
+    /// `apply_postings` at `time`, without recording an event or sending
+    /// webhooks. Returns what webhooks would be sent.
+    fn post(&mut self, postings: &[Posting], time: i64) -> Result<Vec<webhook::AccountEvent>, UserDBError> {
+        let ids = posting_accounts(postings);
+        let mut accts = borrow_many(&mut self.accts, &ids)?;
+        post_to(&mut accts, &ids, &self.acct_ids, postings, time)
+    }
+    /// Add a new escrow, taking its funds from the buyer with `postings`
+    fn open_escrow(&mut self, escrow: escrow::Escrow, postings: &[Posting]) -> Result<(), UserDBError> {
+        let change = events::Change::OpenEscrow { escrow: escrow.clone(), postings: postings.to_vec() };
+        let account_events = self.commit_postings(postings, change, move |userdb, _| {
+            userdb.escrows.insert(escrow.escrow_id, escrow);
+        })?;
+        webhook::notify(account_events);
+        Ok(())
+    }
+    /// `open_escrow` at `time` without recording an event or sending webhooks
+    fn add_escrow(&mut self, escrow: escrow::Escrow, postings: &[Posting],
+                  time: i64) -> Result<Vec<webhook::AccountEvent>, UserDBError> {
+        let account_events = self.post(postings, time)?;
+        self.escrows.insert(escrow.escrow_id, escrow);
+        Ok(account_events)
+    }
+    /// Release or refund escrow `escrow_id`, paying out its funds with
+    /// `postings`. The escrow must still be held.
+    fn close_escrow(&mut self, escrow_id: u64, status: escrow::EscrowStatus, closed_by: String,
+                    postings: &[Posting]) -> Result<(), UserDBError> {
+        let change = events::Change::CloseEscrow {
+            escrow_id: escrow_id,
+            status: status,
+            closed_by: closed_by.clone(),
+            postings: postings.to_vec(),
+        };
+        let account_events = self.commit_postings(postings, change, move |userdb, time| {
+            userdb.end_escrow(escrow_id, status, closed_by, time);
+        })?;
+        webhook::notify(account_events);
+        self.count_transfers(postings);
+        Ok(())
+    }
+    /// `close_escrow` at `time` without recording an event or sending webhooks
+    fn settle_escrow(&mut self, escrow_id: u64, status: escrow::EscrowStatus, closed_by: String,
+                     postings: &[Posting], time: i64) -> Result<Vec<webhook::AccountEvent>, UserDBError> {
+        let account_events = self.post(postings, time)?;
+        self.end_escrow(escrow_id, status, closed_by, time);
+        Ok(account_events)
+    }
+    fn end_escrow(&mut self, escrow_id: u64, status: escrow::EscrowStatus, closed_by: String, time: i64) {
+        if let Some(escrow) = self.escrows.get_mut(&escrow_id) {
+            escrow.status = status;
+            escrow.closed_at = Some(time);
+            escrow.closed_by = Some(closed_by);
+        }
+    }
+    /// `review::hold_transfer` at `time` without recording an event or
+    /// sending webhooks
+    fn add_review(&mut self, review_id: u64, held: review::HeldTransfer, postings: &[Posting],
+                  time: i64) -> Result<Vec<webhook::AccountEvent>, UserDBError> {
+        let account_events = self.post(postings, time)?;
+        self.reviews.get_mut().unwrap().insert(review_id, held);
+        Ok(account_events)
+    }
+    /// Approve or reject held transfer `review_id`, paying out or returning
+    /// its funds with `postings`. The transfer must still be pending.
+    fn close_review(&mut self, review_id: u64, status: review::ReviewStatus, decision: review::Decision,
+                    postings: &[Posting]) -> Result<(), UserDBError> {
+        let change = events::Change::DecideReview {
+            review_id: review_id,
+            status: status,
+            decision: decision.clone(),
+            postings: postings.to_vec(),
+        };
+        let account_events = self.commit_postings(postings, change, move |userdb, _| {
+            userdb.reviews.get_mut().unwrap().settle(review_id, status, decision);
+        })?;
+        webhook::notify(account_events);
+        self.count_transfers(postings);
+        Ok(())
+    }
+    /// `close_review` at `time` without recording an event or sending webhooks
+    fn settle_review(&mut self, review_id: u64, status: review::ReviewStatus, decision: review::Decision,
+                     postings: &[Posting], time: i64) -> Result<Vec<webhook::AccountEvent>, UserDBError> {
+        let account_events = self.post(postings, time)?;
+        self.reviews.get_mut().unwrap().settle(review_id, status, decision);
+        Ok(account_events)
+    }
+    /// Add a new dispute, provisionally paying the sender back with `postings`
+    fn open_dispute(&mut self, dispute: disputes::Dispute, postings: &[Posting]) -> Result<(), UserDBError> {
+        let change = events::Change::OpenDispute { dispute: dispute.clone(), postings: postings.to_vec() };
+        let account_events = self.commit_postings(postings, change, move |userdb, _| {
+            userdb.disputes.insert(dispute.dispute_id, dispute);
+        })?;
+        webhook::notify(account_events);
+        Ok(())
+    }
+    /// `open_dispute` at `time` without recording an event or sending webhooks
+    fn add_dispute(&mut self, dispute: disputes::Dispute, postings: &[Posting],
+                   time: i64) -> Result<Vec<webhook::AccountEvent>, UserDBError> {
+        let account_events = self.post(postings, time)?;
+        self.disputes.insert(dispute.dispute_id, dispute);
+        Ok(account_events)
+    }
+    /// Add `evidence` to dispute `dispute_id`, which must still be open
+    fn add_dispute_evidence(&mut self, dispute_id: u64, evidence: disputes::Evidence) -> Result<(), UserDBError> {
+        let change = events::Change::DisputeEvidence { dispute_id: dispute_id, evidence: evidence.clone() };
+        self.commit(change, move |userdb, _| {
+            if let Some(dispute) = userdb.disputes.get_mut(&dispute_id) {
+                dispute.add_evidence(evidence);
+            }
+        })
+    }
+    /// Decide or withdraw dispute `dispute_id`, making the final `postings`.
+    /// The dispute must still be open.
+    fn close_dispute(&mut self, dispute_id: u64, status: disputes::DisputeStatus, closed_by: String,
+                     postings: &[Posting]) -> Result<(), UserDBError> {
+        let change = events::Change::CloseDispute {
+            dispute_id: dispute_id,
+            status: status,
+            closed_by: closed_by.clone(),
+            postings: postings.to_vec(),
+        };
+        let account_events = self.commit_postings(postings, change, move |userdb, time| {
+            userdb.end_dispute(dispute_id, status, closed_by, time);
+        })?;
+        webhook::notify(account_events);
+        Ok(())
+    }
+    /// `close_dispute` at `time` without recording an event or sending webhooks
+    fn settle_dispute(&mut self, dispute_id: u64, status: disputes::DisputeStatus, closed_by: String,
+                      postings: &[Posting], time: i64) -> Result<Vec<webhook::AccountEvent>, UserDBError> {
+        let account_events = self.post(postings, time)?;
+        self.end_dispute(dispute_id, status, closed_by, time);
+        Ok(account_events)
+    }
+    fn end_dispute(&mut self, dispute_id: u64, status: disputes::DisputeStatus, closed_by: String, time: i64) {
+        if let Some(dispute) = self.disputes.get_mut(&dispute_id) {
+            dispute.status = status;
+            dispute.closed_at = Some(time);
+            dispute.closed_by = Some(closed_by);
+        }
+    }
+    /// Freeze the account at `idx` for the reason given, or unfreeze it
+    fn set_frozen(&mut self, idx: usize, frozen: Option<String>) -> Result<(), UserDBError> {
+        if self.get_mut(idx).is_none() {
+            return Err(UserDBError::NoSuchAccount(idx))
+        }
+        let change = events::Change::FreezeAccount { account: idx, frozen: frozen.clone() };
+        self.commit(change, move |userdb, _| userdb.get_mut(idx).unwrap().frozen = frozen)
+    }
+    /// Set the verification level of the account at `idx`, as verified by
+    /// `verified_by`
+    fn set_kyc_level(&mut self, idx: usize, level: kyc::Level, verified_by: String) -> Result<(), UserDBError> {
+        if self.get_mut(idx).is_none() {
+            return Err(UserDBError::NoSuchAccount(idx))
+        }
+        let change = events::Change::SetKycLevel { account: idx, level: level, verified_by: verified_by };
+        self.commit(change, move |userdb, _| userdb.get_mut(idx).unwrap().kyc_level = level)
+    }
+    /// Set or clear the interest rate override of the account at `idx`
+    fn set_interest_rate(&mut self, idx: usize, rate_bps: Option<u32>) -> Result<(), UserDBError> {
+        if self.get_mut(idx).is_none() {
+            return Err(UserDBError::NoSuchAccount(idx))
+        }
+        let change = events::Change::SetAccountInterest { account: idx, rate_bps: rate_bps };
+        self.commit(change, move |userdb, _| userdb.get_mut(idx).unwrap().interest_rate_bps = rate_bps)
+    }
+    /// Add the interest accrued on `day`, as `(account, millionths of a
+    /// base unit)`
+    fn accrue_interest(&mut self, day: i64, accruals: Vec<(usize, i64)>) -> Result<(), UserDBError> {
+        let accrued = self.accrued_totals(&accruals)?;
+        self.commit(events::Change::AccrueInterest { day: day, accruals: accruals }, move |userdb, _| {
+            userdb.set_accrued(day, accrued);
+        })
+    }
+    /// `accrue_interest` without recording an event
+    fn add_accruals(&mut self, day: i64, accruals: &[(usize, i64)]) -> Result<(), UserDBError> {
+        let accrued = self.accrued_totals(accruals)?;
+        self.set_accrued(day, accrued);
+        Ok(())
+    }
+    /// The interest each account will have accrued once `accruals` are added
+    fn accrued_totals(&mut self, accruals: &[(usize, i64)]) -> Result<Vec<(usize, i64)>, UserDBError> {
+        let mut accrued = vec![];
+        for &(idx, amount) in accruals {
+            match self.get_mut(idx).map(|acct| acct.accrued_interest.checked_add(amount)) {
+                Some(Some(total)) => accrued.push((idx, total)),
+                Some(None) => return Err(UserDBError::Overflow(idx)),
+                None => return Err(UserDBError::NoSuchAccount(idx)),
+            }
+        }
+        Ok(accrued)
+    }
+    fn set_accrued(&mut self, day: i64, accrued: Vec<(usize, i64)>) {
+        for (idx, total) in accrued {
+            self.get_mut(idx).unwrap().accrued_interest = total;
+        }
+        self.last_accrual_day = Some(day);
+    }
+    /// Pay out accrued interest with `postings` (see `interest::payout_postings`),
+    /// taking what's paid off the accrued interest of each account
+    fn pay_interest(&mut self, postings: &[Posting]) -> Result<(), UserDBError> {
+        let paid = interest_paid(postings)?;
+        let account_events = self.commit_postings(postings, events::Change::PayInterest(postings.to_vec()),
+                                                  move |userdb, _| userdb.take_accrued(paid))?;
+        webhook::notify(account_events);
+        Ok(())
+    }
+    /// `pay_interest` at `time` without recording an event or sending webhooks
+    fn post_interest(&mut self, postings: &[Posting], time: i64) -> Result<Vec<webhook::AccountEvent>, UserDBError> {
+        let paid = interest_paid(postings)?;
+        let account_events = self.post(postings, time)?;
+        self.take_accrued(paid);
+        Ok(account_events)
+    }
+    fn take_accrued(&mut self, paid: Vec<(usize, i64)>) {
+        for (idx, micros) in paid {
+            self.get_mut(idx).unwrap().accrued_interest -= micros;
+        }
+    }
+}
+
+/// Interest paid out by `postings` to each account, in millionths of a
+/// base unit
+fn interest_paid(postings: &[Posting]) -> Result<Vec<(usize, i64)>, UserDBError> {
+    let mut paid = vec![];
+    for posting in postings.iter().filter(|posting| posting.kind == EntryKind::Interest && posting.amount > 0) {
+        match posting.amount.checked_mul(interest::MICROS_PER_UNIT) {
+            Some(micros) => paid.push((posting.account, micros)),
+            None => return Err(UserDBError::Overflow(posting.account)),
+        }
+    }
+    Ok(paid)
+}
+
+/// The distinct accounts `postings` touch, in the order they're first posted to
+fn posting_accounts(postings: &[Posting]) -> Vec<usize> {
+    let mut ids: Vec<usize> = vec![];
+    for posting in postings {
+        if !ids.contains(&posting.account) {
+            ids.push(posting.account);
+        }
+    }
+    ids
+}
+
+/// Positions in `ids` in order of the account index at each, checking the
+/// accounts are distinct and exist
+fn index_order(ids: &[usize], num_accts: usize) -> Result<Vec<usize>, UserDBError> {
+    let mut order: Vec<usize> = (0..ids.len()).collect();
+    order.sort_by_key(|&pos| ids[pos]);
+    for pair in order.windows(2) {
+        if ids[pair[0]] == ids[pair[1]] {
+            return Err(UserDBError::DuplicateAccount(ids[pair[0]]))
+        }
+    }
+    if let Some(&id) = ids.iter().find(|&&id| id >= num_accts) {
+        return Err(UserDBError::NoSuchAccount(id))
+    }
+    Ok(order)
+}
+
+/// See `UserDB::get_many_mut`
+fn borrow_many<'a>(accts: &'a mut [Mutex<UserAccount>], ids: &[usize]) -> Result<Vec<&'a mut UserAccount>, UserDBError> {
+    let order = index_order(ids, accts.len())?;
+    // Walk the accounts in index order, splitting off each one requested
+    let mut found: Vec<Option<&mut UserAccount>> = ids.iter().map(|_| None).collect();
+    let mut rest: &mut [Mutex<UserAccount>] = accts;
+    let mut rest_start = 0;
+    for pos in order {
+        let id = ids[pos];
+        let (acct, tail) = { rest }.split_at_mut(id - rest_start).1.split_first_mut().unwrap();
+        found[pos] = Some(acct.get_mut().unwrap());
+        rest = tail;
+        rest_start = id + 1;
+    }
+    Ok(found.into_iter().map(Option::unwrap).collect())
+}
+
+/// Add `deltas[i]` to the balance of `accts[i]`, the account at internal
+/// index `ids[i]`. Either every balance is updated, or none are and the
+/// reason is returned.
+#[cfg(test)]
+fn add_to_balances<A: DerefMut<Target = UserAccount>>(accts: &mut [A], ids: &[usize],
+                                                     deltas: &[i64]) -> Result<(), UserDBError> {
+    let mut new_balances = vec![];
+    for ((acct, &id), &delta) in accts.iter().zip(ids).zip(deltas) {
+        match acct.balance.checked_add(delta) {
+            Some(balance) => new_balances.push(balance),
+            None => return Err(UserDBError::Overflow(id)),
+        }
+    }
+    for (acct, balance) in accts.iter_mut().zip(new_balances) {
+        acct.balance = balance;
+    }
+    Ok(())
+}
+
+/// `UserDB::post` to accounts already borrowed or locked, `accts[i]` being
+/// the account at internal index `ids[i]`. Every account the postings touch
+/// must be there.
+fn post_to<A: DerefMut<Target = UserAccount>>(accts: &mut [A], ids: &[usize], acct_ids: &[Option<AccountId>],
+                                             postings: &[Posting], time: i64) -> Result<Vec<webhook::AccountEvent>, UserDBError> {
+    let deltas = posting_deltas(accts, ids, postings)?;
+    Ok(post_checked(accts, ids, acct_ids, postings, &deltas, time))
+}
+
+/// Change in the balance of each of `accts` from `postings`, checking
+/// `post_to` can make them without changing anything
+fn posting_deltas<A: DerefMut<Target = UserAccount>>(accts: &[A], ids: &[usize],
+                                                    postings: &[Posting]) -> Result<Vec<i64>, UserDBError> {
+    if let Some(posting) = postings.iter().find(|posting| posting.counterparty == Some(posting.account)) {
+        return Err(UserDBError::DuplicateAccount(posting.account))
+    }
+    let mut deltas = vec![0i64; ids.len()];
+    for posting in postings {
+        let delta = &mut deltas[ids.iter().position(|&id| id == posting.account).unwrap()];
+        match delta.checked_add(posting.amount) {
+            Some(total) => *delta = total,
+            None => return Err(UserDBError::Overflow(posting.account)),
+        }
+    }
+    for ((acct, &id), &delta) in accts.iter().zip(ids).zip(&deltas) {
+        if acct.balance.checked_add(delta).is_none() {
+            return Err(UserDBError::Overflow(id))
+        }
+    }
+    Ok(deltas)
+}
+
+/// `post_to`, once `posting_deltas` has checked the postings and worked out
+/// `deltas`
+fn post_checked<A: DerefMut<Target = UserAccount>>(accts: &mut [A], ids: &[usize], acct_ids: &[Option<AccountId>],
+                                                  postings: &[Posting], deltas: &[i64], time: i64) -> Vec<webhook::AccountEvent> {
+    let pos = |account: usize| ids.iter().position(|&id| id == account).unwrap();
+    let mut balances: Vec<i64> = accts.iter().map(|acct| acct.balance).collect();
+    for (acct, &delta) in accts.iter_mut().zip(deltas) {
+        acct.balance += delta;
+    }
+
+    // Go forward from the starting balances to give each entry the balance
+    // just after it
+    let public_id = |idx: usize| acct_ids.get(idx).and_then(|&id| id);
+    let mut account_events = vec![];
+    for posting in postings {
+        let pos = pos(posting.account);
+        balances[pos] += posting.amount;
+        let entry = Entry {
+            time: time,
+            kind: posting.kind,
+            amount: posting.amount,
+            counterparty: posting.counterparty,
+            balance: balances[pos],
+        };
+        if let Some(account_id) = public_id(posting.account) {
+            account_events.push(webhook::AccountEvent {
+                account_id: account_id,
+                counterparty: posting.counterparty.and_then(&public_id),
+                entry: entry.clone(),
+            });
+        }
+        accts[pos].history.push(entry);
+    }
+    account_events
+}
+
+/// Accounts locked by `UserDB::lock_many`, which stay locked until this is
+/// dropped or used to apply postings
+struct LockedAccounts<'a> {
+    userdb: &'a UserDB,
+    ids: Vec<usize>,
+    accts: Vec<MutexGuard<'a, UserAccount>>,
+}
+impl<'a> LockedAccounts<'a> {
+    /// The locked accounts, in the order they were asked for
+    fn accts_mut(&mut self) -> Vec<&mut UserAccount> {
+        self.accts.iter_mut().map(|acct| &mut **acct).collect()
+    }
+    /// `UserDB::apply_postings`, for callers holding only the `USERDB` read
+    /// lock. Besides the locked accounts, postings can only touch system
+    /// accounts - those are locked here in index order, after the customer
+    /// accounts and before the event log. Nothing holding a system account
+    /// waits for a customer account, and nothing holding the event log
+    /// waits for any account, so none of these can deadlock.
+    fn apply_postings(self, postings: &[Posting]) -> Result<(), UserDBError> {
+        self.commit_postings(postings, events::Change::Postings).map(|_| ())
+    }
+    /// `apply_postings`, recording the change `change` makes of the postings
+    /// rather than just the postings. The event is written before any
+    /// balance changes, so nothing is made which isn't in the log. Returns
+    /// the time of its event.
+    fn commit_postings<F>(self, postings: &[Posting], change: F) -> Result<i64, UserDBError>
+            where F: FnOnce(Vec<Posting>) -> events::Change {
+        let LockedAccounts { userdb, mut ids, mut accts } = self;
+        let mut system_ids: Vec<usize> = postings.iter()
+            .map(|posting| posting.account)
+            .filter(|id| !ids.contains(id))
+            .collect();
+        system_ids.sort();
+        system_ids.dedup();
+        for id in system_ids {
+            debug_assert!(userdb.public_id(id).is_none());
+            match userdb.accts.get(id) {
+                Some(acct) => accts.push(acct.lock().unwrap()),
+                None => return Err(UserDBError::NoSuchAccount(id)),
+            }
+            ids.push(id);
+        }
+        let (time, account_events) = events::commit::<_, UserDBError, _, _>(userdb, move |time| {
+            let deltas = posting_deltas(&accts, &ids, postings)?;
+            Ok((change(postings.to_vec()), move || {
+                (time, post_checked(&mut accts, &ids, &userdb.acct_ids, postings, &deltas, time))
+            }))
+        })?;
+        webhook::notify(account_events);
+        userdb.count_transfers(postings);
+        Ok(time)
+    }
+}
+
+/// Reasons a batch of accounts can't be borrowed or updated together
+#[derive(Debug, PartialEq)]
+enum UserDBError {
+    /// No account has this index
+    NoSuchAccount(usize),
+    /// This account was asked for more than once
+    DuplicateAccount(usize),
+    /// The balance of this account would overflow
+    Overflow(usize),
+    /// The change couldn't be written to the event log, so wasn't made
+    Unrecorded,
+}
+impl From<events::EventError> for UserDBError {
+    fn from(_: events::EventError) -> UserDBError {
+        UserDBError::Unrecorded
+    }
+}
+
+#[test]
+fn test_account_ids() {
+    let mut userdb = UserDB::new();
+    let sysacct = userdb.addsysacct(UserAccount::fakeacct()).unwrap();
+    let id1 = userdb.addacct(UserAccount::fakeacct()).unwrap();
+    let id2 = userdb.addacct(UserAccount::fakeacct()).unwrap();
+    assert!(id1 != id2);
+    assert_eq!(userdb.lookup(&None, &id1.to_string()), Some(1));
+    assert_eq!(userdb.lookup(&None, &id2.to_string()), Some(2));
+    assert_eq!(userdb.lookup(&None, &sysacct.to_string()), None);
+    assert_eq!(userdb.lookup(&None, "1"), None);
+    let masked = id1.masked();
+    assert!(masked.starts_with("****") && id1.to_string().ends_with(&masked[4..]) && masked.len() == 8);
+}
+
+#[test]
+fn test_get_many_mut() {
+    let mut userdb = UserDB::new();
+    for _ in 0..4 {
+        userdb.addsysacct(UserAccount::fakeacct()).unwrap();
+    }
+    for (i, acct) in userdb.get_many_mut(&[3, 0, 2]).unwrap().into_iter().enumerate() {
+        acct.balance = i as i64 + 1;
+    }
+    let balances: Vec<i64> = userdb.balances();
+    assert_eq!(balances, vec![2, 0, 3, 1]);
+    assert_eq!(userdb.get_many_mut(&[1, 2, 1]).err(), Some(UserDBError::DuplicateAccount(1)));
+    assert_eq!(userdb.get_many_mut(&[1, 4]).err(), Some(UserDBError::NoSuchAccount(4)));
+
+    // A failed batch leaves every balance alone
+    assert_eq!(userdb.apply_deltas(&[(0, -2), (2, i64::MAX)]), Err(UserDBError::Overflow(2)));
+    assert_eq!(userdb.apply_deltas(&[(0, -2), (0, 2)]), Err(UserDBError::DuplicateAccount(0)));
+    assert_eq!(userdb.get(0).unwrap().balance, 2);
+    assert_eq!(userdb.apply_deltas(&[(0, -2), (1, 2)]), Ok(()));
+    assert_eq!(userdb.get(0).unwrap().balance, 0);
+    assert_eq!(userdb.get(1).unwrap().balance, 2);
+
+    // Locking works the same way, and threads locking the same accounts in
+    // different orders take turns rather than deadlocking
+    let balances: Vec<i64> = userdb.lock_many(&[3, 1]).unwrap().accts_mut().iter().map(|acct| acct.balance).collect();
+    assert_eq!(balances, vec![1, 2]);
+    assert_eq!(userdb.lock_many(&[1, 2, 1]).err(), Some(UserDBError::DuplicateAccount(1)));
+    assert_eq!(userdb.lock_many(&[1, 4]).err(), Some(UserDBError::NoSuchAccount(4)));
+    let userdb = &userdb;
+    crossbeam::scope(|scope| {
+        for &ids in &[[0, 1], [1, 0]] {
+            scope.spawn(move || for _ in 0..1000 {
+                let mut locked = userdb.lock_many(&ids).unwrap();
+                let mut accts = locked.accts_mut();
+                accts[0].balance -= 1;
+                accts[1].balance += 1;
+            });
+        }
+    });
+    assert_eq!(userdb.balances(), vec![0, 2, 3, 1]);
+}
+
+#[test]
+fn test_apply_postings() {
+    let mut userdb = UserDB::new();
+    for _ in 0..3 {
+        userdb.addsysacct(UserAccount::fakeacct()).unwrap();
+    }
+    userdb.get_mut(0).unwrap().balance = 100;
+    let plan = TransferPlan { from: 0, to: 1, amount: 50, charge_amount: 2, charge_account: 2 };
+    userdb.apply_postings(&plan.postings(0)).unwrap();
+    let balances: Vec<i64> = userdb.balances();
+    assert_eq!(balances, vec![48, 50, 2]);
+    let history: Vec<(EntryKind, i64, i64)> = userdb.get(0).unwrap().history.iter()
+        .map(|entry| (entry.kind, entry.amount, entry.balance))
+        .collect();
+    assert_eq!(history, vec![(EntryKind::TransferOut, -50, 50), (EntryKind::Charge, -2, 48)]);
+    assert_eq!(userdb.get(2).unwrap().history[0].counterparty, Some(0));
+
+    // Nothing is recorded for a failed batch
+    let plan = TransferPlan { from: 1, to: 0, amount: i64::MAX, charge_amount: 0, charge_account: 2 };
+    assert_eq!(userdb.apply_postings(&plan.postings(1)), Err(UserDBError::Overflow(0)));
+    assert_eq!(userdb.get(0).unwrap().history.len(), 2);
+    assert_eq!(userdb.get(1).unwrap().history.len(), 1);
+    // Combining a transfer to the same account would leave just the charge
+    let plan = TransferPlan { from: 1, to: 1, amount: 20, charge_amount: 1, charge_account: 2 };
+    assert_eq!(userdb.apply_postings(&plan.postings(1)), Err(UserDBError::DuplicateAccount(1)));
+    assert_eq!(userdb.lock_many(&[1]).unwrap().apply_postings(&plan.postings(1)), Err(UserDBError::DuplicateAccount(1)));
+    assert_eq!(userdb.balances(), vec![48, 50, 2]);
+    assert_eq!(userdb.get(1).unwrap().history.len(), 1);
+}
+
+#[cfg(test)]
+use proptest::prelude::*;
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn transfers_conserve_money(
+        balances in prop::collection::vec(-1000i64..1 << 50, 2..8),
+        transfer_charge in 0.0..100.0f64,
+        transfers in prop::collection::vec((0..9usize, 0..9usize, 0i64..1 << 50), 0..50),
+    ) {
+        // Account 0 takes the charges, but can still be transferred to and from
+        let mut userdb = UserDB::new();
+        for &balance in &balances {
+            let id = userdb.addsysacct(UserAccount::fakeacct()).unwrap();
+            userdb.get_mut(id).unwrap().balance = balance;
+        }
+        let currency_detail = currency::CurrencyDetail {
+            id: currency::CurrencyId(1),
+            name: "TEST".to_owned(),
+            overdraft_limit: 0,
+            transfer_charge: transfer_charge,
+            transfer_charge_account: 0,
+            review_hold_account: 1,
+            interest_rate_bps: 0,
+            interest_expense_account: None,
+            exponent: 0,
+            symbol: String::new(),
+            locale: "en".to_owned(),
+        };
+        let total: i64 = balances.iter().sum();
+        for (from, to, amount) in transfers {
+            let before: Vec<i64> = userdb.balances();
+            let applied = TransferPlan::new(from, to, amount, &currency_detail)
+                .map(|plan| userdb.apply_postings(&plan.postings(from)).is_ok())
+                .unwrap_or(false);
+            let after: Vec<i64> = userdb.balances();
+            if !applied {
+                prop_assert_eq!(&before, &after);
+            }
+            prop_assert_eq!(after.iter().sum::<i64>(), total);
         }
     }
 }
 
-const FAKE_CHARGE_ACCT: usize = 0;
-const USD_CHARGE_ACCT:  usize = 1;
-const EUR_CHARGE_ACCT:  usize = 2;
-const GBP_CHARGE_ACCT:  usize = 3;
 lazy_static! {
-    // Separate by currency for tax reasons
-    static ref USERDB: RwLock<UserDB> = RwLock::new(UserDB { accts: vec![
-        UserAccount::fakeacct(),
-        UserAccount::new("USD").unwrap(),
-        UserAccount::new("EUR").unwrap(),
-        UserAccount::new("GBP").unwrap(),
-    ]});
+    // Charge accounts are added as each currency is loaded
+    static ref USERDB: RwLock<UserDB> = RwLock::new(UserDB::new());
+}
+
+/// Lock `USERDB` for reading, timing the wait for `/metrics`
+fn read_userdb() -> RwLockReadGuard<'static, UserDB> {
+    let start = Instant::now();
+    let userdb = USERDB.read().unwrap();
+    metrics::observe_lock_wait("read", start.elapsed());
+    userdb
+}
+
+/// Lock `USERDB` for writing, timing the wait for `/metrics`
+fn write_userdb() -> RwLockWriteGuard<'static, UserDB> {
+    let start = Instant::now();
+    let userdb = USERDB.write().unwrap();
+    metrics::observe_lock_wait("write", start.elapsed());
+    userdb
 }
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    let leader = match args.get(1).map(|arg| &arg[..]) {
+        Some("verify-audit") => process::exit(audit::verify_cmd(&args[2..])),
+        Some("export-ledger") => process::exit(export::export_cmd(&args[2..])),
+        Some("follow") => match args.get(2) {
+            Some(leader) => Some(leader.clone()),
+            None => {
+                println!("usage: underhanded-rs follow <leader replication address>");
+                process::exit(2)
+            },
+        },
+        _ => None,
+    };
+    let replication_secret = env::var("QUADCURR_REPLICATION_SECRET").ok();
+    if replication_secret.as_ref().map_or(false, |secret| secret.len() < replication::MIN_SECRET_LEN) {
+        println!("QUADCURR_REPLICATION_SECRET must be at least {} characters", replication::MIN_SECRET_LEN);
+        process::exit(2)
+    }
+    if leader.is_some() && replication_secret.is_none() {
+        println!("QUADCURR_REPLICATION_SECRET must be set to the leader's to follow it");
+        process::exit(2)
+    }
+
+    let head = audit::open(audit::AUDIT_LOG).unwrap();
+    println!("Audit log head: {}", head);
+    let (userdb, currencies) = events::open(events::EVENT_LOG).unwrap();
+    println!("Replayed {} events", events::num_events());
+    *USERDB.write().unwrap() = userdb;
+    currency::replace_currencies(currencies);
+    // Followers get their currencies from the leader
+    if leader.is_none() {
+        currency::load_currencies(&mut USERDB.write().unwrap(), currency::CURRENCY_CONFIG, "startup").unwrap();
+    }
+    if Path::new(tenants::TENANT_CONFIG).is_file() {
+        let num_tenants = tenants::load_tenants(tenants::TENANT_CONFIG).unwrap();
+        println!("Loaded {} tenants", num_tenants);
+        if let Err(tenants::TenantError(msg)) = tenants::set_operator_token(env::var("QUADCURR_OPERATOR_TOKEN").ok()) {
+            println!("QUADCURR_OPERATOR_TOKEN: {}", msg);
+            process::exit(2)
+        }
+        // Followers get tenant charge accounts from the leader too
+        if leader.is_none() {
+            tenants::provision(&mut USERDB.write().unwrap()).unwrap();
+        }
+    } else {
+        println!("No tenant config, every account is the operator's");
+    }
+    // Limits only matter for changes, which followers make once promoted
+    if leader.is_none() {
+        if Path::new(kyc::KYC_CONFIG).is_file() {
+            let num_currencies = kyc::load_limits(kyc::KYC_CONFIG).unwrap();
+            println!("Loaded verification limits for {} currencies", num_currencies);
+        } else {
+            println!("No kyc config, accounts are not limited by verification level");
+        }
+    }
+    if let Ok(url) = env::var("QUADCURR_KYC_PROVIDER_URL") {
+        println!("Checking identity evidence with {}", url);
+        kyc::set_provider(Box::new(kyc::HttpProvider::new(url)));
+    }
+    if Path::new(review::REVIEWER_CONFIG).is_file() {
+        let num_reviewers = review::load_reviewers(review::REVIEWER_CONFIG).unwrap();
+        println!("Loaded {} reviewers", num_reviewers);
+    } else {
+        println!("No reviewer config, flagged transfers cannot be reviewed");
+    }
+    if Path::new(screening::SANCTIONS_DIR).is_dir() {
+        let num_entries = screening::load_lists(screening::SANCTIONS_DIR).unwrap();
+        println!("Loaded {} sanctions list entries", num_entries);
+    } else {
+        println!("No sanctions lists, account holders are not screened");
+    }
+    let num_cases = screening::open(screening::CASE_STORE).unwrap();
+    println!("{} compliance cases open", num_cases);
+    let num_policies = approvals::open(approvals::APPROVAL_STORE).unwrap();
+    println!("Loaded {} approval policies", num_policies);
+
+    let num_queued = webhook::open(webhook::WEBHOOK_STORE).unwrap();
+    println!("{} webhook events waiting to be delivered", num_queued);
+    webhook::start_worker();
+    interest::start_worker();
+    escrow::start_worker();
+    disputes::start_worker();
+    screening::start_watcher(screening::SANCTIONS_DIR);
+
+    let replication_addr = env::var("QUADCURR_REPLICATION_ADDR")
+        .unwrap_or_else(|_| replication::REPLICATION_ADDR.to_owned());
+    match replication_secret {
+        Some(ref secret) => {
+            replication::start_listener(&replication_addr, secret.clone()).unwrap();
+            println!("Replicating to followers from {}", replication_addr);
+        },
+        None => println!("Not replicating to followers, QUADCURR_REPLICATION_SECRET is not set"),
+    }
+    if let (Some(leader), Some(secret)) = (leader, replication_secret) {
+        println!("Following leader at {}, read-only until promoted", leader);
+        replication::start_follower(leader, secret);
+    }
+
+    let http_addr = env::var("QUADCURR_HTTP_ADDR").unwrap_or_else(|_| "0.0.0.0:3000".to_owned());
+    let handler = Metered(ReadOnly(Tenanted(Notifying(router()))));
+    match env::var("QUADCURR_HTTP_SERVER").as_ref().map(|server| &server[..]) {
+        Ok("async") => {
+            println!("Async server starting on {}", http_addr);
+            async_server::serve(&http_addr, handler).unwrap();
+        },
+        Ok("iron") | Err(_) => {
+            println!("Server starting on {}", http_addr);
+            Iron::new(handler).http(&http_addr[..]).unwrap();
+        },
+        Ok(server) => {
+            println!("QUADCURR_HTTP_SERVER must be iron or async, not {}", server);
+            process::exit(2)
+        },
+    }
+}
+
+/// Every route, served by both the iron and async servers
+fn router() -> Router {
     let mut router = Router::new();
+    router.get("/healthz", routes::healthz_handler, "healthz");
+    router.get("/readyz", routes::readyz_handler, "readyz");
+    router.get("/metrics", routes::metrics_handler, "metrics");
+    router.get("/replication/status", routes::replicationstatus_handler, "replicationstatus");
+    router.post("/replication/promote", Audited("promote", routes::promote_handler), "promote"); // admin
     router.get("/dumpbalance", routes::dumpbalance, "dumpbalance"); // debug
-    router.post("/makeaccount", routes::makeaccount_handler, "makeaccount");
-    router.post("/deposit", routes::deposit_handler, "deposit");
-    router.post("/transfer", routes::transfer_handler, "transfer");
+    router.post("/addcurrency", Audited("addcurrency", routes::addcurrency_handler), "addcurrency"); // admin
+    router.post("/currencies/reload", Audited("reloadcurrencies", routes::reloadcurrencies_handler), "reloadcurrencies"); // admin
+    router.post("/currencies/chargeaccount", Audited("setchargeaccount", routes::setchargeaccount_handler), "setchargeaccount"); // admin
+    router.get("/accounts", routes::listaccounts_handler, "accounts"); // admin
+    router.get("/ledger", routes::exportledger_handler, "ledger"); // admin
+    router.post("/replay", Audited("replay", routes::replay_handler), "replay"); // admin
+    router.post("/interest/currencyrate", Audited("setcurrencyrate", routes::setcurrencyrate_handler), "setcurrencyrate"); // admin
+    router.post("/interest/accountrate", Audited("setaccountrate", routes::setaccountrate_handler), "setaccountrate"); // admin
+    router.get("/interest/preview", routes::interestpreview_handler, "interestpreview"); // admin
+    router.post("/makeaccount", Audited("makeaccount", routes::makeaccount_handler), "makeaccount");
+    router.post("/deposit", Audited("deposit", routes::deposit_handler), "deposit");
+    router.post("/transfer", Audited("transfer", routes::transfer_handler), "transfer");
+    router.post("/transfers/batch", Audited("batchtransfer", routes::batchtransfer_handler), "batchtransfer");
+    router.post("/approvals/policy", Audited("setapprovalpolicy", routes::setapprovalpolicy_handler), "setapprovalpolicy"); // admin
+    router.post("/approvals/policy/remove", Audited("removeapprovalpolicy", routes::removeapprovalpolicy_handler), "removeapprovalpolicy"); // admin
+    router.get("/approvals", routes::listapprovals_handler, "approvals");
+    router.post("/approvals/approve", Audited("approvetransfer", routes::approvetransfer_handler), "approvetransfer");
+    router.post("/approvals/reject", Audited("rejecttransfer", routes::rejecttransfer_handler), "rejecttransfer");
+    router.post("/escrow/open", Audited("openescrow", routes::openescrow_handler), "openescrow");
+    router.get("/escrow", routes::getescrow_handler, "escrow");
+    router.post("/escrow/release", Audited("releaseescrow", routes::releaseescrow_handler), "releaseescrow");
+    router.post("/escrow/refund", Audited("refundescrow", routes::refundescrow_handler), "refundescrow");
+    router.post("/dispute/open", Audited("opendispute", routes::opendispute_handler), "opendispute");
+    router.get("/dispute", routes::getdispute_handler, "dispute");
+    router.post("/dispute/evidence", Audited("disputeevidence", routes::disputeevidence_handler), "disputeevidence");
+    router.post("/dispute/withdraw", Audited("withdrawdispute", routes::withdrawdispute_handler), "withdrawdispute");
+    router.post("/dispute/resolve", Audited("resolvedispute", routes::resolvedispute_handler), "resolvedispute");
+    router.post("/account/freeze", Audited("freezeaccount", routes::freezeaccount_handler), "freezeaccount");
+    router.get("/kyc", routes::getkyc_handler, "kyc");
+    router.post("/kyc/level", Audited("setkyclevel", routes::setkyclevel_handler), "setkyclevel"); // admin
+    router.post("/kyc/verify", Audited("verifykyc", routes::verifykyc_handler), "verifykyc");
+    router.get("/statement", routes::statement_handler, "statement");
+    router.get("/reviews", routes::listreviews_handler, "reviews"); // admin
+    router.get("/review", routes::getreview_handler, "review"); // admin
+    router.post("/review/approve", Audited("approvereview", routes::approvereview_handler), "approvereview"); // admin
+    router.post("/review/reject", Audited("rejectreview", routes::rejectreview_handler), "rejectreview"); // admin
+    router.post("/webhooks/register", Audited("registerwebhook", routes::registerwebhook_handler), "registerwebhook");
+    router.post("/webhooks/unregister", Audited("unregisterwebhook", routes::unregisterwebhook_handler), "unregisterwebhook");
+    router.get("/webhooks/deadletters", routes::deadletters_handler, "deadletters"); // admin
+    router.post("/webhooks/deadletters/retry", Audited("retrydeadletter", routes::retrydeadletter_handler), "retrydeadletter"); // admin
+    router.post("/compliance/reload", Audited("reloadsanctions", routes::reloadsanctions_handler), "reloadsanctions"); // admin
+    router.get("/compliance/cases", routes::listcases_handler, "cases"); // admin
+    router.post("/compliance/cases/close", Audited("closecase", routes::closecase_handler), "closecase"); // admin
+    router
+}
+
+/// The lock around each account, and what the model of concurrent
+/// transfers in `routes` runs on. These are std's, except in tests built
+/// with `RUSTFLAGS="--cfg loom"`, where loom runs the model once for every
+/// way its threads can interleave.
+mod sync {
+    #[cfg(not(all(test, loom)))]
+    pub use std::sync::{Mutex, MutexGuard};
+    #[cfg(all(test, loom))]
+    pub use self::model::{Mutex, MutexGuard};
+
+    #[cfg(all(test, not(loom)))]
+    pub use std::sync::mpsc;
+    #[cfg(all(test, not(loom)))]
+    pub use std::thread;
+    #[cfg(all(test, loom))]
+    pub use loom::sync::mpsc;
+    #[cfg(all(test, loom))]
+    pub use loom::thread;
+
+    /// Run `f` once, or under loom, for every interleaving of the threads it
+    /// starts with up to two preemptions (more rarely finds anything new and
+    /// takes far longer)
+    #[cfg(all(test, not(loom)))]
+    pub fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+        f()
+    }
+    #[cfg(all(test, loom))]
+    pub fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+        let mut builder = ::loom::model::Builder::new();
+        builder.preemption_bound = Some(2);
+        builder.check(f)
+    }
+
+    #[cfg(all(test, loom))]
+    mod model {
+        use loom;
+        use std::ops::{Deref, DerefMut};
+        use std::sync::{self, LockResult};
 
-    println!("Server starting on port 3000");
-    Iron::new(router).http("0.0.0.0:3000").unwrap();
+        /// loom's `Mutex` has no `get_mut`, so the value lives in a std
+        /// `Mutex` which is only ever locked while holding loom's
+        pub struct Mutex<T> {
+            model: loom::sync::Mutex<()>,
+            value: sync::Mutex<T>,
+        }
+        impl<T> Mutex<T> {
+            pub fn new(value: T) -> Mutex<T> {
+                Mutex { model: loom::sync::Mutex::new(()), value: sync::Mutex::new(value) }
+            }
+            pub fn lock(&self) -> LockResult<MutexGuard<T>> {
+                let model = self.model.lock().unwrap();
+                Ok(MutexGuard { value: self.value.lock().unwrap(), _model: model })
+            }
+            pub fn get_mut(&mut self) -> LockResult<&mut T> {
+                self.value.get_mut()
+            }
+        }
+
+        pub struct MutexGuard<'a, T: 'a> {
+            // Declared first so it's unlocked first
+            value: sync::MutexGuard<'a, T>,
+            _model: loom::sync::MutexGuard<'a, ()>,
+        }
+        impl<'a, T> Deref for MutexGuard<'a, T> {
+            type Target = T;
+            fn deref(&self) -> &T {
+                &self.value
+            }
+        }
+        impl<'a, T> DerefMut for MutexGuard<'a, T> {
+            fn deref_mut(&mut self) -> &mut T {
+                &mut self.value
+            }
+        }
+    }
 }
 
 mod routes {
+    use std::cmp;
     use std::i64;
+    use std::mem;
+    use std::path::Path;
     use std::time::{Duration, Instant};
     use std::thread;
 
+    use chrono::{DateTime, UTC};
+
     use crossbeam;
 
+    use iron::headers::{Authorization, Bearer, ContentType};
+    use iron::mime::{Mime, SubLevel, TopLevel};
     use iron::prelude::{IronResult, Request, Response};
     use iron::status;
 
     use serde_json;
 
-    use super::USERDB;
+    use super::{LockedAccounts, UserDB, UserDBError, read_userdb, unix_now, write_userdb};
+    use super::{EntryKind, Posting, TransferPlan};
     use super::UserAccount;
     use super::currency;
-    use super::fraud::fraud_checker;
+    use super::fraud::{FraudError, fraud_checker};
+    use super::approvals::{self, ApprovalError};
+    use super::audit;
+    use super::batch;
+    use super::disputes::{self, Dispute, DisputeError};
+    use super::escrow::{self, Escrow, EscrowError};
+    use super::events;
+    use super::export;
+    use super::interest;
+    use super::kyc;
+    use super::metrics;
+    use super::replication;
+    use super::review;
+    use super::screening;
+    use super::statement::{self, Format, Statement};
+    use super::tenants;
+    use super::webhook;
+
+    #[cfg(test)]
+    use proptest::prelude::*;
 
     // Consider requests in the last `RATE_LIMIT_SECS` secs to contribute
     // towards rate limiting
@@ -125,132 +1349,7541 @@ mod routes {
     // milliseconds to the delay to fulfil the request
     const MS_PER_REQ_RATE: usize = 50;
 
+    /// Why a change was turned away when the event log couldn't be written
+    static UNRECORDED: &'static str = "could not write the event log";
+
     macro_rules! resp {
         ($status:ident, $msg:expr) => {{
             Ok(Response::with((status::$status, $msg)))
         }};
     }
 
+    /// Always ok while the server is up
+    pub fn healthz_handler(_: &mut Request) -> IronResult<Response> {
+        resp!(Ok, "ok")
+    }
+
+    /// Ok once every currency has been loaded, so accounts can be used
+    pub fn readyz_handler(_: &mut Request) -> IronResult<Response> {
+        if !events::is_open() {
+            resp!(ServiceUnavailable, "event log not open")
+        } else if replication::is_following() {
+            if !replication::is_connected() {
+                resp!(ServiceUnavailable, "not connected to the leader")
+            } else if !replication::is_caught_up() {
+                resp!(ServiceUnavailable, "catching up with the leader")
+            } else {
+                resp!(Ok, "ready")
+            }
+        } else if currency::currencies_loaded() {
+            resp!(Ok, "ready")
+        } else {
+            resp!(ServiceUnavailable, "currencies not loaded")
+        }
+    }
+
+    /// Whether this is following a leader, and how far it's got
+    pub fn replicationstatus_handler(_: &mut Request) -> IronResult<Response> {
+        resp!(Ok, serde_json::to_string(&replication::status()).unwrap())
+    }
+
+    /// Stop following the leader and start accepting changes, loading any
+    /// currencies and tenant charge accounts the replicated log doesn't have
+    /// yet
+    pub fn promote_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let mut userdb = write_userdb();
+        if !replication::promote(&mut userdb) {
+            return resp!(BadRequest, "not following a leader")
+        }
+        if let Err(currency::CurrencyError(msg)) = currency::load_currencies(&mut userdb, currency::CURRENCY_CONFIG, "promote") {
+            return resp!(InternalServerError, format!("promoted, but loading currencies failed: {}", msg))
+        }
+        if Path::new(kyc::KYC_CONFIG).is_file() {
+            if let Err(kyc::KycConfigError(msg)) = kyc::load_limits(kyc::KYC_CONFIG) {
+                return resp!(InternalServerError, format!("promoted, but loading verification limits failed: {}", msg))
+            }
+        }
+        match tenants::provision(&mut userdb) {
+            Ok(_) => resp!(Ok, serde_json::to_string(&replication::status()).unwrap()),
+            Err(tenants::TenantError(msg)) =>
+                resp!(InternalServerError, format!("promoted, but provisioning tenants failed: {}", msg)),
+        }
+    }
+
+    /// Metrics in the Prometheus text format
+    pub fn metrics_handler(_: &mut Request) -> IronResult<Response> {
+        let content_type: Mime = "text/plain; version=0.0.4".parse().unwrap();
+        Ok(Response::with((status::Ok, content_type, metrics::render())))
+    }
+
     #[derive(Deserialize)]
     struct DumpBalance {
-        account_id: usize,
+        account_id: String,
+        as_of: Option<String>,
     }
+    /// Current balance of `account_id`, or the balance at `as_of` (an RFC
+    /// 3339 timestamp) if given. The balance is in base units, followed by
+    /// how it's written in the account's currency.
     pub fn dumpbalance(req: &mut Request) -> IronResult<Response> {
         let obj: DumpBalance = serde_json::from_reader(&mut req.body).unwrap();
-        let userdb = USERDB.read().unwrap();
-        resp!(Ok, format!("acct {} has balance {}\n",
-            obj.account_id, userdb.get(obj.account_id).unwrap().balance))
+        let userdb = read_userdb();
+        let acct = userdb.lookup(&tenants::tenant(req), &obj.account_id).unwrap();
+        let as_of = match obj.as_of {
+            Some(as_of) => as_of,
+            None => {
+                let balance = userdb.get(acct).unwrap().balance;
+                return resp!(Ok, format!("acct {} has balance {} ({})\n",
+                    obj.account_id, balance, formatted(&userdb, acct, balance).unwrap_or_default()))
+            },
+        };
+        let time = match DateTime::parse_from_rfc3339(&as_of) {
+            Ok(time) => time.timestamp(),
+            Err(_) => return resp!(BadRequest, "as_of must be an RFC 3339 timestamp"),
+        };
+        match events::balance_at(acct, time) {
+            Some(balance) => resp!(Ok, format!("acct {} had balance {} ({}) at {}\n", obj.account_id, balance,
+                                               formatted(&userdb, acct, balance).unwrap_or_default(), as_of)),
+            None => resp!(BadRequest, "account did not exist at as_of"),
+        }
+    }
+
+    fn blocked(blocked: screening::Blocked) -> IronResult<Response> {
+        resp!(Forbidden, format!("blocked by sanctions screening, compliance case {}", blocked.case_id))
+    }
+
+    /// Screen the holders of `accts` before they take part in `operation`
+    fn screen_holders(userdb: &UserDB, operation: &str, accts: &[usize]) -> Result<(), screening::Blocked> {
+        for &idx in accts {
+            let holder_name = userdb.get(idx).unwrap().holder_name.clone();
+            if let Some(name) = holder_name {
+                screening::check(operation, &name, userdb.public_id(idx).map(|id| id.to_string()))?;
+            }
+        }
+        Ok(())
     }
 
     #[derive(Deserialize)]
     struct MakeAccount {
         currency: String,
+        holder_name: String,
     }
-    /// Create account `account_name` with the currency set to `currency`
+    /// Create an account for `holder_name` with the currency set to
+    /// `currency`, unless the holder is on a sanctions list. The account is
+    /// in the namespace of the tenant making the request, if any, and must
+    /// be in one of its currencies.
     pub fn makeaccount_handler(req: &mut Request) -> IronResult<Response> {
-        let obj: MakeAccount = serde_json::from_reader(&mut req.body).unwrap();
-        let mut userdb = USERDB.write().unwrap();
-        if let Some(acct) = UserAccount::new(&obj.currency) {
-            resp!(Ok, userdb.addacct(acct).to_string())
-        } else {
-            resp!(BadRequest, "invalid currency specified")
+        let obj: MakeAccount = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        if obj.holder_name.trim().is_empty() {
+            return resp!(BadRequest, "holder_name is required")
         }
-    }
-
+        let tenant = tenants::tenant(req);
+        let mut acct = match UserAccount::new(&obj.currency) {
+            Some(ref acct) if !tenants::offers(&tenant, &obj.currency) => return resp!(BadRequest, "invalid currency specified"),
+            Some(acct) => acct,
+            None => return resp!(BadRequest, "invalid currency specified"),
+        };
+        if let Err(err) = screening::check("makeaccount", &obj.holder_name, None) {
+            return blocked(err)
+        }
+        acct.holder_name = Some(obj.holder_name);
+        acct.tenant = tenant;
+        let mut userdb = write_userdb();
+        match userdb.addacct(acct) {
+            Ok(account_id) => resp!(Ok, account_id.to_string()),
+            Err(_) => resp!(InternalServerError, UNRECORDED),
+        }
+    }
+
+    #[derive(Serialize)]
+    struct AccountSummary {
+        account_id: String,
+        currency: String,
+        holder_name: Option<String>,
+        balance: i64,
+        balance_formatted: Option<String>,
+        tenant: Option<String>,
+        kyc_level: &'static str,
+        frozen: Option<String>,
+    }
+    /// Every account in the caller's namespace, oldest first - all of them
+    /// for the operator. System accounts aren't listed.
+    pub fn listaccounts_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let tenant = tenants::tenant(req);
+        let userdb = read_userdb();
+        let mut accounts = vec![];
+        for idx in 0..userdb.accts.len() {
+            let account_id = match userdb.public_id(idx) {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            let acct = userdb.get(idx).unwrap();
+            if tenant.is_some() && acct.tenant != tenant {
+                continue
+            }
+            let detail = currency::get_currency(acct.currency);
+            accounts.push(AccountSummary {
+                account_id: account_id,
+                currency: detail.as_ref().map_or(String::new(), |detail| detail.name.clone()),
+                holder_name: acct.holder_name.clone(),
+                balance: acct.balance,
+                balance_formatted: detail.map(|detail| detail.format(acct.balance)),
+                tenant: acct.tenant.clone(),
+                kyc_level: acct.kyc_level.name(),
+                frozen: acct.frozen.clone(),
+            });
+        }
+        resp!(Ok, serde_json::to_string(&accounts).unwrap())
+    }
+
+    /// Every event so far as a `beancount` (the default) or `ledger`
+    /// journal, like `underhanded-rs export-ledger`. The format is taken
+    /// from the query string. The journal covers every tenant, so only the
+    /// operator can have it.
+    pub fn exportledger_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        if tenants::tenant(req).is_some() {
+            return resp!(Unauthorized, "operator token required")
+        }
+        let mut format = export::Format::Beancount;
+        for (key, value) in req.url.as_ref().query_pairs() {
+            if key == "format" {
+                match export::Format::parse(&value) {
+                    Some(parsed) => format = parsed,
+                    None => return resp!(BadRequest, "format must be beancount or ledger"),
+                }
+            }
+        }
+        match events::snapshot() {
+            Ok((userdb, currencies, events)) => {
+                let content_type: Mime = "text/plain; charset=utf-8".parse().unwrap();
+                Ok(Response::with((status::Ok, content_type, export::journal(&userdb, &currencies, &events, format))))
+            },
+            Err(err) => resp!(InternalServerError, format!("event log invalid: {:?}", err)),
+        }
+    }
+
+    #[derive(Serialize)]
+    struct ReplaySummary {
+        events: usize,
+        accounts: usize,
+        /// Accounts whose rebuilt balance or currency differs from before
+        mismatched: Vec<String>,
+    }
+    /// Throw away every account and currency and rebuild them by replaying
+    /// the event log
+    pub fn replay_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let mut userdb = write_userdb();
+        let (mut rebuilt, currencies) = match events::open(events::EVENT_LOG) {
+            Ok(state) => state,
+            Err(err) => return resp!(InternalServerError, format!("event log invalid: {:?}", err)),
+        };
+        let mut mismatched = vec![];
+        for idx in 0..cmp::max(userdb.accts.len(), rebuilt.accts.len()) {
+            let same = match (userdb.get(idx), rebuilt.get(idx)) {
+                (Some(old), Some(new)) => old.balance == new.balance && old.currency == new.currency,
+                _ => false,
+            };
+            if !same {
+                let public_id = userdb.public_id(idx).or(rebuilt.public_id(idx));
+                mismatched.push(public_id.map_or(format!("system account {}", idx), |id| id.to_string()));
+            }
+        }
+        // Rate limiting isn't in the log, so carry it over
+        for (new, old) in rebuilt.accts.iter_mut().zip(userdb.accts.iter_mut()) {
+            let old_transfers = &mut old.get_mut().unwrap().recent_transfers;
+            new.get_mut().unwrap().recent_transfers = mem::replace(old_transfers, vec![]);
+        }
+        let summary = ReplaySummary { events: events::num_events(), accounts: rebuilt.accts.len(), mismatched: mismatched };
+        *userdb = rebuilt;
+        currency::replace_currencies(currencies);
+        resp!(Ok, serde_json::to_string(&summary).unwrap())
+    }
+
+    /// Id of the admin making the request, from the bearer token in the
+    /// Authorization header
+    fn admin(req: &Request) -> Option<String> {
+        req.headers.get::<Authorization<Bearer>>()
+            .and_then(|auth| review::authenticate_role(&auth.token, review::ADMIN_ROLE))
+    }
+
+    #[derive(Deserialize)]
+    struct SetCurrencyRate {
+        currency: String,
+        rate_bps: u32,
+    }
+    /// Set the yearly interest rate paid on positive balances in `currency`,
+    /// in basis points
+    pub fn setcurrencyrate_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let obj: SetCurrencyRate = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let mut userdb = write_userdb();
+        match currency::set_interest_rate(&mut userdb, &obj.currency, obj.rate_bps) {
+            Ok(()) => resp!(Ok, ""),
+            Err(currency::CurrencyError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct SetAccountRate {
+        account_id: String,
+        rate_bps: Option<u32>,
+    }
+    /// Override the interest rate of `account_id`, or go back to its
+    /// currency's rate if `rate_bps` is null
+    pub fn setaccountrate_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let obj: SetAccountRate = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        if obj.rate_bps.map_or(false, |rate| rate > interest::MAX_RATE_BPS) {
+            return resp!(BadRequest, "interest rate too high")
+        }
+        let mut userdb = write_userdb();
+        let acct = match userdb.lookup(&tenants::tenant(req), &obj.account_id) {
+            Some(acct) => acct,
+            None => return resp!(BadRequest, "user does not exist"),
+        };
+        let currency = currency::get_currency(userdb.get(acct).unwrap().currency);
+        if currency.map_or(true, |detail| detail.interest_expense_account.is_none()) {
+            return resp!(BadRequest, "set an interest rate for the currency first, even if zero")
+        }
+        if userdb.set_interest_rate(acct, obj.rate_bps).is_err() {
+            return resp!(InternalServerError, UNRECORDED)
+        }
+        resp!(Ok, "")
+    }
+
+    #[derive(Serialize)]
+    struct InterestPreview {
+        payout_date: String,
+        payouts: Vec<interest::PayoutPreview>,
+    }
+    /// What the next month end interest payout would pay each account, if
+    /// balances don't change before then
+    pub fn interestpreview_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let userdb = read_userdb();
+        let (payout_date, payouts) = interest::preview(&userdb, &currency::all_currencies(), interest::today());
+        let payouts = payouts.into_iter()
+            .filter(|payout| can_see(req, &userdb, Some(&payout.account_id)))
+            .collect();
+        resp!(Ok, serde_json::to_string(&InterestPreview {
+            payout_date: payout_date.format("%Y-%m-%d").to_string(),
+            payouts: payouts,
+        }).unwrap())
+    }
+
+    /// Whether something about account `account_id` can be shown to whoever
+    /// made `req`. A tenant's requests only see its own accounts, and
+    /// nothing without an account, while the operator's see everything.
+    fn can_see(req: &Request, userdb: &UserDB, account_id: Option<&String>) -> bool {
+        match tenants::tenant(req) {
+            None => true,
+            tenant => account_id.map_or(false, |account_id| userdb.lookup(&tenant, account_id).is_some()),
+        }
+    }
+
+    /// Add a new currency, provisioning its transfer charge account.
+    /// Currencies are shared by every tenant, so only the operator can.
+    pub fn addcurrency_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        if tenants::tenant(req).is_some() {
+            return resp!(Unauthorized, "operator token required")
+        }
+        let obj: currency::CurrencyConfig = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let mut userdb = write_userdb();
+        match currency::add_currency(&mut userdb, obj) {
+            Ok(_) => resp!(Ok, ""),
+            Err(currency::CurrencyError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    #[derive(Serialize)]
+    struct ReloadedCurrencies {
+        currencies: usize,
+    }
+    /// Load any currencies added to the currency config since startup.
+    /// Currencies already loaded can't be changed this way. Currencies are
+    /// shared by every tenant, so only the operator can.
+    pub fn reloadcurrencies_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        if tenants::tenant(req).is_some() {
+            return resp!(Unauthorized, "operator token required")
+        }
+        let mut userdb = write_userdb();
+        match currency::load_currencies(&mut userdb, currency::CURRENCY_CONFIG, "reloadcurrencies") {
+            Ok(()) => resp!(Ok, serde_json::to_string(&ReloadedCurrencies {
+                currencies: currency::all_currencies().len(),
+            }).unwrap()),
+            Err(currency::CurrencyError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct SetChargeAccount {
+        currency: String,
+        /// Whose charge account in the currency, or the currency's own if
+        /// none
+        tenant: Option<String>,
+        enabled: bool,
+    }
+    /// Disable a charge account, so transfers whose charge would go to it
+    /// are made free of charge, or enable it again. Tenants can only change
+    /// their own.
+    pub fn setchargeaccount_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let obj: SetChargeAccount = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let caller = tenants::tenant(req);
+        if caller.is_some() && obj.tenant != caller {
+            return resp!(BadRequest, "tenant must be the caller's")
+        }
+        let mut userdb = write_userdb();
+        match currency::set_charge_account_enabled(&mut userdb, &obj.currency, &obj.tenant, obj.enabled) {
+            Ok(()) => resp!(Ok, ""),
+            Err(currency::CurrencyError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    /// `amount` in minor units of the currency of account `acct`
+    fn amount_in(userdb: &UserDB, acct: usize, amount: &currency::Amount) -> Result<i64, &'static str> {
+        let currency = match userdb.get(acct) {
+            Some(acct) => acct.currency,
+            None => return Err("user does not exist"),
+        };
+        match currency::get_currency(currency) {
+            Some(detail) => amount.minor_units(&detail),
+            None => Err("account has no valid currency"),
+        }
+    }
+
+    /// `amount` written in the currency of account `acct`, for responses to
+    /// give alongside the base units
+    fn formatted(userdb: &UserDB, acct: usize, amount: i64) -> Option<String> {
+        let currency = userdb.get(acct).map(|acct| acct.currency)?;
+        currency::get_currency(currency).map(|detail| detail.format(amount))
+    }
+
     #[derive(Deserialize)]
     struct Deposit {
-        account_id: usize,
-        amount: u64,
+        account_id: String,
+        amount: currency::Amount,
     }
-    /// Deposit `amount` of the base unit of the currency for the specified
-    /// `account_id` into that account's balance
+    /// Deposit `amount` of the currency for the specified `account_id` into
+    /// that account's balance, either in base units or as a decimal string
     pub fn deposit_handler(req: &mut Request) -> IronResult<Response> {
-        let obj: Deposit = serde_json::from_reader(&mut req.body).unwrap();
-        assert!(obj.amount < i64::MAX as u64);
-        let amount = obj.amount as i64;
-        match USERDB.write().unwrap().get_mut(obj.account_id) {
-            Some(ua) => ua.balance += amount,
+        let obj: Deposit = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let userdb = read_userdb();
+        let acct = match userdb.lookup(&tenants::tenant(req), &obj.account_id) {
+            Some(acct) => acct,
             None => return resp!(BadRequest, "user does not exist"),
+        };
+        let amount = match amount_in(&userdb, acct, &obj.amount) {
+            Ok(amount) => amount,
+            Err(msg) => return resp!(BadRequest, msg),
+        };
+        match make_deposit(&userdb, acct, amount) {
+            Ok(()) => resp!(Ok, ""),
+            Err(msg) => resp!(BadRequest, msg),
+        }
+    }
+
+    /// Deposit `amount` into `acct`, as long as it stays within the balance
+    /// limit of the account's verification level
+    fn make_deposit(userdb: &UserDB, acct: usize, amount: i64) -> Result<(), &'static str> {
+        let deposit = Posting { account: acct, amount: amount, kind: EntryKind::Deposit, counterparty: None };
+        let mut locked = match userdb.lock_many(&[acct]) {
+            Ok(locked) => locked,
+            Err(_) => return Err("user does not exist"),
+        };
+        if let Err(err) = kyc::check_deposit(locked.accts_mut()[0], amount) {
+            return Err(err.message())
+        }
+        match locked.apply_postings(&[deposit]) {
+            Ok(()) => Ok(()),
+            Err(UserDBError::Unrecorded) => Err(UNRECORDED),
+            Err(_) => Err("deposit would overflow balance"),
         }
-        resp!(Ok, "")
     }
 
     #[derive(Deserialize)]
     struct Transfer {
-        account_from: usize,
-        account_to: usize,
-        amount: u64,
+        account_from: String,
+        account_to: String,
+        amount: currency::Amount,
     }
-    /// Transfer `amount` from `account_from` to `account_to`
+    /// Filter old requests, note down that a request has been attempted (to
+    /// contribute towards rate limiting) and calculate how much the user
+    /// should be rate limited right now
+    fn rate_limit(acct: &mut UserAccount) -> Duration {
+        let rate_limit_bound = Duration::new(RATE_LIMIT_SECS, 0);
+        acct.recent_transfers.retain(|inst| inst.elapsed() < rate_limit_bound);
+        acct.recent_transfers.push(Instant::now());
+        let wait_millis = acct.recent_transfers.len() * MS_PER_REQ_RATE;
+        // Cap at a 4s wait to not overflow u32
+        let wait_millis = if wait_millis > 4000 { 4000 } else { wait_millis } as u32;
+        let wait = Duration::new(0, wait_millis * 1_000_000);
+        metrics::observe_rate_limit(wait);
+        wait
+    }
+
+    /// Transfer `amount` from `account_from` to `account_to`. If the sender
+    /// has an approval policy covering the amount, the transfer waits for
+    /// approval instead.
     pub fn transfer_handler(req: &mut Request) -> IronResult<Response> {
-        let obj: Transfer = serde_json::from_reader(&mut req.body).unwrap();
-        assert!(obj.amount < i64::MAX as u64);
-        let amount = obj.amount as i64;
+        let obj: Transfer = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let userdb = read_userdb();
+        match transfer_accounts(&userdb, &tenants::tenant(req), &obj) {
+            Ok((from, to, amount)) => transfer_response(make_transfer(&userdb, from, to, amount)),
+            Err(resp) => resp,
+        }
+    }
+
+    /// The accounts for `transfer_handler` to transfer between and the
+    /// amount in the sender's currency, or the response if the transfer
+    /// can't be made straight away. The sender must be in `tenant`'s
+    /// namespace, and the recipient in one it can pay.
+    fn transfer_accounts(userdb: &UserDB, tenant: &Option<String>,
+                         obj: &Transfer) -> Result<(usize, usize, i64), IronResult<Response>> {
+        let from = userdb.lookup(tenant, &obj.account_from);
+        let (from, to) = match (from, from.and_then(|from| userdb.lookup_payee(from, &obj.account_to))) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Err(resp!(BadRequest, "one or both accounts do not exist")),
+        };
+        let amount = match amount_in(userdb, from, &obj.amount) {
+            Ok(amount) => amount,
+            Err(msg) => return Err(resp!(BadRequest, msg)),
+        };
         if amount < currency::MINIMUM_TRANSFER_AMOUNT {
-            return resp!(BadRequest, "below minimum transfer")
+            return Err(resp!(BadRequest, "below minimum transfer"))
         }
+        if let Err(err) = screen_holders(userdb, "transfer", &[from, to]) {
+            return Err(blocked(err))
+        }
+        let account_from = userdb.public_id(from).unwrap().to_string();
+        if approvals::threshold(&account_from).map_or(false, |threshold| amount as u64 >= threshold) {
+            // Turn away transfers which could never be made before asking
+            // anyone to approve them
+            if from == to {
+                return Err(resp!(BadRequest, "cannot transfer to the same account"))
+            }
+            let from_currency = userdb.get(from).unwrap().currency;
+            if userdb.get(to).unwrap().currency != from_currency {
+                return Err(resp!(BadRequest, "user account currencies do not match"))
+            }
+            let account_to = userdb.public_id(to).unwrap().to_string();
+            match approvals::submit(&account_from, &account_to, amount as u64, unix_now()) {
+                Ok(Some(pending_id)) => return Err(resp!(Accepted, format!("transfer awaiting approval {}", pending_id))),
+                Ok(None) => (),
+                Err(ApprovalError(msg)) => return Err(resp!(BadRequest, msg)),
+            }
+        }
+        Ok((from, to, amount))
+    }
 
-        let mut userdb = USERDB.write().unwrap();
-        let mut currency_detail = None;
-        // Check the transfer is valid
-        {
-        let (acct_from, acct_to) = match userdb.get2_mut(obj.account_from, obj.account_to) {
-            (Some(acct_from), Some(acct_to)) => (acct_from, acct_to),
+    /// What happened to a transfer which passed every check
+    enum TransferOutcome {
+        Made,
+        /// Flagged by the fraud checker and held for review
+        Held(u64),
+    }
+    impl TransferOutcome {
+        fn description(&self) -> String {
+            match *self {
+                TransferOutcome::Made => "transfer made".to_owned(),
+                TransferOutcome::Held(review_id) => format!("transfer held for review {}", review_id),
+            }
+        }
+    }
+    fn transfer_response(result: Result<TransferOutcome, &'static str>) -> IronResult<Response> {
+        match result {
+            Ok(TransferOutcome::Made) => resp!(Ok, ""),
+            Ok(held) => resp!(Accepted, held.description()),
+            Err(msg) => resp!(BadRequest, msg),
+        }
+    }
+
+    /// Check and make a transfer of `amount` from `from` to `to`, rate
+    /// limiting the sender and holding it for review if the fraud checker
+    /// flags either account. Only the two accounts are locked meanwhile, so
+    /// transfers between other accounts aren't held up.
+    fn make_transfer(userdb: &UserDB, from: usize, to: usize, amount: i64) -> Result<TransferOutcome, &'static str> {
+        checked_transfer(userdb, from, to, amount, |acct_from, acct_to, rate_limit_wait| {
+            crossbeam::scope(|scope| {
+                let err_spawns = vec![
+                    scope.spawn(|| fraud_checker().check(acct_from)),
+                    scope.spawn(|| fraud_checker().check(acct_to)),
+                ];
+                // Do the rate limit in parallel with fraud checking, no need for the user to
+                // have to wait twice
+                thread::sleep(rate_limit_wait);
+                // Retrieve the fraud check results
+                let mut fraud_err = None;
+                for spawn in err_spawns {
+                    if let Some(err) = spawn.join() {
+                        fraud_err = Some(err);
+                    }
+                }
+                fraud_err
+            })
+        })
+    }
+
+    /// `make_transfer`, with `check` given both accounts and the rate limit
+    /// wait to check them for fraud while they're locked
+    fn checked_transfer<F>(userdb: &UserDB, from: usize, to: usize, amount: i64,
+                           check: F) -> Result<TransferOutcome, &'static str>
+            where F: FnOnce(&mut UserAccount, &mut UserAccount, Duration) -> Option<FraudError> {
+        let mut locked = match userdb.lock_many(&[from, to]) {
+            Ok(locked) => locked,
+            Err(UserDBError::DuplicateAccount(_)) =>
+                return Err("cannot transfer to the same account"),
+            Err(_) => return Err("one or both accounts do not exist"),
+        };
+        let (currency_detail, fraud_err) = {
+            let mut accts = locked.accts_mut();
+            let acct_to = accts.pop().unwrap();
+            let acct_from = accts.pop().unwrap();
+            // The request isn't totally wrong, so it counts towards rate limiting
+            let rate_limit_wait = rate_limit(acct_from);
+            let currency_detail = validate_transfer(acct_from, acct_to, amount)?;
+            (currency_detail, check(acct_from, acct_to, rate_limit_wait))
+        };
+        complete_transfer(locked, from, to, amount, &currency_detail, fraud_err)
+    }
+
+    /// A transfer started by `begin_transfer`, waiting on the rate limit and
+    /// the fraud checker
+    pub struct PendingTransfer {
+        from: usize,
+        to: usize,
+        amount: i64,
+        /// How long the sender is rate limited for
+        pub wait: Duration,
+    }
+
+    /// `transfer_handler` up to where it waits, for the async server. Counts
+    /// the transfer towards rate limiting and returns copies of the accounts
+    /// to check for fraud. The accounts aren't kept locked, so they can be
+    /// used while waiting - `finish_transfer` checks them again.
+    pub fn begin_transfer(tenant: &Option<String>,
+                          body: &[u8]) -> Result<(PendingTransfer, Vec<UserAccount>), IronResult<Response>> {
+        let obj: Transfer = serde_json::from_slice(body).unwrap();
+        let userdb = read_userdb();
+        let (from, to, amount) = transfer_accounts(&userdb, tenant, &obj)?;
+        prepare_transfer(&userdb, from, to, amount).map_err(|err| transfer_response(Err(err)))
+    }
+
+    fn prepare_transfer(userdb: &UserDB, from: usize, to: usize,
+                        amount: i64) -> Result<(PendingTransfer, Vec<UserAccount>), &'static str> {
+        let mut locked = match userdb.lock_many(&[from, to]) {
+            Ok(locked) => locked,
+            Err(UserDBError::DuplicateAccount(_)) => return Err("cannot transfer to the same account"),
+            Err(_) => return Err("one or both accounts do not exist"),
+        };
+        let mut accts = locked.accts_mut();
+        let wait = rate_limit(accts[0]);
+        validate_transfer(accts[0], accts[1], amount)?;
+        let pending = PendingTransfer { from: from, to: to, amount: amount, wait: wait };
+        Ok((pending, accts.into_iter().map(|acct| acct.clone()).collect()))
+    }
+
+    /// Make a transfer started by `begin_transfer` once it's waited, or hold
+    /// it for review given the reason the fraud checker flagged it
+    pub fn finish_transfer(pending: PendingTransfer, fraud_err: Option<FraudError>) -> IronResult<Response> {
+        transfer_response(settle_transfer(&read_userdb(), pending, fraud_err))
+    }
+
+    fn settle_transfer(userdb: &UserDB, pending: PendingTransfer,
+                       fraud_err: Option<FraudError>) -> Result<TransferOutcome, &'static str> {
+        let mut locked = match userdb.lock_many(&[pending.from, pending.to]) {
+            Ok(locked) => locked,
+            Err(_) => return Err("one or both accounts do not exist"),
+        };
+        // Either account may have changed while waiting
+        let currency_detail = {
+            let accts = locked.accts_mut();
+            validate_transfer(accts[0], accts[1], pending.amount)?
+        };
+        complete_transfer(locked, pending.from, pending.to, pending.amount, &currency_detail, fraud_err)
+    }
+
+    /// Check `acct_from` can pay `amount` and its charge to `acct_to`, within
+    /// the limits of both their verification levels, returning the details
+    /// of their currency, with the charge going to the sender's tenant
+    fn validate_transfer(acct_from: &UserAccount, acct_to: &UserAccount,
+                         amount: i64) -> Result<currency::CurrencyDetail, &'static str> {
+        if acct_from.frozen.is_some() {
+            return Err("account_from is frozen")
+        }
+        let detail = match currency::tenant_currency(acct_from.currency, &acct_from.tenant) {
+            Some(detail) => detail,
+            None => return Err("account_from has no valid currency"),
+        };
+        let total = amount.saturating_add(detail.transfer_charge_for(amount));
+        if acct_from.balance.saturating_add(detail.overdraft_limit as i64) < total {
+            return Err("balance too low in account_from")
+        }
+        if acct_from.currency != acct_to.currency {
+            return Err("user account currencies do not match")
+        }
+        if let Err(err) = kyc::check_transfer(acct_from, acct_to, amount, unix_now()) {
+            return Err(err.message())
+        }
+        Ok(detail)
+    }
+
+    /// Make a validated transfer between the `locked` accounts, or hold it
+    /// for review if the fraud checker flagged it
+    fn complete_transfer(locked: LockedAccounts, from: usize, to: usize, amount: i64,
+                         currency_detail: &currency::CurrencyDetail,
+                         fraud_err: Option<FraudError>) -> Result<TransferOutcome, &'static str> {
+        // Transfer is validated, let's go!
+        let plan = match TransferPlan::new(from, to, amount, currency_detail) {
+            Some(plan) => plan,
+            None => return Err("amount too large"),
+        };
+        if let Some(FraudError(flag_reason)) = fraud_err {
+            // Possible fraud, hold onto the money until a reviewer has a look
+            let hold_account = currency_detail.review_hold_account;
+            metrics::count_fraud_flag("held");
+            return match review::hold_transfer(locked, plan, hold_account, flag_reason) {
+                Ok(review_id) => Ok(TransferOutcome::Held(review_id)),
+                Err(UserDBError::Unrecorded) => Err(UNRECORDED),
+                Err(_) => Err("transfer would overflow a balance"),
+            }
+        }
+        match locked.apply_postings(&plan.postings(from)) {
+            Ok(()) => Ok(TransferOutcome::Made),
+            Err(UserDBError::Unrecorded) => Err(UNRECORDED),
+            Err(_) => Err("transfer would overflow a balance"),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct BatchTransfer {
+        account_from: String,
+        transfers: Vec<batch::BatchItem>,
+    }
+    #[derive(Serialize)]
+    struct BatchSummary {
+        transfers: usize,
+        amount: i64,
+        amount_formatted: String,
+        charges: i64,
+        charges_formatted: String,
+    }
+    #[derive(Serialize)]
+    struct BatchRejection {
+        error: &'static str,
+        items: Vec<batch::ItemError>,
+    }
+    fn batch_rejection(err: batch::BatchError) -> IronResult<Response> {
+        let rejection = match err {
+            batch::BatchError::Batch(error) => BatchRejection { error: error, items: vec![] },
+            batch::BatchError::Items(items) => BatchRejection { error: "invalid transfers in batch", items: items },
+        };
+        resp!(BadRequest, serde_json::to_string(&rejection).unwrap())
+    }
+    /// Make a batch of transfers from one account, either all of them or
+    /// none. Takes json `{"account_from", "transfers": [{"account_to",
+    /// "amount"}]}`, or with a `text/csv` content type, `account_to,amount`
+    /// lines with `account_from` in the query string. The rate limit delay
+    /// is only paid once per batch.
+    pub fn batchtransfer_handler(req: &mut Request) -> IronResult<Response> {
+        let is_csv = match req.headers.get::<ContentType>() {
+            Some(&ContentType(Mime(TopLevel::Text, SubLevel::Ext(ref sub), _))) => sub == "csv",
+            _ => false,
+        };
+        let (account_from, items) = if is_csv {
+            let account_from = req.url.as_ref().query_pairs()
+                .find(|&(ref key, _)| key == "account_from")
+                .map(|(_, value)| value.into_owned());
+            let account_from = match account_from {
+                Some(account_from) => account_from,
+                None => return batch_rejection(batch::BatchError::Batch("account_from is required")),
+            };
+            match batch::parse_csv(audit::raw_body(req)) {
+                Ok(items) => (account_from, items),
+                Err(err) => return batch_rejection(err),
+            }
+        } else {
+            let obj: BatchTransfer = serde_json::from_slice(audit::raw_body(req)).unwrap();
+            (obj.account_from, obj.transfers)
+        };
+
+        let userdb = read_userdb();
+        let from = match userdb.lookup(&tenants::tenant(req), &account_from) {
+            Some(from) => from,
+            None => return batch_rejection(batch::BatchError::Batch("account_from does not exist")),
+        };
+        let (currency, tenant) = {
+            let acct_from = userdb.get(from).unwrap();
+            (acct_from.currency, acct_from.tenant.clone())
+        };
+        let currency_detail = match currency::tenant_currency(currency, &tenant) {
+            Some(detail) => detail,
+            None => return batch_rejection(batch::BatchError::Batch("account_from has no valid currency")),
+        };
+        let plans = match batch::plan(&userdb, from, &currency_detail, &items) {
+            Ok(plans) => plans,
+            Err(err) => return batch_rejection(err),
+        };
+        if let Some(threshold) = approvals::threshold(&userdb.public_id(from).unwrap().to_string()) {
+            let needing_approval = batch::needing_approval(&plans, threshold);
+            if !needing_approval.is_empty() {
+                return batch_rejection(batch::BatchError::Items(needing_approval))
+            }
+        }
+        let holders = batch::accounts(from, &plans);
+        if let Err(err) = screen_holders(&userdb, "batchtransfer", &holders) {
+            return blocked(err)
+        }
+
+        // Only the sender is fraud checked - there's no way to hold part of
+        // a batch, so a flagged sender has to make the transfers one by one
+        let mut locked = userdb.lock_many(&holders).unwrap();
+        let fraud_err = {
+            let mut accts = locked.accts_mut();
+            let acct_from = &mut *accts[0];
+            crossbeam::scope(|scope| {
+                let rate_limit_wait = rate_limit(acct_from);
+                let check = scope.spawn(|| fraud_checker().check(acct_from));
+                thread::sleep(rate_limit_wait);
+                check.join()
+            })
+        };
+        if fraud_err.is_some() {
+            metrics::count_fraud_flag("rejected");
+            return batch_rejection(batch::BatchError::Batch(
+                "account_from flagged as possible fraud, transfers must be made individually"))
+        }
+        // The batch was planned before its accounts were locked
+        if let Err(err) = batch::check(&locked.accts_mut(), &holders, &plans, &currency_detail) {
+            return batch_rejection(err)
+        }
+
+        let postings: Vec<Posting> = plans.iter().flat_map(|plan| plan.postings(from)).collect();
+        match locked.apply_postings(&postings) {
+            Ok(()) => {
+                let amount = plans.iter().map(|plan| plan.amount).sum();
+                let charges = plans.iter().map(|plan| plan.charge_amount).sum();
+                resp!(Ok, serde_json::to_string(&BatchSummary {
+                    transfers: plans.len(),
+                    amount: amount,
+                    amount_formatted: currency_detail.format(amount),
+                    charges: charges,
+                    charges_formatted: currency_detail.format(charges),
+                }).unwrap())
+            },
+            Err(UserDBError::Unrecorded) => resp!(InternalServerError, UNRECORDED),
+            Err(_) => batch_rejection(batch::BatchError::Batch("transfer would overflow a balance")),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct OpenEscrow {
+        account_from: String,
+        account_to: String,
+        amount: currency::Amount,
+        expires_at: String,
+    }
+    #[derive(Serialize)]
+    struct OpenedEscrow {
+        escrow_id: u64,
+    }
+    /// Hold `amount` from the buyer `account_from`, plus the transfer
+    /// charge, in escrow for the seller `account_to` until it's released or
+    /// refunded, or `expires_at` (an RFC 3339 timestamp) passes
+    pub fn openescrow_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: OpenEscrow = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let expires_at = match DateTime::parse_from_rfc3339(&obj.expires_at) {
+            Ok(time) => time.timestamp(),
+            Err(_) => return resp!(BadRequest, "expires_at must be an RFC 3339 timestamp"),
+        };
+        let now = UTC::now().timestamp();
+        if expires_at <= now || expires_at > now + escrow::MAX_ESCROW_SECS {
+            return resp!(BadRequest, "expires_at must be in the future and within a year")
+        }
+
+        let userdb = read_userdb();
+        let from = userdb.lookup(&tenants::tenant(req), &obj.account_from);
+        let (from, to) = match (from, from.and_then(|from| userdb.lookup_payee(from, &obj.account_to))) {
+            (Some(from), Some(to)) => (from, to),
             _ => return resp!(BadRequest, "one or both accounts do not exist"),
         };
-        let maybe_err = crossbeam::scope(|scope| {
-            // The request isn't totally wrong, so filter old requests, note down that
-            // a request has been attempted (to contribute towards rate limiting) and
-            // calculate how much the user should be rate limited right now (
-            let rate_limit_bound = Duration::new(RATE_LIMIT_SECS, 0);
-            acct_from.recent_transfers.retain(|inst| inst.elapsed() < rate_limit_bound);
-            acct_from.recent_transfers.push(Instant::now());
-            let wait_millis = acct_from.recent_transfers.len() * MS_PER_REQ_RATE;
-            // Cap at a 4s wait to not overflow u32
-            let wait_millis = if wait_millis > 4000 { 4000 } else { wait_millis } as u32;
-            let rate_limit_wait = Duration::new(0, wait_millis * 1_000_000);
-
-            if acct_from.balance + (acct_from.currency.overdraft_limit as i64) < amount {
-                return Some(resp!(BadRequest, "balance too low in account_from"))
-            }
-            if acct_from.currency != acct_to.currency {
-                return Some(resp!(BadRequest, "user account currencies do not match"))
-            }
-            let err_spawns = vec![
-                scope.spawn(|| fraud_checker().check(acct_from)),
-                scope.spawn(|| fraud_checker().check(acct_to)),
-            ];
-            // Do the rate limit in parallel with fraud checking, no need for the user to
-            // have to wait twice
-            thread::sleep(rate_limit_wait);
-            // Save the currency details
-            currency_detail = Some(&*acct_from.currency);
-            // Retrieve the fraud check results
-            for spawn in err_spawns {
-                if let Some(_fraud_err) = spawn.join() {
-                    return Some(resp!(BadRequest, "request rejected, possible fraud"))
-                }
+        if from == to {
+            return resp!(BadRequest, "cannot transfer to the same account")
+        }
+        if let Err(err) = screen_holders(&userdb, "escrow", &[from, to]) {
+            return blocked(err)
+        }
+        let (currency, tenant) = {
+            let acct_from = userdb.get(from).unwrap();
+            (acct_from.currency, acct_from.tenant.clone())
+        };
+        if userdb.get(to).unwrap().currency != currency {
+            return resp!(BadRequest, "user account currencies do not match")
+        }
+        let currency_detail = match currency::tenant_currency(currency, &tenant) {
+            Some(detail) => detail,
+            None => return resp!(BadRequest, "account_from has no valid currency"),
+        };
+        let amount = match obj.amount.minor_units(&currency_detail) {
+            Ok(amount) => amount,
+            Err(msg) => return resp!(BadRequest, msg),
+        };
+        if amount < currency::MINIMUM_TRANSFER_AMOUNT {
+            return resp!(BadRequest, "below minimum transfer")
+        }
+        let plan = match TransferPlan::new(from, to, amount, &currency_detail) {
+            Some(plan) => plan,
+            None => return resp!(BadRequest, "amount too large"),
+        };
+        // Checked again under the write lock, as the accounts are unlocked
+        // to take it
+        let can_open = |userdb: &UserDB, acct_from: &UserAccount| {
+            if acct_from.frozen.is_some() {
+                return Err("account_from is frozen")
             }
-            None
-        });
-        if let Some(err) = maybe_err {
-            return err
+            let threshold = approvals::threshold(&userdb.public_id(from).unwrap().to_string());
+            if threshold.map_or(false, |threshold| escrow::held_for(userdb, from, to).saturating_add(amount) as u64 >= threshold) {
+                return Err("needs approval, make it as a single transfer")
+            }
+            if acct_from.balance.saturating_add(currency_detail.overdraft_limit as i64) < plan.total() {
+                return Err("balance too low in account_from")
+            }
+            Ok(())
+        };
+
+        // Releasing is a transfer which is never held for review, so the
+        // fraud checker has its say now
+        let flagged = {
+            let mut locked = userdb.lock_many(&[from, to]).unwrap();
+            let mut accts = locked.accts_mut();
+            let acct_to = accts.pop().unwrap();
+            let acct_from = accts.pop().unwrap();
+            if let Err(msg) = can_open(&userdb, acct_from) {
+                return resp!(BadRequest, msg)
+            }
+            if let Err(err) = kyc::check_transfer(acct_from, acct_to, amount, now) {
+                return resp!(BadRequest, err.message())
+            }
+            crossbeam::scope(|scope| {
+                let rate_limit_wait = rate_limit(acct_from);
+                let checks = vec![
+                    scope.spawn(|| fraud_checker().check(acct_from)),
+                    scope.spawn(|| fraud_checker().check(acct_to)),
+                ];
+                thread::sleep(rate_limit_wait);
+                checks.into_iter().fold(false, |flagged, check| check.join().is_some() || flagged)
+            })
+        };
+        if flagged {
+            metrics::count_fraud_flag("rejected");
+            return resp!(BadRequest, "flagged as possible fraud, make a normal transfer so it can be reviewed")
+        }
+        drop(userdb);
+
+        let mut userdb = write_userdb();
+        if let Err(msg) = can_open(&userdb, &userdb.get(from).unwrap()) {
+            return resp!(BadRequest, msg)
+        }
+        match escrow::open(&mut userdb, &plan, expires_at) {
+            Ok(escrow_id) => resp!(Ok, serde_json::to_string(&OpenedEscrow { escrow_id: escrow_id }).unwrap()),
+            Err(escrow::EscrowError::Ledger(UserDBError::Unrecorded)) => resp!(InternalServerError, UNRECORDED),
+            Err(_) => resp!(BadRequest, "transfer would overflow a balance"),
+        }
+    }
+
+    #[derive(Serialize)]
+    struct EscrowSummary {
+        escrow_id: u64,
+        /// Who is looking - `buyer`, `seller` or `arbiter <id>`. The other
+        /// party's account id is never shown, as it's all it takes to use
+        /// their account.
+        role: String,
+        amount: i64,
+        amount_formatted: Option<String>,
+        held_charge: i64,
+        held_charge_formatted: Option<String>,
+        opened_at: i64,
+        expires_at: i64,
+        status: escrow::EscrowStatus,
+        closed_at: Option<i64>,
+        closed_by: Option<String>,
+    }
+    impl EscrowSummary {
+        fn new(userdb: &UserDB, escrow: &Escrow, role: String) -> EscrowSummary {
+            EscrowSummary {
+                escrow_id: escrow.escrow_id,
+                role: role,
+                amount: escrow.amount,
+                amount_formatted: formatted(userdb, escrow.buyer, escrow.amount),
+                held_charge: escrow.held_charge,
+                held_charge_formatted: formatted(userdb, escrow.buyer, escrow.held_charge),
+                opened_at: escrow.opened_at,
+                expires_at: escrow.expires_at,
+                status: escrow.status,
+                closed_at: escrow.closed_at,
+                closed_by: escrow.closed_by.clone(),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct EscrowRequest {
+        escrow_id: u64,
+        /// The buyer or seller making the request, unless an arbiter is
+        account_id: Option<String>,
+    }
+    /// Id of the arbiter making the request, from the bearer token in the
+    /// Authorization header
+    fn arbiter(req: &Request) -> Option<String> {
+        req.headers.get::<Authorization<Bearer>>()
+            .and_then(|auth| review::authenticate_role(&auth.token, review::ARBITER_ROLE))
+    }
+
+    /// Who is acting on `escrow` - an arbiter, going by the bearer token, or
+    /// else the buyer or seller named by `account_id`
+    fn escrow_party(req: &Request, userdb: &UserDB, escrow: &Escrow, account_id: &Option<String>) -> Option<String> {
+        if let Some(arbiter) = arbiter(req) {
+            return Some(format!("arbiter {}", arbiter))
+        }
+        match account_id.as_ref().and_then(|account_id| userdb.lookup(&tenants::tenant(req), account_id)) {
+            Some(idx) if idx == escrow.buyer => Some("buyer".to_owned()),
+            Some(idx) if idx == escrow.seller => Some("seller".to_owned()),
+            _ => None,
+        }
+    }
+
+    fn escrow_error(err: EscrowError) -> IronResult<Response> {
+        match err {
+            EscrowError::NoSuchEscrow => resp!(BadRequest, "escrow does not exist"),
+            EscrowError::Closed => resp!(BadRequest, "escrow already released or refunded"),
+            EscrowError::BalanceTooLow => resp!(BadRequest, "balance too low in account_from to pay the higher charge"),
+            EscrowError::Ledger(UserDBError::Unrecorded) => resp!(InternalServerError, UNRECORDED),
+            EscrowError::Ledger(_) => resp!(BadRequest, "transfer would overflow a balance"),
+        }
+    }
+
+    /// Show escrow `escrow_id` to its buyer, seller or an arbiter
+    pub fn getescrow_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: EscrowRequest = serde_json::from_reader(&mut req.body).unwrap();
+        let userdb = read_userdb();
+        let escrow = match userdb.escrows.get(&obj.escrow_id) {
+            Some(escrow) => escrow,
+            None => return escrow_error(EscrowError::NoSuchEscrow),
+        };
+        let role = match escrow_party(req, &userdb, escrow, &obj.account_id) {
+            Some(role) => role,
+            None => return resp!(Unauthorized, "buyer, seller or arbiter required"),
+        };
+        resp!(Ok, serde_json::to_string(&EscrowSummary::new(&userdb, escrow, role)).unwrap())
+    }
+
+    /// Pay escrow `escrow_id` out to the seller, charged as a transfer made
+    /// now. Only the buyer or an arbiter can release it.
+    pub fn releaseescrow_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: EscrowRequest = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let mut userdb = write_userdb();
+        let escrow = match userdb.escrows.get(&obj.escrow_id) {
+            Some(escrow) => escrow.clone(),
+            None => return escrow_error(EscrowError::NoSuchEscrow),
+        };
+        let closed_by = match escrow_party(req, &userdb, &escrow, &obj.account_id) {
+            Some(ref party) if party == "seller" => return resp!(Unauthorized, "buyer or arbiter required"),
+            Some(party) => party,
+            None => return resp!(Unauthorized, "buyer or arbiter required"),
+        };
+        if let Err(err) = screen_holders(&userdb, "escrow", &[escrow.buyer, escrow.seller]) {
+            return blocked(err)
+        }
+        // The seller may have been paid up to their balance limit since the
+        // escrow was opened
+        if let Err(err) = kyc::check_receive(&userdb.get(escrow.seller).unwrap(), escrow.amount) {
+            return resp!(BadRequest, err.message())
+        }
+        let (currency, tenant) = {
+            let buyer = userdb.get(escrow.buyer).unwrap();
+            (buyer.currency, buyer.tenant.clone())
+        };
+        let currency_detail = currency::tenant_currency(currency, &tenant).unwrap(); // account currencies are always loaded
+        match escrow::release(&mut userdb, obj.escrow_id, &currency_detail, closed_by) {
+            Ok(()) => resp!(Ok, ""),
+            Err(err) => escrow_error(err),
+        }
+    }
+
+    /// Return escrow `escrow_id` to the buyer before it expires. Only the
+    /// seller or an arbiter can refund it.
+    pub fn refundescrow_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: EscrowRequest = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let mut userdb = write_userdb();
+        let closed_by = match userdb.escrows.get(&obj.escrow_id) {
+            Some(escrow) => escrow_party(req, &userdb, escrow, &obj.account_id),
+            None => return escrow_error(EscrowError::NoSuchEscrow),
+        };
+        let closed_by = match closed_by {
+            Some(ref party) if party == "buyer" => return resp!(Unauthorized, "seller or arbiter required"),
+            Some(party) => party,
+            None => return resp!(Unauthorized, "seller or arbiter required"),
+        };
+        match escrow::refund(&mut userdb, obj.escrow_id, closed_by) {
+            Ok(()) => resp!(Ok, ""),
+            Err(err) => escrow_error(err),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct OpenDispute {
+        account_id: String,
+        entry_id: usize,
+        reason: String,
+    }
+    #[derive(Serialize)]
+    struct OpenedDispute {
+        dispute_id: u64,
+    }
+    /// Dispute the transfer out of `account_id` at `entry_id`, as numbered
+    /// in statements. Its amount is paid back by the recipient straight
+    /// away, until the dispute is decided.
+    pub fn opendispute_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: OpenDispute = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        if obj.reason.trim().is_empty() || obj.reason.len() > disputes::MAX_NOTE_LEN {
+            return resp!(BadRequest, "reason is required, and at most 2000 bytes")
         }
+        let mut userdb = write_userdb();
+        let sender = match userdb.lookup(&tenants::tenant(req), &obj.account_id) {
+            Some(sender) => sender,
+            None => return resp!(BadRequest, "user does not exist"),
+        };
+        let recipient = userdb.get(sender).unwrap().history.get(obj.entry_id).and_then(|entry| entry.counterparty);
+        if let Err(err) = screen_holders(&userdb, "dispute", &Some(sender).into_iter().chain(recipient).collect::<Vec<_>>()) {
+            return blocked(err)
+        }
+        // Any fee goes to the same charge account as the transfer's charge
+        let (currency, tenant) = {
+            let acct = userdb.get(sender).unwrap();
+            (acct.currency, acct.tenant.clone())
+        };
+        let currency_detail = currency::tenant_currency(currency, &tenant).unwrap(); // account currencies are always loaded
+        match disputes::open(&mut userdb, sender, obj.entry_id, obj.reason, &currency_detail) {
+            Ok(dispute_id) => resp!(Ok, serde_json::to_string(&OpenedDispute { dispute_id: dispute_id }).unwrap()),
+            Err(err) => dispute_error(err),
+        }
+    }
+
+    #[derive(Serialize)]
+    struct DisputeSummary {
+        dispute_id: u64,
+        /// Who is looking - `sender`, `recipient` or `arbiter <id>`. The
+        /// other party's account id is never shown, as it's all it takes to
+        /// use their account.
+        role: String,
+        entry_id: usize,
+        amount: i64,
+        amount_formatted: Option<String>,
+        fee: i64,
+        fee_formatted: Option<String>,
+        reason: String,
+        opened_at: i64,
+        respond_by: i64,
+        status: disputes::DisputeStatus,
+        evidence: Vec<disputes::Evidence>,
+        closed_at: Option<i64>,
+        closed_by: Option<String>,
+    }
+    impl DisputeSummary {
+        fn new(userdb: &UserDB, dispute: &Dispute, role: String) -> DisputeSummary {
+            DisputeSummary {
+                dispute_id: dispute.dispute_id,
+                role: role,
+                entry_id: dispute.entry_id,
+                amount: dispute.amount,
+                amount_formatted: formatted(userdb, dispute.sender, dispute.amount),
+                fee: dispute.fee,
+                fee_formatted: formatted(userdb, dispute.sender, dispute.fee),
+                reason: dispute.reason.clone(),
+                opened_at: dispute.opened_at,
+                respond_by: dispute.respond_by,
+                status: dispute.status,
+                evidence: dispute.evidence.clone(),
+                closed_at: dispute.closed_at,
+                closed_by: dispute.closed_by.clone(),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct DisputeRequest {
+        dispute_id: u64,
+        /// The sender or recipient making the request, unless an arbiter is
+        account_id: Option<String>,
+    }
+    /// Who is acting on `dispute` - an arbiter, going by the bearer token,
+    /// or else the sender or recipient named by `account_id`
+    fn dispute_party(req: &Request, userdb: &UserDB, dispute: &Dispute, account_id: &Option<String>) -> Option<String> {
+        if let Some(arbiter) = arbiter(req) {
+            return Some(format!("arbiter {}", arbiter))
         }
+        match account_id.as_ref().and_then(|account_id| userdb.lookup(&tenants::tenant(req), account_id)) {
+            Some(idx) if idx == dispute.sender => Some("sender".to_owned()),
+            Some(idx) if idx == dispute.recipient => Some("recipient".to_owned()),
+            _ => None,
+        }
+    }
+
+    fn dispute_error(err: DisputeError) -> IronResult<Response> {
+        match err {
+            DisputeError::NoSuchDispute => resp!(BadRequest, "dispute does not exist"),
+            DisputeError::NoSuchTransfer => resp!(BadRequest, "no transfer out of account_id at entry_id"),
+            DisputeError::TooLate => resp!(BadRequest, "transfer is too old to dispute"),
+            DisputeError::AlreadyDisputed => resp!(BadRequest, "transfer already disputed"),
+            DisputeError::Frozen => resp!(BadRequest, "account_id is frozen"),
+            DisputeError::Closed => resp!(BadRequest, "dispute already decided or withdrawn"),
+            DisputeError::Ledger(UserDBError::Unrecorded) => resp!(InternalServerError, UNRECORDED),
+            DisputeError::Ledger(_) => resp!(BadRequest, "transfer would overflow a balance"),
+        }
+    }
+
+    /// Show dispute `dispute_id`, with its evidence, to its sender,
+    /// recipient or an arbiter
+    pub fn getdispute_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: DisputeRequest = serde_json::from_reader(&mut req.body).unwrap();
+        let userdb = read_userdb();
+        let dispute = match userdb.disputes.get(&obj.dispute_id) {
+            Some(dispute) => dispute,
+            None => return dispute_error(DisputeError::NoSuchDispute),
+        };
+        let role = match dispute_party(req, &userdb, dispute, &obj.account_id) {
+            Some(role) => role,
+            None => return resp!(Unauthorized, "sender, recipient or arbiter required"),
+        };
+        resp!(Ok, serde_json::to_string(&DisputeSummary::new(&userdb, dispute, role)).unwrap())
+    }
+
+    #[derive(Deserialize)]
+    struct DisputeEvidence {
+        dispute_id: u64,
+        account_id: Option<String>,
+        note: String,
+    }
+    /// Add a note to dispute `dispute_id` from its sender, recipient or an
+    /// arbiter. The recipient's first note puts the dispute under review,
+    /// so it's no longer decided for the sender at the deadline.
+    pub fn disputeevidence_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: DisputeEvidence = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        if obj.note.trim().is_empty() || obj.note.len() > disputes::MAX_NOTE_LEN {
+            return resp!(BadRequest, "note is required, and at most 2000 bytes")
+        }
+        let mut userdb = write_userdb();
+        let party = match userdb.disputes.get(&obj.dispute_id) {
+            Some(dispute) => dispute_party(req, &userdb, dispute, &obj.account_id),
+            None => return dispute_error(DisputeError::NoSuchDispute),
+        };
+        let party = match party {
+            Some(party) => party,
+            None => return resp!(Unauthorized, "sender, recipient or arbiter required"),
+        };
+        match disputes::add_evidence(&mut userdb, obj.dispute_id, party, obj.note) {
+            Ok(()) => resp!(Ok, ""),
+            Err(err) => dispute_error(err),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct ResolveDispute {
+        dispute_id: u64,
+        /// `sender` or `recipient`
+        in_favour_of: String,
+    }
+    /// Decide dispute `dispute_id`. Only an arbiter can.
+    pub fn resolvedispute_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: ResolveDispute = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let arbiter = match arbiter(req) {
+            Some(arbiter) => arbiter,
+            None => return resp!(Unauthorized, "arbiter required"),
+        };
+        let for_sender = match &obj.in_favour_of[..] {
+            "sender" => true,
+            "recipient" => false,
+            _ => return resp!(BadRequest, "in_favour_of must be sender or recipient"),
+        };
+        let mut userdb = write_userdb();
+        match disputes::resolve(&mut userdb, obj.dispute_id, for_sender, format!("arbiter {}", arbiter)) {
+            Ok(()) => resp!(Ok, ""),
+            Err(err) => dispute_error(err),
+        }
+    }
+
+    /// Withdraw dispute `dispute_id`, giving the recipient the money back.
+    /// Only the sender can.
+    pub fn withdrawdispute_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: DisputeRequest = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let mut userdb = write_userdb();
+        let is_sender = match userdb.disputes.get(&obj.dispute_id) {
+            Some(dispute) => obj.account_id.as_ref()
+                .and_then(|account_id| userdb.lookup(&tenants::tenant(req), account_id)) == Some(dispute.sender),
+            None => return dispute_error(DisputeError::NoSuchDispute),
+        };
+        if !is_sender {
+            return resp!(Unauthorized, "sender required")
+        }
+        match disputes::withdraw(&mut userdb, obj.dispute_id) {
+            Ok(()) => resp!(Ok, ""),
+            Err(err) => dispute_error(err),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct FreezeAccount {
+        account_id: String,
+        /// Why the account is being frozen, or none to unfreeze it
+        reason: Option<String>,
+    }
+    /// Freeze `account_id` so it can't send money, or unfreeze it - whether
+    /// it was frozen by an arbiter or a dispute. Only an arbiter can.
+    pub fn freezeaccount_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: FreezeAccount = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let arbiter = match arbiter(req) {
+            Some(arbiter) => arbiter,
+            None => return resp!(Unauthorized, "arbiter required"),
+        };
+        let mut userdb = write_userdb();
+        let idx = match userdb.lookup(&tenants::tenant(req), &obj.account_id) {
+            Some(idx) => idx,
+            None => return resp!(BadRequest, "user does not exist"),
+        };
+        let frozen = match obj.reason {
+            Some(ref reason) if reason.trim().is_empty() => return resp!(BadRequest, "reason must not be empty"),
+            Some(reason) => Some(format!("arbiter {}: {}", arbiter, reason)),
+            None => None,
+        };
+        if userdb.set_frozen(idx, frozen).is_err() {
+            return resp!(InternalServerError, UNRECORDED)
+        }
+        resp!(Ok, "")
+    }
+
+    #[derive(Deserialize)]
+    struct KycRequest {
+        account_id: String,
+    }
+    #[derive(Serialize)]
+    struct KycStatus {
+        account_id: String,
+        level: &'static str,
+        /// Limits at the account's level, none where it's unlimited
+        balance_limit: Option<i64>,
+        balance_limit_formatted: Option<String>,
+        transfer_limit: Option<i64>,
+        transfer_limit_formatted: Option<String>,
+        monthly_limit: Option<i64>,
+        monthly_limit_formatted: Option<String>,
+        /// Counted towards the monthly limit so far this month
+        sent_this_month: i64,
+        sent_this_month_formatted: Option<String>,
+    }
+    /// Verification level of `account_id`, the limits it's held to and how
+    /// much it's sent towards its monthly limit
+    pub fn getkyc_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: KycRequest = serde_json::from_reader(&mut req.body).unwrap();
+        let userdb = read_userdb();
+        let idx = match userdb.lookup(&tenants::tenant(req), &obj.account_id) {
+            Some(idx) => idx,
+            None => return resp!(BadRequest, "user does not exist"),
+        };
+        let (level, currency, sent) = {
+            let acct = userdb.get(idx).unwrap();
+            (acct.kyc_level, acct.currency, kyc::sent_since(&acct.history, kyc::month_start(unix_now())))
+        };
+        let limits = kyc::limits(currency, level);
+        let format = |amount: Option<i64>| amount.and_then(|amount| formatted(&userdb, idx, amount));
+        let status = KycStatus {
+            account_id: obj.account_id,
+            level: level.name(),
+            balance_limit: limits.balance,
+            balance_limit_formatted: format(limits.balance),
+            transfer_limit: limits.transfer,
+            transfer_limit_formatted: format(limits.transfer),
+            monthly_limit: limits.monthly_volume,
+            monthly_limit_formatted: format(limits.monthly_volume),
+            sent_this_month: sent,
+            sent_this_month_formatted: format(Some(sent)),
+        };
+        resp!(Ok, serde_json::to_string(&status).unwrap())
+    }
+
+    #[derive(Deserialize)]
+    struct SetKycLevel {
+        account_id: String,
+        level: String,
+    }
+    /// Set the verification level of `account_id`, e.g. once its holder's
+    /// documents have been checked by hand. Needs an admin token.
+    pub fn setkyclevel_handler(req: &mut Request) -> IronResult<Response> {
+        let admin = match admin(req) {
+            Some(admin) => admin,
+            None => return resp!(Unauthorized, "admin token required"),
+        };
+        let obj: SetKycLevel = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let level = match kyc::Level::parse(&obj.level) {
+            Some(level) => level,
+            None => return resp!(BadRequest, "level must be unverified, basic or full"),
+        };
+        let mut userdb = write_userdb();
+        let idx = match userdb.lookup(&tenants::tenant(req), &obj.account_id) {
+            Some(idx) => idx,
+            None => return resp!(BadRequest, "user does not exist"),
+        };
+        if userdb.set_kyc_level(idx, level, format!("admin {}", admin)).is_err() {
+            return resp!(InternalServerError, UNRECORDED)
+        }
+        resp!(Ok, "")
+    }
+
+    #[derive(Deserialize)]
+    struct VerifyKyc {
+        account_id: String,
+        /// Passed on to the verification provider as it is
+        evidence: serde_json::Value,
+    }
+    #[derive(Serialize)]
+    struct KycLevel {
+        level: &'static str,
+    }
+    /// Have the verification provider check `evidence` of who holds
+    /// `account_id`, raising the account to the level it verifies. Levels
+    /// are never lowered this way. Nothing is locked while the provider
+    /// checks.
+    pub fn verifykyc_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: VerifyKyc = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let tenant = tenants::tenant(req);
+        let holder_name = {
+            let userdb = read_userdb();
+            match userdb.lookup(&tenant, &obj.account_id) {
+                Some(idx) => userdb.get(idx).unwrap().holder_name.clone(),
+                None => return resp!(BadRequest, "user does not exist"),
+            }
+        };
+        let (provider, level) = match kyc::verify(&obj.account_id, holder_name.as_ref().map(|name| &name[..]), &obj.evidence) {
+            Ok(verified) => verified,
+            Err(kyc::VerifyError::NoProvider) => return resp!(ServiceUnavailable, "no verification provider"),
+            Err(kyc::VerifyError::Failed(msg)) => return resp!(BadGateway, format!("verification failed: {}", msg)),
+        };
+        let mut userdb = write_userdb();
+        let idx = userdb.lookup(&tenant, &obj.account_id).unwrap();
+        if level > userdb.get(idx).unwrap().kyc_level && userdb.set_kyc_level(idx, level, provider).is_err() {
+            return resp!(InternalServerError, UNRECORDED)
+        }
+        let level = userdb.get(idx).unwrap().kyc_level;
+        resp!(Ok, serde_json::to_string(&KycLevel { level: level.name() }).unwrap())
+    }
+
+    /// Statement for `account_id` covering `from` to `to`, both optional and
+    /// inclusive `YYYY-MM-DD` days (UTC), rendered in `format` - csv (the
+    /// default), json or ofx. Parameters are taken from the query string.
+    pub fn statement_handler(req: &mut Request) -> IronResult<Response> {
+        let mut account_id = None;
+        let mut from = None;
+        let mut to = None;
+        let mut format = Format::Csv;
+        for (key, value) in req.url.as_ref().query_pairs() {
+            match &*key {
+                "account_id" => account_id = Some(value.into_owned()),
+                "from" => match statement::parse_day(&value) {
+                    Some(day) => from = Some(day),
+                    None => return resp!(BadRequest, "from must be a YYYY-MM-DD date"),
+                },
+                "to" => match statement::parse_day(&value) {
+                    // Include the whole of the last day
+                    Some(day) => to = Some(day + 24 * 60 * 60),
+                    None => return resp!(BadRequest, "to must be a YYYY-MM-DD date"),
+                },
+                "format" => match Format::parse(&value) {
+                    Some(f) => format = f,
+                    None => return resp!(BadRequest, "format must be csv, json or ofx"),
+                },
+                _ => return resp!(BadRequest, "unknown parameter"),
+            }
+        }
+        let account_id = match account_id {
+            Some(account_id) => account_id,
+            None => return resp!(BadRequest, "account_id is required"),
+        };
+
+        let userdb = read_userdb();
+        let acct = match userdb.lookup(&tenants::tenant(req), &account_id) {
+            Some(acct) => userdb.get(acct).unwrap(),
+            None => return resp!(BadRequest, "user does not exist"),
+        };
+        let currency = currency::get_currency(acct.currency).unwrap(); // account currencies are always loaded
+        let statement = Statement::new(account_id, currency, &acct.history, from, to,
+            |idx| userdb.public_id(idx).map_or(String::new(), |id| id.masked()));
+        let content_type: Mime = format.content_type().parse().unwrap();
+        Ok(Response::with((status::Ok, content_type, statement.render(format))))
+    }
+
+    /// Id of the reviewer making the request, from the bearer token in the
+    /// Authorization header
+    pub fn reviewer(req: &Request) -> Option<String> {
+        req.headers.get::<Authorization<Bearer>>()
+            .and_then(|auth| review::authenticate(&auth.token))
+    }
+
+    #[derive(Serialize)]
+    struct ReviewSummary {
+        review_id: u64,
+        account_from: Option<String>,
+        account_to: Option<String>,
+        amount: i64,
+        amount_formatted: Option<String>,
+        charge_amount: i64,
+        charge_amount_formatted: Option<String>,
+        flag_reason: String,
+        flagged_at: i64,
+        status: review::ReviewStatus,
+        decision: Option<review::Decision>,
+    }
+    impl ReviewSummary {
+        fn new(userdb: &UserDB, review_id: u64, transfer: review::HeldTransfer) -> ReviewSummary {
+            let public_id = |idx| userdb.public_id(idx).map(|id| id.to_string());
+            ReviewSummary {
+                review_id: review_id,
+                account_from: public_id(transfer.transfer.from),
+                account_to: public_id(transfer.transfer.to),
+                amount: transfer.transfer.amount,
+                amount_formatted: formatted(userdb, transfer.transfer.from, transfer.transfer.amount),
+                charge_amount: transfer.transfer.charge_amount,
+                charge_amount_formatted: formatted(userdb, transfer.transfer.from, transfer.transfer.charge_amount),
+                flag_reason: transfer.flag_reason,
+                flagged_at: transfer.flagged_at,
+                status: transfer.status,
+                decision: transfer.decision,
+            }
+        }
+    }
+
+    /// List transfers flagged as possible fraud which are awaiting a decision
+    pub fn listreviews_handler(req: &mut Request) -> IronResult<Response> {
+        if reviewer(req).is_none() {
+            return resp!(Unauthorized, "reviewer token required")
+        }
+        let userdb = read_userdb();
+        let summaries: Vec<_> = review::pending_transfers(&userdb).into_iter()
+            .map(|(review_id, transfer)| ReviewSummary::new(&userdb, review_id, transfer))
+            .filter(|summary| can_see(req, &userdb, summary.account_from.as_ref()))
+            .collect();
+        resp!(Ok, serde_json::to_string(&summaries).unwrap())
+    }
+
+    #[derive(Deserialize)]
+    struct GetReview {
+        review_id: u64,
+    }
+    /// Show flagged transfer `review_id`, including any decision made on it
+    pub fn getreview_handler(req: &mut Request) -> IronResult<Response> {
+        if reviewer(req).is_none() {
+            return resp!(Unauthorized, "reviewer token required")
+        }
+        let obj: GetReview = serde_json::from_reader(&mut req.body).unwrap();
+        let userdb = read_userdb();
+        let summary = review::get_transfer(&userdb, obj.review_id)
+            .map(|transfer| ReviewSummary::new(&userdb, obj.review_id, transfer));
+        match summary {
+            Some(ref summary) if can_see(req, &userdb, summary.account_from.as_ref()) =>
+                resp!(Ok, serde_json::to_string(summary).unwrap()),
+            _ => resp!(BadRequest, "review does not exist"),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct DecideReview {
+        review_id: u64,
+        reason: String,
+    }
+    /// Approve flagged transfer `review_id`, paying out the held funds
+    pub fn approvereview_handler(req: &mut Request) -> IronResult<Response> {
+        decide_review(req, true)
+    }
+    /// Reject flagged transfer `review_id`, returning the held funds to the
+    /// sender
+    pub fn rejectreview_handler(req: &mut Request) -> IronResult<Response> {
+        decide_review(req, false)
+    }
+    fn decide_review(req: &mut Request, approve: bool) -> IronResult<Response> {
+        let reviewer = match reviewer(req) {
+            Some(reviewer) => reviewer,
+            None => return resp!(Unauthorized, "reviewer token required"),
+        };
+        let obj: DecideReview = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        if obj.reason.is_empty() {
+            return resp!(BadRequest, "a reason is required")
+        }
+        let mut userdb = write_userdb();
+        let visible = review::get_transfer(&userdb, obj.review_id).map_or(false, |transfer| {
+            let account_from = userdb.public_id(transfer.transfer.from).map(|id| id.to_string());
+            can_see(req, &userdb, account_from.as_ref())
+        });
+        if !visible {
+            return resp!(BadRequest, "review does not exist")
+        }
+        match review::decide_transfer(&mut userdb, obj.review_id, approve, reviewer, obj.reason) {
+            Ok(()) => {
+                metrics::count_review_decision(approve);
+                resp!(Ok, "")
+            },
+            Err(review::ReviewError::NoSuchReview) => resp!(BadRequest, "review does not exist"),
+            Err(review::ReviewError::AlreadyDecided) => resp!(BadRequest, "review already decided"),
+            Err(review::ReviewError::Ledger(UserDBError::Unrecorded)) => resp!(InternalServerError, UNRECORDED),
+            Err(_) => resp!(BadRequest, "transfer would overflow a balance"),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct SetApprovalPolicy {
+        account_id: String,
+        threshold: currency::Amount,
+        required: usize,
+        approvers: Vec<String>,
+        expiry_secs: i64,
+    }
+    /// Make transfers of at least `threshold` from `account_id` wait for
+    /// `required` of `approvers` to approve them within `expiry_secs`. The
+    /// response has each approver's token, which is never shown again.
+    pub fn setapprovalpolicy_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let obj: SetApprovalPolicy = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let userdb = read_userdb();
+        let (idx, account_id) = match userdb.lookup(&tenants::tenant(req), &obj.account_id).and_then(|idx| userdb.public_id(idx).map(|id| (idx, id))) {
+            Some((idx, account_id)) => (idx, account_id.to_string()),
+            None => return resp!(BadRequest, "user does not exist"),
+        };
+        let threshold = match amount_in(&userdb, idx, &obj.threshold) {
+            Ok(threshold) => threshold as u64,
+            Err(msg) => return resp!(BadRequest, msg),
+        };
+        match approvals::set_policy(&account_id, threshold, obj.required, obj.approvers, obj.expiry_secs) {
+            Ok(policy) => resp!(Ok, serde_json::to_string(&policy).unwrap()),
+            Err(ApprovalError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct RemoveApprovalPolicy {
+        account_id: String,
+    }
+    /// Stop transfers from `account_id` needing approval, cancelling any
+    /// waiting for it
+    pub fn removeapprovalpolicy_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let obj: RemoveApprovalPolicy = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let userdb = read_userdb();
+        let account_id = match userdb.lookup(&tenants::tenant(req), &obj.account_id).and_then(|idx| userdb.public_id(idx)) {
+            Some(account_id) => account_id.to_string(),
+            None => return resp!(BadRequest, "user does not exist"),
+        };
+        match approvals::remove_policy(&account_id) {
+            Ok(()) => resp!(Ok, ""),
+            Err(ApprovalError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    /// Account and id of the approver making the request, from the bearer
+    /// token in the Authorization header
+    fn approver(req: &Request) -> Option<(String, String)> {
+        req.headers.get::<Authorization<Bearer>>()
+            .and_then(|auth| approvals::authenticate(&auth.token))
+    }
+
+    /// List transfers waiting for the approver making the request
+    pub fn listapprovals_handler(req: &mut Request) -> IronResult<Response> {
+        let (account_id, approver) = match approver(req) {
+            Some(approver) => approver,
+            None => return resp!(Unauthorized, "approver token required"),
+        };
+        resp!(Ok, serde_json::to_string(&approvals::awaiting(&account_id, &approver, unix_now())).unwrap())
+    }
+
+    #[derive(Deserialize)]
+    struct ApproveTransfer {
+        pending_id: u64,
+    }
+    /// Approve transfer `pending_id`. The transfer is made, with all the
+    /// usual checks, as soon as it has enough approvals.
+    pub fn approvetransfer_handler(req: &mut Request) -> IronResult<Response> {
+        let (account_id, approver) = match approver(req) {
+            Some(approver) => approver,
+            None => return resp!(Unauthorized, "approver token required"),
+        };
+        let obj: ApproveTransfer = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let userdb = read_userdb();
+        let transfer = match approvals::approve(obj.pending_id, &account_id, &approver, unix_now()) {
+            Ok(Some(transfer)) => transfer,
+            Ok(None) => return resp!(Accepted, "approved, waiting for more approvals"),
+            Err(ApprovalError(msg)) => return resp!(BadRequest, msg),
+        };
+        // The approver's token is for the sender, whichever tenant's it is
+        let from = userdb.lookup(&None, &transfer.account_from);
+        let (from, to) = match (from, from.and_then(|from| userdb.lookup_payee(from, &transfer.account_to))) {
+            (Some(from), Some(to)) => (from, to),
+            _ => {
+                approvals::finish(transfer.pending_id, Err("one or both accounts do not exist".to_owned()));
+                return resp!(BadRequest, "one or both accounts do not exist")
+            },
+        };
+        if let Err(err) = screen_holders(&userdb, "transfer", &[from, to]) {
+            approvals::finish(transfer.pending_id, Err(format!("blocked by sanctions screening, compliance case {}", err.case_id)));
+            return blocked(err)
+        }
+        let result = make_transfer(&userdb, from, to, transfer.amount as i64);
+        approvals::finish(transfer.pending_id, match result {
+            Ok(ref outcome) => Ok(outcome.description()),
+            Err(msg) => Err(msg.to_owned()),
+        });
+        transfer_response(result)
+    }
+
+    #[derive(Deserialize)]
+    struct RejectTransfer {
+        pending_id: u64,
+        reason: String,
+    }
+    /// Turn down transfer `pending_id`, so it's never made
+    pub fn rejecttransfer_handler(req: &mut Request) -> IronResult<Response> {
+        let (account_id, approver) = match approver(req) {
+            Some(approver) => approver,
+            None => return resp!(Unauthorized, "approver token required"),
+        };
+        let obj: RejectTransfer = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        if obj.reason.is_empty() {
+            return resp!(BadRequest, "a reason is required")
+        }
+        match approvals::reject(obj.pending_id, &account_id, &approver, obj.reason, unix_now()) {
+            Ok(()) => resp!(Ok, ""),
+            Err(ApprovalError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    #[derive(Serialize)]
+    struct ReloadedSanctions {
+        entries: usize,
+    }
+    /// Reload the sanctions lists now, rather than waiting for the change
+    /// to be noticed
+    pub fn reloadsanctions_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        match screening::load_lists(screening::SANCTIONS_DIR) {
+            Ok(num_entries) => resp!(Ok, serde_json::to_string(&ReloadedSanctions { entries: num_entries }).unwrap()),
+            Err(screening::ScreeningError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    /// List compliance cases which haven't been closed
+    pub fn listcases_handler(req: &mut Request) -> IronResult<Response> {
+        if reviewer(req).is_none() {
+            return resp!(Unauthorized, "reviewer token required")
+        }
+        let userdb = read_userdb();
+        let cases: Vec<_> = screening::open_cases().into_iter()
+            .filter(|case| can_see(req, &userdb, case.account_id.as_ref()))
+            .collect();
+        resp!(Ok, serde_json::to_string(&cases).unwrap())
+    }
+
+    #[derive(Deserialize)]
+    struct CloseCase {
+        case_id: u64,
+        false_positive: bool,
+        reason: String,
+    }
+    /// Close compliance case `case_id`. If `false_positive`, the holder
+    /// isn't blocked by the same list entry again.
+    pub fn closecase_handler(req: &mut Request) -> IronResult<Response> {
+        let reviewer = match reviewer(req) {
+            Some(reviewer) => reviewer,
+            None => return resp!(Unauthorized, "reviewer token required"),
+        };
+        let obj: CloseCase = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        if obj.reason.is_empty() {
+            return resp!(BadRequest, "a reason is required")
+        }
+        // Closed cases aren't tenants' to see, so they get no further
+        let visible = tenants::tenant(req).is_none() || {
+            let userdb = read_userdb();
+            screening::open_cases().iter()
+                .any(|case| case.case_id == obj.case_id && can_see(req, &userdb, case.account_id.as_ref()))
+        };
+        if !visible {
+            return resp!(BadRequest, "case does not exist")
+        }
+        let decision = review::Decision { reviewer: reviewer, reason: obj.reason, time: unix_now() };
+        match screening::close_case(obj.case_id, obj.false_positive, decision) {
+            Ok(()) => resp!(Ok, ""),
+            Err(screening::ScreeningError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct RegisterWebhook {
+        account_id: String,
+        url: String,
+    }
+    #[derive(Serialize)]
+    struct RegisteredWebhook {
+        webhook_id: String,
+        secret: String,
+    }
+    /// Send events for `account_id` to `url`. The secret for checking event
+    /// signatures is only ever shown in this response.
+    pub fn registerwebhook_handler(req: &mut Request) -> IronResult<Response> {
+        let obj: RegisterWebhook = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let userdb = read_userdb();
+        let account_id = match userdb.lookup(&tenants::tenant(req), &obj.account_id).and_then(|idx| userdb.public_id(idx)) {
+            Some(account_id) => account_id,
+            None => return resp!(BadRequest, "user does not exist"),
+        };
+        match webhook::register(&account_id, &obj.url) {
+            Ok(webhook) => resp!(Ok, serde_json::to_string(&RegisteredWebhook {
+                webhook_id: webhook.id,
+                secret: webhook.secret,
+            }).unwrap()),
+            Err(webhook::WebhookError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct UnregisterWebhook {
+        webhook_id: String,
+    }
+    /// Stop sending events to webhook `webhook_id`
+    pub fn unregisterwebhook_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let obj: UnregisterWebhook = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let in_namespace = webhook::account_of(&obj.webhook_id)
+            .map_or(true, |account_id| read_userdb().lookup(&tenants::tenant(req), &account_id).is_some());
+        if !in_namespace {
+            return resp!(BadRequest, "webhook does not exist")
+        }
+        match webhook::unregister(&obj.webhook_id) {
+            Ok(()) => resp!(Ok, ""),
+            Err(webhook::WebhookError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    /// List webhook events which couldn't be delivered
+    pub fn deadletters_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let userdb = read_userdb();
+        let dead_letters: Vec<_> = webhook::dead_letters().into_iter()
+            .filter(|delivery| can_see(req, &userdb, webhook::account_of(&delivery.webhook_id).as_ref()))
+            .collect();
+        resp!(Ok, serde_json::to_string(&dead_letters).unwrap())
+    }
+
+    #[derive(Deserialize)]
+    struct RetryDeadLetter {
+        delivery_id: u64,
+    }
+    /// Queue dead letter `delivery_id` to be delivered again
+    pub fn retrydeadletter_handler(req: &mut Request) -> IronResult<Response> {
+        if admin(req).is_none() {
+            return resp!(Unauthorized, "admin token required")
+        }
+        let obj: RetryDeadLetter = serde_json::from_slice(audit::raw_body(req)).unwrap();
+        let visible = {
+            let userdb = read_userdb();
+            webhook::dead_letters().iter().any(|delivery| {
+                delivery.id == obj.delivery_id && can_see(req, &userdb, webhook::account_of(&delivery.webhook_id).as_ref())
+            })
+        };
+        if !visible {
+            return resp!(BadRequest, "dead letter does not exist")
+        }
+        match webhook::retry_dead_letter(obj.delivery_id) {
+            Ok(()) => resp!(Ok, ""),
+            Err(webhook::WebhookError(msg)) => resp!(BadRequest, msg),
+        }
+    }
+
+    /// A `UserDB` holding the system accounts of every configured currency,
+    /// which are put in the global currency table the first time
+    #[cfg(test)]
+    fn test_userdb() -> UserDB {
+        use std::sync::Once;
+        static ADD_CURRENCIES: Once = Once::new();
+        let configs = currency::read_config(currency::CURRENCY_CONFIG).unwrap();
+        let mut userdb = UserDB::new();
+        for config in &configs {
+            userdb.addsysacct(UserAccount::with_currency(config.id)).unwrap();
+            userdb.addsysacct(UserAccount::with_currency(config.id)).unwrap();
+        }
+        // Opened in the same order as `CurrencyTable::add` would
+        ADD_CURRENCIES.call_once(|| currency::update_currencies(|table| {
+            for (i, config) in configs.into_iter().enumerate() {
+                table.restore(config, 2 * i, 2 * i + 1).unwrap();
+            }
+        }));
+        userdb
+    }
+
+    #[cfg(test)]
+    #[derive(Clone, Debug)]
+    enum Op {
+        /// Open an account in the nth configured currency
+        MakeAccount(usize),
+        Deposit(usize, i64),
+        /// `flagged` by the fraud checker, and checked with the accounts
+        /// `unlocked` as the async server does
+        Transfer { from: usize, to: usize, amount: i64, flagged: bool, unlocked: bool },
+    }
+
+    #[cfg(test)]
+    proptest! {
+        #[test]
+        fn transfers_keep_invariants(ops in prop::collection::vec(prop_oneof![
+            (0..3usize).prop_map(Op::MakeAccount),
+            (0..8usize, prop_oneof![9 => 0..200i64, 1 => 0..1i64 << 40])
+                .prop_map(|(acct, amount)| Op::Deposit(acct, amount)),
+            (0..8usize, 0..8usize, prop_oneof![9 => 0..200i64, 1 => 0..i64::MAX], any::<bool>(), any::<bool>())
+                .prop_map(|(from, to, amount, flagged, unlocked)|
+                          Op::Transfer { from: from, to: to, amount: amount, flagged: flagged, unlocked: unlocked }),
+        ], 0..40)) {
+            let mut userdb = test_userdb();
+            let currencies = currency::all_currencies();
+            let mut accts = vec![];
+            let mut deposited = vec![0i64; currencies.len()];
+            for op in ops {
+                let before = userdb.balances();
+                let mut expected = before.clone();
+                match op {
+                    Op::MakeAccount(currency) => {
+                        let acct = UserAccount::new(&currencies[currency].name).unwrap();
+                        let account_id = userdb.addacct(acct).unwrap();
+                        accts.push(userdb.lookup(&None, &account_id.to_string()).unwrap());
+                        expected.push(0);
+                    },
+                    Op::Deposit(_, _) | Op::Transfer { .. } if accts.is_empty() => continue,
+                    Op::Deposit(acct, amount) => {
+                        let acct = accts[acct % accts.len()];
+                        prop_assert!(make_deposit(&userdb, acct, amount).is_ok());
+                        let currency = userdb.get(acct).unwrap().currency;
+                        deposited[currencies.iter().position(|detail| detail.id == currency).unwrap()] += amount;
+                        expected[acct] += amount;
+                    },
+                    Op::Transfer { from, to, amount, flagged, unlocked } => {
+                        let (from, to) = (accts[from % accts.len()], accts[to % accts.len()]);
+                        let fraud_err = || if flagged { Some(FraudError("flagged".to_owned())) } else { None };
+                        let result = if unlocked {
+                            prepare_transfer(&userdb, from, to, amount)
+                                .and_then(|(pending, _)| settle_transfer(&userdb, pending, fraud_err()))
+                        } else {
+                            checked_transfer(&userdb, from, to, amount, |_, _, _| fraud_err())
+                        };
+
+                        // Made exactly when the sender can cover the amount
+                        // and charge without going past their overdraft
+                        let currency = userdb.get(from).unwrap().currency;
+                        let detail = currency::get_currency(currency).unwrap();
+                        let total = amount.saturating_add(detail.transfer_charge_for(amount));
+                        let valid = from != to && userdb.get(to).unwrap().currency == currency &&
+                            before[from].saturating_add(detail.overdraft_limit as i64) >= total;
+                        prop_assert_eq!(result.is_ok(), valid);
+                        // Charges only go to the currency's charge account,
+                        // and flagged transfers go to its hold account whole
+                        match result {
+                            Ok(TransferOutcome::Made) => {
+                                prop_assert!(!flagged);
+                                let plan = TransferPlan::new(from, to, amount, &detail).unwrap();
+                                prop_assert_eq!(plan.charge_account, detail.transfer_charge_account);
+                                expected[from] -= plan.total();
+                                expected[to] += amount;
+                                expected[plan.charge_account] += plan.charge_amount;
+                            },
+                            Ok(TransferOutcome::Held(_)) => {
+                                prop_assert!(flagged);
+                                expected[from] -= total;
+                                expected[detail.review_hold_account] += total;
+                            },
+                            Err(_) => (),
+                        }
+                    },
+                }
+
+                let after = userdb.balances();
+                prop_assert_eq!(&after, &expected);
+                for (i, detail) in currencies.iter().enumerate() {
+                    let in_currency: Vec<usize> = (0..after.len())
+                        .filter(|&acct| userdb.get(acct).unwrap().currency == detail.id)
+                        .collect();
+                    prop_assert_eq!(in_currency.iter().map(|&acct| after[acct]).sum::<i64>(), deposited[i]);
+                    for acct in in_currency {
+                        let overdraft = if accts.contains(&acct) { detail.overdraft_limit as i64 } else { 0 };
+                        prop_assert!(after[acct] >= -overdraft);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every interleaving (see `sync::model`) of transfers which check for
+    /// fraud with the accounts locked, in both directions between the same
+    /// accounts, and one checking them unlocked as the async server does.
+    /// The fraud checker is swapped for a thread answering through channels
+    /// the same way, as loom can't run the real one.
+    #[test]
+    fn test_transfer_model() {
+        use std::sync::Arc;
+        use super::sync::{self, mpsc, thread};
+
+        sync::model(|| {
+            let mut userdb = test_userdb();
+            let usd = currency::lookup_currency("USD").unwrap();
+            let (a, b) = (userdb.accts.len(), userdb.accts.len() + 1);
+            for &acct in &[a, b] {
+                userdb.addacct(UserAccount::with_currency(usd.id)).unwrap();
+                userdb.get_mut(acct).unwrap().balance = 100;
+            }
+            let userdb = Arc::new(userdb);
+
+            let (checker, requests) = mpsc::channel::<Option<(mpsc::Sender<UserAccount>, UserAccount)>>();
+            let checker_thread = thread::spawn(move || {
+                while let Some((reply, acct)) = requests.recv().unwrap() {
+                    reply.send(acct).unwrap();
+                }
+            });
+            // As `FraudChecker::check`
+            let check = |checker: &mpsc::Sender<_>, acct: &mut UserAccount| {
+                let (reply, checked) = mpsc::channel();
+                checker.send(Some((reply, mem::replace(acct, UserAccount::fakeacct())))).unwrap();
+                *acct = checked.recv().unwrap();
+            };
+
+            // Locked in both directions on their own threads
+            let locked: Vec<_> = vec![(a, b, 60), (b, a, 10)].into_iter()
+                .map(|(from, to, amount)| {
+                    let (userdb, checker) = (userdb.clone(), checker.clone());
+                    thread::spawn(move || checked_transfer(&userdb, from, to, amount, |acct_from, acct_to, _| {
+                        check(&checker, acct_from);
+                        check(&checker, acct_to);
+                        None
+                    }))
+                })
+                .collect();
+            // And unlocked on this one, as loom only runs four threads at once
+            let unlocked = prepare_transfer(&userdb, a, b, 60).and_then(|(pending, accts)| {
+                for mut acct in accts {
+                    check(&checker, &mut acct);
+                }
+                settle_transfer(&userdb, pending, None)
+            });
+            let mut results: Vec<_> = locked.into_iter().map(|transfer| transfer.join().unwrap()).collect();
+            results.push(unlocked);
+            checker.send(None).unwrap();
+            checker_thread.join().unwrap();
+
+            // a can only afford one transfer of 60 plus 2 charge, and nothing
+            // sees the fraud checker's stand in account
+            let made = results.iter().filter(|result| result.is_ok()).count();
+            assert_eq!(made, 2);
+            for result in results {
+                if let Err(msg) = result {
+                    assert_eq!(msg, "balance too low in account_from");
+                }
+            }
+            let balances = userdb.balances();
+            assert_eq!(balances[usd.transfer_charge_account], 3);
+            assert_eq!(balances[a..].to_vec(), vec![48, 149]);
+        });
+    }
+}
+
+mod statement {
+    use chrono::{NaiveDate, TimeZone, UTC};
+
+    use serde_json;
+
+    use super::Entry;
+    use super::currency::CurrencyDetail;
+
+    /// Formats a statement can be rendered in
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Format {
+        Csv,
+        Json,
+        Ofx,
+    }
+    impl Format {
+        pub fn parse(format: &str) -> Option<Format> {
+            match format {
+                "csv" => Some(Format::Csv),
+                "json" => Some(Format::Json),
+                "ofx" => Some(Format::Ofx),
+                _ => None,
+            }
+        }
+        pub fn content_type(&self) -> &'static str {
+            match *self {
+                Format::Csv => "text/csv",
+                Format::Json => "application/json",
+                Format::Ofx => "application/x-ofx",
+            }
+        }
+    }
+
+    /// Seconds since the unix epoch at the start of a `YYYY-MM-DD` day (UTC)
+    pub fn parse_day(day: &str) -> Option<i64> {
+        NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()
+            .map(|date| date.and_hms(0, 0, 0).timestamp())
+    }
+
+    pub struct StatementLine {
+        /// Position of the entry in the account history, unique per account
+        pub entry_id: usize,
+        pub entry: Entry,
+        /// Masked public id of the other account involved (see
+        /// `AccountId::masked`), empty for none or for system accounts
+        pub counterparty: String,
+    }
+
+    pub struct Statement {
+        pub account_id: String,
+        pub currency: CurrencyDetail,
+        /// Start of the period covered (inclusive), seconds since the epoch
+        pub from: Option<i64>,
+        /// End of the period covered (exclusive), seconds since the epoch
+        pub to: Option<i64>,
+        pub opening_balance: i64,
+        pub closing_balance: i64,
+        pub lines: Vec<StatementLine>,
+    }
+    impl Statement {
+        /// Build a statement from an account `history`, using `counterparty`
+        /// to find how other accounts are shown
+        pub fn new<F>(account_id: String, currency: CurrencyDetail, history: &[Entry],
+                      from: Option<i64>, to: Option<i64>, counterparty: F) -> Statement
+                where F: Fn(usize) -> String {
+            let after_from = |entry: &Entry| from.map_or(true, |from| entry.time >= from);
+            let before_to = |entry: &Entry| to.map_or(true, |to| entry.time < to);
+            let opening_balance = history.iter().take_while(|entry| !after_from(entry))
+                .last().map_or(0, |entry| entry.balance);
+            let lines: Vec<StatementLine> = history.iter().enumerate()
+                .filter(|&(_, entry)| after_from(entry) && before_to(entry))
+                .map(|(entry_id, entry)| StatementLine {
+                    entry_id: entry_id,
+                    entry: entry.clone(),
+                    counterparty: entry.counterparty.map_or(String::new(), &counterparty),
+                })
+                .collect();
+            let closing_balance = lines.last().map_or(opening_balance, |line| line.entry.balance);
+            Statement {
+                account_id: account_id,
+                currency: currency,
+                from: from,
+                to: to,
+                opening_balance: opening_balance,
+                closing_balance: closing_balance,
+                lines: lines,
+            }
+        }
+
+        pub fn render(&self, format: Format) -> String {
+            match format {
+                Format::Csv => self.render_csv(),
+                Format::Json => self.render_json(),
+                Format::Ofx => self.render_ofx(),
+            }
+        }
+
+        fn render_csv(&self) -> String {
+            let mut out = "date,description,amount,balance,counterparty\n".to_owned();
+            let from = self.from.map_or(String::new(), rfc3339);
+            out += &format!("{},opening balance,,{},\n", from, self.opening_balance);
+            for line in &self.lines {
+                out += &format!("{},{},{},{},{}\n", rfc3339(line.entry.time),
+                    line.entry.kind.description(), line.entry.amount, line.entry.balance,
+                    line.counterparty);
+            }
+            let to = self.to.map_or(String::new(), rfc3339);
+            out += &format!("{},closing balance,,{},\n", to, self.closing_balance);
+            out
+        }
+
+        fn render_json(&self) -> String {
+            #[derive(Serialize)]
+            struct JsonLine<'a> {
+                date: String,
+                description: &'static str,
+                amount: i64,
+                amount_formatted: String,
+                balance: i64,
+                balance_formatted: String,
+                counterparty: &'a str,
+            }
+            #[derive(Serialize)]
+            struct JsonStatement<'a> {
+                account_id: &'a str,
+                currency: &'a str,
+                from: Option<String>,
+                to: Option<String>,
+                opening_balance: i64,
+                opening_balance_formatted: String,
+                closing_balance: i64,
+                closing_balance_formatted: String,
+                lines: Vec<JsonLine<'a>>,
+            }
+            serde_json::to_string(&JsonStatement {
+                account_id: &self.account_id,
+                currency: &self.currency.name,
+                from: self.from.map(rfc3339),
+                to: self.to.map(rfc3339),
+                opening_balance: self.opening_balance,
+                opening_balance_formatted: self.currency.format(self.opening_balance),
+                closing_balance: self.closing_balance,
+                closing_balance_formatted: self.currency.format(self.closing_balance),
+                lines: self.lines.iter().map(|line| JsonLine {
+                    date: rfc3339(line.entry.time),
+                    description: line.entry.kind.description(),
+                    amount: line.entry.amount,
+                    amount_formatted: self.currency.format(line.entry.amount),
+                    balance: line.entry.balance,
+                    balance_formatted: self.currency.format(line.entry.balance),
+                    counterparty: &line.counterparty,
+                }).collect(),
+            }).unwrap()
+        }
+
+        /// OFX 2 bank statement, which most bookkeeping software can import.
+        /// OFX amounts are decimals of the major unit.
+        fn render_ofx(&self) -> String {
+            let now = super::unix_now();
+            let mut transactions = String::new();
+            for line in &self.lines {
+                let trntype = match line.entry.kind {
+                    super::EntryKind::Deposit => "DEP",
+                    super::EntryKind::Charge if line.entry.amount < 0 => "FEE",
+                    super::EntryKind::Interest if line.entry.amount > 0 => "INT",
+                    _ if line.entry.amount < 0 => "DEBIT",
+                    _ => "CREDIT",
+                };
+                transactions += &format!(
+                    "<STMTTRN><TRNTYPE>{}</TRNTYPE><DTPOSTED>{}</DTPOSTED><TRNAMT>{}</TRNAMT>\
+                     <FITID>{}</FITID><NAME>{}</NAME></STMTTRN>\n",
+                    trntype, ofx_time(line.entry.time), self.currency.decimal(line.entry.amount), line.entry_id,
+                    line.entry.kind.description());
+            }
+            let first_time = self.lines.first().map_or(now, |line| line.entry.time);
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n\
+                 <?OFX OFXHEADER=\"200\" VERSION=\"220\" SECURITY=\"NONE\" OLDFILEUID=\"NONE\" NEWFILEUID=\"NONE\"?>\n\
+                 <OFX>\n\
+                 <SIGNONMSGSRSV1><SONRS><STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\
+                 <DTSERVER>{now}</DTSERVER><LANGUAGE>ENG</LANGUAGE></SONRS></SIGNONMSGSRSV1>\n\
+                 <BANKMSGSRSV1><STMTTRNRS><TRNUID>0</TRNUID><STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\n\
+                 <STMTRS><CURDEF>{currency}</CURDEF>\n\
+                 <BANKACCTFROM><BANKID>QUADCURR</BANKID><ACCTID>{account_id}</ACCTID><ACCTTYPE>CHECKING</ACCTTYPE></BANKACCTFROM>\n\
+                 <BANKTRANLIST><DTSTART>{start}</DTSTART><DTEND>{end}</DTEND>\n\
+                 {transactions}\
+                 </BANKTRANLIST>\n\
+                 <LEDGERBAL><BALAMT>{closing}</BALAMT><DTASOF>{end}</DTASOF></LEDGERBAL>\n\
+                 </STMTRS></STMTTRNRS></BANKMSGSRSV1>\n\
+                 </OFX>\n",
+                now = ofx_time(now),
+                currency = self.currency.name,
+                account_id = self.account_id,
+                start = ofx_time(self.from.unwrap_or(first_time)),
+                end = ofx_time(self.to.map_or(now, |to| if to < now { to } else { now })),
+                transactions = transactions,
+                closing = self.currency.decimal(self.closing_balance))
+        }
+    }
+
+    fn rfc3339(time: i64) -> String {
+        UTC.timestamp(time, 0).to_rfc3339()
+    }
+
+    fn ofx_time(time: i64) -> String {
+        UTC.timestamp(time, 0).format("%Y%m%d%H%M%S").to_string()
+    }
+
+    #[test]
+    fn test_statement() {
+        use super::EntryKind;
+        use super::currency::CurrencyId;
+        fn entry(time: i64, kind: EntryKind, amount: i64, balance: i64) -> Entry {
+            Entry { time: time, kind: kind, amount: amount, counterparty: Some(7), balance: balance }
+        }
+        let gbp = CurrencyDetail {
+            id: CurrencyId(3),
+            name: "GBP".to_owned(),
+            overdraft_limit: 0,
+            transfer_charge: 1.0,
+            transfer_charge_account: 0,
+            review_hold_account: 0,
+            interest_rate_bps: 0,
+            interest_expense_account: None,
+            exponent: 2,
+            symbol: "£".to_owned(),
+            locale: "en-GB".to_owned(),
+        };
+        let day = 24 * 60 * 60;
+        let history = vec![
+            entry(0, EntryKind::Deposit, 500, 500),
+            entry(day, EntryKind::TransferOut, -100, 400),
+            entry(day, EntryKind::Charge, -1, 399),
+            entry(2 * day, EntryKind::TransferIn, 50, 449),
+        ];
+        let statement = Statement::new("acct".to_owned(), gbp.clone(), &history,
+                                       parse_day("1970-01-02"), parse_day("1970-01-03"),
+                                       |idx| idx.to_string());
+        assert_eq!(statement.opening_balance, 500);
+        assert_eq!(statement.closing_balance, 399);
+        assert_eq!(statement.lines.iter().map(|line| line.entry_id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(statement.render(Format::Csv),
+                   "date,description,amount,balance,counterparty\n\
+                    1970-01-02T00:00:00+00:00,opening balance,,500,\n\
+                    1970-01-02T00:00:00+00:00,transfer out,-100,400,7\n\
+                    1970-01-02T00:00:00+00:00,transfer charge,-1,399,7\n\
+                    1970-01-03T00:00:00+00:00,closing balance,,399,\n");
+        let ofx = statement.render(Format::Ofx);
+        assert!(ofx.contains("<TRNTYPE>FEE</TRNTYPE><DTPOSTED>19700102000000</DTPOSTED><TRNAMT>-0.01</TRNAMT>"));
+        assert!(ofx.contains("<BALAMT>3.99</BALAMT>"));
+        let json: serde_json::Value = serde_json::from_str(&statement.render(Format::Json)).unwrap();
+        assert_eq!(json["lines"][0]["amount_formatted"], "-£1.00");
+        assert_eq!(json["closing_balance_formatted"], "£3.99");
+
+        let statement = Statement::new("acct".to_owned(), gbp, &history,
+                                       None, None, |idx| idx.to_string());
+        assert_eq!(statement.opening_balance, 0);
+        assert_eq!(statement.closing_balance, 449);
+        assert_eq!(statement.lines.len(), 4);
+    }
+}
+
+mod batch {
+    use std::collections::BTreeMap;
+    use std::i64;
+    use std::str;
+
+    use super::{TransferPlan, UserAccount, UserDB, unix_now};
+    use super::currency::{self, CurrencyDetail};
+    use super::kyc;
+
+    /// Most transfers accepted in one batch
+    pub const MAX_BATCH_TRANSFERS: usize = 1000;
+
+    /// Header line allowed at the start of a csv batch
+    const CSV_HEADER: &'static str = "account_to,amount";
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    pub struct BatchItem {
+        pub account_to: String,
+        pub amount: currency::Amount,
+    }
+
+    /// Problem with one transfer in a batch
+    #[derive(Serialize, Debug, PartialEq)]
+    pub struct ItemError {
+        /// Position of the transfer in the batch, counting from 1 (and not
+        /// counting any csv header)
+        pub item: usize,
+        pub error: &'static str,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum BatchError {
+        /// Problem with the batch as a whole
+        Batch(&'static str),
+        /// Problems with individual transfers - every one found, not just
+        /// the first
+        Items(Vec<ItemError>),
+    }
+
+    /// Read a batch from csv lines of `account_to,amount`, optionally with
+    /// a `CSV_HEADER` line first. Blank lines are skipped. Amounts are base
+    /// units, or decimals if they have a decimal point.
+    pub fn parse_csv(body: &[u8]) -> Result<Vec<BatchItem>, BatchError> {
+        let body = match str::from_utf8(body) {
+            Ok(body) => body,
+            Err(_) => return Err(BatchError::Batch("csv must be utf-8")),
+        };
+        let mut lines = body.lines().map(str::trim).filter(|line| !line.is_empty()).peekable();
+        if lines.peek() == Some(&CSV_HEADER) {
+            lines.next();
+        }
+        let mut items = vec![];
+        let mut errors = vec![];
+        for (i, line) in lines.enumerate() {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != 2 {
+                errors.push(ItemError { item: i + 1, error: "expected account_to,amount" });
+                continue
+            }
+            let amount = match fields[1].parse() {
+                Ok(amount) => currency::Amount::Minor(amount),
+                Err(_) if fields[1].contains('.') => currency::Amount::Decimal(fields[1].to_owned()),
+                Err(_) => { errors.push(ItemError { item: i + 1, error: "amount must be a number" }); continue },
+            };
+            items.push(BatchItem { account_to: fields[0].to_owned(), amount: amount });
+        }
+        if errors.is_empty() { Ok(items) } else { Err(BatchError::Items(errors)) }
+    }
+
+    /// Check every transfer in a batch from `from`, whose currency is
+    /// `currency_detail`, and that the sender can cover the lot as `check`
+    /// does. Nothing is changed - the plans can be applied together with
+    /// `UserDB::apply_postings`. No account may be locked by the caller.
+    pub fn plan(userdb: &UserDB, from: usize, currency_detail: &CurrencyDetail,
+                items: &[BatchItem]) -> Result<Vec<TransferPlan>, BatchError> {
+        if items.is_empty() {
+            return Err(BatchError::Batch("batch has no transfers"))
+        }
+        if items.len() > MAX_BATCH_TRANSFERS {
+            return Err(BatchError::Batch("batch has too many transfers"))
+        }
+        let mut plans = vec![];
+        let mut errors = vec![];
+        for (i, item) in items.iter().enumerate() {
+            let error = |error| ItemError { item: i + 1, error: error };
+            let to = match userdb.lookup_payee(from, &item.account_to) {
+                Some(to) => to,
+                None => { errors.push(error("account_to does not exist")); continue },
+            };
+            if to == from {
+                errors.push(error("cannot transfer to the same account"));
+                continue
+            }
+            let amount = match item.amount.minor_units(currency_detail) {
+                Ok(amount) => amount,
+                Err(msg) => { errors.push(error(msg)); continue },
+            };
+            if amount < currency::MINIMUM_TRANSFER_AMOUNT {
+                errors.push(error("below minimum transfer"));
+                continue
+            }
+            if userdb.get(to).unwrap().currency != currency_detail.id {
+                errors.push(error("user account currencies do not match"));
+                continue
+            }
+            match TransferPlan::new(from, to, amount, currency_detail) {
+                Some(plan) => plans.push(plan),
+                None => errors.push(error("amount too large")),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(BatchError::Items(errors))
+        }
+        let ids = accounts(from, &plans);
+        let mut locked = userdb.lock_many(&ids).unwrap();
+        check(&locked.accts_mut(), &ids, &plans, currency_detail)?;
+        Ok(plans)
+    }
+
+    /// The sender of the batch `plans` followed by each account it pays,
+    /// once each
+    pub fn accounts(from: usize, plans: &[TransferPlan]) -> Vec<usize> {
+        let mut ids = vec![from];
+        for plan in plans {
+            if !ids.contains(&plan.to) {
+                ids.push(plan.to);
+            }
+        }
+        ids
+    }
+
+    /// Check the sender of the batch `plans` can cover the lot including
+    /// charges, within the limits of its verification level and those of
+    /// the accounts paid. `accts` are the accounts at `ids`, as `accounts`
+    /// lists them.
+    pub fn check(accts: &[&mut UserAccount], ids: &[usize], plans: &[TransferPlan],
+                 currency_detail: &CurrencyDetail) -> Result<(), BatchError> {
+        let acct_from = &accts[0];
+        if acct_from.frozen.is_some() {
+            return Err(BatchError::Batch("account_from is frozen"))
+        }
+        let errors: Vec<ItemError> = plans.iter().enumerate()
+            .filter_map(|(i, plan)| kyc::check_transfer_size(acct_from, plan.amount).err()
+                .map(|err| ItemError { item: i + 1, error: err.message() }))
+            .collect();
+        if !errors.is_empty() {
+            return Err(BatchError::Items(errors))
+        }
+
+        let total = match plans.iter().fold(Some(0i64), |total, plan| total.and_then(|t| t.checked_add(plan.total()))) {
+            Some(total) => total,
+            None => return Err(BatchError::Batch("batch total too large")),
+        };
+        if acct_from.balance.saturating_add(currency_detail.overdraft_limit as i64) < total {
+            return Err(BatchError::Batch("balance too low in account_from"))
+        }
+        let sent: i64 = plans.iter().map(|plan| plan.amount).sum();
+        if let Err(err) = kyc::check_monthly_volume(acct_from, sent, unix_now()) {
+            return Err(BatchError::Batch(err.message()))
+        }
+        for (&to, &amount) in &received(plans) {
+            let acct_to = &accts[ids.iter().position(|&id| id == to).unwrap()];
+            if let Err(err) = kyc::check_receive(acct_to, amount) {
+                return Err(BatchError::Batch(err.message()))
+            }
+        }
+        Ok(())
+    }
+
+    /// What each account is paid by the batch `plans` in all - several
+    /// transfers in a batch may pay the same account
+    fn received(plans: &[TransferPlan]) -> BTreeMap<usize, i64> {
+        let mut received = BTreeMap::new();
+        for plan in plans {
+            *received.entry(plan.to).or_insert(0) += plan.amount;
+        }
+        received
+    }
+
+    /// Transfers in the batch `plans` needing approval under a policy for
+    /// transfers of at least `threshold`. What an account is paid by the
+    /// whole batch counts, so splitting a payment doesn't get round it.
+    pub fn needing_approval(plans: &[TransferPlan], threshold: u64) -> Vec<ItemError> {
+        let received = received(plans);
+        plans.iter().enumerate()
+            .filter(|&(_, plan)| received[&plan.to] as u64 >= threshold)
+            .map(|(i, _)| ItemError { item: i + 1, error: "needs approval, make it as a single transfer" })
+            .collect()
+    }
+
+    #[test]
+    fn test_batch() {
+        use super::EntryKind;
+        use super::currency::{Amount, CurrencyId};
+
+        assert_eq!(parse_csv(b"account_to,amount\n\na, 50\r\nb,0.60\n").unwrap(), vec![
+            BatchItem { account_to: "a".to_owned(), amount: Amount::Minor(50) },
+            BatchItem { account_to: "b".to_owned(), amount: Amount::Decimal("0.60".to_owned()) },
+        ]);
+        assert_eq!(parse_csv(b"a,50\na\nb,-1\n"), Err(BatchError::Items(vec![
+            ItemError { item: 2, error: "expected account_to,amount" },
+            ItemError { item: 3, error: "amount must be a number" },
+        ])));
+
+        let mut userdb = UserDB::new();
+        let charge_account = userdb.addsysacct(UserAccount::with_currency(CurrencyId(1))).unwrap();
+        let detail = CurrencyDetail { overdraft_limit: 10, ..currency::test_usd(2.0, charge_account) };
+        let ids: Vec<String> = (0..3).map(|_| userdb.addacct(UserAccount::with_currency(CurrencyId(1))).unwrap().to_string()).collect();
+        let other = userdb.addacct(UserAccount::with_currency(CurrencyId(2))).unwrap().to_string();
+        let from = userdb.lookup(&None, &ids[0]).unwrap();
+        userdb.get_mut(from).unwrap().balance = 192;
+        let item = |account_to: &str, amount| BatchItem { account_to: account_to.to_owned(), amount: Amount::Minor(amount) };
+        let decimal = |account_to: &str, amount: &str| BatchItem { account_to: account_to.to_owned(),
+                                                                   amount: Amount::Decimal(amount.to_owned()) };
+
+        assert_eq!(plan(&userdb, from, &detail, &[]), Err(BatchError::Batch("batch has no transfers")));
+        assert_eq!(plan(&userdb, from, &detail, &[item(&ids[1], 100), item("nope", 100), item(&ids[0], 100),
+                                                  item(&ids[2], 49), item(&other, 100), decimal(&ids[1], "1.005")]),
+                   Err(BatchError::Items(vec![
+                       ItemError { item: 2, error: "account_to does not exist" },
+                       ItemError { item: 3, error: "cannot transfer to the same account" },
+                       ItemError { item: 4, error: "below minimum transfer" },
+                       ItemError { item: 5, error: "user account currencies do not match" },
+                       ItemError { item: 6, error: "amount has more decimal places than the currency" },
+                   ])));
+        // 100 + 2 and 100 + 2 is 204, more than the 192 balance plus 10 overdraft
+        assert_eq!(plan(&userdb, from, &detail, &[item(&ids[1], 100), item(&ids[2], 100)]),
+                   Err(BatchError::Batch("balance too low in account_from")));
+        let plans = plan(&userdb, from, &detail, &[item(&ids[1], 100), decimal(&ids[2], "0.98")]).unwrap();
+        assert_eq!(needing_approval(&plans, 100), vec![ItemError { item: 1, error: "needs approval, make it as a single transfer" }]);
+        // 60 and 50 to the same account is 110, over the threshold together
+        let split = plan(&userdb, from, &detail, &[item(&ids[1], 60), item(&ids[2], 60), item(&ids[1], 50)]).unwrap();
+        assert_eq!(needing_approval(&split, 100).iter().map(|err| err.item).collect::<Vec<_>>(), vec![1, 3]);
+        let postings: Vec<_> = plans.iter().flat_map(|plan| plan.postings(from)).collect();
+        userdb.apply_postings(&postings).unwrap();
+        let balances: Vec<i64> = userdb.balances();
+        assert_eq!(balances, vec![4, -10, 100, 98, 0]);
+        let history: Vec<EntryKind> = userdb.get(from).unwrap().history.iter().map(|e| e.kind).collect();
+        assert_eq!(history, vec![EntryKind::TransferOut, EntryKind::Charge, EntryKind::TransferOut, EntryKind::Charge]);
+    }
+}
+
+mod review {
+    use serde_json;
+
+    use std::cmp;
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::io;
+    use std::sync::RwLock;
+
+    use super::{EntryKind, LockedAccounts, Posting, TransferPlan, UserDB, UserDBError, unix_now};
+    use super::events::Change;
+
+    /// Config file listing reviewers and their api tokens
+    pub static REVIEWER_CONFIG: &'static str = "reviewers.json";
+
+    /// Role allowing a reviewer to release or refund any escrow
+    pub static ARBITER_ROLE: &'static str = "arbiter";
+
+    /// Role allowing a reviewer to make the admin calls, like adding a
+    /// currency
+    pub static ADMIN_ROLE: &'static str = "admin";
+
+    /// Shortest reviewer token accepted, to stop trivially guessable ones
+    const MIN_TOKEN_LEN: usize = 16;
+
+    lazy_static! {
+        /// Reviewers allowed to decide on flagged transfers
+        static ref REVIEWERS: RwLock<Vec<Reviewer>> = RwLock::new(vec![]);
+    }
+
+    #[derive(Deserialize)]
+    struct Reviewer {
+        /// Name recorded against the reviewer's decisions
+        id: String,
+        /// Secret the reviewer authenticates with
+        token: String,
+        /// Anything the reviewer can do besides deciding on flagged
+        /// transfers and compliance cases, e.g. `ARBITER_ROLE`
+        #[serde(default)]
+        roles: Vec<String>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum ReviewError {
+        /// Reviewer config is missing or malformed
+        BadConfig(&'static str),
+        /// No flagged transfer has this review id
+        NoSuchReview,
+        /// The transfer has already been approved or rejected
+        AlreadyDecided,
+        /// Moving the held funds failed
+        Ledger(UserDBError),
+    }
+    impl From<io::Error> for ReviewError {
+        fn from(_: io::Error) -> ReviewError {
+            ReviewError::BadConfig("cannot read reviewer config")
+        }
+    }
+    impl From<serde_json::Error> for ReviewError {
+        fn from(_: serde_json::Error) -> ReviewError {
+            ReviewError::BadConfig("invalid reviewer config")
+        }
+    }
+    impl From<UserDBError> for ReviewError {
+        fn from(err: UserDBError) -> ReviewError {
+            ReviewError::Ledger(err)
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+    pub enum ReviewStatus {
+        Pending,
+        Approved,
+        Rejected,
+    }
+
+    /// Record of a reviewer deciding what to do with a flagged transfer or
+    /// compliance case
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct Decision {
+        /// Id of the reviewer from the reviewer config
+        pub reviewer: String,
+        /// Why the reviewer made the decision
+        pub reason: String,
+        /// Seconds since the unix epoch
+        pub time: i64,
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct HeldTransfer {
+        pub transfer: TransferPlan,
+        /// System account holding the funds taken from the sender
+        pub hold_account: usize,
+        /// Why the fraud checker flagged the transfer
+        pub flag_reason: String,
+        /// Seconds since the unix epoch
+        pub flagged_at: i64,
+        pub status: ReviewStatus,
+        /// Set once the status is no longer `Pending`
+        pub decision: Option<Decision>,
+    }
+
+    /// Transfers flagged by the fraud checker, kept in the `UserDB` so the
+    /// event log rebuilds them along with their held funds
+    pub struct ReviewQueue {
+        transfers: BTreeMap<u64, HeldTransfer>,
+        next_id: u64,
+    }
+    impl ReviewQueue {
+        pub fn new() -> ReviewQueue {
+            ReviewQueue { transfers: BTreeMap::new(), next_id: 1 }
+        }
+        pub fn get(&self, review_id: u64) -> Option<&HeldTransfer> {
+            self.transfers.get(&review_id)
+        }
+        /// Add a transfer whose funds have been held
+        pub fn insert(&mut self, review_id: u64, held: HeldTransfer) {
+            self.next_id = cmp::max(self.next_id, review_id + 1);
+            self.transfers.insert(review_id, held);
+        }
+        /// Mark a transfer as decided, once its funds have been moved
+        pub fn settle(&mut self, review_id: u64, status: ReviewStatus, decision: Decision) {
+            if let Some(transfer) = self.transfers.get_mut(&review_id) {
+                transfer.status = status;
+                transfer.decision = Some(decision);
+            }
+        }
+        /// Postings and new status for approving (paying the held funds out
+        /// as the original transfer would have) or rejecting (returning them
+        /// to the sender) a pending transfer
+        fn decide(&self, review_id: u64, approve: bool) -> Result<(Vec<Posting>, ReviewStatus), ReviewError> {
+            let transfer = match self.transfers.get(&review_id) {
+                Some(transfer) => transfer,
+                None => return Err(ReviewError::NoSuchReview),
+            };
+            if transfer.status != ReviewStatus::Pending {
+                return Err(ReviewError::AlreadyDecided)
+            }
+            let hold_account = transfer.hold_account;
+            if approve {
+                Ok((transfer.transfer.postings(hold_account), ReviewStatus::Approved))
+            } else {
+                let (from, total) = (transfer.transfer.from, transfer.transfer.total());
+                Ok((vec![
+                    Posting::new(hold_account, -total, EntryKind::Release, from),
+                    Posting::new(from, total, EntryKind::Release, hold_account),
+                ], ReviewStatus::Rejected))
+            }
+        }
+    }
+
+    /// Load the reviewer config, replacing any reviewers already loaded.
+    /// Returns how many reviewers there are.
+    pub fn load_reviewers(path: &str) -> Result<usize, ReviewError> {
+        let reviewers: Vec<Reviewer> = serde_json::from_reader(File::open(path)?)?;
+        if reviewers.iter().any(|r| r.id.is_empty() || r.token.len() < MIN_TOKEN_LEN) {
+            return Err(ReviewError::BadConfig("reviewer ids and tokens must be set"))
+        }
+        let num_reviewers = reviewers.len();
+        *REVIEWERS.write().unwrap() = reviewers;
+        Ok(num_reviewers)
+    }
+
+    /// Find the id of the reviewer with api token `token`
+    pub fn authenticate(token: &str) -> Option<String> {
+        REVIEWERS.read().unwrap().iter()
+            .find(|r| constant_time_eq(r.token.as_bytes(), token.as_bytes()))
+            .map(|r| r.id.clone())
+    }
+
+    /// Find the id of the reviewer with api token `token`, if they have
+    /// `role`
+    pub fn authenticate_role(token: &str, role: &str) -> Option<String> {
+        REVIEWERS.read().unwrap().iter()
+            .find(|r| constant_time_eq(r.token.as_bytes(), token.as_bytes()))
+            .and_then(|r| if r.roles.iter().any(|r| r == role) { Some(r.id.clone()) } else { None })
+    }
+
+    /// Compare without stopping at the first difference, so response times
+    /// don't reveal how much of a token was right
+    pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
+    /// Take the funds for a transfer out of the sender's account, which
+    /// must be locked, into `hold_account` and queue the transfer for
+    /// review. Returns the review id.
+    pub fn hold_transfer(locked: LockedAccounts, transfer: TransferPlan, hold_account: usize,
+                         flag_reason: String) -> Result<u64, UserDBError> {
+        let userdb = locked.userdb;
+        let mut queue = userdb.reviews.lock().unwrap();
+        let review_id = queue.next_id;
+        let total = transfer.total();
+        let postings = [
+            Posting::new(transfer.from, -total, EntryKind::Hold, hold_account),
+            Posting::new(hold_account, total, EntryKind::Hold, transfer.from),
+        ];
+        let flagged_at = locked.commit_postings(&postings, |postings| Change::HoldTransfer {
+            review_id: review_id,
+            transfer: transfer,
+            hold_account: hold_account,
+            flag_reason: flag_reason.clone(),
+            postings: postings,
+        })?;
+        queue.insert(review_id, HeldTransfer {
+            transfer: transfer,
+            hold_account: hold_account,
+            flag_reason: flag_reason,
+            flagged_at: flagged_at,
+            status: ReviewStatus::Pending,
+            decision: None,
+        });
+        Ok(review_id)
+    }
+
+    /// Approve or reject pending transfer `review_id`, see
+    /// `ReviewQueue::decide`
+    pub fn decide_transfer(userdb: &mut UserDB, review_id: u64, approve: bool,
+                           reviewer: String, reason: String) -> Result<(), ReviewError> {
+        let decision = Decision { reviewer: reviewer, reason: reason, time: unix_now() };
+        let (postings, status) = userdb.reviews.get_mut().unwrap().decide(review_id, approve)?;
+        userdb.close_review(review_id, status, decision, &postings)?;
+        Ok(())
+    }
+
+    pub fn pending_transfers(userdb: &UserDB) -> Vec<(u64, HeldTransfer)> {
+        userdb.reviews.lock().unwrap().transfers.iter()
+            .filter(|&(_, transfer)| transfer.status == ReviewStatus::Pending)
+            .map(|(&review_id, transfer)| (review_id, transfer.clone()))
+            .collect()
+    }
+
+    pub fn get_transfer(userdb: &UserDB, review_id: u64) -> Option<HeldTransfer> {
+        userdb.reviews.lock().unwrap().get(review_id).cloned()
+    }
+
+    #[test]
+    fn test_review() {
+        use super::UserAccount;
+        let mut userdb = UserDB::new();
+        for _ in 0..4 {
+            let id = userdb.addsysacct(UserAccount::fakeacct()).unwrap();
+            userdb.get_mut(id).unwrap().balance = 100;
+        }
+        let balances = |userdb: &UserDB| -> Vec<i64> {
+            userdb.balances()
+        };
+        let decide = |userdb: &mut UserDB, review_id, approve| {
+            decide_transfer(userdb, review_id, approve, "r".to_owned(), "ok".to_owned())
+        };
+        // Transfer 50 from 0 to 1, with a charge of 1 going to 2. 3 is the hold account
+        let transfer = TransferPlan { from: 0, to: 1, amount: 50, charge_amount: 1, charge_account: 2 };
+
+        let approved = hold_transfer(userdb.lock_many(&[0]).unwrap(), transfer, 3, "flagged".to_owned()).unwrap();
+        assert_eq!(balances(&userdb), vec![49, 100, 100, 151]);
+        decide(&mut userdb, approved, true).unwrap();
+        assert_eq!(balances(&userdb), vec![49, 150, 101, 100]);
+        assert_eq!(decide(&mut userdb, approved, false), Err(ReviewError::AlreadyDecided));
+
+        let rejected = hold_transfer(userdb.lock_many(&[0]).unwrap(), transfer, 3, "flagged".to_owned()).unwrap();
+        assert_eq!(balances(&userdb), vec![-2, 150, 101, 151]);
+        assert_eq!(pending_transfers(&userdb).iter().map(|&(id, _)| id).collect::<Vec<_>>(), vec![rejected]);
+        decide(&mut userdb, rejected, false).unwrap();
+        assert_eq!(balances(&userdb), vec![49, 150, 101, 100]);
+        assert_eq!(get_transfer(&userdb, rejected).unwrap().status, ReviewStatus::Rejected);
+        let history: Vec<EntryKind> = userdb.get(0).unwrap().history.iter().map(|e| e.kind).collect();
+        assert_eq!(history, vec![EntryKind::Hold, EntryKind::Hold, EntryKind::Release]);
+        assert_eq!(decide(&mut userdb, 99, true), Err(ReviewError::NoSuchReview));
+    }
+}
+
+mod audit {
+    use iron::{Handler, typemap};
+    use iron::prelude::{IronResult, Request, Response};
+    use iron::status;
+
+    use serde_json;
+
+    use sha2::{Digest, Sha256};
+
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::net::IpAddr;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use super::{routes, tenants, unix_now};
+
+    /// Append-only file holding the audit log, one json entry per line
+    pub static AUDIT_LOG: &'static str = "audit.log";
+
+    /// `prev_hash` of the first entry in the log
+    const GENESIS_HASH: &'static str =
+        "0000000000000000000000000000000000000000000000000000000000000000";
+
+    /// Outcome recorded for a call whose handler panicked
+    pub static PANICKED: &'static str = "panicked";
+
+    /// Response to calls which can't be audited, as the log can't be
+    /// written to
+    pub static UNAVAILABLE: &'static str = "audit log unavailable";
+
+    lazy_static! {
+        /// Global audit log, opened at startup
+        static ref AUDITLOG: Mutex<Option<AuditLog>> = Mutex::new(None);
+    }
+
+    /// A record of one state-changing call. Each entry includes the hash of
+    /// the one before, so editing or removing an entry breaks the chain.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct AuditEntry {
+        /// Position in the log, counting from 0
+        pub seq: u64,
+        /// Seconds since the unix epoch
+        pub time: i64,
+        /// Who made the call - `reviewer:<id>`, a remote ip, or `startup`
+        pub caller: String,
+        /// What was called, e.g. a route name
+        pub action: String,
+        /// Hex sha256 of the request body
+        pub body_sha256: String,
+        /// Http status of the response, or what happened for internal calls
+        pub outcome: String,
+        /// `hash` of the previous entry, or `GENESIS_HASH`
+        pub prev_hash: String,
+        /// Hex sha256 of every other field, see `AuditEntry::compute_hash`
+        pub hash: String,
+    }
+    impl AuditEntry {
+        fn compute_hash(&self) -> String {
+            let fields = [self.seq.to_string(), self.time.to_string(), self.caller.clone(),
+                          self.action.clone(), self.body_sha256.clone(), self.outcome.clone(),
+                          self.prev_hash.clone()];
+            let mut hasher = Sha256::default();
+            for field in &fields {
+                // Length prefix so text can't be moved from one field to the next
+                hasher.input(format!("{}:", field.len()).as_bytes());
+                hasher.input(field.as_bytes());
+            }
+            format!("{:x}", hasher.result())
+        }
+    }
+
+    pub fn sha256_hex(data: &[u8]) -> String {
+        let mut hasher = Sha256::default();
+        hasher.input(data);
+        format!("{:x}", hasher.result())
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum AuditError {
+        Io(String),
+        /// Entry on this line (counting from 1) isn't valid json
+        Malformed(usize),
+        /// Entry on this line has been edited, or entries around it removed
+        Tampered(usize),
+    }
+    impl From<io::Error> for AuditError {
+        fn from(err: io::Error) -> AuditError {
+            AuditError::Io(err.to_string())
+        }
+    }
+
+    struct AuditLog {
+        file: File,
+        next_seq: u64,
+        last_hash: String,
+        /// Why the log can't be written to, once a write has failed. The
+        /// failed write may have left part of an entry, so nothing more is
+        /// added until a restart verifies the log.
+        failed: Option<String>,
+    }
+    impl AuditLog {
+        fn append(&mut self, caller: &str, action: &str, body: &[u8], outcome: &str) -> io::Result<()> {
+            if let Some(ref err) = self.failed {
+                return Err(io::Error::new(io::ErrorKind::Other, err.clone()))
+            }
+            let result = self.write_entry(caller, action, body, outcome);
+            if let Err(ref err) = result {
+                self.failed = Some(err.to_string());
+            }
+            result
+        }
+        fn write_entry(&mut self, caller: &str, action: &str, body: &[u8], outcome: &str) -> io::Result<()> {
+            let mut entry = AuditEntry {
+                seq: self.next_seq,
+                time: unix_now(),
+                caller: caller.to_owned(),
+                action: action.to_owned(),
+                body_sha256: sha256_hex(body),
+                outcome: outcome.to_owned(),
+                prev_hash: self.last_hash.clone(),
+                hash: String::new(),
+            };
+            entry.hash = entry.compute_hash();
+            let mut line = serde_json::to_string(&entry).unwrap();
+            line.push('\n');
+            self.file.write_all(line.as_bytes())?;
+            self.file.sync_data()?;
+            self.next_seq += 1;
+            self.last_hash = entry.hash;
+            Ok(())
+        }
+    }
+
+    /// Check every entry links to the one before and hasn't been edited.
+    /// Returns the number of entries and the hash of the last one.
+    pub fn verify<R: Read>(log: R) -> Result<(u64, String), AuditError> {
+        let mut num_entries = 0;
+        let mut last_hash = GENESIS_HASH.to_owned();
+        for (i, line) in BufReader::new(log).lines().enumerate() {
+            let line = line?;
+            let entry: AuditEntry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(_) => return Err(AuditError::Malformed(i + 1)),
+            };
+            if entry.seq != num_entries || entry.prev_hash != last_hash ||
+                    entry.hash != entry.compute_hash() {
+                return Err(AuditError::Tampered(i + 1))
+            }
+            num_entries += 1;
+            last_hash = entry.hash;
+        }
+        Ok((num_entries, last_hash))
+    }
+
+    /// Open the audit log at `path`, creating it if needed, and continue the
+    /// chain from its last entry. Refuses to use a log that doesn't verify.
+    /// Returns the hash of the last entry.
+    pub fn open(path: &str) -> Result<String, AuditError> {
+        let (next_seq, last_hash) = if Path::new(path).is_file() {
+            verify(File::open(path)?)?
+        } else {
+            (0, GENESIS_HASH.to_owned())
+        };
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        *AUDITLOG.lock().unwrap() = Some(AuditLog {
+            file: file,
+            next_seq: next_seq,
+            last_hash: last_hash.clone(),
+            failed: None,
+        });
+        Ok(last_hash)
+    }
+
+    /// Add an entry to the audit log
+    pub fn record(caller: &str, action: &str, body: &[u8], outcome: &str) -> Result<(), AuditError> {
+        let mut auditlog = AUDITLOG.lock().unwrap();
+        match auditlog.as_mut() {
+            Some(auditlog) => Ok(auditlog.append(caller, action, body, outcome)?),
+            None => Err(AuditError::Io("audit log not open".to_owned())),
+        }
+    }
+
+    /// `record` a call which has already been made, so all that can be done
+    /// if it can't be recorded is to say so
+    pub fn record_call(caller: &str, action: &str, body: &[u8], outcome: &str) {
+        if let Err(err) = record(caller, action, body, outcome) {
+            println!("Failed to audit {} by {}: {:?}", action, caller, err);
+        }
+    }
+
+    /// Whether calls can be recorded. Audited calls are turned away rather
+    /// than made without a record once they can't.
+    pub fn writable() -> bool {
+        AUDITLOG.lock().unwrap().as_ref().map_or(false, |auditlog| auditlog.failed.is_none())
+    }
+
+    /// Entry point for `underhanded-rs verify-audit [path] [expected head hash]`.
+    /// Entries deleted from the end of the log can only be noticed by
+    /// comparing the head hash with one noted down earlier (it's printed at
+    /// startup). Returns the process exit code.
+    pub fn verify_cmd(args: &[String]) -> i32 {
+        let path = args.get(0).map_or(AUDIT_LOG, |path| &path[..]);
+        let result = File::open(path).map_err(AuditError::from).and_then(verify);
+        match (result, args.get(1)) {
+            (Ok((_, ref head)), Some(expected)) if head != expected => {
+                println!("audit log head is {}, expected {} - entries removed from the end", head, expected);
+                1
+            },
+            (Ok((num_entries, head)), _) => {
+                println!("audit log ok: {} entries, head {}", num_entries, head);
+                0
+            },
+            (Err(err), _) => {
+                println!("audit log invalid: {:?}", err);
+                1
+            },
+        }
+    }
+
+    /// Request body of an audited request, see `raw_body`
+    struct RawBody;
+    impl typemap::Key for RawBody {
+        type Value = Vec<u8>;
+    }
+
+    /// Body of a request handled by an `Audited` handler. The body has
+    /// already been read, so `req.body` is empty.
+    pub fn raw_body<'a>(req: &'a Request) -> &'a [u8] {
+        req.extensions.get::<RawBody>().map_or(&[], |body| &body[..])
+    }
+
+    /// Handler wrapper which records every call in the audit log, as the
+    /// action named by the first field. Calls which panic are recorded as
+    /// `PANICKED` before carrying on panicking.
+    pub struct Audited<H>(pub &'static str, pub H);
+    impl<H: Handler> Handler for Audited<H> {
+        fn handle(&self, req: &mut Request) -> IronResult<Response> {
+            if !writable() {
+                return Ok(Response::with((status::ServiceUnavailable, UNAVAILABLE)))
+            }
+            let mut body = vec![];
+            req.body.read_to_end(&mut body).unwrap();
+            let caller = caller(routes::reviewer(req), tenants::tenant(req), req.remote_addr.ip());
+            req.extensions.insert::<RawBody>(body);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| self.1.handle(req)));
+            let outcome = result.as_ref().map(outcome).unwrap_or_else(|_| PANICKED.to_owned());
+            record_call(&caller, self.0, raw_body(req), &outcome);
+            result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+        }
+    }
+
+    /// Who made a call, as recorded in the audit log: the reviewer it
+    /// authenticated as, if any, then the tenant, otherwise the address it
+    /// came from
+    pub fn caller(reviewer: Option<String>, tenant: Option<String>, addr: IpAddr) -> String {
+        match (reviewer, tenant) {
+            (Some(reviewer), _) => format!("reviewer:{}", reviewer),
+            (None, Some(tenant)) => format!("tenant:{}", tenant),
+            (None, None) => addr.to_string(),
+        }
+    }
+
+    /// How a call turned out, as recorded in the audit log
+    pub fn outcome(result: &IronResult<Response>) -> String {
+        match *result {
+            Ok(ref resp) => resp.status.map_or("no status".to_owned(), |status| status.to_string()),
+            Err(ref err) => format!("error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        use std::io::Cursor;
+        let path = ::std::env::temp_dir().join(format!("quadcurr-audit-{}", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let file = OpenOptions::new().append(true).create(true).open(&path).unwrap();
+        let mut log = AuditLog { file: file, next_seq: 0, last_hash: GENESIS_HASH.to_owned(), failed: None };
+        for i in 0..3 {
+            log.append("127.0.0.1", "deposit", format!("body {}", i).as_bytes(), "200 OK").unwrap();
+        }
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        // Once a write fails nothing more is added, even if it would work
+        let mut broken = AuditLog { file: File::open(&path).unwrap(), next_seq: 3, last_hash: log.last_hash.clone(), failed: None };
+        assert!(broken.append("127.0.0.1", "deposit", b"body 3", "200 OK").is_err());
+        broken.file = OpenOptions::new().append(true).open(&path).unwrap();
+        assert!(broken.append("127.0.0.1", "deposit", b"body 3", "200 OK").is_err());
+        assert_eq!(broken.next_seq, 3);
+        ::std::fs::remove_file(&path).unwrap();
+
+        let (num_entries, head) = verify(Cursor::new(contents.clone())).unwrap();
+        assert_eq!((num_entries, head), (3, log.last_hash.clone()));
+        let lines: Vec<&str> = contents.lines().collect();
+        let edited = contents.replace("200 OK", "400 Bad Request");
+        assert_eq!(verify(Cursor::new(edited)), Err(AuditError::Tampered(1)));
+        let deleted = format!("{}\n{}\n", lines[0], lines[2]);
+        assert_eq!(verify(Cursor::new(deleted)), Err(AuditError::Tampered(2)));
+        let truncated = format!("{}\n{}", lines[0], &lines[1][..10]);
+        assert_eq!(verify(Cursor::new(truncated)), Err(AuditError::Malformed(2)));
+    }
+}
+
+mod events {
+    use serde_json;
+
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::sync::mpsc::{self, Receiver, SyncSender};
+
+    use std::sync::atomic::Ordering;
+
+    use super::{AccountId, Posting, TransferPlan, UserAccount, UserDB};
+    use super::currency::{CurrencyConfig, CurrencyId, CurrencyTable};
+    use super::disputes::{Dispute, DisputeStatus, Evidence};
+    use super::escrow::{Escrow, EscrowStatus};
+    use super::review::{Decision, HeldTransfer, ReviewStatus};
+    use super::kyc::Level;
+
+    /// Append-only file holding every change to the `UserDB`, one json event
+    /// per line. Replayed at startup to rebuild accounts and currencies.
+    pub static EVENT_LOG: &'static str = "events.log";
+
+    /// Take a checkpoint of every balance after this many events
+    const CHECKPOINT_INTERVAL: usize = 1000;
+
+    /// Most events waiting to be sent to a follower. One which falls this
+    /// far behind is dropped, and catches up from the log when it
+    /// reconnects.
+    const SUBSCRIBER_BACKLOG: usize = 10000;
+
+    lazy_static! {
+        /// Global event store, opened at startup. Nothing is recorded while
+        /// this is `None`.
+        static ref EVENTS: Mutex<Option<EventStore>> = Mutex::new(None);
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub enum Change {
+        /// Account at internal index `account` was added, with a public id
+        /// unless it's a system account
+        OpenAccount {
+            account: usize,
+            account_id: Option<String>,
+            currency: CurrencyId,
+            #[serde(default)]
+            holder_name: Option<String>,
+            #[serde(default)]
+            tenant: Option<String>,
+        },
+        /// Currency added, using system accounts already opened
+        AddCurrency { config: CurrencyConfig, charge_account: usize, hold_account: usize },
+        /// System account opened to take a tenant's transfer charges in a
+        /// currency
+        AddTenantChargeAccount { tenant: String, currency: CurrencyId, charge_account: usize },
+        /// A batch of postings applied by `UserDB::apply_postings`
+        Postings(Vec<Posting>),
+        /// Currency interest rate set, paid from `expense_account`
+        SetCurrencyInterest { currency: CurrencyId, rate_bps: u32, expense_account: usize },
+        /// How a currency's amounts are written changed in `currencies.json`
+        SetCurrencyPresentation { currency: CurrencyId, exponent: u32, symbol: String, locale: String },
+        /// Account interest rate override set or cleared
+        SetAccountInterest { account: usize, rate_bps: Option<u32> },
+        /// Interest accrued for `day`, see `UserDB::accrue_interest`
+        AccrueInterest { day: i64, accruals: Vec<(usize, i64)> },
+        /// Accrued interest paid out, see `UserDB::pay_interest`
+        PayInterest(Vec<Posting>),
+        /// Escrow opened, see `UserDB::open_escrow`
+        OpenEscrow { escrow: Escrow, postings: Vec<Posting> },
+        /// Escrow released or refunded, see `UserDB::close_escrow`
+        CloseEscrow { escrow_id: u64, status: EscrowStatus, closed_by: String, postings: Vec<Posting> },
+        /// Transfer flagged by the fraud checker held for review, see
+        /// `review::hold_transfer`
+        HoldTransfer {
+            review_id: u64,
+            transfer: TransferPlan,
+            hold_account: usize,
+            flag_reason: String,
+            postings: Vec<Posting>,
+        },
+        /// Held transfer approved or rejected, see `UserDB::close_review`
+        DecideReview { review_id: u64, status: ReviewStatus, decision: Decision, postings: Vec<Posting> },
+        /// Dispute opened, see `UserDB::open_dispute`
+        OpenDispute { dispute: Dispute, postings: Vec<Posting> },
+        /// Evidence added to an open dispute
+        DisputeEvidence { dispute_id: u64, evidence: Evidence },
+        /// Dispute decided or withdrawn, see `UserDB::close_dispute`
+        CloseDispute { dispute_id: u64, status: DisputeStatus, closed_by: String, postings: Vec<Posting> },
+        /// Account frozen for a reason, or unfrozen
+        FreezeAccount { account: usize, frozen: Option<String> },
+        /// Account verification level set by a reviewer or verification
+        /// provider
+        SetKycLevel { account: usize, level: Level, verified_by: String },
+        /// Charge account stopped or started taking transfer charges
+        SetChargeAccountEnabled { charge_account: usize, enabled: bool },
+    }
+    impl Change {
+        /// Postings the change applies, if any
+        pub fn postings(&self) -> &[Posting] {
+            match *self {
+                Change::Postings(ref postings) |
+                Change::PayInterest(ref postings) |
+                Change::OpenEscrow { ref postings, .. } |
+                Change::CloseEscrow { ref postings, .. } |
+                Change::HoldTransfer { ref postings, .. } |
+                Change::DecideReview { ref postings, .. } |
+                Change::OpenDispute { ref postings, .. } |
+                Change::CloseDispute { ref postings, .. } => postings,
+                _ => &[],
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Event {
+        /// Position in the log, counting from 0
+        pub seq: u64,
+        /// Seconds since the unix epoch, never less than the event before
+        pub time: i64,
+        pub change: Change,
+    }
+
+    /// Balance of every account, by internal index, after the first `seq`
+    /// events
+    struct Checkpoint {
+        seq: usize,
+        /// Time of the last event included
+        time: i64,
+        balances: Vec<i64>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum EventError {
+        Io(String),
+        /// Event on this line (counting from 1) isn't valid json
+        Malformed(usize),
+        /// Event on this line is out of order, or can't be applied
+        Inconsistent(usize),
+    }
+    impl From<io::Error> for EventError {
+        fn from(err: io::Error) -> EventError {
+            EventError::Io(err.to_string())
+        }
+    }
+
+    struct EventStore {
+        /// Where new events are appended, if anywhere
+        file: Option<File>,
+        events: Vec<Event>,
+        /// Balance of every account after the last event
+        balances: Vec<i64>,
+        checkpoints: Vec<Checkpoint>,
+        checkpoint_interval: usize,
+        /// Followers streaming events as they're appended, see `subscribe`
+        subscribers: Vec<SyncSender<Event>>,
+    }
+    impl EventStore {
+        fn new(checkpoint_interval: usize) -> EventStore {
+            EventStore {
+                file: None,
+                events: vec![],
+                balances: vec![],
+                checkpoints: vec![],
+                checkpoint_interval: checkpoint_interval,
+                subscribers: vec![],
+            }
+        }
+        /// Append a new event to the log
+        fn push(&mut self, time: i64, change: Change) -> io::Result<()> {
+            let event = Event { seq: self.events.len() as u64, time: time, change: change };
+            self.append(event)
+        }
+        /// Append an event, already numbered, to the log and send it to
+        /// subscribers. Subscribers which have gone away, or are too far
+        /// behind, are dropped.
+        fn append(&mut self, event: Event) -> io::Result<()> {
+            if let Some(ref mut file) = self.file {
+                let mut line = serde_json::to_string(&event).unwrap();
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+                file.sync_data()?;
+            }
+            self.subscribers.retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+            self.add(event);
+            Ok(())
+        }
+        /// Keep track of an event which is already in the log
+        fn add(&mut self, event: Event) {
+            if let Change::OpenAccount { .. } = event.change {
+                self.balances.push(0);
+            }
+            for posting in event.change.postings() {
+                self.balances[posting.account] += posting.amount;
+            }
+            let time = event.time;
+            self.events.push(event);
+            if self.events.len() % self.checkpoint_interval == 0 {
+                self.checkpoints.push(Checkpoint {
+                    seq: self.events.len(),
+                    time: time,
+                    balances: self.balances.clone(),
+                });
+            }
+        }
+        /// Balance of the account at internal index `account` once every
+        /// event up to and including `as_of` had happened, starting from the
+        /// latest checkpoint before then. `None` if the account didn't exist
+        /// yet.
+        fn balance_at(&self, account: usize, as_of: i64) -> Option<i64> {
+            let (start, mut balance) = match self.checkpoints.iter().rev().find(|cp| cp.time <= as_of) {
+                Some(cp) => (cp.seq, cp.balances.get(account).cloned()),
+                None => (0, None),
+            };
+            for event in self.events[start..].iter().take_while(|event| event.time <= as_of) {
+                if let Change::OpenAccount { account: idx, .. } = event.change {
+                    if idx == account {
+                        balance = Some(0);
+                    }
+                }
+                for posting in event.change.postings().iter().filter(|posting| posting.account == account) {
+                    balance = balance.map(|balance| balance + posting.amount);
+                }
+            }
+            balance
+        }
+    }
+
+    /// Apply `event` to `userdb` and `currencies` without recording it, if
+    /// it's the event numbered `seq` and can be applied. Returns whether it
+    /// was applied.
+    fn apply(userdb: &mut UserDB, currencies: &mut CurrencyTable, event: &Event, seq: u64) -> bool {
+        if event.seq != seq || event.time < userdb.last_time.load(Ordering::SeqCst) {
+            return false
+        }
+        userdb.last_time.store(event.time, Ordering::SeqCst);
+        match event.change {
+            Change::OpenAccount { account, ref account_id, currency, ref holder_name, ref tenant } => {
+                let id = match *account_id {
+                    Some(ref id) => match AccountId::parse(id) {
+                        Some(id) if !userdb.public_ids.contains_key(&id) => Some(id),
+                        _ => return false,
+                    },
+                    None => None,
+                };
+                let mut acct = UserAccount::with_currency(currency);
+                acct.holder_name = holder_name.clone();
+                acct.tenant = tenant.clone();
+                account == userdb.accts.len() && userdb.open_account(acct, id) == account
+            },
+            Change::AddCurrency { ref config, charge_account, hold_account } =>
+                charge_account < userdb.accts.len() && hold_account < userdb.accts.len() &&
+                    currencies.restore(config.clone(), charge_account, hold_account).is_ok(),
+            Change::AddTenantChargeAccount { ref tenant, currency, charge_account } =>
+                charge_account < userdb.accts.len() &&
+                    currencies.restore_tenant_charge_account(tenant.clone(), currency, charge_account).is_ok(),
+            Change::Postings(ref postings) => userdb.post(postings, event.time).is_ok(),
+            Change::SetCurrencyInterest { currency, rate_bps, expense_account } =>
+                expense_account < userdb.accts.len() &&
+                    currencies.restore_interest_rate(currency, rate_bps, expense_account).is_ok(),
+            Change::SetCurrencyPresentation { currency, exponent, ref symbol, ref locale } =>
+                currencies.restore_presentation(currency, exponent, symbol.clone(), locale.clone()).is_ok(),
+            Change::SetAccountInterest { account, rate_bps } => match userdb.get_mut(account) {
+                Some(acct) => {
+                    acct.interest_rate_bps = rate_bps;
+                    true
+                },
+                None => false,
+            },
+            Change::AccrueInterest { day, ref accruals } => userdb.add_accruals(day, accruals).is_ok(),
+            Change::PayInterest(ref postings) => userdb.post_interest(postings, event.time).is_ok(),
+            Change::OpenEscrow { ref escrow, ref postings } =>
+                !userdb.escrows.contains_key(&escrow.escrow_id) && escrow.account < userdb.accts.len() &&
+                    userdb.add_escrow(escrow.clone(), postings, event.time).is_ok(),
+            Change::CloseEscrow { escrow_id, status, ref closed_by, ref postings } =>
+                userdb.escrows.get(&escrow_id).map_or(false, |escrow| escrow.status == EscrowStatus::Held) &&
+                    userdb.settle_escrow(escrow_id, status, closed_by.clone(), postings, event.time).is_ok(),
+            Change::HoldTransfer { review_id, transfer, hold_account, ref flag_reason, ref postings } => {
+                let held = HeldTransfer {
+                    transfer: transfer,
+                    hold_account: hold_account,
+                    flag_reason: flag_reason.clone(),
+                    flagged_at: event.time,
+                    status: ReviewStatus::Pending,
+                    decision: None,
+                };
+                userdb.reviews.get_mut().unwrap().get(review_id).is_none() &&
+                    userdb.add_review(review_id, held, postings, event.time).is_ok()
+            },
+            Change::DecideReview { review_id, status, ref decision, ref postings } =>
+                userdb.reviews.get_mut().unwrap().get(review_id)
+                    .map_or(false, |held| held.status == ReviewStatus::Pending) &&
+                    userdb.settle_review(review_id, status, decision.clone(), postings, event.time).is_ok(),
+            Change::OpenDispute { ref dispute, ref postings } =>
+                !userdb.disputes.contains_key(&dispute.dispute_id) &&
+                    userdb.add_dispute(dispute.clone(), postings, event.time).is_ok(),
+            Change::DisputeEvidence { dispute_id, ref evidence } => match userdb.disputes.get_mut(&dispute_id) {
+                Some(ref mut dispute) if !dispute.status.is_closed() => {
+                    dispute.add_evidence(evidence.clone());
+                    true
+                },
+                _ => false,
+            },
+            Change::CloseDispute { dispute_id, status, ref closed_by, ref postings } =>
+                userdb.disputes.get(&dispute_id).map_or(false, |dispute| !dispute.status.is_closed()) &&
+                    userdb.settle_dispute(dispute_id, status, closed_by.clone(), postings, event.time).is_ok(),
+            Change::FreezeAccount { account, ref frozen } => match userdb.get_mut(account) {
+                Some(acct) => {
+                    acct.frozen = frozen.clone();
+                    true
+                },
+                None => false,
+            },
+            Change::SetKycLevel { account, level, .. } => match userdb.get_mut(account) {
+                Some(acct) => {
+                    acct.kyc_level = level;
+                    true
+                },
+                None => false,
+            },
+            Change::SetChargeAccountEnabled { charge_account, enabled } =>
+                currencies.restore_charge_account_enabled(charge_account, enabled).is_ok(),
+        }
+    }
+
+    /// Rebuild accounts and currencies from scratch by applying every event
+    /// in `log`
+    fn replay<R: Read>(log: R, checkpoint_interval: usize)
+            -> Result<(UserDB, CurrencyTable, EventStore), EventError> {
+        let mut userdb = UserDB::new();
+        let mut currencies = CurrencyTable::new();
+        let mut store = EventStore::new(checkpoint_interval);
+        for (i, line) in BufReader::new(log).lines().enumerate() {
+            let line = line?;
+            let event: Event = match serde_json::from_str(&line) {
+                Ok(event) => event,
+                Err(_) => return Err(EventError::Malformed(i + 1)),
+            };
+            if !apply(&mut userdb, &mut currencies, &event, i as u64) {
+                return Err(EventError::Inconsistent(i + 1))
+            }
+            store.add(event);
+        }
+        Ok((userdb, currencies, store))
+    }
+
+    /// Open the event log at `path`, creating it if needed, and rebuild the
+    /// accounts and currencies it describes. New events are appended to it
+    /// from now on. Also used by admins to rebuild everything at runtime -
+    /// the caller must hold the `USERDB` lock so nothing changes meanwhile.
+    pub fn open(path: &str) -> Result<(UserDB, CurrencyTable), EventError> {
+        let (userdb, currencies, mut store) = if Path::new(path).is_file() {
+            replay(File::open(path)?, CHECKPOINT_INTERVAL)?
+        } else {
+            (UserDB::new(), CurrencyTable::new(), EventStore::new(CHECKPOINT_INTERVAL))
+        };
+        store.file = Some(OpenOptions::new().append(true).create(true).open(path)?);
+        *EVENTS.lock().unwrap() = Some(store);
+        Ok((userdb, currencies))
+    }
+
+    /// Rebuild the accounts and currencies from the events recorded so far,
+    /// leaving the live ones alone. Also returns the events.
+    pub fn snapshot() -> Result<(UserDB, CurrencyTable, Vec<Event>), EventError> {
+        let events = match *EVENTS.lock().unwrap() {
+            Some(ref store) => store.events.clone(),
+            None => return Err(EventError::Io("event log not open".to_owned())),
+        };
+        let mut userdb = UserDB::new();
+        let mut currencies = CurrencyTable::new();
+        for (i, event) in events.iter().enumerate() {
+            if !apply(&mut userdb, &mut currencies, event, i as u64) {
+                return Err(EventError::Inconsistent(i + 1))
+            }
+        }
+        Ok((userdb, currencies, events))
+    }
+
+    /// Rebuild the accounts and currencies in `log`, without opening it for
+    /// new events. Also returns the events.
+    pub fn read<R: Read>(log: R) -> Result<(UserDB, CurrencyTable, Vec<Event>), EventError> {
+        let (userdb, currencies, store) = replay(log, CHECKPOINT_INTERVAL)?;
+        Ok((userdb, currencies, store.events))
+    }
+
+    /// Record a change and make it in one go, for callers holding only the
+    /// `USERDB` read lock - nothing else changes under the write lock.
+    /// `prepare` is given the time of the change's event, checks the change
+    /// can be made and returns what to record along with what makes it. The
+    /// event is written and synced before the change is made, so nothing
+    /// happens which isn't in the log - if it can't be written, the change
+    /// isn't made. Changes made this way are recorded in the order they're
+    /// made, so the log stays in time order.
+    pub fn commit<T, E, F, M>(userdb: &UserDB, prepare: F) -> Result<T, E>
+            where F: FnOnce(i64) -> Result<(Change, M), E>, M: FnOnce() -> T, E: From<EventError> {
+        let mut events = EVENTS.lock().unwrap();
+        let time = userdb.now();
+        let (change, make) = prepare(time)?;
+        if let Some(store) = events.as_mut() {
+            store.push(time, change).map_err(EventError::from)?;
+        }
+        Ok(make())
+    }
+
+    /// Append an event streamed from the leader to the log, then apply it
+    /// as if it had happened here. Nothing is applied which isn't in the log
+    /// here, so a follower never has changes it would lose on restarting -
+    /// one which then can't be applied stops the follower, as the log needs
+    /// looking at. The caller must hold the `USERDB` lock.
+    pub fn follow(userdb: &mut UserDB, currencies: &mut CurrencyTable, event: Event) -> Result<(), EventError> {
+        let mut events = EVENTS.lock().unwrap();
+        let store = match events.as_mut() {
+            Some(store) => store,
+            None => return Err(EventError::Io("event log not open".to_owned())),
+        };
+        let seq = store.events.len() as u64;
+        if event.seq != seq {
+            return Err(EventError::Inconsistent(seq as usize + 1))
+        }
+        store.append(event.clone())?;
+        if !apply(userdb, currencies, &event, seq) {
+            return Err(EventError::Inconsistent(seq as usize + 1))
+        }
+        Ok(())
+    }
+
+    /// Events from `from` onwards, and a channel receiving every event
+    /// appended after them. `None` if the log isn't open or has fewer than
+    /// `from` events.
+    pub fn subscribe(from: u64) -> Option<(Vec<Event>, Receiver<Event>)> {
+        let mut events = EVENTS.lock().unwrap();
+        match events.as_mut() {
+            Some(store) if from as usize <= store.events.len() => {
+                let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BACKLOG);
+                store.subscribers.push(sender);
+                Some((store.events[from as usize..].to_vec(), receiver))
+            },
+            _ => None,
+        }
+    }
+
+    /// Add an event to the log, returning its time. Callers holding the
+    /// `USERDB` write lock make the change once it's recorded - see
+    /// `UserDB::commit`.
+    pub fn record(userdb: &UserDB, change: Change) -> Result<i64, EventError> {
+        commit(userdb, |time| Ok((change, move || time)))
+    }
+
+    /// Whether the event log is open for new events
+    pub fn is_open() -> bool {
+        EVENTS.lock().unwrap().is_some()
+    }
+
+    pub fn num_events() -> usize {
+        EVENTS.lock().unwrap().as_ref().map_or(0, |store| store.events.len())
+    }
+
+    /// See `EventStore::balance_at`
+    pub fn balance_at(account: usize, as_of: i64) -> Option<i64> {
+        EVENTS.lock().unwrap().as_ref().and_then(|store| store.balance_at(account, as_of))
+    }
+
+    #[test]
+    fn test_replay() {
+        use std::io::Cursor;
+        use super::EntryKind;
+
+        let account_id = AccountId::new();
+        let changes = vec![
+            (100, Change::OpenAccount { account: 0, account_id: None, currency: CurrencyId(1), holder_name: None, tenant: None }),
+            (100, Change::OpenAccount { account: 1, account_id: Some(account_id.to_string()), currency: CurrencyId(1),
+                                        holder_name: Some("Jo Bloggs".to_owned()), tenant: Some("acme".to_owned()) }),
+            (200, Change::Postings(vec![Posting { account: 1, amount: 100, kind: EntryKind::Deposit, counterparty: None }])),
+            (300, Change::Postings(vec![Posting { account: 1, amount: 50, kind: EntryKind::Deposit, counterparty: None }])),
+            (400, Change::Postings(vec![Posting::new(1, -30, EntryKind::TransferOut, 0),
+                                        Posting::new(0, 30, EntryKind::TransferIn, 1)])),
+            (500, Change::SetKycLevel { account: 1, level: Level::Basic, verified_by: "reviewer carol".to_owned() }),
+        ];
+        let lines: Vec<String> = changes.into_iter().enumerate()
+            .map(|(seq, (time, change))| serde_json::to_string(&Event { seq: seq as u64, time: time, change: change }).unwrap())
+            .collect();
+        let log = lines.join("\n");
+
+        let (userdb, _, store) = replay(Cursor::new(log.clone()), 2).unwrap();
+        let balances: Vec<i64> = userdb.balances();
+        assert_eq!(balances, vec![30, 120]);
+        assert_eq!(userdb.lookup(&Some("acme".to_owned()), &account_id.to_string()), Some(1));
+        assert_eq!(userdb.lookup(&Some("other".to_owned()), &account_id.to_string()), None);
+        let times: Vec<i64> = userdb.get(1).unwrap().history.iter().map(|entry| entry.time).collect();
+        assert_eq!(times, vec![200, 300, 400]);
+        assert_eq!(userdb.get(1).unwrap().kyc_level, Level::Basic);
+        assert_eq!(store.checkpoints.iter().map(|cp| cp.seq).collect::<Vec<_>>(), vec![2, 4, 6]);
+        assert_eq!(store.balances, balances);
+
+        let as_of = |account, time| store.balance_at(account, time);
+        assert_eq!((as_of(1, 99), as_of(1, 100), as_of(1, 250), as_of(1, 300)), (None, Some(0), Some(100), Some(150)));
+        assert_eq!((as_of(1, 350), as_of(0, 399), as_of(0, 400), as_of(1, 1000)), (Some(150), Some(0), Some(30), Some(120)));
+
+        let out_of_order = log.replace(r#""seq":3"#, r#""seq":4"#);
+        assert_eq!(replay(Cursor::new(out_of_order), 2).err(), Some(EventError::Inconsistent(4)));
+        let missing_account = log.replace(r#""account":0,"amount":30"#, r#""account":2,"amount":30"#);
+        assert_eq!(replay(Cursor::new(missing_account), 2).err(), Some(EventError::Inconsistent(5)));
+        let truncated = format!("{}\n{}", lines[0], &lines[1][..10]);
+        assert_eq!(replay(Cursor::new(truncated), 2).err(), Some(EventError::Malformed(2)));
+
+        // Transfers held for review come back with their hold
+        let transfer = TransferPlan { from: 1, to: 0, amount: 20, charge_amount: 0, charge_account: 0 };
+        let hold = |seq| Event { seq: seq, time: 600, change: Change::HoldTransfer {
+            review_id: 1,
+            transfer: transfer,
+            hold_account: 0,
+            flag_reason: "flagged".to_owned(),
+            postings: vec![Posting::new(1, -20, EntryKind::Hold, 0), Posting::new(0, 20, EntryKind::Hold, 1)],
+        } };
+        let held = format!("{}\n{}", log, serde_json::to_string(&hold(6)).unwrap());
+        let (userdb, _, _) = replay(Cursor::new(held.clone()), 2).unwrap();
+        assert_eq!(userdb.balances(), vec![50, 100]);
+        let pending = super::review::pending_transfers(&userdb);
+        assert_eq!((pending.len(), pending[0].0, pending[0].1.flagged_at), (1, 1, 600));
+        let held_twice = format!("{}\n{}", held, serde_json::to_string(&hold(7)).unwrap());
+        assert_eq!(replay(Cursor::new(held_twice), 2).err(), Some(EventError::Inconsistent(8)));
+    }
+}
+
+mod export {
+    use chrono::{TimeZone, UTC};
+
+    use std::collections::HashMap;
+    use std::fmt::Write;
+    use std::fs::File;
+
+    use super::{EntryKind, UserDB};
+    use super::currency::{CurrencyDetail, CurrencyId, CurrencyTable};
+    use super::disputes::DisputeStatus;
+    use super::escrow::EscrowStatus;
+    use super::events::{self, Change, Event};
+
+    /// Seconds in a day, for dating the closing balance assertions
+    const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Format {
+        Beancount,
+        /// ledger-cli, also read by hledger
+        Ledger,
+    }
+    impl Format {
+        pub fn parse(name: &str) -> Option<Format> {
+            match name {
+                "beancount" => Some(Format::Beancount),
+                "ledger" => Some(Format::Ledger),
+                _ => None,
+            }
+        }
+    }
+
+    /// Journal account for every account, by internal index. Customer
+    /// money is owed to customers, so their accounts are liabilities. They
+    /// go by internal index too - the public id is all it takes to use an
+    /// account, so it never goes in a journal.
+    fn account_names(userdb: &UserDB, currencies: &CurrencyTable) -> Vec<String> {
+        let mut names: Vec<Option<String>> = vec![None; userdb.accts.len()];
+        for detail in currencies.details() {
+            names[detail.transfer_charge_account] = Some(format!("Income:TransferCharges:{}", detail.name));
+            names[detail.review_hold_account] = Some(format!("Liabilities:ReviewHolds:{}", detail.name));
+            if let Some(acct) = detail.interest_expense_account {
+                names[acct] = Some(format!("Expenses:Interest:{}", detail.name));
+            }
+        }
+        for (&(ref tenant, currency), &acct) in currencies.tenant_charge_accounts() {
+            let detail = currencies.details().iter().find(|detail| detail.id == currency).unwrap();
+            names[acct] = Some(format!("Income:TransferCharges:{}:{}", detail.name, tenant.to_uppercase()));
+        }
+        for escrow in userdb.escrows.values() {
+            names[escrow.account] = Some(format!("Liabilities:Escrow:{}", escrow.escrow_id));
+        }
+        names.into_iter().enumerate().map(|(idx, name)| name.unwrap_or_else(|| {
+            match userdb.public_id(idx) {
+                Some(_) => format!("Liabilities:Customers:{}", idx),
+                None => format!("Liabilities:System:{}", idx),
+            }
+        })).collect()
+    }
+
+    /// Where deposited money comes from, in the journal
+    fn cash_account(commodity: &str) -> String {
+        format!("Assets:Cash:{}", commodity)
+    }
+
+    fn date(time: i64) -> String {
+        UTC.timestamp(time, 0).format("%Y-%m-%d").to_string()
+    }
+
+    /// Description of the money movement in `change`
+    fn narration(change: &Change) -> String {
+        match *change {
+            Change::OpenEscrow { ref escrow, .. } => format!("escrow {} opened", escrow.escrow_id),
+            Change::CloseEscrow { escrow_id, status: EscrowStatus::Refunded, .. } =>
+                format!("escrow {} refunded", escrow_id),
+            Change::CloseEscrow { escrow_id, .. } => format!("escrow {} released", escrow_id),
+            Change::OpenDispute { ref dispute, .. } => format!("dispute {} opened", dispute.dispute_id),
+            Change::CloseDispute { dispute_id, status: DisputeStatus::WonBySender, .. } =>
+                format!("dispute {} chargeback fee", dispute_id),
+            Change::CloseDispute { dispute_id, .. } => format!("dispute {} reversed", dispute_id),
+            Change::PayInterest(_) => "interest".to_owned(),
+            _ => {
+                let postings = change.postings();
+                if postings.iter().any(|posting| posting.kind == EntryKind::TransferOut) {
+                    "transfer".to_owned()
+                } else {
+                    postings[0].kind.description().to_owned()
+                }
+            },
+        }
+    }
+
+    /// Write the accounts and money movements in `events` as a journal.
+    /// `userdb` and `currencies` are what the events rebuild, and give every
+    /// account its final name. Beancount journals end with a balance
+    /// assertion for every account.
+    pub fn journal(userdb: &UserDB, currencies: &CurrencyTable, events: &[Event], format: Format) -> String {
+        let names = account_names(userdb, currencies);
+        let details: HashMap<CurrencyId, &CurrencyDetail> = currencies.details().iter()
+            .map(|detail| (detail.id, detail))
+            .collect();
+        let detail = |idx: usize| details.get(&userdb.get(idx).unwrap().currency).cloned();
+        let commodity = |idx: usize| detail(idx).map_or("UNKNOWN", |detail| &detail.name[..]);
+        // Journals want amounts in major units, e.g. 5.00 rather than 500
+        let amount = |idx: usize, amount: i64| detail(idx).map_or(amount.to_string(), |detail| detail.decimal(amount));
+        let mut out = String::new();
+        if format == Format::Beancount {
+            writeln!(out, "option \"title\" \"QuadCurr\"").unwrap();
+        }
+        for event in events {
+            let day = date(event.time);
+            match (format, &event.change) {
+                (Format::Beancount, &Change::AddCurrency { ref config, .. }) => {
+                    writeln!(out, "\n{} commodity {}", day, config.name).unwrap();
+                    writeln!(out, "{} open {} {}", day, cash_account(&config.name), config.name).unwrap();
+                },
+                (Format::Ledger, &Change::AddCurrency { ref config, .. }) => {
+                    writeln!(out, "\ncommodity {}", config.name).unwrap();
+                    writeln!(out, "account {}", cash_account(&config.name)).unwrap();
+                },
+                (Format::Beancount, &Change::OpenAccount { account, .. }) =>
+                    writeln!(out, "{} open {} {}", day, names[account], commodity(account)).unwrap(),
+                (Format::Ledger, &Change::OpenAccount { account, .. }) =>
+                    writeln!(out, "account {}", names[account]).unwrap(),
+                _ => (),
+            }
+            let postings = event.change.postings();
+            if postings.is_empty() {
+                continue
+            }
+            match format {
+                Format::Beancount => writeln!(out, "\n{} * \"{}\"", day, narration(&event.change)).unwrap(),
+                Format::Ledger => writeln!(out, "\n{} * {}", day, narration(&event.change)).unwrap(),
+            }
+            for posting in postings {
+                let cur = commodity(posting.account);
+                writeln!(out, "  {}  {} {}", names[posting.account], amount(posting.account, -posting.amount), cur).unwrap();
+                if posting.kind == EntryKind::Deposit {
+                    writeln!(out, "  {}  {} {}", cash_account(cur), amount(posting.account, posting.amount), cur).unwrap();
+                }
+            }
+        }
+        if let (Format::Beancount, Some(last)) = (format, events.last()) {
+            // Balances are asserted at the start of the day, so the day after
+            // the last event
+            let day = date(last.time + SECS_PER_DAY);
+            writeln!(out, "").unwrap();
+            for (idx, balance) in userdb.balances().into_iter().enumerate() {
+                writeln!(out, "{} balance {}  {} {}", day, names[idx], amount(idx, -balance), commodity(idx)).unwrap();
+            }
+        }
+        out
+    }
+
+    /// Entry point for `underhanded-rs export-ledger <beancount|ledger> [path]`.
+    /// Writes the journal for the event log at `path` to stdout. Returns the
+    /// exit code.
+    pub fn export_cmd(args: &[String]) -> i32 {
+        let format = match args.get(0).and_then(|name| Format::parse(name)) {
+            Some(format) => format,
+            None => {
+                eprintln!("usage: underhanded-rs export-ledger <beancount|ledger> [event log]");
+                return 2
+            },
+        };
+        let path = args.get(1).map_or(events::EVENT_LOG, |path| &path[..]);
+        match File::open(path).map_err(events::EventError::from).and_then(events::read) {
+            Ok((userdb, currencies, events)) => {
+                print!("{}", journal(&userdb, &currencies, &events, format));
+                0
+            },
+            Err(err) => {
+                eprintln!("event log invalid: {:?}", err);
+                1
+            },
+        }
+    }
+
+    #[test]
+    fn test_export() {
+        use std::io::Cursor;
+        use serde_json;
+        use super::{AccountId, Posting};
+        use super::currency::CurrencyConfig;
+
+        let account_id = AccountId::new();
+        let customer = "Liabilities:Customers:2";
+        let config = CurrencyConfig { id: CurrencyId(1), name: "GBP".to_owned(), overdraft_limit: 0, transfer_charge: 1.0,
+                                      exponent: 2, symbol: "£".to_owned(), locale: "en-GB".to_owned() };
+        let changes = vec![
+            (0, Change::OpenAccount { account: 0, account_id: None, currency: CurrencyId(1), holder_name: None, tenant: None }),
+            (0, Change::OpenAccount { account: 1, account_id: None, currency: CurrencyId(1), holder_name: None, tenant: None }),
+            (0, Change::AddCurrency { config: config, charge_account: 0, hold_account: 1 }),
+            (0, Change::OpenAccount { account: 2, account_id: Some(account_id.to_string()), currency: CurrencyId(1),
+                                      holder_name: None, tenant: None }),
+            (0, Change::OpenAccount { account: 3, account_id: None, currency: CurrencyId(1), holder_name: None, tenant: None }),
+            (100, Change::Postings(vec![Posting { account: 2, amount: 500, kind: EntryKind::Deposit, counterparty: None }])),
+            (86400, Change::Postings(vec![Posting::new(2, -200, EntryKind::TransferOut, 3),
+                                          Posting::new(3, 200, EntryKind::TransferIn, 2),
+                                          Posting::new(2, -2, EntryKind::Charge, 0),
+                                          Posting::new(0, 2, EntryKind::Charge, 2)])),
+        ];
+        let log: Vec<String> = changes.into_iter().enumerate()
+            .map(|(seq, (time, change))| serde_json::to_string(&Event { seq: seq as u64, time: time, change: change }).unwrap())
+            .collect();
+        let (userdb, currencies, events) = events::read(Cursor::new(log.join("\n"))).unwrap();
+
+        let beancount = journal(&userdb, &currencies, &events, Format::Beancount);
+        let expected = vec![
+            "option \"title\" \"QuadCurr\"".to_owned(),
+            "1970-01-01 open Income:TransferCharges:GBP GBP".to_owned(),
+            "1970-01-01 open Liabilities:ReviewHolds:GBP GBP".to_owned(),
+            "".to_owned(),
+            "1970-01-01 commodity GBP".to_owned(),
+            "1970-01-01 open Assets:Cash:GBP GBP".to_owned(),
+            format!("1970-01-01 open {} GBP", customer),
+            "1970-01-01 open Liabilities:System:3 GBP".to_owned(),
+            "".to_owned(),
+            "1970-01-01 * \"deposit\"".to_owned(),
+            format!("  {}  -5.00 GBP", customer),
+            "  Assets:Cash:GBP  5.00 GBP".to_owned(),
+            "".to_owned(),
+            "1970-01-02 * \"transfer\"".to_owned(),
+            format!("  {}  2.00 GBP", customer),
+            "  Liabilities:System:3  -2.00 GBP".to_owned(),
+            format!("  {}  0.02 GBP", customer),
+            "  Income:TransferCharges:GBP  -0.02 GBP".to_owned(),
+            "".to_owned(),
+            "1970-01-03 balance Income:TransferCharges:GBP  -0.02 GBP".to_owned(),
+            "1970-01-03 balance Liabilities:ReviewHolds:GBP  0.00 GBP".to_owned(),
+            format!("1970-01-03 balance {}  -2.98 GBP", customer),
+            "1970-01-03 balance Liabilities:System:3  -2.00 GBP".to_owned(),
+        ];
+        assert_eq!(beancount.lines().collect::<Vec<_>>(), expected);
+        assert!(!beancount.to_lowercase().contains(&account_id.to_string()));
+
+        let ledger = journal(&userdb, &currencies, &events, Format::Ledger);
+        assert!(ledger.starts_with("account Income:TransferCharges:GBP\n"));
+        assert!(ledger.contains("\ncommodity GBP\naccount Assets:Cash:GBP\n"));
+        assert!(ledger.contains("\n1970-01-01 * deposit\n"));
+        assert!(!ledger.contains("balance"));
+    }
+}
+
+mod replication {
+    use iron::method::Method;
+    use iron::middleware::Handler;
+    use iron::prelude::{IronResult, Request, Response};
+    use iron::status;
+
+    use serde_json;
+
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc::Receiver;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{UserDB, write_userdb};
+    use super::currency;
+    use super::events::{self, Event, EventError};
+    use super::review::constant_time_eq;
+
+    /// Where followers connect to stream events, unless overridden by the
+    /// `QUADCURR_REPLICATION_ADDR` environment variable. Only local
+    /// followers can connect unless it's changed.
+    pub static REPLICATION_ADDR: &'static str = "127.0.0.1:3001";
+
+    /// Shortest `QUADCURR_REPLICATION_SECRET` accepted, to stop trivially
+    /// guessable ones
+    pub const MIN_SECRET_LEN: usize = 16;
+
+    // How long a follower waits before reconnecting to the leader
+    const RETRY_SECS: u64 = 1;
+
+    lazy_static! {
+        /// Address of the leader while following, `None` once leading
+        static ref LEADER: Mutex<Option<String>> = Mutex::new(None);
+    }
+
+    /// Whether a follower is connected to the leader and applying its events
+    static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+    /// How many events the leader had when the follower last connected
+    static LEADER_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Serialize)]
+    pub struct Status {
+        /// `leader` or `follower`
+        pub role: &'static str,
+        pub leader: Option<String>,
+        pub connected: bool,
+        /// Whether a follower has every event the leader had when it
+        /// connected
+        pub caught_up: bool,
+        pub events: usize,
+    }
+
+    pub fn is_following() -> bool {
+        LEADER.lock().unwrap().is_some()
+    }
+
+    pub fn is_connected() -> bool {
+        CONNECTED.load(Ordering::SeqCst)
+    }
+
+    /// Whether a follower is connected and has applied every event the
+    /// leader had when it connected
+    pub fn is_caught_up() -> bool {
+        is_connected() && events::num_events() >= LEADER_EVENTS.load(Ordering::SeqCst)
+    }
+
+    pub fn status() -> Status {
+        let leader = LEADER.lock().unwrap().clone();
+        Status {
+            role: if leader.is_some() { "follower" } else { "leader" },
+            leader: leader,
+            connected: is_connected(),
+            caught_up: is_caught_up(),
+            events: events::num_events(),
+        }
+    }
+
+    /// Stop following and accept changes from now on. The caller must hold
+    /// the `USERDB` lock, so no event from the old leader is half applied.
+    /// Returns false if this wasn't following.
+    pub fn promote(_: &mut UserDB) -> bool {
+        CONNECTED.store(false, Ordering::SeqCst);
+        LEADER.lock().unwrap().take().is_some()
+    }
+
+    /// Write `backlog` then every event from `updates` to `out`, one json
+    /// event per line, until the follower goes away or the event log is
+    /// reopened
+    fn send<W: Write>(mut out: W, backlog: Vec<Event>, updates: Receiver<Event>) -> io::Result<()> {
+        for event in backlog.into_iter().chain(updates) {
+            let mut line = serde_json::to_string(&event).unwrap();
+            line.push('\n');
+            out.write_all(line.as_bytes())?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Read what a follower asks for - the shared secret, then how many
+    /// events it already has, each on a line of its own. Returns the number
+    /// of events.
+    fn read_request<R: BufRead>(mut reader: R, secret: &str) -> io::Result<u64> {
+        let mut given = String::new();
+        reader.read_line(&mut given)?;
+        if !constant_time_eq(given.trim_right_matches('\n').as_bytes(), secret.as_bytes()) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "wrong replication secret"))
+        }
+        let mut count = String::new();
+        reader.read_line(&mut count)?;
+        match count.trim().parse() {
+            Ok(from) => Ok(from),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "expected an event count")),
+        }
+    }
+
+    /// Serve one follower, once it's given the shared secret. The reply
+    /// starts with how many events there are here, so the follower knows
+    /// when it's caught up.
+    fn serve(mut stream: TcpStream, secret: &str) -> io::Result<()> {
+        let from = read_request(BufReader::new(stream.try_clone()?), secret)?;
+        match events::subscribe(from) {
+            Some((backlog, updates)) => {
+                stream.write_all(format!("{}\n", from as usize + backlog.len()).as_bytes())?;
+                send(stream, backlog, updates)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "follower is ahead of the event log")),
+        }
+    }
+
+    /// Accept followers with `secret` on `addr`, streaming each the events
+    /// it's missing then every new one as it's recorded
+    pub fn start_listener(addr: &str, secret: String) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let secret = secret.clone();
+                thread::spawn(move || {
+                    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                    if let Err(err) = serve(stream, &secret) {
+                        println!("Stopped replicating to {}: {}", peer, err);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    /// Call `apply` on each event streamed by `reader`, stopping at the
+    /// first it fails on
+    fn receive<R: BufRead, F>(reader: R, mut apply: F) -> Result<(), EventError>
+            where F: FnMut(Event) -> Result<(), EventError> {
+        for (i, line) in reader.lines().enumerate() {
+            match serde_json::from_str(&line?) {
+                Ok(event) => apply(event)?,
+                Err(_) => return Err(EventError::Malformed(i + 1)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Connect to the leader with `secret` and apply its events until the
+    /// connection drops or this is promoted
+    fn follow(leader: &str, secret: &str) -> Result<(), EventError> {
+        let mut stream = TcpStream::connect(leader)?;
+        stream.write_all(format!("{}\n{}\n", secret, events::num_events()).as_bytes())?;
+        let mut reader = BufReader::new(stream);
+        let mut first_line = String::new();
+        reader.read_line(&mut first_line)?;
+        match first_line.trim().parse() {
+            Ok(leader_events) => LEADER_EVENTS.store(leader_events, Ordering::SeqCst),
+            Err(_) => return Err(EventError::Io("expected an event count".to_owned())),
+        }
+        CONNECTED.store(true, Ordering::SeqCst);
+        receive(reader, |event| {
+            let mut userdb = write_userdb();
+            if !is_following() {
+                return Err(EventError::Io("promoted".to_owned()))
+            }
+            currency::update_currencies(|currencies| events::follow(&mut userdb, currencies, event))
+        })
+    }
+
+    /// Follow the leader whose replication listener is at `leader`, which
+    /// was started with `secret`, reconnecting whenever the connection
+    /// drops, until promoted
+    pub fn start_follower(leader: String, secret: String) {
+        *LEADER.lock().unwrap() = Some(leader.clone());
+        thread::spawn(move || {
+            while is_following() {
+                let result = follow(&leader, &secret);
+                CONNECTED.store(false, Ordering::SeqCst);
+                match result {
+                    Err(ref err) if !is_following() => println!("Stopped following {}: {:?}", leader, err),
+                    Err(err) => println!("Lost leader {}: {:?}", leader, err),
+                    Ok(()) => println!("Leader {} closed the connection", leader),
+                }
+                thread::sleep(Duration::from_secs(RETRY_SECS));
+            }
+        });
+    }
+
+    /// Why changes are turned away while following
+    pub static READ_ONLY: &'static str = "read-only follower, send changes to the leader";
+
+    /// Handler wrapper which turns away everything but reads while
+    /// following, as only the leader can change anything. Promotion, which
+    /// needs an admin, is the exception.
+    pub struct ReadOnly<H>(pub H);
+    impl<H: Handler> Handler for ReadOnly<H> {
+        fn handle(&self, req: &mut Request) -> IronResult<Response> {
+            if req.method != Method::Get && req.url.path() != ["replication", "promote"] && is_following() {
+                return Ok(Response::with((status::ServiceUnavailable, READ_ONLY)))
+            }
+            self.0.handle(req)
+        }
+    }
+
+    #[test]
+    fn test_replication() {
+        use std::sync::mpsc;
+        use super::{EntryKind, Posting};
+        use super::currency::CurrencyId;
+        use super::events::Change;
+
+        let changes = vec![
+            Change::OpenAccount { account: 0, account_id: None, currency: CurrencyId(1), holder_name: None, tenant: None },
+            Change::OpenAccount { account: 1, account_id: None, currency: CurrencyId(1), holder_name: None, tenant: None },
+            Change::Postings(vec![Posting { account: 1, amount: 100, kind: EntryKind::Deposit, counterparty: None }]),
+            Change::Postings(vec![Posting::new(1, -30, EntryKind::TransferOut, 0), Posting::new(0, 30, EntryKind::TransferIn, 1)]),
+        ];
+        let events: Vec<Event> = changes.into_iter().enumerate()
+            .map(|(seq, change)| Event { seq: seq as u64, time: 100, change: change })
+            .collect();
+
+        // The leader has the first three events and records the last while
+        // the follower, which already has the first, is connected
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, updates) = mpsc::channel();
+        let backlog = events[..3].to_vec();
+        let leader = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut first_line = String::new();
+            BufReader::new(stream.try_clone().unwrap()).read_line(&mut first_line).unwrap();
+            let from: usize = first_line.trim().parse().unwrap();
+            send(stream, backlog[from..].to_vec(), updates).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"1\n").unwrap();
+        sender.send(events[3].clone()).unwrap();
+        drop(sender);
+        let mut received = vec![];
+        receive(BufReader::new(stream), |event| {
+            received.push(event);
+            Ok(())
+        }).unwrap();
+        leader.join().unwrap();
+        assert_eq!(received, events[1..].to_vec());
+        let log: Vec<String> = events[..1].iter().chain(&received)
+            .map(|event| serde_json::to_string(event).unwrap())
+            .collect();
+        let (replica, _, _) = events::read(io::Cursor::new(log.join("\n"))).unwrap();
+        assert_eq!(replica.balances(), vec![30, 70]);
+
+        // A stream which breaks off mid-event stops at the bad line
+        let bad = format!("{}\n{{\"seq\":", serde_json::to_string(&events[0]).unwrap());
+        assert_eq!(receive(io::Cursor::new(bad), |_| Ok(())), Err(EventError::Malformed(2)));
+
+        // Followers get nothing without the secret
+        let secret = "0123456789abcdef";
+        assert_eq!(read_request(io::Cursor::new(format!("{}\n12\n", secret)), secret).unwrap(), 12);
+        assert_eq!(read_request(io::Cursor::new("0123456789abcdeF\n12\n"), secret).unwrap_err().kind(),
+                   io::ErrorKind::PermissionDenied);
+        assert_eq!(read_request(io::Cursor::new("0\n"), secret).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(read_request(io::Cursor::new(format!("{}\n", secret)), secret).is_err());
+    }
+}
+
+mod async_server {
+    use futures::{Future, Stream, future};
+    use futures_cpupool::CpuPool;
+
+    use hyper;
+    use hyper::buffer::BufReader;
+    use hyper::net::NetworkStream;
+    use hyper_async::{self, Chunk, Headers, Method, StatusCode};
+    use hyper_async::header::{Authorization, Bearer, ContentLength, Host};
+    use hyper_async::server::{Http, Request, Response, Service};
+
+    use iron::{self, Handler};
+    use iron::prelude::IronResult;
+
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::{Core, Handle, Timeout};
+
+    use std::io::{self, Cursor, Read, Write};
+    use std::net::SocketAddr;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::audit;
+    use super::fraud::fraud_checker;
+    use super::metrics;
+    use super::replication;
+    use super::review;
+    use super::routes;
+    use super::tenants;
+    use super::webhook;
+
+    // Threads running handlers which block, i.e. every route but transfers
+    const POOL_THREADS: usize = 32;
+
+    /// A response made on the handler pool, ready to send back
+    struct Reply {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+    impl Reply {
+        /// The response iron would send for `result`
+        fn from_iron(result: IronResult<iron::Response>) -> Reply {
+            let mut resp = result.unwrap_or_else(|err| err.response);
+            let mut headers: Vec<(String, String)> = resp.headers.iter()
+                .filter(|header| !header.is::<hyper::header::ContentLength>())
+                .map(|header| (header.name().to_owned(), header.value_string()))
+                .collect();
+            let mut body = vec![];
+            if let Some(ref mut resp_body) = resp.body {
+                resp_body.write_body(&mut body).unwrap();
+                if !resp.headers.has::<hyper::header::ContentType>() {
+                    headers.push(("Content-Type".to_owned(), "text/plain".to_owned()));
+                }
+            }
+            Reply {
+                // Default to a 404 if no response code was set, like iron
+                status: resp.status.map_or(404, |status| status.to_u16()),
+                headers: headers,
+                body: body,
+            }
+        }
+        /// The response to a handler which panicked
+        fn panicked() -> Reply {
+            Reply { status: 500, headers: vec![], body: vec![] }
+        }
+        fn into_response(self) -> Response {
+            let mut headers = Headers::new();
+            for (name, value) in self.headers {
+                headers.append_raw(name, value);
+            }
+            headers.set(ContentLength(self.body.len() as u64));
+            Response::new()
+                .with_status(StatusCode::try_from(self.status).unwrap_or(StatusCode::InternalServerError))
+                .with_headers(headers)
+                .with_body(self.body)
+        }
+    }
+
+    /// A request already read off the wire, for hyper 0.10 to parse as if it
+    /// had come in over a connection
+    struct ReadRequest {
+        data: Cursor<Vec<u8>>,
+        peer: SocketAddr,
+    }
+    impl Read for ReadRequest {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.data.read(buf)
+        }
+    }
+    impl Write for ReadRequest {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl NetworkStream for ReadRequest {
+        fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+            Ok(self.peer)
+        }
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+        fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Write out a request as HTTP/1.1, with its body already read
+    fn http1(method: &Method, uri: &hyper_async::Uri, headers: &Headers, body: &[u8], local: SocketAddr) -> Vec<u8> {
+        let mut raw = format!("{} {} HTTP/1.1\r\n", method, uri).into_bytes();
+        for header in headers.iter() {
+            if header.is::<ContentLength>() || header.name().eq_ignore_ascii_case("transfer-encoding") {
+                continue
+            }
+            write!(raw, "{}: {}\r\n", header.name(), header.value_string()).unwrap();
+        }
+        if !headers.has::<Host>() {
+            write!(raw, "Host: {}\r\n", local).unwrap();
+        }
+        write!(raw, "Content-Length: {}\r\n\r\n", body.len()).unwrap();
+        raw.extend_from_slice(body);
+        raw
+    }
+
+    /// Run `handler` on a request written out by `http1`, as iron would
+    fn handle_iron(handler: &Handler, raw: Vec<u8>, remote: SocketAddr, local: SocketAddr) -> Reply {
+        let mut stream = ReadRequest { data: Cursor::new(raw), peer: remote };
+        let mut reader = BufReader::new(&mut stream as &mut NetworkStream);
+        let result = hyper::server::Request::new(&mut reader, remote)
+            .map_err(|err| err.to_string())
+            .and_then(|http_req| iron::Request::from_http(http_req, local, &iron::Protocol::http()));
+        match result {
+            Ok(mut req) => Reply::from_iron(handler.handle(&mut req)),
+            Err(_) => Reply { status: 400, headers: vec![], body: vec![] },
+        }
+    }
+
+    /// Serves every route from `handler` on a pool of threads, apart from
+    /// transfers, which are checked on the pool but wait for the rate limit
+    /// and fraud checker on the event loop
+    struct QuadCurr {
+        handler: Arc<Handler>,
+        pool: CpuPool,
+        handle: Handle,
+        local: SocketAddr,
+        remote: SocketAddr,
+    }
+    impl QuadCurr {
+        /// Run `f` on the handler pool, answering with a 500 if it panics
+        fn blocking<F>(pool: &CpuPool, f: F) -> Box<Future<Item = Reply, Error = ()>>
+                where F: FnOnce() -> Reply + Send + 'static {
+            Box::new(pool.spawn_fn(move || Ok(panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| Reply::panicked()))))
+        }
+
+        /// `routes::transfer_handler`, wrapped like the other routes, but
+        /// waiting without holding up a thread
+        fn transfer(&self, caller: String, tenant: Option<String>, body: Vec<u8>) -> Box<Future<Item = Reply, Error = ()>> {
+            if replication::is_following() {
+                return Box::new(future::ok(Reply::from_iron(Ok(iron::Response::with(
+                    (iron::status::ServiceUnavailable, replication::READ_ONLY))))))
+            }
+            if !audit::writable() {
+                return Box::new(future::ok(Reply::from_iron(Ok(iron::Response::with(
+                    (iron::status::ServiceUnavailable, audit::UNAVAILABLE))))))
+            }
+            // Every outcome is audited, including panics, as `audit::Audited`
+            // would
+            let audited = |caller: &str, body: &[u8], result: thread::Result<IronResult<iron::Response>>| {
+                match result {
+                    Ok(result) => {
+                        audit::record_call(caller, "transfer", body, &audit::outcome(&result));
+                        Reply::from_iron(result)
+                    },
+                    Err(_) => {
+                        audit::record_call(caller, "transfer", body, audit::PANICKED);
+                        Reply::panicked()
+                    },
+                }
+            };
+            let (pool, handle) = (self.pool.clone(), self.handle.clone());
+            let begun = pool.spawn_fn(move || {
+                Ok(match panic::catch_unwind(AssertUnwindSafe(|| routes::begin_transfer(&tenant, &body))) {
+                    Ok(Ok(pending)) => Ok((pending, caller, body)),
+                    Ok(Err(result)) => Err(audited(&caller, &body, Ok(result))),
+                    Err(payload) => Err(audited(&caller, &body, Err(payload))),
+                })
+            });
+            Box::new(begun.and_then(move |begun| -> Box<Future<Item = Reply, Error = ()>> {
+                let ((pending, accts), caller, body) = match begun {
+                    Ok(begun) => begun,
+                    Err(reply) => return Box::new(future::ok(reply)),
+                };
+                // Do the rate limit in parallel with fraud checking, as
+                // `routes::make_transfer` does
+                let wait = Timeout::new(pending.wait, &handle).unwrap().map_err(|_| ());
+                let checker = fraud_checker();
+                let checks = future::join_all(accts.into_iter().map(|acct| checker.check_async(acct)).collect::<Vec<_>>())
+                    .map_err(|_| ());
+                Box::new(wait.join(checks).and_then(move |((), checked)| {
+                    let fraud_err = checked.into_iter().filter_map(|(_, err)| err).last();
+                    QuadCurr::blocking(&pool, move || {
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| routes::finish_transfer(pending, fraud_err)));
+                        webhook::flush_or_report();
+                        audited(&caller, &body, result)
+                    })
+                }))
+            }))
+        }
+    }
+    impl Service for QuadCurr {
+        type Request = Request;
+        type Response = Response;
+        type Error = hyper_async::Error;
+        type Future = Box<Future<Item = Response, Error = hyper_async::Error>>;
+
+        fn call(&self, req: Request) -> Self::Future {
+            let start = Instant::now();
+            let (method, uri, _, headers, body) = req.deconstruct();
+            let (handler, pool, local, remote) = (self.handler.clone(), self.pool.clone(), self.local, self.remote);
+            let transfer = method == Method::Post && uri.path() == "/transfer";
+            let reviewer = headers.get::<Authorization<Bearer>>().map(|auth| auth.token.clone());
+            let tenant_token = tenants::token(headers.get_raw(tenants::TOKEN_HEADER).and_then(|raw| raw.one()));
+            let this = QuadCurr {
+                handler: handler.clone(),
+                pool: pool.clone(),
+                handle: self.handle.clone(),
+                local: local,
+                remote: remote,
+            };
+            Box::new(body.concat2().and_then(move |body| {
+                let reply: Box<Future<Item = Reply, Error = ()>> = if transfer {
+                    // Tokens are checked as `tenants::Tenanted` would
+                    match tenants::identify(tenant_token, "/transfer") {
+                        Err(msg) => Box::new(future::ok(Reply::from_iron(Ok(iron::Response::with(
+                            (iron::status::Unauthorized, msg)))))),
+                        Ok(tenant) => {
+                            let reviewer = reviewer.and_then(|token| review::authenticate(&token));
+                            this.transfer(audit::caller(reviewer, tenant.clone(), remote.ip()), tenant, body.to_vec())
+                        },
+                    }
+                } else {
+                    let raw = http1(&method, &uri, &headers, &body, local);
+                    QuadCurr::blocking(&pool, move || handle_iron(&*handler, raw, remote, local))
+                };
+                reply.then(move |reply| {
+                    let reply = reply.unwrap_or_else(|()| Reply::panicked());
+                    if transfer {
+                        metrics::observe_request("/transfer", Some(reply.status), start.elapsed());
+                    }
+                    Ok(reply.into_response())
+                })
+            }))
+        }
+    }
+
+    /// Serve `handler` on `addr` until the process exits
+    pub fn serve<H: Handler>(addr: &str, handler: H) -> io::Result<()> {
+        let addr: SocketAddr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid address")),
+        };
+        let mut core = Core::new()?;
+        let handle = core.handle();
+        let listener = TcpListener::bind(&addr, &handle)?;
+        let (handler, pool): (Arc<Handler>, _) = (Arc::new(handler), CpuPool::new(POOL_THREADS));
+        let http = Http::<Chunk>::new();
+        let connections = listener.incoming().for_each(move |(socket, remote)| {
+            let service = QuadCurr {
+                handler: handler.clone(),
+                pool: pool.clone(),
+                handle: handle.clone(),
+                local: addr,
+                remote: remote,
+            };
+            handle.spawn(http.serve_connection(socket, service).map(|_| ())
+                .map_err(|err| println!("Connection failed: {}", err)));
+            Ok(())
+        });
+        core.run(connections)
+    }
+
+    #[test]
+    fn test_handle_iron() {
+        use iron::prelude::{Request as IronRequest, Response as IronResponse};
+        use iron::status;
+        use hyper_async::header::ContentType;
+
+        let echo = |req: &mut IronRequest| {
+            let mut body = String::new();
+            req.body.read_to_string(&mut body).unwrap();
+            Ok(IronResponse::with((status::Ok, format!("{} {} {}", req.method, req.url.path().join("/"), body))))
+        };
+        let local: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let remote: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+
+        // The body is passed on however it came in, without a Host header
+        let mut headers = Headers::new();
+        headers.set_raw("Transfer-Encoding", "chunked");
+        let uri = "/a/b?c=d".parse().unwrap();
+        let raw = http1(&Method::Post, &uri, &headers, b"{}", local);
+        let reply = handle_iron(&echo, raw, remote, local);
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body, b"POST a/b {}".to_vec());
+        assert_eq!(reply.headers, vec![("Content-Type".to_owned(), "text/plain".to_owned())]);
+        let resp = reply.into_response();
+        assert_eq!(resp.headers().get::<ContentLength>(), Some(&ContentLength(11)));
+        assert_eq!(resp.headers().get::<ContentType>(), Some(&ContentType("text/plain".parse().unwrap())));
+
+        // No status or body is an empty 404, as from iron
+        let raw = http1(&Method::Get, &"/".parse().unwrap(), &Headers::new(), b"", local);
+        let reply = handle_iron(&|_: &mut IronRequest| Ok(IronResponse::new()), raw, remote, local);
+        assert_eq!((reply.status, reply.headers, reply.body), (404, vec![], vec![]));
+    }
+}
+
+mod webhook {
+    use chrono::UTC;
+
+    use hmac::{Hmac, Mac};
+
+    use hyper::Client;
+    use hyper::Url;
+    use hyper::header::{Connection, ContentType, Headers};
+
+    use iron::Handler;
+    use iron::prelude::{IronResult, Request, Response};
+
+    use rand::Rng;
+    use rand::os::OsRng;
+
+    use serde_json;
+
+    use sha2::Sha256;
+
+    use std::cmp;
+    use std::fs::{self, File};
+    use std::io::{self, Read, Write};
+    use std::mem;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use super::{AccountId, Entry, EntryKind};
+
+    /// Registered webhooks and undelivered events, rewritten after every
+    /// change so nothing queued is lost on restart
+    pub static WEBHOOK_STORE: &'static str = "webhooks.json";
+
+    /// Header holding `sha256=<hex hmac-sha256 of the body>`, keyed with the
+    /// webhook's secret
+    pub static SIGNATURE_HEADER: &'static str = "X-QuadCurr-Signature";
+
+    /// Failed deliveries are retried after 1s, 2s, 4s... up to
+    /// `MAX_BACKOFF_MS`, then dead-lettered after `MAX_ATTEMPTS` tries
+    const BASE_BACKOFF_MS: i64 = 1000;
+    const MAX_BACKOFF_MS: i64 = 60 * 60 * 1000;
+    const MAX_ATTEMPTS: u32 = 12;
+
+    // How long to wait for a receiver, and how often to look for due events
+    const TIMEOUT_SECS: u64 = 10;
+    const POLL_MS: u64 = 200;
+
+    lazy_static! {
+        /// Global webhook store, opened at startup. Events aren't queued
+        /// while this is `None`.
+        static ref WEBHOOKS: Mutex<Option<WebhookStore>> = Mutex::new(None);
+
+        /// Events held by `notify` for `flush` to queue. Only ever locked
+        /// after `WEBHOOKS`, if that's locked at all.
+        static ref PENDING: Mutex<Vec<AccountEvent>> = Mutex::new(vec![]);
+    }
+
+    /// Whether the store has been opened, so `notify` has to hold events
+    static ENABLED: AtomicBool = AtomicBool::new(false);
+
+    #[derive(Debug, PartialEq)]
+    pub struct WebhookError(pub &'static str);
+
+    /// A balance change on an api account, see `UserDB::apply_postings`
+    pub struct AccountEvent {
+        pub account_id: AccountId,
+        pub counterparty: Option<AccountId>,
+        pub entry: Entry,
+    }
+
+    /// Json body of an event, as sent to receivers
+    #[derive(Serialize)]
+    struct EventBody {
+        /// Unique per event, and the same on every retry, so receivers can
+        /// ignore duplicates
+        event_id: u64,
+        /// `deposit`, `transfer_in`, `transfer_out`, `charge`, `interest`,
+        /// `escrow`, `escrow_refund`, `dispute`, `dispute_reversal` or
+        /// `chargeback_fee`
+        event: &'static str,
+        account_id: String,
+        /// Change to the balance, in base units of the account currency
+        amount: i64,
+        /// Balance after the change
+        balance: i64,
+        counterparty: Option<String>,
+        /// Seconds since the unix epoch
+        time: i64,
+    }
+
+    /// Name of the event sent for an entry, if receivers are told about
+    /// entries of this kind
+    fn event_name(kind: EntryKind) -> Option<&'static str> {
+        match kind {
+            EntryKind::Deposit => Some("deposit"),
+            EntryKind::TransferIn => Some("transfer_in"),
+            EntryKind::TransferOut => Some("transfer_out"),
+            EntryKind::Charge => Some("charge"),
+            EntryKind::Interest => Some("interest"),
+            EntryKind::Escrow => Some("escrow"),
+            EntryKind::EscrowRefund => Some("escrow_refund"),
+            EntryKind::Dispute => Some("dispute"),
+            EntryKind::DisputeReversal => Some("dispute_reversal"),
+            EntryKind::ChargebackFee => Some("chargeback_fee"),
+            EntryKind::Hold | EntryKind::Release => None,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Webhook {
+        pub id: String,
+        /// Events for this account are sent to `url`
+        pub account_id: String,
+        pub url: String,
+        /// Hex key for the hmac in `SIGNATURE_HEADER`
+        pub secret: String,
+    }
+
+    /// An event waiting to be sent to a webhook, or given up on
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Delivery {
+        /// Also the `event_id` in the body
+        pub id: u64,
+        pub webhook_id: String,
+        pub body: String,
+        /// Failed attempts so far
+        pub attempts: u32,
+        /// Milliseconds since the unix epoch
+        pub next_attempt_ms: i64,
+        pub last_error: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Default, Clone)]
+    struct WebhookStore {
+        /// Where to save the store, if anywhere
+        #[serde(skip_serializing, skip_deserializing)]
+        path: Option<PathBuf>,
+        /// Whether the last save failed, so the next `flush` has to save
+        /// even with nothing new to queue
+        #[serde(skip_serializing, skip_deserializing)]
+        unsaved: bool,
+        next_id: u64,
+        webhooks: Vec<Webhook>,
+        queue: Vec<Delivery>,
+        dead_letters: Vec<Delivery>,
+    }
+    impl WebhookStore {
+        fn register(&mut self, account_id: &AccountId, url: &str) -> Result<Webhook, WebhookError> {
+            match Url::parse(url) {
+                Ok(ref parsed) if parsed.scheme() == "http" && parsed.host().is_some() => (),
+                _ => return Err(WebhookError("url must be an absolute http url")),
+            }
+            let secret: [u8; 32] = OsRng::new().unwrap().gen();
+            let webhook = Webhook {
+                id: Uuid::new_v4().hyphenated().to_string(),
+                account_id: account_id.to_string(),
+                url: url.to_owned(),
+                secret: secret.iter().map(|b| format!("{:02x}", b)).collect(),
+            };
+            self.webhooks.push(webhook.clone());
+            Ok(webhook)
+        }
+        /// Remove a webhook, dropping any events still queued for it
+        fn unregister(&mut self, webhook_id: &str) -> Result<(), WebhookError> {
+            let before = self.webhooks.len();
+            self.webhooks.retain(|webhook| webhook.id != webhook_id);
+            if self.webhooks.len() == before {
+                return Err(WebhookError("webhook does not exist"))
+            }
+            self.queue.retain(|delivery| delivery.webhook_id != webhook_id);
+            Ok(())
+        }
+        /// Queue the event for `event` with every webhook for its account.
+        /// Returns whether anything was queued.
+        fn enqueue(&mut self, event: &AccountEvent, now_ms: i64) -> bool {
+            let name = match event_name(event.entry.kind) {
+                Some(name) => name,
+                None => return false,
+            };
+            let account_id = event.account_id.to_string();
+            let mut queued = false;
+            for webhook in self.webhooks.iter().filter(|webhook| webhook.account_id == account_id) {
+                let body = EventBody {
+                    event_id: self.next_id,
+                    event: name,
+                    account_id: account_id.clone(),
+                    amount: event.entry.amount,
+                    balance: event.entry.balance,
+                    counterparty: event.counterparty.map(|id| id.masked()),
+                    time: event.entry.time,
+                };
+                self.queue.push(Delivery {
+                    id: self.next_id,
+                    webhook_id: webhook.id.clone(),
+                    body: serde_json::to_string(&body).unwrap(),
+                    attempts: 0,
+                    next_attempt_ms: now_ms,
+                    last_error: None,
+                });
+                self.next_id += 1;
+                queued = true;
+            }
+            queued
+        }
+        /// Deliveries due to be attempted, with the webhook to send each to
+        fn due(&self, now_ms: i64) -> Vec<(Delivery, Webhook)> {
+            self.queue.iter()
+                .filter(|delivery| delivery.next_attempt_ms <= now_ms)
+                .filter_map(|delivery| {
+                    self.webhooks.iter().find(|webhook| webhook.id == delivery.webhook_id)
+                        .map(|webhook| (delivery.clone(), webhook.clone()))
+                })
+                .collect()
+        }
+        /// Remove a delivery once it succeeds, otherwise back off or move it
+        /// to the dead letters
+        fn record_attempt(&mut self, delivery_id: u64, result: Result<(), String>, now_ms: i64) {
+            // May have gone if the webhook was removed mid-delivery
+            let pos = match self.queue.iter().position(|delivery| delivery.id == delivery_id) {
+                Some(pos) => pos,
+                None => return,
+            };
+            let err = match result {
+                Ok(()) => {
+                    self.queue.remove(pos);
+                    return
+                },
+                Err(err) => err,
+            };
+            let attempts = self.queue[pos].attempts + 1;
+            if attempts >= MAX_ATTEMPTS {
+                let mut delivery = self.queue.remove(pos);
+                delivery.attempts = attempts;
+                delivery.last_error = Some(err);
+                self.dead_letters.push(delivery);
+            } else {
+                let delivery = &mut self.queue[pos];
+                delivery.attempts = attempts;
+                delivery.next_attempt_ms = now_ms + backoff_ms(attempts);
+                delivery.last_error = Some(err);
+            }
+        }
+        /// Move a dead letter back to the queue, to be tried again now
+        fn retry_dead_letter(&mut self, delivery_id: u64, now_ms: i64) -> Result<(), WebhookError> {
+            let pos = match self.dead_letters.iter().position(|delivery| delivery.id == delivery_id) {
+                Some(pos) => pos,
+                None => return Err(WebhookError("dead letter does not exist")),
+            };
+            if !self.webhooks.iter().any(|webhook| webhook.id == self.dead_letters[pos].webhook_id) {
+                return Err(WebhookError("webhook no longer exists"))
+            }
+            let mut delivery = self.dead_letters.remove(pos);
+            delivery.attempts = 0;
+            delivery.next_attempt_ms = now_ms;
+            self.queue.push(delivery);
+            Ok(())
+        }
+        /// Write the store out if it has a path. Written to a temporary file
+        /// first so a crash can't leave it half written.
+        fn save(&self) -> io::Result<()> {
+            let path = match self.path {
+                Some(ref path) => path,
+                None => return Ok(()),
+            };
+            let tmp_path = path.with_extension("tmp");
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(serde_json::to_string(self).unwrap().as_bytes())?;
+            file.sync_data()?;
+            fs::rename(&tmp_path, path)
+        }
+        /// `save`, trying again on the next `flush` if it fails. Anything
+        /// changed stays changed in memory either way.
+        fn persist(&mut self) -> Result<(), WebhookError> {
+            self.unsaved = self.save().is_err();
+            if self.unsaved {
+                return Err(WebhookError("could not write webhook store"))
+            }
+            Ok(())
+        }
+    }
+
+    /// Delay before the next try after `attempts` failures
+    fn backoff_ms(attempts: u32) -> i64 {
+        let shift = cmp::min(attempts - 1, 32);
+        cmp::min(BASE_BACKOFF_MS << shift, MAX_BACKOFF_MS)
+    }
+
+    fn now_ms() -> i64 {
+        let now = UTC::now();
+        now.timestamp() * 1000 + now.timestamp_subsec_millis() as i64
+    }
+
+    /// Hex hmac-sha256 of `body`, keyed with `secret`
+    pub fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+        mac.input(body);
+        format!("{:x}", mac.result().code())
+    }
+
+    /// Post `body` to the webhook. Any 2xx response counts as delivered.
+    fn deliver(client: &Client, webhook: &Webhook, body: &str) -> Result<(), String> {
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set(Connection::close());
+        let signature = format!("sha256={}", sign(&webhook.secret, body.as_bytes()));
+        headers.set_raw(SIGNATURE_HEADER, vec![signature.into_bytes()]);
+        match client.post(&webhook.url[..]).headers(headers).body(body).send() {
+            Ok(ref resp) if resp.status.is_success() => Ok(()),
+            Ok(resp) => Err(format!("receiver responded {}", resp.status)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// Make one attempt at every due delivery. The store is only locked
+    /// between requests, never while waiting on a receiver.
+    fn deliver_due(store: &Mutex<Option<WebhookStore>>, client: &Client, now_ms: i64) {
+        let due = match *store.lock().unwrap() {
+            Some(ref store) => store.due(now_ms),
+            None => return,
+        };
+        if due.is_empty() {
+            return
+        }
+        let results: Vec<_> = due.iter()
+            .map(|&(ref delivery, ref webhook)| (delivery.id, deliver(client, webhook, &delivery.body)))
+            .collect();
+        let mut store = store.lock().unwrap();
+        let store = store.as_mut().unwrap();
+        for (delivery_id, result) in results {
+            store.record_attempt(delivery_id, result, now_ms);
+        }
+        if let Err(WebhookError(msg)) = store.persist() {
+            println!("Failed to record webhook deliveries: {}", msg);
+        }
+    }
+
+    /// Open the webhook store at `path`, creating it if needed, so events
+    /// start being queued. Returns the number of events already queued.
+    pub fn open(path: &str) -> Result<usize, WebhookError> {
+        let mut store = if Path::new(path).is_file() {
+            let mut contents = String::new();
+            File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+                .map_err(|_| WebhookError("could not read webhook store"))?;
+            serde_json::from_str(&contents).map_err(|_| WebhookError("invalid webhook store"))?
+        } else {
+            WebhookStore::default()
+        };
+        store.path = Some(PathBuf::from(path));
+        store.save().map_err(|_| WebhookError("could not write webhook store"))?;
+        let num_queued = store.queue.len();
+        *WEBHOOKS.lock().unwrap() = Some(store);
+        ENABLED.store(true, Ordering::SeqCst);
+        Ok(num_queued)
+    }
+
+    /// Start the background thread which delivers queued events
+    pub fn start_worker() {
+        thread::spawn(|| {
+            let mut client = Client::new();
+            client.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+            client.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+            loop {
+                // Picks up events from changes made in the background too
+                flush_or_report();
+                deliver_due(&WEBHOOKS, &client, now_ms());
+                thread::sleep(Duration::from_millis(POLL_MS));
+            }
+        });
+    }
+
+    /// Hold on to events for `flush` to queue for any webhooks registered
+    /// for their accounts. Balances change with accounts locked, so this
+    /// never waits on the store or writes anything.
+    pub fn notify(events: Vec<AccountEvent>) {
+        if ENABLED.load(Ordering::SeqCst) && !events.is_empty() {
+            PENDING.lock().unwrap().extend(events);
+        }
+    }
+
+    /// Queue the events held by `notify` and save the store. If saving
+    /// fails the events stay queued, and the next flush saves them.
+    pub fn flush() -> Result<(), WebhookError> {
+        let mut webhooks = WEBHOOKS.lock().unwrap();
+        let events = mem::replace(&mut *PENDING.lock().unwrap(), vec![]);
+        let store = match webhooks.as_mut() {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        let now_ms = now_ms();
+        let mut queued = false;
+        for event in &events {
+            queued |= store.enqueue(event, now_ms);
+        }
+        if queued || store.unsaved {
+            store.persist()?;
+        }
+        Ok(())
+    }
+
+    /// `flush`, for callers which can only say it failed
+    pub fn flush_or_report() {
+        if let Err(WebhookError(msg)) = flush() {
+            println!("Failed to queue webhook events: {}", msg);
+        }
+    }
+
+    /// Handler wrapper which queues the webhook events for each request
+    /// once it's done, and its locks are released
+    pub struct Notifying<H>(pub H);
+    impl<H: Handler> Handler for Notifying<H> {
+        fn handle(&self, req: &mut Request) -> IronResult<Response> {
+            let result = self.0.handle(req);
+            flush_or_report();
+            result
+        }
+    }
+
+    /// Run `f` on the global store and save it afterwards. Nothing is
+    /// changed if it can't be saved.
+    fn with_store<T, F>(f: F) -> Result<T, WebhookError>
+            where F: FnOnce(&mut WebhookStore) -> Result<T, WebhookError> {
+        let mut webhooks = WEBHOOKS.lock().unwrap();
+        let store = match webhooks.as_mut() {
+            Some(store) => store,
+            None => return Err(WebhookError("webhooks are not enabled")),
+        };
+        let before = store.clone();
+        let result = f(store)?;
+        if store.save().is_err() {
+            *store = before;
+            return Err(WebhookError("could not write webhook store"))
+        }
+        store.unsaved = false;
+        Ok(result)
+    }
+
+    /// Register `url` to receive events for an account. The returned webhook
+    /// holds the secret receivers check signatures with.
+    pub fn register(account_id: &AccountId, url: &str) -> Result<Webhook, WebhookError> {
+        with_store(|store| store.register(account_id, url))
+    }
+
+    pub fn unregister(webhook_id: &str) -> Result<(), WebhookError> {
+        with_store(|store| store.unregister(webhook_id))
+    }
+
+    /// Public id of the account webhook `webhook_id` is for
+    pub fn account_of(webhook_id: &str) -> Option<String> {
+        WEBHOOKS.lock().unwrap().as_ref()
+            .and_then(|store| store.webhooks.iter().find(|webhook| webhook.id == webhook_id))
+            .map(|webhook| webhook.account_id.clone())
+    }
+
+    /// Deliveries which failed `MAX_ATTEMPTS` times
+    pub fn dead_letters() -> Vec<Delivery> {
+        WEBHOOKS.lock().unwrap().as_ref().map_or(vec![], |store| store.dead_letters.clone())
+    }
+
+    pub fn retry_dead_letter(delivery_id: u64) -> Result<(), WebhookError> {
+        with_store(|store| store.retry_dead_letter(delivery_id, now_ms()))
+    }
+
+    #[test]
+    fn test_delivery() {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+        use std::sync::mpsc;
+
+        // Receiver answering with each status in turn, and passing back the
+        // signature and body of each request
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(vec![200, 500, 200]) {
+                let mut stream = BufReader::new(stream.unwrap());
+                let (mut signature, mut content_length) = (String::new(), 0);
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).unwrap();
+                    let line = line.trim_right().to_lowercase();
+                    if line.is_empty() {
+                        break
+                    } else if line.starts_with("x-quadcurr-signature:") {
+                        signature = line["x-quadcurr-signature:".len()..].trim().to_owned();
+                    } else if line.starts_with("content-length:") {
+                        content_length = line["content-length:".len()..].trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                stream.read_exact(&mut body).unwrap();
+                write!(stream.get_mut(), "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+                tx.send((signature, String::from_utf8(body).unwrap())).unwrap();
+            }
+        });
+
+        let account_id = AccountId::new();
+        let mut store = WebhookStore::default();
+        assert_eq!(store.register(&account_id, "ftp://127.0.0.1/"), Err(WebhookError("url must be an absolute http url")));
+        let webhook = store.register(&account_id, &url).unwrap();
+        let other = store.register(&AccountId::new(), &url).unwrap();
+        let event = |kind, amount, balance| AccountEvent {
+            account_id: account_id,
+            counterparty: None,
+            entry: Entry { time: 1000, kind: kind, amount: amount, counterparty: None, balance: balance },
+        };
+        assert!(!store.enqueue(&event(EntryKind::Hold, -5, 0), 0));
+        assert!(store.enqueue(&event(EntryKind::Deposit, 5, 5), 0));
+        assert_eq!(store.queue.len(), 1);
+        assert_eq!(store.queue[0].webhook_id, webhook.id);
+        let store = Mutex::new(Some(store));
+        let client = Client::new();
+
+        // Delivered first time, signed with the webhook secret
+        deliver_due(&store, &client, 0);
+        let (signature, body) = rx.recv().unwrap();
+        assert_eq!(signature, format!("sha256={}", sign(&webhook.secret, body.as_bytes())));
+        assert_eq!(body, format!(r#"{{"event_id":0,"event":"deposit","account_id":"{}","amount":5,"balance":5,"counterparty":null,"time":1000}}"#, account_id));
+        assert!(store.lock().unwrap().as_ref().unwrap().queue.is_empty());
+
+        // Failure backs off, then succeeds once due again
+        store.lock().unwrap().as_mut().unwrap().enqueue(&event(EntryKind::TransferOut, -5, 0), 0);
+        deliver_due(&store, &client, 0);
+        rx.recv().unwrap();
+        {
+            let store = store.lock().unwrap();
+            let delivery = &store.as_ref().unwrap().queue[0];
+            assert_eq!((delivery.attempts, delivery.next_attempt_ms), (1, BASE_BACKOFF_MS));
+            assert_eq!(delivery.last_error, Some("receiver responded 500 Internal Server Error".to_owned()));
+        }
+        deliver_due(&store, &client, BASE_BACKOFF_MS - 1);
+        assert!(rx.try_recv().is_err());
+        deliver_due(&store, &client, BASE_BACKOFF_MS);
+        assert!(rx.recv().unwrap().1.contains(r#""event":"transfer_out""#));
+        assert!(store.lock().unwrap().as_ref().unwrap().queue.is_empty());
+
+        // Dead-lettered after too many failures
+        let mut store = store.into_inner().unwrap().unwrap();
+        store.enqueue(&event(EntryKind::Charge, -1, -1), 0);
+        for attempt in 0..MAX_ATTEMPTS {
+            let id = store.queue[0].id;
+            store.record_attempt(id, Err("refused".to_owned()), attempt as i64);
+        }
+        assert!(store.queue.is_empty());
+        assert_eq!(store.dead_letters.len(), 1);
+        assert_eq!(store.dead_letters[0].attempts, MAX_ATTEMPTS);
+        let id = store.dead_letters[0].id;
+        assert_eq!(store.retry_dead_letter(id + 1, 0), Err(WebhookError("dead letter does not exist")));
+        store.retry_dead_letter(id, 0).unwrap();
+        assert_eq!(store.queue.len(), 1);
+        store.unregister(&webhook.id).unwrap();
+        assert!(store.queue.is_empty());
+        assert_eq!(store.unregister(&webhook.id), Err(WebhookError("webhook does not exist")));
+        assert_eq!(store.webhooks, vec![other]);
+
+        // A store which can't be written is saved again next time
+        store.path = Some(::std::env::temp_dir().join("quadcurr-no-such-dir").join("webhooks.json"));
+        assert_eq!(store.persist(), Err(WebhookError("could not write webhook store")));
+        assert!(store.unsaved);
+        store.path = None;
+        assert_eq!(store.persist(), Ok(()));
+        assert!(!store.unsaved);
+    }
+}
+
+mod approvals {
+    use rand::Rng;
+    use rand::os::OsRng;
+
+    use serde_json;
+
+    use std::fs::{self, File};
+    use std::io::{self, Read, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    use super::review::constant_time_eq;
+
+    /// Approval policies and the transfers waiting on them, rewritten after
+    /// every change
+    pub static APPROVAL_STORE: &'static str = "approvals.json";
+
+    /// Longest a transfer can wait for approval
+    pub const MAX_EXPIRY_SECS: i64 = 30 * 24 * 60 * 60;
+
+    lazy_static! {
+        /// Global approval store. Policies are only kept in memory until
+        /// it's opened at startup.
+        static ref APPROVALS: Mutex<ApprovalStore> = Mutex::new(ApprovalStore::default());
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct ApprovalError(pub &'static str);
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Approver {
+        pub id: String,
+        /// Secret the approver authenticates with, only shown when the
+        /// policy is set
+        pub token: String,
+    }
+
+    /// Transfers of at least `threshold` from `account_id` need `required`
+    /// of `approvers` to approve them within `expiry_secs`
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Policy {
+        pub account_id: String,
+        pub threshold: u64,
+        pub required: usize,
+        pub approvers: Vec<Approver>,
+        pub expiry_secs: i64,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+    pub enum ApprovalStatus {
+        Pending,
+        /// Quorum reached and the transfer is being made
+        Approved,
+        /// Made, or held for fraud review - see `outcome`
+        Executed,
+        /// Quorum reached but the transfer couldn't be made - see `outcome`
+        Failed,
+        /// An approver turned it down
+        Rejected,
+        /// Quorum wasn't reached in time
+        Expired,
+        /// The account's policy changed while it was pending
+        Cancelled,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Approval {
+        pub approver: String,
+        /// Seconds since the unix epoch
+        pub time: i64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct PendingTransfer {
+        pub pending_id: u64,
+        pub account_from: String,
+        pub account_to: String,
+        pub amount: u64,
+        /// Seconds since the unix epoch
+        pub created_at: i64,
+        pub expires_at: i64,
+        /// Approvals needed, and who can give them, under the policy at the
+        /// time
+        pub required: usize,
+        pub approvers: Vec<String>,
+        pub approvals: Vec<Approval>,
+        pub status: ApprovalStatus,
+        /// What happened once the status is no longer `Pending` or
+        /// `Approved`, e.g. a rejection reason
+        pub outcome: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Default, Clone)]
+    struct ApprovalStore {
+        /// Where to save the store, if anywhere
+        #[serde(skip_serializing, skip_deserializing)]
+        path: Option<PathBuf>,
+        next_id: u64,
+        policies: Vec<Policy>,
+        transfers: Vec<PendingTransfer>,
+    }
+    impl ApprovalStore {
+        /// Set the policy for an account, replacing any it had. Each approver
+        /// gets a new token. Transfers pending under the old policy are
+        /// cancelled.
+        fn set_policy(&mut self, account_id: &str, threshold: u64, required: usize,
+                      approver_ids: Vec<String>, expiry_secs: i64) -> Result<Policy, ApprovalError> {
+            if approver_ids.iter().any(|id| id.is_empty()) {
+                return Err(ApprovalError("approver ids must be set"))
+            }
+            if approver_ids.iter().enumerate().any(|(i, id)| approver_ids[..i].contains(id)) {
+                return Err(ApprovalError("approver ids must be unique"))
+            }
+            if required == 0 || required > approver_ids.len() {
+                return Err(ApprovalError("required must be between 1 and the number of approvers"))
+            }
+            if expiry_secs <= 0 || expiry_secs > MAX_EXPIRY_SECS {
+                return Err(ApprovalError("expiry_secs must be positive and at most 30 days"))
+            }
+            let mut rng = OsRng::new().unwrap();
+            let approvers = approver_ids.into_iter().map(|id| {
+                let token: [u8; 32] = rng.gen();
+                Approver { id: id, token: token.iter().map(|b| format!("{:02x}", b)).collect() }
+            }).collect();
+            let policy = Policy {
+                account_id: account_id.to_owned(),
+                threshold: threshold,
+                required: required,
+                approvers: approvers,
+                expiry_secs: expiry_secs,
+            };
+            self.remove_policy(account_id);
+            self.policies.push(policy.clone());
+            Ok(policy)
+        }
+        /// Remove an account's policy, cancelling transfers pending under it.
+        /// Returns whether it had one.
+        fn remove_policy(&mut self, account_id: &str) -> bool {
+            let before = self.policies.len();
+            self.policies.retain(|policy| policy.account_id != account_id);
+            for transfer in self.transfers.iter_mut().filter(|transfer| {
+                transfer.account_from == account_id && transfer.status == ApprovalStatus::Pending
+            }) {
+                transfer.status = ApprovalStatus::Cancelled;
+            }
+            self.policies.len() != before
+        }
+        fn policy(&self, account_id: &str) -> Option<&Policy> {
+            self.policies.iter().find(|policy| policy.account_id == account_id)
+        }
+        /// Queue a transfer for approval if it's covered by the sender's
+        /// policy. Returns the pending id if so.
+        fn submit(&mut self, account_from: &str, account_to: &str, amount: u64, now: i64) -> Option<u64> {
+            let (required, approvers, expiry_secs) = match self.policy(account_from) {
+                Some(policy) if amount >= policy.threshold => (
+                    policy.required,
+                    policy.approvers.iter().map(|approver| approver.id.clone()).collect(),
+                    policy.expiry_secs,
+                ),
+                _ => return None,
+            };
+            self.next_id += 1;
+            self.transfers.push(PendingTransfer {
+                pending_id: self.next_id,
+                account_from: account_from.to_owned(),
+                account_to: account_to.to_owned(),
+                amount: amount,
+                created_at: now,
+                expires_at: now + expiry_secs,
+                required: required,
+                approvers: approvers,
+                approvals: vec![],
+                status: ApprovalStatus::Pending,
+                outcome: None,
+            });
+            Some(self.next_id)
+        }
+        /// The account and approver id `token` belongs to
+        fn authenticate(&self, token: &str) -> Option<(String, String)> {
+            self.policies.iter()
+                .flat_map(|policy| policy.approvers.iter().map(move |approver| (policy, approver)))
+                .find(|&(_, approver)| constant_time_eq(approver.token.as_bytes(), token.as_bytes()))
+                .map(|(policy, approver)| (policy.account_id.clone(), approver.id.clone()))
+        }
+        /// Mark transfers which weren't approved in time as expired
+        fn expire(&mut self, now: i64) {
+            for transfer in self.transfers.iter_mut().filter(|transfer| {
+                transfer.status == ApprovalStatus::Pending && transfer.expires_at <= now
+            }) {
+                transfer.status = ApprovalStatus::Expired;
+            }
+        }
+        /// A transfer `approver` for `account_id` can still decide on
+        fn pending_mut(&mut self, pending_id: u64, account_id: &str, approver: &str,
+                       now: i64) -> Result<&mut PendingTransfer, ApprovalError> {
+            self.expire(now);
+            let transfer = match self.transfers.iter_mut().find(|transfer| transfer.pending_id == pending_id) {
+                Some(transfer) => transfer,
+                None => return Err(ApprovalError("pending transfer does not exist")),
+            };
+            if transfer.account_from != account_id || !transfer.approvers.iter().any(|id| id == approver) {
+                return Err(ApprovalError("not an approver for this transfer"))
+            }
+            match transfer.status {
+                ApprovalStatus::Pending => Ok(transfer),
+                ApprovalStatus::Expired => Err(ApprovalError("transfer approval expired")),
+                _ => Err(ApprovalError("transfer already decided")),
+            }
+        }
+        /// Add an approval. Returns the transfer, now `Approved`, once it has
+        /// enough to be made.
+        fn approve(&mut self, pending_id: u64, account_id: &str, approver: &str,
+                   now: i64) -> Result<Option<PendingTransfer>, ApprovalError> {
+            let transfer = self.pending_mut(pending_id, account_id, approver, now)?;
+            if transfer.approvals.iter().any(|approval| approval.approver == approver) {
+                return Err(ApprovalError("already approved"))
+            }
+            transfer.approvals.push(Approval { approver: approver.to_owned(), time: now });
+            if transfer.approvals.len() < transfer.required {
+                return Ok(None)
+            }
+            transfer.status = ApprovalStatus::Approved;
+            Ok(Some(transfer.clone()))
+        }
+        fn reject(&mut self, pending_id: u64, account_id: &str, approver: &str, reason: String,
+                  now: i64) -> Result<(), ApprovalError> {
+            let transfer = self.pending_mut(pending_id, account_id, approver, now)?;
+            transfer.status = ApprovalStatus::Rejected;
+            transfer.outcome = Some(format!("rejected by {}: {}", approver, reason));
+            Ok(())
+        }
+        /// Record what happened when an approved transfer was made
+        fn finish(&mut self, pending_id: u64, result: Result<String, String>) {
+            if let Some(transfer) = self.transfers.iter_mut().find(|transfer| transfer.pending_id == pending_id) {
+                let (status, outcome) = match result {
+                    Ok(outcome) => (ApprovalStatus::Executed, outcome),
+                    Err(outcome) => (ApprovalStatus::Failed, outcome),
+                };
+                transfer.status = status;
+                transfer.outcome = Some(outcome);
+            }
+        }
+        /// Write the store out if it has a path, via a temporary file so a
+        /// crash can't leave it half written
+        fn save(&self) -> io::Result<()> {
+            let path = match self.path {
+                Some(ref path) => path,
+                None => return Ok(()),
+            };
+            let tmp_path = path.with_extension("tmp");
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(serde_json::to_string(self).unwrap().as_bytes())?;
+            file.sync_data()?;
+            fs::rename(&tmp_path, path)
+        }
+    }
+
+    /// Open the approval store at `path`, creating it if needed. Returns
+    /// the number of policies.
+    pub fn open(path: &str) -> Result<usize, ApprovalError> {
+        let mut store: ApprovalStore = if Path::new(path).is_file() {
+            let mut contents = String::new();
+            File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+                .map_err(|_| ApprovalError("could not read approval store"))?;
+            serde_json::from_str(&contents).map_err(|_| ApprovalError("invalid approval store"))?
+        } else {
+            ApprovalStore::default()
+        };
+        store.path = Some(PathBuf::from(path));
+        store.save().map_err(|_| ApprovalError("could not write approval store"))?;
+        let num_policies = store.policies.len();
+        *APPROVALS.lock().unwrap() = store;
+        Ok(num_policies)
+    }
+
+    /// Run `f` on the global store and save it afterwards. Nothing is
+    /// changed if it can't be saved.
+    fn with_store<T, F>(f: F) -> Result<T, ApprovalError>
+            where F: FnOnce(&mut ApprovalStore) -> Result<T, ApprovalError> {
+        let mut store = APPROVALS.lock().unwrap();
+        let before = store.clone();
+        let result = f(&mut store)?;
+        if store.save().is_err() {
+            *store = before;
+            return Err(ApprovalError("could not write approval store"))
+        }
+        Ok(result)
+    }
+
+    /// See `ApprovalStore::set_policy`
+    pub fn set_policy(account_id: &str, threshold: u64, required: usize, approvers: Vec<String>,
+                      expiry_secs: i64) -> Result<Policy, ApprovalError> {
+        with_store(|store| store.set_policy(account_id, threshold, required, approvers, expiry_secs))
+    }
+
+    pub fn remove_policy(account_id: &str) -> Result<(), ApprovalError> {
+        with_store(|store| if store.remove_policy(account_id) {
+            Ok(())
+        } else {
+            Err(ApprovalError("account has no approval policy"))
+        })
+    }
+
+    /// Smallest transfer from `account_id` which needs approval, if any do
+    pub fn threshold(account_id: &str) -> Option<u64> {
+        APPROVALS.lock().unwrap().policy(account_id).map(|policy| policy.threshold)
+    }
+
+    /// See `ApprovalStore::submit`
+    pub fn submit(account_from: &str, account_to: &str, amount: u64, now: i64) -> Result<Option<u64>, ApprovalError> {
+        with_store(|store| Ok(store.submit(account_from, account_to, amount, now)))
+    }
+
+    /// See `ApprovalStore::authenticate`
+    pub fn authenticate(token: &str) -> Option<(String, String)> {
+        APPROVALS.lock().unwrap().authenticate(token)
+    }
+
+    /// Transfers waiting for `approver` of `account_id`
+    pub fn awaiting(account_id: &str, approver: &str, now: i64) -> Vec<PendingTransfer> {
+        let mut store = APPROVALS.lock().unwrap();
+        store.expire(now);
+        store.transfers.iter()
+            .filter(|transfer| transfer.status == ApprovalStatus::Pending && transfer.account_from == account_id)
+            .filter(|transfer| transfer.approvers.iter().any(|id| id == approver))
+            .filter(|transfer| !transfer.approvals.iter().any(|approval| approval.approver == approver))
+            .cloned()
+            .collect()
+    }
+
+    /// See `ApprovalStore::approve`
+    pub fn approve(pending_id: u64, account_id: &str, approver: &str,
+                   now: i64) -> Result<Option<PendingTransfer>, ApprovalError> {
+        with_store(|store| store.approve(pending_id, account_id, approver, now))
+    }
+
+    pub fn reject(pending_id: u64, account_id: &str, approver: &str, reason: String,
+                  now: i64) -> Result<(), ApprovalError> {
+        with_store(|store| store.reject(pending_id, account_id, approver, reason, now))
+    }
+
+    /// See `ApprovalStore::finish`. The transfer has been made or turned
+    /// down by now, so the outcome is kept even if it can't be saved.
+    pub fn finish(pending_id: u64, result: Result<String, String>) {
+        let mut store = APPROVALS.lock().unwrap();
+        store.finish(pending_id, result);
+        if store.save().is_err() {
+            println!("Could not write the outcome of approved transfer {}", pending_id);
+        }
+    }
+
+    #[test]
+    fn test_approvals() {
+        let mut store = ApprovalStore::default();
+        let approvers = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+        assert_eq!(store.set_policy("corp", 1000, 3, approvers(&["cfo", "ceo"]), 3600).err(),
+                   Some(ApprovalError("required must be between 1 and the number of approvers")));
+        assert_eq!(store.set_policy("corp", 1000, 1, approvers(&["cfo", "cfo"]), 3600).err(),
+                   Some(ApprovalError("approver ids must be unique")));
+        let policy = store.set_policy("corp", 1000, 2, approvers(&["cfo", "ceo", "cto"]), 3600).unwrap();
+        let token = |id: &str| policy.approvers.iter().find(|approver| approver.id == id).unwrap().token.clone();
+        assert_eq!(store.authenticate(&token("ceo")), Some(("corp".to_owned(), "ceo".to_owned())));
+        assert_eq!(store.authenticate("nope"), None);
+
+        // Under the threshold, or from another account, needs no approval
+        assert_eq!(store.submit("corp", "x", 999, 0), None);
+        assert_eq!(store.submit("other", "x", 5000, 0), None);
+
+        // Two of three approve
+        let approved = store.submit("corp", "x", 1000, 0).unwrap();
+        assert_eq!(store.approve(approved, "other", "cfo", 10), Err(ApprovalError("not an approver for this transfer")));
+        assert_eq!(store.approve(approved, "corp", "cfo", 10), Ok(None));
+        assert_eq!(store.approve(approved, "corp", "cfo", 11), Err(ApprovalError("already approved")));
+        let transfer = store.approve(approved, "corp", "cto", 12).unwrap().unwrap();
+        assert_eq!((transfer.status, transfer.amount), (ApprovalStatus::Approved, 1000));
+        assert_eq!(store.approve(approved, "corp", "ceo", 13), Err(ApprovalError("transfer already decided")));
+        store.finish(approved, Ok("made".to_owned()));
+        assert_eq!(store.transfers[0].status, ApprovalStatus::Executed);
+
+        // Rejected by one approver, or not approved in time
+        let rejected = store.submit("corp", "x", 2000, 0).unwrap();
+        store.reject(rejected, "corp", "ceo", "unknown payee".to_owned(), 10).unwrap();
+        assert_eq!(store.approve(rejected, "corp", "cfo", 11), Err(ApprovalError("transfer already decided")));
+        let expired = store.submit("corp", "x", 2000, 0).unwrap();
+        store.approve(expired, "corp", "cfo", 10).unwrap();
+        assert_eq!(store.approve(expired, "corp", "ceo", 3600), Err(ApprovalError("transfer approval expired")));
+
+        // A new policy cancels what's pending and gives out new tokens
+        let cancelled = store.submit("corp", "x", 2000, 0).unwrap();
+        let new_policy = store.set_policy("corp", 500, 1, approvers(&["cfo"]), 60).unwrap();
+        assert!(new_policy.approvers[0].token != token("cfo"));
+        assert_eq!(store.authenticate(&token("cfo")), None);
+        assert_eq!(store.approve(cancelled, "corp", "cfo", 10), Err(ApprovalError("transfer already decided")));
+        assert_eq!(store.transfers[3].status, ApprovalStatus::Cancelled);
+        assert!(store.remove_policy("corp"));
+        assert!(!store.remove_policy("corp"));
+    }
+}
+
+mod escrow {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{EntryKind, Posting, TransferPlan, UserDB, UserDBError, write_userdb};
+    use super::currency::CurrencyDetail;
+    use super::replication;
+
+    /// Longest an escrow can be held before it expires
+    pub const MAX_ESCROW_SECS: i64 = 365 * 24 * 60 * 60;
+
+    // How often the worker looks for expired escrows
+    const CHECK_SECS: u64 = 30;
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+    pub enum EscrowStatus {
+        Held,
+        /// Paid out to the seller
+        Released,
+        /// Returned to the buyer
+        Refunded,
+    }
+
+    /// Funds held in a system account until they're released to the seller
+    /// or refunded to the buyer
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct Escrow {
+        pub escrow_id: u64,
+        pub buyer: usize,
+        pub seller: usize,
+        /// System account holding the funds
+        pub account: usize,
+        pub amount: i64,
+        /// Transfer charge when the escrow was opened, held on top of
+        /// `amount`. The charge at release is what's paid, with any
+        /// difference settled with the buyer.
+        pub held_charge: i64,
+        /// Seconds since the unix epoch
+        pub opened_at: i64,
+        /// Seconds since the unix epoch
+        pub expires_at: i64,
+        pub status: EscrowStatus,
+        /// Set once the status is no longer `Held`
+        pub closed_at: Option<i64>,
+        /// `buyer`, `seller`, `arbiter <id>` or `expiry`
+        pub closed_by: Option<String>,
+    }
+    impl Escrow {
+        /// Total held, including the charge
+        fn held(&self) -> i64 {
+            self.amount + self.held_charge
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum EscrowError {
+        NoSuchEscrow,
+        /// The escrow has already been released or refunded
+        Closed,
+        /// The charge has gone up since the escrow was opened, and the
+        /// buyer can't pay the difference
+        BalanceTooLow,
+        /// Moving the held funds failed
+        Ledger(UserDBError),
+    }
+    impl From<UserDBError> for EscrowError {
+        fn from(err: UserDBError) -> EscrowError {
+            EscrowError::Ledger(err)
+        }
+    }
+
+    /// Move `amount` from `buyer` to a new escrow account
+    fn moved(escrow: &Escrow, amount: i64) -> Vec<Posting> {
+        vec![
+            Posting::new(escrow.buyer, -amount, EntryKind::Escrow, escrow.account),
+            Posting::new(escrow.account, amount, EntryKind::Escrow, escrow.buyer),
+        ]
+    }
+
+    /// Move `amount` from the escrow account back to the buyer
+    fn refunded(escrow: &Escrow, amount: i64) -> Vec<Posting> {
+        vec![
+            Posting::new(escrow.account, -amount, EntryKind::EscrowRefund, escrow.buyer),
+            Posting::new(escrow.buyer, amount, EntryKind::EscrowRefund, escrow.account),
+        ]
+    }
+
+    /// Postings paying `escrow` out as the transfer `plan`, made at release,
+    /// with the buyer paying in or getting back any difference between the
+    /// charge held and the charge now
+    fn release_postings(escrow: &Escrow, plan: &TransferPlan) -> Vec<Posting> {
+        let left_over = escrow.held() - plan.total();
+        let mut postings = if left_over < 0 { moved(escrow, -left_over) } else { vec![] };
+        postings.extend(plan.postings(escrow.account));
+        if left_over > 0 {
+            postings.extend(refunded(escrow, left_over));
+        }
+        postings
+    }
+
+    fn held_escrow(userdb: &UserDB, escrow_id: u64) -> Result<Escrow, EscrowError> {
+        match userdb.escrows.get(&escrow_id) {
+            Some(escrow) if escrow.status == EscrowStatus::Held => Ok(escrow.clone()),
+            Some(_) => Err(EscrowError::Closed),
+            None => Err(EscrowError::NoSuchEscrow),
+        }
+    }
+
+    /// What `buyer` has in escrows still held for `seller`
+    pub fn held_for(userdb: &UserDB, buyer: usize, seller: usize) -> i64 {
+        userdb.escrows.values()
+            .filter(|escrow| escrow.buyer == buyer && escrow.seller == seller && escrow.status == EscrowStatus::Held)
+            .map(|escrow| escrow.amount)
+            .sum()
+    }
+
+    /// Open an escrow for the transfer `plan` from the buyer to the seller,
+    /// holding the amount and charge in a new system account until
+    /// `expires_at`. Checking the transfer is allowed is up to the caller.
+    /// Returns the escrow id.
+    pub fn open(userdb: &mut UserDB, plan: &TransferPlan, expires_at: i64) -> Result<u64, EscrowError> {
+        let currency = userdb.get(plan.from).unwrap().currency;
+        let account = userdb.addsysacct(super::UserAccount::with_currency(currency))?;
+        let escrow = Escrow {
+            escrow_id: userdb.escrows.len() as u64 + 1,
+            buyer: plan.from,
+            seller: plan.to,
+            account: account,
+            amount: plan.amount,
+            held_charge: plan.charge_amount,
+            opened_at: userdb.now(),
+            expires_at: expires_at,
+            status: EscrowStatus::Held,
+            closed_at: None,
+            closed_by: None,
+        };
+        let escrow_id = escrow.escrow_id;
+        let postings = moved(&escrow, escrow.held());
+        userdb.open_escrow(escrow, &postings)?;
+        Ok(escrow_id)
+    }
+
+    /// Pay escrow `escrow_id` out to the seller as a transfer, charged as
+    /// `currency_detail` charges transfers now
+    pub fn release(userdb: &mut UserDB, escrow_id: u64, currency_detail: &CurrencyDetail,
+                   closed_by: String) -> Result<(), EscrowError> {
+        let escrow = held_escrow(userdb, escrow_id)?;
+        let plan = match TransferPlan::new(escrow.buyer, escrow.seller, escrow.amount, currency_detail) {
+            Some(plan) => plan,
+            None => return Err(EscrowError::Ledger(UserDBError::Overflow(escrow.buyer))),
+        };
+        let left_over = escrow.held() - plan.total();
+        let balance = userdb.get(escrow.buyer).unwrap().balance;
+        if left_over < 0 && balance.saturating_add(currency_detail.overdraft_limit as i64) < -left_over {
+            return Err(EscrowError::BalanceTooLow)
+        }
+        userdb.close_escrow(escrow_id, EscrowStatus::Released, closed_by, &release_postings(&escrow, &plan))?;
+        Ok(())
+    }
+
+    /// Return everything held for escrow `escrow_id` to the buyer
+    pub fn refund(userdb: &mut UserDB, escrow_id: u64, closed_by: String) -> Result<(), EscrowError> {
+        let escrow = held_escrow(userdb, escrow_id)?;
+        userdb.close_escrow(escrow_id, EscrowStatus::Refunded, closed_by, &refunded(&escrow, escrow.held()))?;
+        Ok(())
+    }
+
+    /// Refund every escrow which expired by `now`. One which can't be
+    /// refunded is reported and left for the next sweep, without holding up
+    /// the rest. Returns how many were refunded.
+    pub fn expire(userdb: &mut UserDB, now: i64) -> usize {
+        let expired: Vec<u64> = userdb.escrows.values()
+            .filter(|escrow| escrow.status == EscrowStatus::Held && escrow.expires_at <= now)
+            .map(|escrow| escrow.escrow_id)
+            .collect();
+        let mut refunded = 0;
+        for escrow_id in expired {
+            match refund(userdb, escrow_id, "expiry".to_owned()) {
+                Ok(()) => refunded += 1,
+                Err(err) => println!("Escrow {} expiry failed: {:?}", escrow_id, err),
+            }
+        }
+        refunded
+    }
+
+    /// Start the background thread which refunds expired escrows
+    pub fn start_worker() {
+        thread::spawn(|| {
+            loop {
+                // Followers get refunds from the leader
+                if !replication::is_following() {
+                    let mut userdb = write_userdb();
+                    let now = userdb.now();
+                    expire(&mut userdb, now);
+                }
+                thread::sleep(Duration::from_secs(CHECK_SECS));
+            }
+        });
+    }
+
+    #[test]
+    fn test_escrow() {
+        use super::UserAccount;
+        use super::currency::{self, CurrencyId};
+
+        let mut userdb = UserDB::new();
+        let charge_account = userdb.addsysacct(UserAccount::with_currency(CurrencyId(1))).unwrap();
+        let buyer = userdb.addsysacct(UserAccount::with_currency(CurrencyId(1))).unwrap();
+        let seller = userdb.addsysacct(UserAccount::with_currency(CurrencyId(1))).unwrap();
+        userdb.get_mut(buyer).unwrap().balance = 1000;
+        let mut detail = currency::test_usd(2.0, charge_account);
+        let balances = |userdb: &UserDB| -> Vec<i64> { userdb.balances() };
+
+        // 100 plus a charge of 2 is held, then the charge goes up to 5 by release
+        let plan = TransferPlan::new(buyer, seller, 100, &detail).unwrap();
+        let released = open(&mut userdb, &plan, 1000).unwrap();
+        let account = userdb.escrows[&released].account;
+        assert_eq!(balances(&userdb), vec![0, 898, 0, 102]);
+        detail.transfer_charge = 5.0;
+        release(&mut userdb, released, &detail, "buyer".to_owned()).unwrap();
+        assert_eq!(balances(&userdb), vec![5, 895, 100, 0]);
+        let history: Vec<EntryKind> = userdb.get(seller).unwrap().history.iter().map(|e| e.kind).collect();
+        assert_eq!(history, vec![EntryKind::TransferIn]);
+        assert_eq!(userdb.get(seller).unwrap().history[0].counterparty, Some(buyer));
+        assert_eq!(release(&mut userdb, released, &detail, "buyer".to_owned()), Err(EscrowError::Closed));
+
+        // A lower charge at release gives the buyer the difference back
+        let plan = TransferPlan::new(buyer, seller, 100, &detail).unwrap();
+        let cheaper = open(&mut userdb, &plan, 1000).unwrap();
+        detail.transfer_charge = 1.0;
+        release(&mut userdb, cheaper, &detail, "arbiter alice".to_owned()).unwrap();
+        assert_eq!(balances(&userdb)[..3].to_vec(), vec![6, 794, 200]);
+        assert_eq!(userdb.get(userdb.escrows[&cheaper].account).unwrap().balance, 0);
+
+        // Refunded in full on expiry, charge included
+        let plan = TransferPlan::new(buyer, seller, 300, &detail).unwrap();
+        let expiring = open(&mut userdb, &plan, 2000).unwrap();
+        assert_eq!(userdb.get(buyer).unwrap().balance, 491);
+        assert_eq!(expire(&mut userdb, 1999), 0);
+        assert_eq!(expire(&mut userdb, 2000), 1);
+        assert_eq!(userdb.get(buyer).unwrap().balance, 794);
+        let escrow = &userdb.escrows[&expiring];
+        assert_eq!((escrow.status, escrow.closed_by.clone()), (EscrowStatus::Refunded, Some("expiry".to_owned())));
+
+        // A buyer with nothing left can't pay a higher charge at release
+        let plan = TransferPlan::new(buyer, seller, 786, &detail).unwrap();
+        let emptied = open(&mut userdb, &plan, 3000).unwrap();
+        assert_eq!(userdb.get(buyer).unwrap().balance, 0);
+        detail.transfer_charge = 5.0;
+        assert_eq!(release(&mut userdb, emptied, &detail, "buyer".to_owned()), Err(EscrowError::BalanceTooLow));
+        refund(&mut userdb, emptied, "seller".to_owned()).unwrap();
+        assert_eq!(userdb.get(buyer).unwrap().balance, 794);
+        assert_eq!(refund(&mut userdb, 99, "seller".to_owned()), Err(EscrowError::NoSuchEscrow));
+        assert!(account != userdb.escrows[&cheaper].account);
+    }
+}
+
+mod disputes {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{EntryKind, Posting, UserDB, UserDBError, write_userdb};
+    use super::currency::CurrencyDetail;
+    use super::replication;
+
+    /// Longest after a transfer is made that it can be disputed
+    pub const MAX_TRANSFER_AGE_SECS: i64 = 120 * 24 * 60 * 60;
+
+    /// How long the recipient has to respond to a dispute before it's
+    /// decided for the sender
+    pub const RESPONSE_SECS: i64 = 10 * 24 * 60 * 60;
+
+    /// Longest reason or evidence note accepted
+    pub const MAX_NOTE_LEN: usize = 2000;
+
+    // How often the worker looks for disputes past their deadline
+    const CHECK_SECS: u64 = 60;
+
+    /// Where a dispute is up to. It starts `Open`, goes `UnderReview` once
+    /// the recipient responds, and from either is closed with one of the
+    /// others.
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+    pub enum DisputeStatus {
+        /// Waiting for the recipient to respond, until `respond_by`
+        Open,
+        /// The recipient has responded, waiting for an arbiter to decide
+        UnderReview,
+        /// The provisional payment back to the sender stands, and the
+        /// recipient pays a chargeback fee
+        WonBySender,
+        /// The provisional payment is reversed
+        WonByRecipient,
+        /// Withdrawn by the sender, reversed as if the recipient won
+        Withdrawn,
+    }
+    impl DisputeStatus {
+        pub fn is_closed(&self) -> bool {
+            match *self {
+                DisputeStatus::Open | DisputeStatus::UnderReview => false,
+                DisputeStatus::WonBySender | DisputeStatus::WonByRecipient | DisputeStatus::Withdrawn => true,
+            }
+        }
+    }
+
+    /// A note added to a dispute by either party or an arbiter
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct Evidence {
+        /// `sender`, `recipient` or `arbiter <id>`
+        pub party: String,
+        pub note: String,
+        /// Seconds since the unix epoch
+        pub time: i64,
+    }
+
+    /// A transfer the sender says shouldn't have happened, provisionally
+    /// paid back to them by the recipient until it's decided
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct Dispute {
+        pub dispute_id: u64,
+        pub sender: usize,
+        pub recipient: usize,
+        /// Position of the disputed transfer in the sender's history, as
+        /// numbered in statements
+        pub entry_id: usize,
+        pub amount: i64,
+        /// Paid by the recipient if the sender wins - the transfer charge
+        /// on `amount` when the dispute was opened
+        pub fee: i64,
+        /// Charge account of the currency, which the fee goes to
+        pub fee_account: usize,
+        pub reason: String,
+        /// Seconds since the unix epoch
+        pub opened_at: i64,
+        /// Seconds since the unix epoch
+        pub respond_by: i64,
+        pub status: DisputeStatus,
+        pub evidence: Vec<Evidence>,
+        /// Set once the dispute is closed
+        pub closed_at: Option<i64>,
+        /// `sender`, `arbiter <id>` or `expiry`
+        pub closed_by: Option<String>,
+    }
+    impl Dispute {
+        /// What an account frozen by this dispute is frozen for
+        pub fn freeze_reason(&self) -> String {
+            format!("dispute {}", self.dispute_id)
+        }
+        /// Add `evidence`, putting the dispute under review if it's the
+        /// recipient's first response
+        pub fn add_evidence(&mut self, evidence: Evidence) {
+            if self.status == DisputeStatus::Open && evidence.party == "recipient" {
+                self.status = DisputeStatus::UnderReview;
+            }
+            self.evidence.push(evidence);
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum DisputeError {
+        NoSuchDispute,
+        /// The sender has no transfer out to a customer at that entry
+        NoSuchTransfer,
+        /// The transfer is older than `MAX_TRANSFER_AGE_SECS`
+        TooLate,
+        AlreadyDisputed,
+        /// The sender's account is frozen
+        Frozen,
+        /// The dispute has already been decided or withdrawn
+        Closed,
+        /// Moving the disputed funds failed
+        Ledger(UserDBError),
+    }
+    impl From<UserDBError> for DisputeError {
+        fn from(err: UserDBError) -> DisputeError {
+            DisputeError::Ledger(err)
+        }
+    }
+
+    /// Move the disputed amount from the recipient back to the sender
+    fn provisional(dispute: &Dispute) -> Vec<Posting> {
+        vec![
+            Posting::new(dispute.recipient, -dispute.amount, EntryKind::Dispute, dispute.sender),
+            Posting::new(dispute.sender, dispute.amount, EntryKind::Dispute, dispute.recipient),
+        ]
+    }
+
+    /// Undo `provisional`
+    fn reversed(dispute: &Dispute) -> Vec<Posting> {
+        vec![
+            Posting::new(dispute.sender, -dispute.amount, EntryKind::DisputeReversal, dispute.recipient),
+            Posting::new(dispute.recipient, dispute.amount, EntryKind::DisputeReversal, dispute.sender),
+        ]
+    }
+
+    /// Charge the recipient the chargeback fee, if there is one
+    fn fee_charged(dispute: &Dispute) -> Vec<Posting> {
+        if dispute.fee == 0 {
+            return vec![]
+        }
+        vec![
+            Posting::new(dispute.recipient, -dispute.fee, EntryKind::ChargebackFee, dispute.fee_account),
+            Posting::new(dispute.fee_account, dispute.fee, EntryKind::ChargebackFee, dispute.recipient),
+        ]
+    }
+
+    fn open_dispute(userdb: &UserDB, dispute_id: u64) -> Result<Dispute, DisputeError> {
+        match userdb.disputes.get(&dispute_id) {
+            Some(dispute) if !dispute.status.is_closed() => Ok(dispute.clone()),
+            Some(_) => Err(DisputeError::Closed),
+            None => Err(DisputeError::NoSuchDispute),
+        }
+    }
+
+    /// Freeze `debited` if `dispute` just left it with a negative balance,
+    /// and unfreeze either party once they're back to zero or more if any
+    /// dispute froze them - it may not be this one, when several debited
+    /// the same account. Accounts frozen for other reasons are left alone.
+    fn update_freezes(userdb: &mut UserDB, dispute: &Dispute, debited: usize) -> Result<(), UserDBError> {
+        for &idx in &[dispute.sender, dispute.recipient] {
+            let (balance, frozen) = {
+                let acct = userdb.get(idx).unwrap();
+                (acct.balance, acct.frozen.clone())
+            };
+            let frozen_by_dispute = frozen.as_ref().map_or(false, |frozen| {
+                userdb.disputes.values().any(|dispute| dispute.freeze_reason() == *frozen)
+            });
+            if frozen.is_none() && idx == debited && balance < 0 {
+                userdb.set_frozen(idx, Some(dispute.freeze_reason()))?;
+            } else if frozen_by_dispute && balance >= 0 {
+                userdb.set_frozen(idx, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Dispute the transfer at `entry_id` in the history of `sender`, in
+    /// `currency_detail`, provisionally paying its amount back from the
+    /// recipient. Returns the dispute id.
+    pub fn open(userdb: &mut UserDB, sender: usize, entry_id: usize, reason: String,
+                currency_detail: &CurrencyDetail) -> Result<u64, DisputeError> {
+        let now = userdb.now();
+        let (recipient, amount, time) = {
+            let acct = match userdb.get(sender) {
+                Some(acct) => acct,
+                None => return Err(DisputeError::NoSuchTransfer),
+            };
+            if acct.frozen.is_some() {
+                return Err(DisputeError::Frozen)
+            }
+            match acct.history.get(entry_id) {
+                Some(entry) if entry.kind == EntryKind::TransferOut && entry.counterparty.is_some() =>
+                    (entry.counterparty.unwrap(), -entry.amount, entry.time),
+                _ => return Err(DisputeError::NoSuchTransfer),
+            }
+        };
+        if userdb.public_id(recipient).is_none() {
+            return Err(DisputeError::NoSuchTransfer)
+        }
+        if now - time > MAX_TRANSFER_AGE_SECS {
+            return Err(DisputeError::TooLate)
+        }
+        if userdb.disputes.values().any(|dispute| dispute.sender == sender && dispute.entry_id == entry_id) {
+            return Err(DisputeError::AlreadyDisputed)
+        }
+        let dispute = Dispute {
+            dispute_id: userdb.disputes.len() as u64 + 1,
+            sender: sender,
+            recipient: recipient,
+            entry_id: entry_id,
+            amount: amount,
+            fee: currency_detail.transfer_charge_for(amount),
+            fee_account: currency_detail.transfer_charge_account,
+            reason: reason,
+            opened_at: now,
+            respond_by: now + RESPONSE_SECS,
+            status: DisputeStatus::Open,
+            evidence: vec![],
+            closed_at: None,
+            closed_by: None,
+        };
+        let dispute_id = dispute.dispute_id;
+        userdb.open_dispute(dispute.clone(), &provisional(&dispute))?;
+        update_freezes(userdb, &dispute, recipient)?;
+        Ok(dispute_id)
+    }
+
+    /// Add a note from `party` to dispute `dispute_id`
+    pub fn add_evidence(userdb: &mut UserDB, dispute_id: u64, party: String, note: String) -> Result<(), DisputeError> {
+        open_dispute(userdb, dispute_id)?;
+        let evidence = Evidence { party: party, note: note, time: userdb.now() };
+        userdb.add_dispute_evidence(dispute_id, evidence)?;
+        Ok(())
+    }
+
+    /// Close dispute `dispute_id` as `status`. If the sender won the
+    /// recipient pays the fee, otherwise the provisional payment is reversed.
+    fn close(userdb: &mut UserDB, dispute_id: u64, status: DisputeStatus, closed_by: String) -> Result<(), DisputeError> {
+        let dispute = open_dispute(userdb, dispute_id)?;
+        let (postings, debited) = match status {
+            DisputeStatus::WonBySender => (fee_charged(&dispute), dispute.recipient),
+            _ => (reversed(&dispute), dispute.sender),
+        };
+        userdb.close_dispute(dispute_id, status, closed_by, &postings)?;
+        update_freezes(userdb, &dispute, debited)?;
+        Ok(())
+    }
+
+    /// Decide dispute `dispute_id` for the sender or the recipient
+    pub fn resolve(userdb: &mut UserDB, dispute_id: u64, for_sender: bool, closed_by: String) -> Result<(), DisputeError> {
+        let status = if for_sender { DisputeStatus::WonBySender } else { DisputeStatus::WonByRecipient };
+        close(userdb, dispute_id, status, closed_by)
+    }
+
+    /// Withdraw dispute `dispute_id` on behalf of the sender
+    pub fn withdraw(userdb: &mut UserDB, dispute_id: u64) -> Result<(), DisputeError> {
+        close(userdb, dispute_id, DisputeStatus::Withdrawn, "sender".to_owned())
+    }
+
+    /// Decide every dispute the recipient didn't respond to by `now` for
+    /// the sender. Returns how many were decided.
+    pub fn expire(userdb: &mut UserDB, now: i64) -> Result<usize, DisputeError> {
+        let expired: Vec<u64> = userdb.disputes.values()
+            .filter(|dispute| dispute.status == DisputeStatus::Open && dispute.respond_by <= now)
+            .map(|dispute| dispute.dispute_id)
+            .collect();
+        for &dispute_id in &expired {
+            close(userdb, dispute_id, DisputeStatus::WonBySender, "expiry".to_owned())?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Start the background thread which decides disputes past their
+    /// deadline
+    pub fn start_worker() {
+        thread::spawn(|| {
+            loop {
+                // Followers get decisions from the leader
+                if !replication::is_following() {
+                    let mut userdb = write_userdb();
+                    let now = userdb.now();
+                    if let Err(err) = expire(&mut userdb, now) {
+                        println!("Dispute expiry failed: {:?}", err);
+                    }
+                }
+                thread::sleep(Duration::from_secs(CHECK_SECS));
+            }
+        });
+    }
+
+    #[test]
+    fn test_disputes() {
+        use super::{TransferPlan, UserAccount};
+        use super::currency::{self, CurrencyId};
+
+        let mut userdb = UserDB::new();
+        let charge_account = userdb.addsysacct(UserAccount::with_currency(CurrencyId(1))).unwrap();
+        let sender = userdb.addacct(UserAccount::with_currency(CurrencyId(1))).unwrap().to_string();
+        let recipient = userdb.addacct(UserAccount::with_currency(CurrencyId(1))).unwrap().to_string();
+        let (sender, recipient) = (userdb.lookup(&None, &sender).unwrap(), userdb.lookup(&None, &recipient).unwrap());
+        userdb.get_mut(sender).unwrap().balance = 1000;
+        let detail = currency::test_usd(2.0, charge_account);
+        let transfer = |userdb: &mut UserDB, amount| {
+            let plan = TransferPlan::new(sender, recipient, amount, &detail).unwrap();
+            userdb.apply_postings(&plan.postings(sender)).unwrap();
+            userdb.get(sender).unwrap().history.len() - 2
+        };
+        let frozen = |userdb: &UserDB, idx| userdb.get(idx).unwrap().frozen.clone();
+
+        // 100 goes back to the sender, and back again once the recipient wins
+        let first = transfer(&mut userdb, 100);
+        assert_eq!(userdb.balances(), vec![2, 898, 100]);
+        assert_eq!(open(&mut userdb, sender, first + 1, "charge".to_owned(), &detail), Err(DisputeError::NoSuchTransfer));
+        let reversed = open(&mut userdb, sender, first, "not received".to_owned(), &detail).unwrap();
+        assert_eq!(userdb.balances(), vec![2, 998, 0]);
+        assert_eq!(open(&mut userdb, sender, first, "again".to_owned(), &detail), Err(DisputeError::AlreadyDisputed));
+        add_evidence(&mut userdb, reversed, "sender".to_owned(), "never arrived".to_owned()).unwrap();
+        assert_eq!(userdb.disputes[&reversed].status, DisputeStatus::Open);
+        add_evidence(&mut userdb, reversed, "recipient".to_owned(), "tracking number 123".to_owned()).unwrap();
+        assert_eq!(userdb.disputes[&reversed].status, DisputeStatus::UnderReview);
+        resolve(&mut userdb, reversed, false, "arbiter carol".to_owned()).unwrap();
+        assert_eq!(userdb.balances(), vec![2, 898, 100]);
+        assert_eq!(resolve(&mut userdb, reversed, true, "arbiter carol".to_owned()), Err(DisputeError::Closed));
+        assert_eq!(add_evidence(&mut userdb, reversed, "sender".to_owned(), "late".to_owned()), Err(DisputeError::Closed));
+
+        // The recipient has spent the money, so goes negative and is frozen
+        // until the sender withdraws
+        let second = transfer(&mut userdb, 300);
+        userdb.get_mut(recipient).unwrap().balance -= 350;
+        let withdrawn = open(&mut userdb, sender, second, "duplicate".to_owned(), &detail).unwrap();
+        assert_eq!(userdb.get(recipient).unwrap().balance, -250);
+        assert_eq!(frozen(&userdb, recipient), Some("dispute 2".to_owned()));
+        withdraw(&mut userdb, withdrawn).unwrap();
+        assert_eq!((frozen(&userdb, recipient), userdb.get(recipient).unwrap().balance), (None, 50));
+
+        // Frozen by one dispute, and unfrozen by whichever leaves them
+        // back to zero or more
+        let (earlier, later) = (transfer(&mut userdb, 100), transfer(&mut userdb, 100));
+        userdb.get_mut(recipient).unwrap().balance -= 200;
+        let freezing = open(&mut userdb, sender, earlier, "duplicate".to_owned(), &detail).unwrap();
+        let still_negative = open(&mut userdb, sender, later, "duplicate".to_owned(), &detail).unwrap();
+        assert_eq!(frozen(&userdb, recipient), Some(format!("dispute {}", freezing)));
+        withdraw(&mut userdb, freezing).unwrap();
+        assert_eq!((frozen(&userdb, recipient), userdb.get(recipient).unwrap().balance),
+                   (Some(format!("dispute {}", freezing)), -50));
+        withdraw(&mut userdb, still_negative).unwrap();
+        assert_eq!((frozen(&userdb, recipient), userdb.get(recipient).unwrap().balance), (None, 50));
+
+        // Decided for the sender when the recipient doesn't respond in time,
+        // who stays frozen while the fee leaves them negative
+        let third = transfer(&mut userdb, 50);
+        let expiring = open(&mut userdb, sender, third, "fraud".to_owned(), &detail).unwrap();
+        assert_eq!(userdb.get(recipient).unwrap().balance, 50);
+        userdb.get_mut(recipient).unwrap().balance = 0;
+        let respond_by = userdb.disputes[&expiring].respond_by;
+        assert_eq!(expire(&mut userdb, respond_by - 1), Ok(0));
+        assert_eq!(expire(&mut userdb, respond_by), Ok(1));
+        let dispute = &userdb.disputes[&expiring];
+        assert_eq!((dispute.status, dispute.closed_by.clone()), (DisputeStatus::WonBySender, Some("expiry".to_owned())));
+        assert_eq!(userdb.get(recipient).unwrap().balance, -1);
+        assert_eq!(frozen(&userdb, recipient), Some(format!("dispute {}", expiring)));
+        let history: Vec<EntryKind> = userdb.get(recipient).unwrap().history.iter().rev().take(2).map(|e| e.kind).collect();
+        assert_eq!(history, vec![EntryKind::ChargebackFee, EntryKind::Dispute]);
+
+        // Frozen senders can't dispute, and old transfers can't be disputed
+        userdb.set_frozen(sender, Some("arbiter carol: chargeback abuse".to_owned())).unwrap();
+        let fourth = transfer(&mut userdb, 50);
+        assert_eq!(open(&mut userdb, sender, fourth, "fraud".to_owned(), &detail), Err(DisputeError::Frozen));
+        userdb.set_frozen(sender, None).unwrap();
+        userdb.get_mut(sender).unwrap().history[fourth].time -= MAX_TRANSFER_AGE_SECS + 1;
+        assert_eq!(open(&mut userdb, sender, fourth, "fraud".to_owned(), &detail), Err(DisputeError::TooLate));
+        assert_eq!(withdraw(&mut userdb, 99), Err(DisputeError::NoSuchDispute));
+    }
+}
+
+mod interest {
+    use chrono::{Datelike, NaiveDate, NaiveDateTime};
+
+    use std::collections::HashMap;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{EntryKind, Posting, UserDB, UserDBError, unix_now, write_userdb};
+    use super::currency::{self, CurrencyDetail, CurrencyId};
+    use super::replication;
+
+    /// Accrued interest is kept in millionths of a base unit
+    pub const MICROS_PER_UNIT: i64 = 1_000_000;
+
+    /// Highest yearly rate allowed, 100%
+    pub const MAX_RATE_BPS: u32 = 10_000;
+
+    const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+    // How often the worker looks for days to accrue interest for
+    const CHECK_SECS: u64 = 60 * 60;
+
+    /// Interest on `balance` for one day at `rate_bps` a year, in millionths
+    /// of a base unit, rounded down. Nothing is earned on a negative balance.
+    pub fn daily_interest(balance: i64, rate_bps: u32) -> i64 {
+        if balance <= 0 {
+            return 0
+        }
+        // balance * rate_bps / 10_000 / 365 in micros, with the balance split
+        // up so it can't overflow short of enormous balances
+        let micros_rate = rate_bps as i64 * (MICROS_PER_UNIT / 10_000);
+        let (whole_years, rest) = (balance / 365, balance % 365);
+        whole_years.saturating_mul(micros_rate).saturating_add(rest * micros_rate / 365)
+    }
+
+    /// Day (counting from the unix epoch) containing `time`
+    fn day_of(time: i64) -> i64 {
+        time / SECS_PER_DAY
+    }
+
+    fn date(day: i64) -> NaiveDate {
+        NaiveDateTime::from_timestamp(day * SECS_PER_DAY, 0).date()
+    }
+
+    /// Interest is paid out after accruing the last day of each month
+    fn is_month_end(day: i64) -> bool {
+        date(day).month() != date(day + 1).month()
+    }
+
+    /// Rate each api account earns, from its override or its currency. Only
+    /// accounts with a non-zero rate are included.
+    fn rates(userdb: &UserDB, currencies: &HashMap<CurrencyId, &CurrencyDetail>) -> Vec<(usize, u32)> {
+        (0..userdb.accts.len())
+            .filter(|&idx| userdb.public_id(idx).is_some())
+            .filter_map(|idx| {
+                let acct = userdb.get(idx).unwrap();
+                let currency_rate = currencies.get(&acct.currency).map(|detail| detail.interest_rate_bps);
+                acct.interest_rate_bps.or(currency_rate).map(|rate| (idx, rate))
+            })
+            .filter(|&(_, rate)| rate > 0)
+            .collect()
+    }
+
+    /// Postings paying the whole base units of each account's accrued
+    /// interest from its currency's expense account. The fraction of a unit
+    /// left over is carried to the next payout.
+    fn payout_postings(userdb: &UserDB, currencies: &HashMap<CurrencyId, &CurrencyDetail>) -> Vec<Posting> {
+        let mut postings = vec![];
+        for idx in 0..userdb.accts.len() {
+            let acct = userdb.get(idx).unwrap();
+            let amount = acct.accrued_interest / MICROS_PER_UNIT;
+            let expense_acct = currencies.get(&acct.currency).and_then(|detail| detail.interest_expense_account);
+            if let (true, Some(expense_acct)) = (amount > 0, expense_acct) {
+                postings.push(Posting::new(expense_acct, -amount, EntryKind::Interest, idx));
+                postings.push(Posting::new(idx, amount, EntryKind::Interest, expense_acct));
+            }
+        }
+        postings
+    }
+
+    /// Accrue interest on current balances for each day up to and including
+    /// `last_day` which hasn't been accrued yet, paying out at each month
+    /// end. The first run only accrues `last_day`.
+    pub fn run(userdb: &mut UserDB, currencies: &[CurrencyDetail], last_day: i64) -> Result<(), UserDBError> {
+        let currencies: HashMap<CurrencyId, &CurrencyDetail> = currencies.iter().map(|detail| (detail.id, detail)).collect();
+        let first_day = userdb.last_accrual_day.map_or(last_day, |day| day + 1);
+        for day in first_day..last_day + 1 {
+            let accruals = rates(userdb, &currencies).into_iter()
+                .map(|(idx, rate)| (idx, daily_interest(userdb.get(idx).unwrap().balance, rate)))
+                .filter(|&(_, amount)| amount > 0)
+                .collect();
+            userdb.accrue_interest(day, accruals)?;
+            if is_month_end(day) {
+                let postings = payout_postings(userdb, &currencies);
+                if !postings.is_empty() {
+                    userdb.pay_interest(&postings)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[derive(Serialize, Debug, PartialEq)]
+    pub struct PayoutPreview {
+        pub account_id: String,
+        pub currency: String,
+        pub rate_bps: u32,
+        /// Already accrued, in millionths of a base unit
+        pub accrued: i64,
+        /// Base units to be paid, if the balance doesn't change before then
+        pub amount: i64,
+        pub amount_formatted: String,
+    }
+
+    /// The next month end payout, assuming balances stay as they are: the
+    /// day it happens on, and what each account would be paid
+    pub fn preview(userdb: &UserDB, currencies: &[CurrencyDetail], today: i64) -> (NaiveDate, Vec<PayoutPreview>) {
+        let currencies: HashMap<CurrencyId, &CurrencyDetail> = currencies.iter().map(|detail| (detail.id, detail)).collect();
+        let first_day = userdb.last_accrual_day.map_or(today - 1, |day| day + 1);
+        let mut payout_day = first_day;
+        while !is_month_end(payout_day) {
+            payout_day += 1;
+        }
+        let days_left = payout_day - first_day + 1;
+        let rates: HashMap<usize, u32> = rates(userdb, &currencies).into_iter().collect();
+        let mut previews = vec![];
+        for idx in 0..userdb.accts.len() {
+            let acct = userdb.get(idx).unwrap();
+            let (public_id, detail) = match (userdb.public_id(idx), currencies.get(&acct.currency)) {
+                (Some(public_id), Some(detail)) => (public_id, detail),
+                _ => continue,
+            };
+            let rate = rates.get(&idx).cloned().unwrap_or(0);
+            let projected = daily_interest(acct.balance, rate).saturating_mul(days_left)
+                .saturating_add(acct.accrued_interest);
+            if projected / MICROS_PER_UNIT > 0 && detail.interest_expense_account.is_some() {
+                previews.push(PayoutPreview {
+                    account_id: public_id.to_string(),
+                    currency: detail.name.clone(),
+                    rate_bps: rate,
+                    accrued: acct.accrued_interest,
+                    amount: projected / MICROS_PER_UNIT,
+                    amount_formatted: detail.format(projected / MICROS_PER_UNIT),
+                });
+            }
+        }
+        (date(payout_day), previews)
+    }
+
+    pub fn today() -> i64 {
+        day_of(unix_now())
+    }
+
+    /// Start the background thread which accrues interest once each day is
+    /// over
+    pub fn start_worker() {
+        thread::spawn(|| loop {
+            // Followers get interest from the leader
+            if !replication::is_following() {
+                let mut userdb = write_userdb();
+                if let Err(err) = run(&mut userdb, &currency::all_currencies(), today() - 1) {
+                    println!("Interest accrual failed: {:?}", err);
+                }
+            }
+            thread::sleep(Duration::from_secs(CHECK_SECS));
+        });
+    }
+
+    #[test]
+    fn test_interest() {
+        use super::UserAccount;
+
+        // 10% a year on 36500 is 10 a day
+        assert_eq!(daily_interest(36500, 1000), 10 * MICROS_PER_UNIT);
+        assert_eq!(daily_interest(100, 2000), 54794);
+        assert_eq!(daily_interest(-100, 2000), 0);
+        assert_eq!(daily_interest(i64::max_value(), MAX_RATE_BPS), i64::max_value());
+
+        let mut userdb = UserDB::new();
+        let expense_acct = userdb.addsysacct(UserAccount::with_currency(CurrencyId(1))).unwrap();
+        let currencies = vec![CurrencyDetail {
+            interest_rate_bps: 1000,
+            interest_expense_account: Some(expense_acct),
+            ..currency::test_usd(0.0, expense_acct)
+        }];
+        let mut accts = vec![];
+        for &(balance, rate) in &[(36500, None), (-100, None), (100, Some(2000)), (100000, Some(0))] {
+            let id = userdb.addacct(UserAccount::with_currency(CurrencyId(1))).unwrap();
+            let idx = userdb.lookup(&None, &id.to_string()).unwrap();
+            userdb.get_mut(idx).unwrap().balance = balance;
+            userdb.set_interest_rate(idx, rate).unwrap();
+            accts.push(idx);
+        }
+        let day = |d| NaiveDate::from_ymd(2017, 1, 1).signed_duration_since(NaiveDate::from_ymd(1970, 1, 1)).num_days() + d - 1;
+        assert!(!is_month_end(day(30)) && is_month_end(day(31)));
+
+        // Accrue the 30th, then the 31st, pay out, then accrue Feb 1st
+        userdb.last_accrual_day = Some(day(29));
+        run(&mut userdb, &currencies, day(32)).unwrap();
+        assert_eq!(userdb.last_accrual_day, Some(day(32)));
+        let state: Vec<(i64, i64)> = accts.iter()
+            .map(|&idx| {
+                let acct = userdb.get(idx).unwrap();
+                (acct.balance, acct.accrued_interest)
+            })
+            .collect();
+        assert_eq!(state, vec![(36520, daily_interest(36520, 1000)), (-100, 0), (100, 3 * 54794), (100000, 0)]);
+        assert_eq!(userdb.get(expense_acct).unwrap().balance, -20);
+        let entry = userdb.get(accts[0]).unwrap().history.last().cloned().unwrap();
+        assert_eq!((entry.kind, entry.amount, entry.counterparty), (EntryKind::Interest, 20, Some(expense_acct)));
+
+        // 27 more days in February at the same balances, by when the third
+        // account's fractions carried over add up to a whole unit
+        let (payout_date, previews) = preview(&userdb, &currencies, day(33));
+        assert_eq!(payout_date, NaiveDate::from_ymd(2017, 2, 28));
+        let amounts: Vec<i64> = previews.iter().map(|preview| preview.amount).collect();
+        assert_eq!(amounts, vec![28 * daily_interest(36520, 1000) / MICROS_PER_UNIT, 1]);
+    }
+}
+
+mod metrics {
+    use iron::Handler;
+    use iron::prelude::{IronResult, Request, Response};
+
+    use router::{NoRoute, TrailingSlash};
+
+    use std::collections::BTreeMap;
+    use std::fmt::Write;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    /// Upper bounds of histogram buckets, in seconds
+    const BUCKETS: &'static [f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+    /// Name, type and help text of every metric, in the order rendered
+    const METRICS: &'static [(&'static str, &'static str, &'static str)] = &[
+        ("quadcurr_http_requests_total", "counter", "Requests handled, by route and response status"),
+        ("quadcurr_http_request_duration_seconds", "histogram", "Time taken to handle requests, by route"),
+        ("quadcurr_transfers_total", "counter", "Transfers paid out, by currency"),
+        ("quadcurr_transfer_volume_total", "counter", "Base units of currency paid out by transfers, by currency"),
+        ("quadcurr_transfer_charges_total", "counter", "Base units of currency collected as transfer charges, by currency"),
+        ("quadcurr_fraud_flags_total", "counter", "Transfers flagged by the fraud checker, by whether they were held for review or rejected"),
+        ("quadcurr_review_decisions_total", "counter", "Decisions on transfers held for review"),
+        ("quadcurr_screening_hits_total", "counter", "Operations blocked by sanctions screening, by operation"),
+        ("quadcurr_rate_limit_delay_seconds", "histogram", "Delay added to transfer requests by rate limiting"),
+        ("quadcurr_userdb_lock_wait_seconds", "histogram", "Time spent waiting for the USERDB lock, by read or write"),
+    ];
+
+    lazy_static! {
+        static ref METRICS_STATE: Mutex<Metrics> = Mutex::new(Metrics::new());
+    }
+
+    /// Label names and values identifying one series of a metric
+    type Labels = Vec<(&'static str, String)>;
+
+    fn labels(labels: &[(&'static str, &str)]) -> Labels {
+        labels.iter().map(|&(name, value)| (name, value.to_owned())).collect()
+    }
+
+    fn seconds(duration: Duration) -> f64 {
+        duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+    }
+
+    struct Histogram {
+        /// Observations in each of `BUCKETS` - not cumulative, unlike when
+        /// rendered
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    }
+    impl Histogram {
+        fn new() -> Histogram {
+            Histogram { counts: vec![0; BUCKETS.len()], sum: 0.0, count: 0 }
+        }
+        fn observe(&mut self, value: f64) {
+            if let Some(bucket) = BUCKETS.iter().position(|&bound| value <= bound) {
+                self.counts[bucket] += 1;
+            }
+            self.sum += value;
+            self.count += 1;
+        }
+    }
+
+    struct Metrics {
+        counters: BTreeMap<&'static str, BTreeMap<Labels, f64>>,
+        histograms: BTreeMap<&'static str, BTreeMap<Labels, Histogram>>,
+    }
+    impl Metrics {
+        fn new() -> Metrics {
+            Metrics { counters: BTreeMap::new(), histograms: BTreeMap::new() }
+        }
+        fn inc(&mut self, name: &'static str, labels: Labels, by: f64) {
+            *self.counters.entry(name).or_insert_with(BTreeMap::new)
+                .entry(labels).or_insert(0.0) += by;
+        }
+        fn observe(&mut self, name: &'static str, labels: Labels, value: f64) {
+            self.histograms.entry(name).or_insert_with(BTreeMap::new)
+                .entry(labels).or_insert_with(Histogram::new).observe(value);
+        }
+        /// Every metric in the Prometheus text format
+        fn render(&self) -> String {
+            let mut out = String::new();
+            for &(name, kind, help) in METRICS {
+                writeln!(out, "# HELP {} {}", name, help).unwrap();
+                writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+                for (labels, value) in self.counters.get(name).into_iter().flat_map(|series| series) {
+                    writeln!(out, "{}{} {}", name, render_labels(labels, None), value).unwrap();
+                }
+                for (labels, histogram) in self.histograms.get(name).into_iter().flat_map(|series| series) {
+                    let mut cumulative = 0;
+                    for (bound, count) in BUCKETS.iter().zip(&histogram.counts) {
+                        cumulative += *count;
+                        writeln!(out, "{}_bucket{} {}", name, render_labels(labels, Some(&bound.to_string())), cumulative).unwrap();
+                    }
+                    writeln!(out, "{}_bucket{} {}", name, render_labels(labels, Some("+Inf")), histogram.count).unwrap();
+                    writeln!(out, "{}_sum{} {}", name, render_labels(labels, None), histogram.sum).unwrap();
+                    writeln!(out, "{}_count{} {}", name, render_labels(labels, None), histogram.count).unwrap();
+                }
+            }
+            out
+        }
+    }
+
+    /// `{name="value",...}`, with an `le` label for histogram buckets
+    fn render_labels(labels: &Labels, le: Option<&str>) -> String {
+        let mut pairs: Vec<String> = labels.iter()
+            .map(|&(name, ref value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect();
+        if let Some(le) = le {
+            pairs.push(format!("le=\"{}\"", le));
+        }
+        if pairs.is_empty() { String::new() } else { format!("{{{}}}", pairs.join(",")) }
+    }
+
+    fn escape(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+    }
+
+    pub fn render() -> String {
+        METRICS_STATE.lock().unwrap().render()
+    }
+
+    /// A transfer of `amount` was paid out
+    pub fn count_transfer(currency: &str, amount: i64) {
+        let mut metrics = METRICS_STATE.lock().unwrap();
+        metrics.inc("quadcurr_transfers_total", labels(&[("currency", currency)]), 1.0);
+        metrics.inc("quadcurr_transfer_volume_total", labels(&[("currency", currency)]), amount as f64);
+    }
+
+    /// A transfer charge of `amount` was collected
+    pub fn count_charge(currency: &str, amount: i64) {
+        METRICS_STATE.lock().unwrap()
+            .inc("quadcurr_transfer_charges_total", labels(&[("currency", currency)]), amount as f64);
+    }
+
+    /// A transfer was flagged by the fraud checker, then `held` for review
+    /// or `rejected`
+    pub fn count_fraud_flag(outcome: &str) {
+        METRICS_STATE.lock().unwrap()
+            .inc("quadcurr_fraud_flags_total", labels(&[("outcome", outcome)]), 1.0);
+    }
+
+    pub fn count_review_decision(approved: bool) {
+        let decision = if approved { "approved" } else { "rejected" };
+        METRICS_STATE.lock().unwrap()
+            .inc("quadcurr_review_decisions_total", labels(&[("decision", decision)]), 1.0);
+    }
+
+    pub fn count_screening_hit(operation: &str) {
+        METRICS_STATE.lock().unwrap()
+            .inc("quadcurr_screening_hits_total", labels(&[("operation", operation)]), 1.0);
+    }
+
+    pub fn observe_rate_limit(delay: Duration) {
+        METRICS_STATE.lock().unwrap()
+            .observe("quadcurr_rate_limit_delay_seconds", vec![], seconds(delay));
+    }
+
+    /// Time spent waiting for the `USERDB` lock, in `read` or `write` mode
+    pub fn observe_lock_wait(mode: &str, wait: Duration) {
+        METRICS_STATE.lock().unwrap()
+            .observe("quadcurr_userdb_lock_wait_seconds", labels(&[("mode", mode)]), seconds(wait));
+    }
+
+    /// Handler wrapper which counts and times every request, by the path of
+    /// its route. Requests for paths without a route are all counted
+    /// together, so they can't create endless series.
+    pub struct Metered<H>(pub H);
+    impl<H: Handler> Handler for Metered<H> {
+        fn handle(&self, req: &mut Request) -> IronResult<Response> {
+            let start = Instant::now();
+            let result = self.0.handle(req);
+            let elapsed = start.elapsed();
+            let (route, status) = match result {
+                Ok(ref resp) => (format!("/{}", req.url.path().join("/")), resp.status),
+                Err(ref err) if err.error.is::<NoRoute>() || err.error.is::<TrailingSlash>() =>
+                    ("unmatched".to_owned(), err.response.status),
+                Err(ref err) => (format!("/{}", req.url.path().join("/")), err.response.status),
+            };
+            observe_request(&route, status.map(|status| status.to_u16()), elapsed);
+            result
+        }
+    }
+
+    /// Count a request to `route` answered with `status` after `elapsed`
+    pub fn observe_request(route: &str, status: Option<u16>, elapsed: Duration) {
+        let status = status.map_or("none".to_owned(), |status| status.to_string());
+        let mut metrics = METRICS_STATE.lock().unwrap();
+        metrics.inc("quadcurr_http_requests_total", labels(&[("route", route), ("status", &status)]), 1.0);
+        metrics.observe("quadcurr_http_request_duration_seconds", labels(&[("route", route)]), seconds(elapsed));
+    }
+
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics::new();
+        metrics.inc("quadcurr_transfers_total", labels(&[("currency", "USD")]), 1.0);
+        metrics.inc("quadcurr_transfers_total", labels(&[("currency", "USD")]), 1.0);
+        metrics.inc("quadcurr_fraud_flags_total", labels(&[("outcome", "a\"b")]), 1.0);
+        metrics.observe("quadcurr_rate_limit_delay_seconds", vec![], 0.02);
+        metrics.observe("quadcurr_rate_limit_delay_seconds", vec![], 20.0);
+        let rendered = metrics.render();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert!(lines.contains(&"# TYPE quadcurr_transfers_total counter"));
+        assert!(lines.contains(&"quadcurr_transfers_total{currency=\"USD\"} 2"));
+        assert!(lines.contains(&"quadcurr_fraud_flags_total{outcome=\"a\\\"b\"} 1"));
+        assert!(lines.contains(&"quadcurr_rate_limit_delay_seconds_bucket{le=\"0.01\"} 0"));
+        assert!(lines.contains(&"quadcurr_rate_limit_delay_seconds_bucket{le=\"0.025\"} 1"));
+        assert!(lines.contains(&"quadcurr_rate_limit_delay_seconds_bucket{le=\"10\"} 1"));
+        assert!(lines.contains(&"quadcurr_rate_limit_delay_seconds_bucket{le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"quadcurr_rate_limit_delay_seconds_count 2"));
+        assert_eq!(lines.iter().filter(|line| line.starts_with("# HELP")).count(), METRICS.len());
+    }
+}
+
+mod screening {
+    use serde_json;
+
+    use std::cmp;
+    use std::fs::{self, File};
+    use std::io::{self, Read, Write};
+    use std::mem;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Mutex, RwLock};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use unicode_normalization::UnicodeNormalization;
+    use unicode_normalization::char::is_combining_mark;
+
+    use super::{metrics, unix_now};
+    use super::review::Decision;
+
+    /// Directory of sanctions lists to screen account holders against. Every
+    /// `.csv` and `.json` file in it is loaded.
+    pub static SANCTIONS_DIR: &'static str = "sanctions";
+
+    /// Compliance cases opened by screening hits, rewritten after every change
+    pub static CASE_STORE: &'static str = "compliance_cases.json";
+
+    /// Names at least this similar to a listed name or alias are hits, where
+    /// 1.0 is identical once normalised
+    const MATCH_THRESHOLD: f64 = 0.85;
+
+    // How often to look for changes to the lists
+    const RELOAD_SECS: u64 = 30;
+
+    lazy_static! {
+        /// Lists currently screened against. Empty, so nobody is blocked,
+        /// until lists are loaded.
+        static ref SANCTIONS: RwLock<SanctionsList> = RwLock::new(SanctionsList::default());
+
+        /// Global case store. Cases are only kept in memory until it's
+        /// opened at startup.
+        static ref CASES: Mutex<CaseStore> = Mutex::new(CaseStore::default());
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct ScreeningError(pub String);
+
+    /// The operation was blocked by a hit, see compliance case `case_id`
+    #[derive(Debug, PartialEq)]
+    pub struct Blocked {
+        pub case_id: u64,
+    }
+
+    /// Someone on a sanctions list
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    pub struct ListEntry {
+        pub id: String,
+        pub name: String,
+        #[serde(default)]
+        pub aliases: Vec<String>,
+    }
+
+    /// A listed name or alias similar enough to a screened name
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Hit {
+        pub entry_id: String,
+        pub entry_name: String,
+        pub score: f64,
+    }
+
+    #[derive(Default)]
+    struct SanctionsList {
+        entries: Vec<ListEntry>,
+        /// Normalised name and aliases of each entry, each along with its
+        /// words sorted
+        names: Vec<Vec<(String, String)>>,
+        /// Files the list was loaded from, with their modification times and
+        /// lengths, to tell when they change
+        files: Vec<(PathBuf, SystemTime, u64)>,
+    }
+    impl SanctionsList {
+        fn new(entries: Vec<ListEntry>, files: Vec<(PathBuf, SystemTime, u64)>) -> SanctionsList {
+            let names = entries.iter()
+                .map(|entry| {
+                    Some(&entry.name).into_iter().chain(&entry.aliases)
+                        .map(|name| normalise(name))
+                        .filter(|name| !name.is_empty())
+                        .map(|name| { let sorted = sort_words(&name); (name, sorted) })
+                        .collect()
+                })
+                .collect();
+            SanctionsList { entries: entries, names: names, files: files }
+        }
+        /// Every entry `name` is a hit for, closest first
+        fn hits(&self, name: &str) -> Vec<Hit> {
+            let name = normalise(name);
+            if name.is_empty() {
+                return vec![]
+            }
+            let sorted = sort_words(&name);
+            let mut hits: Vec<Hit> = self.entries.iter().zip(&self.names)
+                .filter_map(|(entry, names)| {
+                    let score = names.iter()
+                        .map(|&(ref listed, ref listed_sorted)| {
+                            similarity(&name, listed).max(similarity(&sorted, listed_sorted))
+                        })
+                        .fold(0.0, f64::max);
+                    if score >= MATCH_THRESHOLD {
+                        Some(Hit { entry_id: entry.id.clone(), entry_name: entry.name.clone(), score: score })
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+            hits
+        }
+    }
+
+    /// Put a name in a form where differences in case, accents, alphabet and
+    /// punctuation don't matter - lowercase latin letters, digits and single
+    /// spaces, as far as possible
+    pub fn normalise(name: &str) -> String {
+        let mut out = String::new();
+        for c in name.nfd().filter(|&c| !is_combining_mark(c)).flat_map(char::to_lowercase) {
+            match transliterate(c) {
+                Some(latin) => out.push_str(latin),
+                None if c.is_alphanumeric() => out.push(c),
+                None => out.push(' '),
+            }
+        }
+        out.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    /// Latin spelling of letters which don't decompose into a latin letter
+    /// and accents. Expects lowercase letters with accents already removed.
+    fn transliterate(c: char) -> Option<&'static str> {
+        Some(match c {
+            'ß' => "ss", 'æ' => "ae", 'œ' => "oe", 'ø' => "o", 'ł' => "l", 'đ' | 'ð' => "d",
+            'þ' => "th", 'ı' => "i",
+            // Cyrillic
+            'а' => "a", 'б' => "b", 'в' => "v", 'г' | 'ґ' => "g", 'д' => "d", 'е' => "e",
+            'є' => "ye", 'ж' => "zh", 'з' => "z", 'и' | 'і' => "i", 'к' => "k", 'л' => "l",
+            'м' => "m", 'н' => "n", 'о' => "o", 'п' => "p", 'р' => "r", 'с' => "s", 'т' => "t",
+            'у' => "u", 'ф' => "f", 'х' => "kh", 'ц' => "ts", 'ч' => "ch", 'ш' => "sh",
+            'щ' => "shch", 'ъ' | 'ь' => "", 'ы' => "y", 'э' => "e", 'ю' => "yu", 'я' => "ya",
+            // Greek
+            'α' => "a", 'β' => "v", 'γ' => "g", 'δ' => "d", 'ε' => "e", 'ζ' => "z", 'η' => "i",
+            'θ' => "th", 'ι' => "i", 'κ' => "k", 'λ' => "l", 'μ' => "m", 'ν' => "n", 'ξ' => "x",
+            'ο' => "o", 'π' => "p", 'ρ' => "r", 'σ' | 'ς' => "s", 'τ' => "t", 'υ' => "y",
+            'φ' => "f", 'χ' => "ch", 'ψ' => "ps", 'ω' => "o",
+            _ => return None,
+        })
+    }
+
+    fn sort_words(name: &str) -> String {
+        let mut words: Vec<&str> = name.split_whitespace().collect();
+        words.sort();
+        words.join(" ")
+    }
+
+    /// 1.0 minus the edit distance as a fraction of the longer string
+    fn similarity(a: &str, b: &str) -> f64 {
+        let a: Vec<char> = a.chars().collect();
+        let b: Vec<char> = b.chars().collect();
+        let longest = cmp::max(a.len(), b.len());
+        if longest == 0 {
+            return 1.0
+        }
+        1.0 - levenshtein(&a, &b) as f64 / longest as f64
+    }
+
+    fn levenshtein(a: &[char], b: &[char]) -> usize {
+        let mut row: Vec<usize> = (0..b.len() + 1).collect();
+        for (i, ca) in a.iter().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, cb) in b.iter().enumerate() {
+                let above = row[j + 1];
+                row[j + 1] = if ca == cb { diagonal } else { 1 + cmp::min(diagonal, cmp::min(above, row[j])) };
+                diagonal = above;
+            }
+        }
+        row[b.len()]
+    }
+
+    /// Split a csv line into fields, which may be double quoted
+    fn csv_fields(line: &str) -> Result<Vec<String>, String> {
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                },
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(mem::replace(&mut field, String::new())),
+                c => field.push(c),
+            }
+        }
+        if quoted {
+            return Err("unterminated quote".to_owned())
+        }
+        fields.push(field);
+        Ok(fields)
+    }
+
+    /// Read entries from csv with a header line naming the columns. `name` is
+    /// required, `id` and `aliases` (separated by `;`) are optional and any
+    /// other columns are ignored. Entries without an id are identified by
+    /// `source` and their line number.
+    fn parse_csv(contents: &str, source: &str) -> Result<Vec<ListEntry>, String> {
+        let mut lines = contents.lines().enumerate().filter(|&(_, line)| !line.trim().is_empty());
+        let header = match lines.next() {
+            Some((_, line)) => csv_fields(line)?,
+            None => return Ok(vec![]),
+        };
+        let column = |name| header.iter().position(|field| field.trim().eq_ignore_ascii_case(name));
+        let (name_col, id_col, aliases_col) = (column("name"), column("id"), column("aliases"));
+        let name_col = match name_col {
+            Some(col) => col,
+            None => return Err("no name column".to_owned()),
+        };
+        let mut entries = vec![];
+        for (i, line) in lines {
+            let fields = csv_fields(line).map_err(|err| format!("line {}: {}", i + 1, err))?;
+            let field = |col: Option<usize>| col.and_then(|col| fields.get(col)).map_or("", |field| field.trim());
+            let name = field(Some(name_col));
+            if name.is_empty() {
+                return Err(format!("line {}: no name", i + 1))
+            }
+            let id = match field(id_col) {
+                "" => format!("{}:{}", source, i + 1),
+                id => id.to_owned(),
+            };
+            let aliases = field(aliases_col).split(';').map(str::trim)
+                .filter(|alias| !alias.is_empty()).map(str::to_owned).collect();
+            entries.push(ListEntry { id: id, name: name.to_owned(), aliases: aliases });
+        }
+        Ok(entries)
+    }
+
+    /// List files in `dir`, with their modification times and lengths
+    fn list_files(dir: &Path) -> io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("csv") | Some("json") => (),
+                _ => continue,
+            }
+            let metadata = fs::metadata(&path)?;
+            files.push((path, metadata.modified()?, metadata.len()));
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn read_lists(dir: &Path) -> Result<SanctionsList, ScreeningError> {
+        let files = list_files(dir)
+            .map_err(|err| ScreeningError(format!("cannot read {}: {}", dir.display(), err)))?;
+        let mut entries = vec![];
+        for &(ref path, _, _) in &files {
+            let mut contents = String::new();
+            File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+                .map_err(|err| ScreeningError(format!("cannot read {}: {}", path.display(), err)))?;
+            let source = path.file_name().unwrap().to_string_lossy();
+            let file_entries = if path.extension().unwrap() == "csv" {
+                parse_csv(&contents, &source)
+            } else {
+                serde_json::from_str(&contents).map_err(|err| err.to_string())
+            };
+            entries.extend(file_entries.map_err(|err| ScreeningError(format!("{}: {}", path.display(), err)))?);
+        }
+        if let Some(entry) = entries.iter().find(|entry| normalise(&entry.name).is_empty()) {
+            return Err(ScreeningError(format!("entry {} has no name", entry.id)))
+        }
+        Ok(SanctionsList::new(entries, files))
+    }
+
+    /// Load every list in `dir`, replacing the lists screened against. If
+    /// any file can't be loaded the old lists are kept. Returns how many
+    /// entries there are.
+    pub fn load_lists(dir: &str) -> Result<usize, ScreeningError> {
+        let list = read_lists(Path::new(dir))?;
+        let num_entries = list.entries.len();
+        *SANCTIONS.write().unwrap() = list;
+        Ok(num_entries)
+    }
+
+    /// Start the background thread which reloads the lists in `dir` when a
+    /// file is added, removed or changed
+    pub fn start_watcher(dir: &'static str) {
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(RELOAD_SECS));
+                let changed = match list_files(Path::new(dir)) {
+                    Ok(files) => files != SANCTIONS.read().unwrap().files,
+                    Err(_) => false,
+                };
+                if changed {
+                    match load_lists(dir) {
+                        Ok(num_entries) => println!("Reloaded {} sanctions list entries", num_entries),
+                        Err(ScreeningError(err)) => println!("Sanctions lists not reloaded: {}", err),
+                    }
+                }
+            }
+        });
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+    pub enum CaseStatus {
+        Open,
+        /// The name really is the listed person
+        Confirmed,
+        /// The name isn't the listed person, so isn't blocked for this entry
+        /// again
+        FalsePositive,
+    }
+
+    /// A screening hit which blocked an operation
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct ComplianceCase {
+        pub case_id: u64,
+        /// `makeaccount`, `transfer` or `batchtransfer`
+        pub operation: String,
+        /// Name which was screened
+        pub name: String,
+        /// Account held by `name`, unless the hit blocked opening it
+        pub account_id: Option<String>,
+        pub entry_id: String,
+        pub entry_name: String,
+        /// How similar `name` is to the entry, up to 1.0
+        pub score: f64,
+        /// Seconds since the unix epoch
+        pub opened_at: i64,
+        /// Operations blocked by the same hit while the case was open, after
+        /// the first
+        pub repeat_hits: u32,
+        pub status: CaseStatus,
+        /// Set once the status is no longer `Open`
+        pub decision: Option<Decision>,
+    }
+
+    #[derive(Serialize, Deserialize, Default)]
+    struct CaseStore {
+        /// Where to save the store, if anywhere
+        #[serde(skip_serializing, skip_deserializing)]
+        path: Option<PathBuf>,
+        next_id: u64,
+        cases: Vec<ComplianceCase>,
+    }
+    impl CaseStore {
+        /// Open a case for a hit on `name`, or count it against the case
+        /// already open for the same name and entry. Returns the case id.
+        fn open_case(&mut self, operation: &str, name: &str, account_id: Option<String>,
+                     hit: Hit, now: i64) -> u64 {
+            let normalised = normalise(name);
+            if let Some(case) = self.cases.iter_mut().find(|case| {
+                case.status == CaseStatus::Open && case.entry_id == hit.entry_id &&
+                    normalise(&case.name) == normalised
+            }) {
+                case.repeat_hits += 1;
+                return case.case_id
+            }
+            self.next_id += 1;
+            self.cases.push(ComplianceCase {
+                case_id: self.next_id,
+                operation: operation.to_owned(),
+                name: name.to_owned(),
+                account_id: account_id,
+                entry_id: hit.entry_id,
+                entry_name: hit.entry_name,
+                score: hit.score,
+                opened_at: now,
+                repeat_hits: 0,
+                status: CaseStatus::Open,
+                decision: None,
+            });
+            self.next_id
+        }
+        /// Whether a reviewer has decided `name` isn't listed entry `entry_id`
+        fn cleared(&self, name: &str, entry_id: &str) -> bool {
+            let normalised = normalise(name);
+            self.cases.iter().any(|case| {
+                case.status == CaseStatus::FalsePositive && case.entry_id == entry_id &&
+                    normalise(&case.name) == normalised
+            })
+        }
+        fn close(&mut self, case_id: u64, false_positive: bool, decision: Decision) -> Result<(), ScreeningError> {
+            let case = match self.cases.iter_mut().find(|case| case.case_id == case_id) {
+                Some(case) => case,
+                None => return Err(ScreeningError("case does not exist".to_owned())),
+            };
+            if case.status != CaseStatus::Open {
+                return Err(ScreeningError("case already closed".to_owned()))
+            }
+            case.status = if false_positive { CaseStatus::FalsePositive } else { CaseStatus::Confirmed };
+            case.decision = Some(decision);
+            Ok(())
+        }
+        /// Write the store out if it has a path, via a temporary file so a
+        /// crash can't leave it half written
+        fn save(&self) -> io::Result<()> {
+            let path = match self.path {
+                Some(ref path) => path,
+                None => return Ok(()),
+            };
+            let tmp_path = path.with_extension("tmp");
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(serde_json::to_string(self).unwrap().as_bytes())?;
+            file.sync_data()?;
+            fs::rename(&tmp_path, path)
+        }
+    }
+
+    /// Screen `name` before `operation` goes ahead. A hit, other than one
+    /// already decided to be a false positive, opens a compliance case and
+    /// blocks the operation.
+    pub fn check(operation: &str, name: &str, account_id: Option<String>) -> Result<(), Blocked> {
+        let hits = SANCTIONS.read().unwrap().hits(name);
+        if hits.is_empty() {
+            return Ok(())
+        }
+        let mut store = CASES.lock().unwrap();
+        let hit = match hits.into_iter().find(|hit| !store.cleared(name, &hit.entry_id)) {
+            Some(hit) => hit,
+            None => return Ok(()),
+        };
+        let case_id = store.open_case(operation, name, account_id, hit, unix_now());
+        store.save().unwrap();
+        metrics::count_screening_hit(operation);
+        Err(Blocked { case_id: case_id })
+    }
+
+    /// Open the case store at `path`, creating it if needed. Returns the
+    /// number of open cases.
+    pub fn open(path: &str) -> Result<usize, ScreeningError> {
+        let mut store: CaseStore = if Path::new(path).is_file() {
+            let mut contents = String::new();
+            File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+                .map_err(|_| ScreeningError("could not read case store".to_owned()))?;
+            serde_json::from_str(&contents).map_err(|_| ScreeningError("invalid case store".to_owned()))?
+        } else {
+            CaseStore::default()
+        };
+        store.path = Some(PathBuf::from(path));
+        store.save().map_err(|_| ScreeningError("could not write case store".to_owned()))?;
+        let num_open = store.cases.iter().filter(|case| case.status == CaseStatus::Open).count();
+        *CASES.lock().unwrap() = store;
+        Ok(num_open)
+    }
+
+    pub fn open_cases() -> Vec<ComplianceCase> {
+        CASES.lock().unwrap().cases.iter()
+            .filter(|case| case.status == CaseStatus::Open)
+            .cloned()
+            .collect()
+    }
+
+    /// Close case `case_id`, either confirming the hit or clearing the name
+    /// for that entry from then on
+    pub fn close_case(case_id: u64, false_positive: bool, decision: Decision) -> Result<(), ScreeningError> {
+        let mut store = CASES.lock().unwrap();
+        store.close(case_id, false_positive, decision)?;
+        store.save().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_screening() {
+        assert_eq!(normalise("  José  O'Brien-Müller "), "jose o brien muller");
+        assert_eq!(normalise("Łukasz Gößling"), "lukasz gossling");
+        assert_eq!(normalise("Владимир Путин"), "vladimir putin");
+        assert_eq!(normalise("Αλέξης"), "alexis");
+        assert_eq!(similarity("kitten", "sitting"), 1.0 - 3.0 / 7.0);
+
+        let csv = "id,name,aliases,program\n\
+                   X1,\"DOE, John\",Johnny Doe; J. Doe,SDN\n\
+                   \n\
+                   ,Ivan Petrov,,SDN\n";
+        let mut entries = parse_csv(csv, "list.csv").unwrap();
+        assert_eq!(entries[0], ListEntry {
+            id: "X1".to_owned(),
+            name: "DOE, John".to_owned(),
+            aliases: vec!["Johnny Doe".to_owned(), "J. Doe".to_owned()],
+        });
+        assert_eq!(entries[1].id, "list.csv:4");
+        assert_eq!(parse_csv("id\nX1\n", "list.csv"), Err("no name column".to_owned()));
+        assert_eq!(parse_csv("name\n\"Doe\n", "list.csv"), Err("line 2: unterminated quote".to_owned()));
+        entries.extend(serde_json::from_str::<Vec<ListEntry>>(r#"[{"id": "Y1", "name": "Анна Смирнова"}]"#).unwrap());
+        let list = SanctionsList::new(entries, vec![]);
+
+        let hit_ids = |name| -> Vec<String> { list.hits(name).into_iter().map(|hit| hit.entry_id).collect() };
+        // Word order, case, punctuation, a typo and another alphabet
+        assert_eq!(hit_ids("John Doe"), vec!["X1"]);
+        assert_eq!(hit_ids("johnny doe"), vec!["X1"]);
+        assert_eq!(hit_ids("Ivan Petrow"), vec!["list.csv:4"]);
+        assert_eq!(hit_ids("Anna Smirnova"), vec!["Y1"]);
+        assert!(hit_ids("Jane Dodd").is_empty());
+        assert!(hit_ids("").is_empty());
+
+        let mut store = CaseStore::default();
+        let hit = list.hits("John Doe").pop().unwrap();
+        let case_id = store.open_case("makeaccount", "John Doe", None, hit.clone(), 0);
+        assert_eq!(store.open_case("transfer", "JOHN DOE", None, hit.clone(), 1), case_id);
+        assert_eq!(store.cases[0].repeat_hits, 1);
+        assert!(!store.cleared("john doe", "X1"));
+        let decision = || Decision { reviewer: "r".to_owned(), reason: "different person".to_owned(), time: 2 };
+        store.close(case_id, true, decision()).unwrap();
+        assert!(store.cleared("john doe", "X1"));
+        assert_eq!(store.close(case_id, true, decision()), Err(ScreeningError("case already closed".to_owned())));
+        assert_eq!(store.close(case_id + 1, true, decision()), Err(ScreeningError("case does not exist".to_owned())));
+        // A closed case isn't added to
+        assert!(store.open_case("transfer", "John Doe", None, hit, 3) != case_id);
+    }
+}
+
+mod kyc {
+    use chrono::{Datelike, TimeZone, UTC};
+
+    use hyper::Client;
+    use hyper::header::{Connection, ContentType, Headers};
+
+    use serde_json::{self, Value};
+
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io;
+    use std::sync::RwLock;
+    use std::time::Duration;
+
+    use super::{Entry, EntryKind, UserAccount};
+    use super::currency::{self, Amount, CurrencyId};
+
+    /// Config file giving the limits of each verification level, by
+    /// currency
+    pub static KYC_CONFIG: &'static str = "kyc.json";
+
+    // How long to wait for the verification provider to answer
+    const TIMEOUT_SECS: u64 = 30;
+
+    lazy_static! {
+        /// Limits enforced on accounts. Empty, so nothing is limited, unless
+        /// there's a kyc config.
+        static ref LIMITS: RwLock<LimitTable> = RwLock::new(LimitTable::default());
+
+        /// Provider checking evidence submitted to `/kyc/verify`, if any
+        static ref PROVIDER: RwLock<Option<Box<VerificationProvider>>> = RwLock::new(None);
+    }
+
+    /// How far the identity of an account's holder has been verified. Every
+    /// account starts `Unverified`.
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Level {
+        Unverified,
+        /// Name, address and date of birth checked
+        Basic,
+        /// Identity documents checked too
+        Full,
+    }
+    impl Level {
+        const ALL: [Level; 3] = [Level::Unverified, Level::Basic, Level::Full];
+
+        pub fn name(&self) -> &'static str {
+            match *self {
+                Level::Unverified => "unverified",
+                Level::Basic => "basic",
+                Level::Full => "full",
+            }
+        }
+        pub fn parse(name: &str) -> Option<Level> {
+            Level::ALL.iter().cloned().find(|level| level.name() == name)
+        }
+    }
+
+    /// Limits on accounts at one level in one currency, in base units. None
+    /// is unlimited.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct Limits {
+        /// Most the account can hold
+        pub balance: Option<i64>,
+        /// Most the account can send in one transfer
+        pub transfer: Option<i64>,
+        /// Most the account can send in a calendar month (UTC)
+        pub monthly_volume: Option<i64>,
+    }
+
+    /// Limits of one level as written in the config, in base units or as
+    /// decimals
+    #[derive(Deserialize, Default)]
+    struct LimitsConfig {
+        #[serde(default)]
+        balance: Option<Amount>,
+        #[serde(default)]
+        transfer: Option<Amount>,
+        #[serde(default)]
+        monthly_volume: Option<Amount>,
+    }
+
+    /// Limits of each level in a currency. Levels left out are unlimited.
+    #[derive(Deserialize)]
+    struct CurrencyLimits {
+        #[serde(default)]
+        unverified: LimitsConfig,
+        #[serde(default)]
+        basic: LimitsConfig,
+        #[serde(default)]
+        full: LimitsConfig,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct KycConfigError(pub &'static str);
+    impl From<io::Error> for KycConfigError {
+        fn from(_: io::Error) -> KycConfigError {
+            KycConfigError("cannot read kyc config")
+        }
+    }
+    impl From<serde_json::Error> for KycConfigError {
+        fn from(_: serde_json::Error) -> KycConfigError {
+            KycConfigError("invalid kyc config")
+        }
+    }
+
+    /// Limit an operation would go over
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Limit {
+        /// The account's own balance, by a deposit
+        Balance,
+        /// The balance of the account paid, by a transfer
+        RecipientBalance,
+        Transfer,
+        MonthlyVolume,
+    }
+
+    /// An operation went over `limit`, which accounts at level `needed` (the
+    /// lowest level allowing it) aren't held to
+    #[derive(Debug, PartialEq)]
+    pub struct KycError {
+        pub limit: Limit,
+        /// None if no level allows it
+        pub needed: Option<Level>,
+    }
+    impl KycError {
+        /// What went over which limit, and the verification needed to raise it
+        pub fn message(&self) -> &'static str {
+            match (self.limit, self.needed) {
+                (Limit::Balance, Some(Level::Basic)) =>
+                    "deposit would go over the account's balance limit, basic verification is needed",
+                (Limit::Balance, Some(Level::Full)) =>
+                    "deposit would go over the account's balance limit, full verification is needed",
+                (Limit::Balance, _) =>
+                    "deposit would go over the balance limit of every verification level",
+                (Limit::RecipientBalance, Some(Level::Basic)) =>
+                    "transfer would go over account_to's balance limit, it needs basic verification",
+                (Limit::RecipientBalance, Some(Level::Full)) =>
+                    "transfer would go over account_to's balance limit, it needs full verification",
+                (Limit::RecipientBalance, _) =>
+                    "transfer would go over the balance limit of every verification level",
+                (Limit::Transfer, Some(Level::Basic)) =>
+                    "amount is over account_from's transfer limit, basic verification is needed",
+                (Limit::Transfer, Some(Level::Full)) =>
+                    "amount is over account_from's transfer limit, full verification is needed",
+                (Limit::Transfer, _) =>
+                    "amount is over the transfer limit of every verification level",
+                (Limit::MonthlyVolume, Some(Level::Basic)) =>
+                    "transfer would go over account_from's monthly limit, basic verification is needed",
+                (Limit::MonthlyVolume, Some(Level::Full)) =>
+                    "transfer would go over account_from's monthly limit, full verification is needed",
+                (Limit::MonthlyVolume, _) =>
+                    "transfer would go over the monthly limit of every verification level",
+            }
+        }
+    }
+
+    /// Limits of each level, indexed by level, in every limited currency
+    #[derive(Default)]
+    struct LimitTable {
+        limits: HashMap<CurrencyId, [Limits; 3]>,
+    }
+    impl LimitTable {
+        fn limits(&self, currency: CurrencyId, level: Level) -> Limits {
+            self.limits.get(&currency).map_or(Limits::default(), |levels| levels[level as usize])
+        }
+        /// Error for `acct` going over `limit` by reaching `value`, if it does
+        fn check<F>(&self, acct: &UserAccount, limit: Limit, value: i64, max: F) -> Result<(), KycError>
+                where F: Fn(&Limits) -> Option<i64> {
+            let allows = |level| max(&self.limits(acct.currency, level)).map_or(true, |max| value <= max);
+            if allows(acct.kyc_level) {
+                return Ok(())
+            }
+            let needed = Level::ALL.iter().cloned().find(|&level| level > acct.kyc_level && allows(level));
+            Err(KycError { limit: limit, needed: needed })
+        }
+        fn deposit(&self, acct: &UserAccount, amount: i64) -> Result<(), KycError> {
+            self.check(acct, Limit::Balance, acct.balance.saturating_add(amount), |limits| limits.balance)
+        }
+        fn receive(&self, acct: &UserAccount, amount: i64) -> Result<(), KycError> {
+            self.check(acct, Limit::RecipientBalance, acct.balance.saturating_add(amount), |limits| limits.balance)
+        }
+        fn transfer(&self, acct: &UserAccount, amount: i64) -> Result<(), KycError> {
+            self.check(acct, Limit::Transfer, amount, |limits| limits.transfer)
+        }
+        fn monthly(&self, acct: &UserAccount, amount: i64, now: i64) -> Result<(), KycError> {
+            let sent = sent_since(&acct.history, month_start(now)).saturating_add(amount);
+            self.check(acct, Limit::MonthlyVolume, sent, |limits| limits.monthly_volume)
+        }
+    }
+
+    /// Start of the calendar month (UTC) `time` is in
+    pub fn month_start(time: i64) -> i64 {
+        let time = UTC.timestamp(time, 0);
+        UTC.ymd(time.year(), time.month(), 1).and_hms(0, 0, 0).timestamp()
+    }
+
+    /// Money sent from an account with `history` since `since` - transfers,
+    /// including those held for review or in escrow, less any returned.
+    /// Only returns of money sent since `since` are taken off, so returns
+    /// of earlier holds and escrows can't make room for more. Transfer
+    /// charges don't count.
+    pub fn sent_since(history: &[Entry], since: i64) -> i64 {
+        let in_window = history.iter().rev().take_while(|entry| entry.time >= since).count();
+        // Sent since `since` and not yet returned, by the account it went to
+        let mut unreturned: HashMap<Option<usize>, i64> = HashMap::new();
+        let mut sent = 0i64;
+        for entry in &history[history.len() - in_window..] {
+            match entry.kind {
+                EntryKind::TransferOut | EntryKind::Hold | EntryKind::Escrow => {
+                    let out = unreturned.entry(entry.counterparty).or_insert(0);
+                    *out = out.saturating_sub(entry.amount);
+                    sent = sent.saturating_sub(entry.amount);
+                },
+                EntryKind::Release | EntryKind::EscrowRefund => {
+                    let out = unreturned.entry(entry.counterparty).or_insert(0);
+                    let returned = entry.amount.min(*out);
+                    *out -= returned;
+                    sent -= returned;
+                },
+                _ => (),
+            }
+        }
+        sent
+    }
+
+    /// Load the kyc config, replacing any limits already loaded. It maps
+    /// currency names to the limits of each level. Currencies must be
+    /// loaded first. Returns how many currencies are limited.
+    pub fn load_limits(path: &str) -> Result<usize, KycConfigError> {
+        let config: HashMap<String, CurrencyLimits> = serde_json::from_reader(File::open(path)?)?;
+        let mut table = LimitTable::default();
+        for (name, levels) in config {
+            let detail = match currency::lookup_currency(&name) {
+                Some(detail) => detail,
+                None => return Err(KycConfigError("kyc config names an unknown currency")),
+            };
+            let minor = |amount: &Option<Amount>| match *amount {
+                Some(ref amount) => amount.minor_units(&detail).map(Some).map_err(KycConfigError),
+                None => Ok(None),
+            };
+            let mut limits = [Limits::default(); 3];
+            for (limits, config) in limits.iter_mut().zip(&[levels.unverified, levels.basic, levels.full]) {
+                *limits = Limits {
+                    balance: minor(&config.balance)?,
+                    transfer: minor(&config.transfer)?,
+                    monthly_volume: minor(&config.monthly_volume)?,
+                };
+            }
+            table.limits.insert(detail.id, limits);
+        }
+        let num_currencies = table.limits.len();
+        *LIMITS.write().unwrap() = table;
+        Ok(num_currencies)
+    }
+
+    /// Limits on accounts at `level` in `currency`
+    pub fn limits(currency: CurrencyId, level: Level) -> Limits {
+        LIMITS.read().unwrap().limits(currency, level)
+    }
+
+    /// Check a deposit of `amount` keeps `acct` within its balance limit
+    pub fn check_deposit(acct: &UserAccount, amount: i64) -> Result<(), KycError> {
+        LIMITS.read().unwrap().deposit(acct, amount)
+    }
+
+    /// Check `acct_from` can send `amount` at `now`, and `acct_to` can be
+    /// paid it
+    pub fn check_transfer(acct_from: &UserAccount, acct_to: &UserAccount,
+                          amount: i64, now: i64) -> Result<(), KycError> {
+        let limits = LIMITS.read().unwrap();
+        limits.transfer(acct_from, amount)?;
+        limits.monthly(acct_from, amount, now)?;
+        limits.receive(acct_to, amount)
+    }
+
+    /// Check `acct` can send a single transfer of `amount`, for batches
+    /// whose items are checked one by one
+    pub fn check_transfer_size(acct: &UserAccount, amount: i64) -> Result<(), KycError> {
+        LIMITS.read().unwrap().transfer(acct, amount)
+    }
+
+    /// Check `acct` can send `amount` more this month as of `now`
+    pub fn check_monthly_volume(acct: &UserAccount, amount: i64, now: i64) -> Result<(), KycError> {
+        LIMITS.read().unwrap().monthly(acct, amount, now)
+    }
+
+    /// Check `acct` can be paid `amount`
+    pub fn check_receive(acct: &UserAccount, amount: i64) -> Result<(), KycError> {
+        LIMITS.read().unwrap().receive(acct, amount)
+    }
+
+    /// Checks evidence of who an account holder is, such as an external
+    /// identity verification service
+    pub trait VerificationProvider: Send + Sync {
+        /// Recorded against the levels it verifies accounts to
+        fn name(&self) -> String;
+        /// Level `evidence` verifies the holder of `account_id` to, or why
+        /// it couldn't be checked
+        fn verify(&self, account_id: &str, holder_name: Option<&str>, evidence: &Value) -> Result<Level, String>;
+    }
+
+    /// Check evidence with `provider` from now on
+    pub fn set_provider(provider: Box<VerificationProvider>) {
+        *PROVIDER.write().unwrap() = Some(provider);
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum VerifyError {
+        NoProvider,
+        /// The provider couldn't check the evidence
+        Failed(String),
+    }
+
+    /// `VerificationProvider::verify` with the provider set, along with its
+    /// name
+    pub fn verify(account_id: &str, holder_name: Option<&str>,
+                  evidence: &Value) -> Result<(String, Level), VerifyError> {
+        match *PROVIDER.read().unwrap() {
+            Some(ref provider) => provider.verify(account_id, holder_name, evidence)
+                .map(|level| (provider.name(), level))
+                .map_err(VerifyError::Failed),
+            None => Err(VerifyError::NoProvider),
+        }
+    }
+
+    /// Provider posting evidence as json to a verification service at
+    /// `url`, which answers with the level, e.g. `{"level": "basic"}`
+    pub struct HttpProvider {
+        url: String,
+        client: Client,
+    }
+    impl HttpProvider {
+        pub fn new(url: String) -> HttpProvider {
+            let mut client = Client::new();
+            client.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+            HttpProvider { url: url, client: client }
+        }
+    }
+
+    #[derive(Serialize)]
+    struct VerifyRequest<'a> {
+        account_id: &'a str,
+        holder_name: Option<&'a str>,
+        evidence: &'a Value,
+    }
+    #[derive(Deserialize)]
+    struct VerifyResponse {
+        level: String,
+    }
+
+    impl VerificationProvider for HttpProvider {
+        fn name(&self) -> String {
+            format!("provider {}", self.url)
+        }
+        fn verify(&self, account_id: &str, holder_name: Option<&str>, evidence: &Value) -> Result<Level, String> {
+            let body = serde_json::to_string(&VerifyRequest {
+                account_id: account_id,
+                holder_name: holder_name,
+                evidence: evidence,
+            }).unwrap();
+            let mut headers = Headers::new();
+            headers.set(ContentType::json());
+            headers.set(Connection::close());
+            let resp = match self.client.post(&self.url[..]).headers(headers).body(&body[..]).send() {
+                Ok(ref resp) if !resp.status.is_success() => return Err(format!("provider responded {}", resp.status)),
+                Ok(resp) => resp,
+                Err(err) => return Err(err.to_string()),
+            };
+            let reply: VerifyResponse = serde_json::from_reader(resp).map_err(|_| "invalid provider response".to_owned())?;
+            Level::parse(&reply.level).ok_or_else(|| "provider gave an unknown level".to_owned())
+        }
+    }
+
+    #[test]
+    fn test_kyc() {
+        use std::i64;
+        use super::currency::FAKE_CURRENCY;
 
-        // Transfer is validated, let's go!
-        let currency_detail = currency_detail.unwrap();
-        let charge_amount = f64::ceil(currency_detail.transfer_charge/100.0 * amount as f64) as i64;
-        let charge_account = currency_detail.transfer_charge_account;
+        let limits = |balance, transfer, monthly_volume| Limits {
+            balance: balance,
+            transfer: transfer,
+            monthly_volume: monthly_volume,
+        };
+        let mut table = LimitTable::default();
+        table.limits.insert(FAKE_CURRENCY, [limits(Some(1000), Some(100), Some(300)),
+                                            limits(Some(10000), Some(1000), None),
+                                            limits(None, None, None)]);
 
-        userdb.get_mut(obj.account_from).unwrap().balance -= amount + charge_amount;
-        userdb.get_mut(obj.account_to).unwrap().balance += amount;
-        userdb.get_mut(charge_account).unwrap().balance += charge_amount;
-        resp!(Ok, "")
+        let entry = |time, kind, amount| Entry { time: time, kind: kind, amount: amount, counterparty: None, balance: 0 };
+        let now = UTC.ymd(2017, 3, 15).and_hms(12, 0, 0).timestamp();
+        let start = month_start(now);
+        assert_eq!(start, UTC.ymd(2017, 3, 1).and_hms(0, 0, 0).timestamp());
+        let mut acct = UserAccount::with_currency(FAKE_CURRENCY);
+        acct.balance = 900;
+        acct.history = vec![
+            entry(start - 1, EntryKind::TransferOut, -100),
+            entry(start, EntryKind::TransferOut, -100),
+            entry(start, EntryKind::Charge, -2),
+            entry(start + 1, EntryKind::Hold, -90),
+            entry(start + 2, EntryKind::Release, 90),
+            entry(start + 3, EntryKind::Escrow, -50),
+            entry(start + 4, EntryKind::TransferIn, 500),
+        ];
+        assert_eq!(sent_since(&acct.history, start), 150);
+        // Returns of money sent last month don't count against this month's
+        let returned_late = vec![entry(start - 1, EntryKind::Escrow, -100), entry(start, EntryKind::EscrowRefund, 100)];
+        assert_eq!(sent_since(&returned_late, start), 0);
+
+        assert_eq!(table.deposit(&acct, 100), Ok(()));
+        assert_eq!(table.deposit(&acct, 101), Err(KycError { limit: Limit::Balance, needed: Some(Level::Basic) }));
+        assert_eq!(table.transfer(&acct, 101), Err(KycError { limit: Limit::Transfer, needed: Some(Level::Basic) }));
+        assert_eq!(table.monthly(&acct, 100, now), Ok(()));
+        assert_eq!(table.monthly(&acct, 151, now), Err(KycError { limit: Limit::MonthlyVolume, needed: Some(Level::Basic) }));
+        // Last month's sending doesn't count next month
+        assert_eq!(table.monthly(&acct, 300, now + 31 * 24 * 60 * 60), Ok(()));
+        assert_eq!(table.receive(&acct, 20000), Err(KycError { limit: Limit::RecipientBalance, needed: Some(Level::Full) }));
+
+        acct.kyc_level = Level::Basic;
+        assert_eq!(table.monthly(&acct, 100000, now), Ok(()));
+        assert_eq!(table.transfer(&acct, 1001), Err(KycError { limit: Limit::Transfer, needed: Some(Level::Full) }));
+        acct.kyc_level = Level::Full;
+        assert_eq!(table.deposit(&acct, i64::MAX), Ok(()));
+
+        // Unlimited currencies stay unlimited, even unverified
+        let mut other = UserAccount::with_currency(CurrencyId(1000));
+        other.balance = 900;
+        assert_eq!(table.deposit(&other, 100000), Ok(()));
+        assert_eq!(table.transfer(&other, 100000), Ok(()));
+
+        table.limits.insert(FAKE_CURRENCY, [limits(None, Some(100), None); 3]);
+        acct.kyc_level = Level::Unverified;
+        let err = table.transfer(&acct, 101).unwrap_err();
+        assert_eq!(err.needed, None);
+        assert_eq!(err.message(), "amount is over the transfer limit of every verification level");
+        assert_eq!(KycError { limit: Limit::Balance, needed: Some(Level::Full) }.message(),
+                   "deposit would go over the account's balance limit, full verification is needed");
+
+        assert_eq!(Level::parse("basic"), Some(Level::Basic));
+        assert_eq!(Level::parse("Basic"), None);
+        assert!(Level::Unverified < Level::Basic && Level::Basic < Level::Full);
     }
 }
 
 mod fraud {
+    use futures::Future;
+    use futures::sync::oneshot;
+
     use super::UserAccount;
 
     use std::mem;
@@ -261,7 +8894,7 @@ mod fraud {
     use std::thread;
 
     type FraudCheckResult = (UserAccount, Option<FraudError>);
-    type FraudCheckRequest = (SyncSender<FraudCheckResult>, UserAccount);
+    type FraudCheckRequest = (oneshot::Sender<FraudCheckResult>, UserAccount);
 
     #[derive(Clone)]
     pub struct FraudChecker {
@@ -269,15 +8902,23 @@ mod fraud {
     }
     impl FraudChecker {
         pub fn check(&self, acct: &mut UserAccount) -> Option<FraudError> {
-            let (tx, rx) = sync_channel(0);
+            let (tx, rx) = oneshot::channel();
             self.tx.send((tx, mem::replace(acct, UserAccount::fakeacct()))).unwrap();
-            let (checked_acct, err) = rx.recv().unwrap();
+            let (checked_acct, err) = rx.wait().unwrap();
             *acct = checked_acct;
             err
         }
+        /// `check` for the async server, which resolves once `acct` has been
+        /// checked rather than blocking
+        pub fn check_async(&self, acct: UserAccount) -> oneshot::Receiver<FraudCheckResult> {
+            let (tx, rx) = oneshot::channel();
+            self.tx.send((tx, acct)).unwrap();
+            rx
+        }
     }
 
-    pub struct FraudError(String);
+    /// Reason an account looks fraudulent
+    pub struct FraudError(pub String);
 
     // Start the fraud checker on first use
     lazy_static! {
@@ -288,10 +8929,16 @@ mod fraud {
         let (tx, rx) = sync_channel::<FraudCheckRequest>(0);
         thread::spawn(move || {
             while let Ok((tx, acct)) = rx.recv() {
-                // TODO: query external fraud checking service, perform our own analysis
-                // and send back the summary
-                thread::sleep(Duration::new(0, 500_000_000)); // 0.5s placeholder delay
-                tx.send((acct, None)).unwrap()
+                // Check each account on its own thread, so checks for
+                // unrelated transfers don't queue up behind each other
+                thread::spawn(move || {
+                    // TODO: query external fraud checking service, perform our own analysis
+                    // and send back the summary
+                    thread::sleep(Duration::new(0, 500_000_000)); // 0.5s placeholder delay
+                    // Whoever asked may have gone away, e.g. if the client
+                    // of the async server disconnected
+                    let _ = tx.send((acct, None));
+                });
             }
         });
         FraudChecker { tx: tx }
@@ -302,70 +8949,1079 @@ mod fraud {
     }
 }
 
+mod tenants {
+    use iron::{Handler, typemap};
+    use iron::prelude::{IronResult, Request, Response};
+    use iron::status;
+
+    use serde_json;
+
+    use std::collections::HashSet;
+    use std::fs::File;
+    use std::io;
+    use std::sync::RwLock;
+
+    use super::UserDB;
+    use super::currency;
+    use super::review::constant_time_eq;
+
+    /// Config file listing tenants, their api tokens and currencies
+    pub static TENANT_CONFIG: &'static str = "tenants.json";
+
+    /// Header a tenant's requests give its api token in
+    pub static TOKEN_HEADER: &'static str = "X-QuadCurr-Tenant-Token";
+
+    /// Shortest tenant or operator token accepted, to stop trivially
+    /// guessable ones
+    const MIN_TOKEN_LEN: usize = 16;
+
+    /// Paths which can be requested without a token even once there are
+    /// tenants, for health checks and monitoring
+    static OPEN_PATHS: &'static [&'static str] = &["/healthz", "/readyz", "/metrics"];
+
+    lazy_static! {
+        /// Tenants hosted here, empty unless there's a tenant config
+        static ref TENANTS: RwLock<Vec<Tenant>> = RwLock::new(vec![]);
+        /// Token the operator's requests give once there are tenants, set
+        /// from `QUADCURR_OPERATOR_TOKEN`
+        static ref OPERATOR_TOKEN: RwLock<Option<String>> = RwLock::new(None);
+    }
+
+    /// A merchant platform hosted here. It has its own namespace of
+    /// accounts, which only requests made with its token can name, and its
+    /// own transfer charge account in each of its currencies. Requests made
+    /// with the operator's token, or without any while there are no
+    /// tenants, are the operator's, and can name any account.
+    #[derive(Deserialize, Clone, Debug)]
+    struct Tenant {
+        /// Name of the tenant, kept with each of its accounts
+        id: String,
+        /// Secret the tenant's requests authenticate with
+        token: String,
+        /// Names of the currencies its accounts can be opened in
+        currencies: Vec<String>,
+        /// Other tenants its accounts can send money to. Transfers between
+        /// tenants aren't allowed unless listed here.
+        #[serde(default)]
+        transfers_to: Vec<String>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct TenantError(pub &'static str);
+    impl From<io::Error> for TenantError {
+        fn from(_: io::Error) -> TenantError {
+            TenantError("cannot read tenant config")
+        }
+    }
+    impl From<serde_json::Error> for TenantError {
+        fn from(_: serde_json::Error) -> TenantError {
+            TenantError("invalid tenant config")
+        }
+    }
+
+    /// Check ids and tokens are set and unique, and that every tenant paid
+    /// by another exists. Ids are letters, digits and dashes, so they can
+    /// go in ledger account names.
+    fn validate(tenants: &[Tenant]) -> Result<(), TenantError> {
+        if tenants.iter().any(|t| t.id.is_empty() || t.token.len() < MIN_TOKEN_LEN) {
+            return Err(TenantError("tenant ids and tokens must be set"))
+        }
+        let valid_id = |id: &str| id.starts_with(|c: char| c.is_ascii_alphanumeric()) &&
+            id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !tenants.iter().all(|t| valid_id(&t.id)) {
+            return Err(TenantError("tenant ids must be letters, digits and dashes"))
+        }
+        let mut ids = HashSet::new();
+        let mut tokens = HashSet::new();
+        for tenant in tenants {
+            if !ids.insert(&tenant.id) || !tokens.insert(&tenant.token) {
+                return Err(TenantError("duplicate tenant id or token"))
+            }
+        }
+        if tenants.iter().flat_map(|t| &t.transfers_to).any(|to| !ids.contains(to)) {
+            return Err(TenantError("tenant transfers_to names an unknown tenant"))
+        }
+        Ok(())
+    }
+
+    /// Load the tenant config, replacing any tenants already loaded.
+    /// Returns how many tenants there are.
+    pub fn load_tenants(path: &str) -> Result<usize, TenantError> {
+        let tenants: Vec<Tenant> = serde_json::from_reader(File::open(path)?)?;
+        validate(&tenants)?;
+        let num_tenants = tenants.len();
+        *TENANTS.write().unwrap() = tenants;
+        Ok(num_tenants)
+    }
+
+    /// Provision a transfer charge account for every tenant in each of its
+    /// currencies, unless it already has one. Currencies must be loaded
+    /// first. Returns how many were provisioned.
+    pub fn provision(userdb: &mut UserDB) -> Result<usize, TenantError> {
+        let tenants = TENANTS.read().unwrap().clone();
+        let mut provisioned = 0;
+        for tenant in tenants {
+            for name in &tenant.currencies {
+                let id = match currency::lookup_currency(name) {
+                    Some(detail) => detail.id,
+                    None => return Err(TenantError("tenant currency does not exist")),
+                };
+                match currency::provision_tenant(userdb, &tenant.id, id) {
+                    Ok(true) => provisioned += 1,
+                    Ok(false) => (),
+                    Err(currency::CurrencyError(msg)) => return Err(TenantError(msg)),
+                }
+            }
+        }
+        Ok(provisioned)
+    }
+
+    /// Set the token the operator's requests have to give once there are
+    /// tenants. Must be called after `load_tenants`, and only checked if
+    /// there are any.
+    pub fn set_operator_token(token: Option<String>) -> Result<(), TenantError> {
+        let tenants = TENANTS.read().unwrap();
+        if tenants.is_empty() {
+            return Ok(())
+        }
+        match token {
+            Some(ref token) if token.len() < MIN_TOKEN_LEN =>
+                return Err(TenantError("operator token must be at least as long as tenant tokens")),
+            Some(ref token) if tenants.iter().any(|t| t.token == *token) =>
+                return Err(TenantError("operator token must differ from every tenant's")),
+            Some(_) => (),
+            None => return Err(TenantError("operator token must be set once there are tenants")),
+        }
+        *OPERATOR_TOKEN.write().unwrap() = token;
+        Ok(())
+    }
+
+    /// Work out who made a request for `path` with `token` in its
+    /// `TOKEN_HEADER`: a tenant, or none for the operator. Once there are
+    /// tenants the operator has to give its own token, and requests without
+    /// one are turned away unless they're for one of `OPEN_PATHS`.
+    pub fn identify(token: Option<String>, path: &str) -> Result<Option<String>, &'static str> {
+        identify_among(&TENANTS.read().unwrap(), &OPERATOR_TOKEN.read().unwrap(), token, path)
+    }
+
+    fn identify_among(tenants: &[Tenant], operator: &Option<String>, token: Option<String>,
+                      path: &str) -> Result<Option<String>, &'static str> {
+        let token = match token {
+            Some(token) => token,
+            None if tenants.is_empty() || OPEN_PATHS.contains(&path) => return Ok(None),
+            None => return Err("tenant or operator token required"),
+        };
+        if operator.as_ref().map_or(false, |operator| constant_time_eq(operator.as_bytes(), token.as_bytes())) {
+            return Ok(None)
+        }
+        match tenants.iter().find(|t| constant_time_eq(t.token.as_bytes(), token.as_bytes())) {
+            Some(tenant) => Ok(Some(tenant.id.clone())),
+            None => Err("unknown tenant token"),
+        }
+    }
+
+    /// Whether accounts of `tenant` can be opened in currency
+    /// `currency_name`. The operator's can be opened in any.
+    pub fn offers(tenant: &Option<String>, currency_name: &str) -> bool {
+        match *tenant {
+            Some(ref id) => TENANTS.read().unwrap().iter()
+                .any(|t| t.id == *id && t.currencies.iter().any(|name| name == currency_name)),
+            None => true,
+        }
+    }
+
+    fn pays(tenants: &[Tenant], from: &Option<String>, to: &Option<String>) -> bool {
+        match (from, to) {
+            _ if from == to => true,
+            (&Some(ref from), &Some(ref to)) =>
+                tenants.iter().any(|t| t.id == *from && t.transfers_to.contains(to)),
+            _ => false,
+        }
+    }
+
+    /// Whether accounts of tenant `from` can send money to accounts of
+    /// tenant `to`. Accounts can always pay others in the same namespace,
+    /// but the operator's and tenants' can never pay each other.
+    pub fn may_pay(from: &Option<String>, to: &Option<String>) -> bool {
+        pays(&TENANTS.read().unwrap(), from, to)
+    }
+
+    struct TenantKey;
+    impl typemap::Key for TenantKey {
+        type Value = String;
+    }
+
+    /// Tenant a request handled by `Tenanted` was made by, or none if it's
+    /// the operator's
+    pub fn tenant(req: &Request) -> Option<String> {
+        req.extensions.get::<TenantKey>().cloned()
+    }
+
+    /// Token in the `TOKEN_HEADER` of a request, if it has one
+    pub fn token(raw: Option<&[u8]>) -> Option<String> {
+        raw.map(|raw| String::from_utf8_lossy(raw).trim().to_owned())
+    }
+
+    /// Handler wrapper which works out the tenant making each request from
+    /// its token, see `tenant` and `identify`. Requests with a token which
+    /// isn't the operator's or any tenant's are turned away, as are ones
+    /// without a token once there are tenants.
+    pub struct Tenanted<H>(pub H);
+    impl<H: Handler> Handler for Tenanted<H> {
+        fn handle(&self, req: &mut Request) -> IronResult<Response> {
+            let token = token(req.headers.get_raw(TOKEN_HEADER).and_then(|raw| raw.first()).map(|raw| &raw[..]));
+            let path = format!("/{}", req.url.path().join("/"));
+            match identify(token, &path) {
+                Ok(Some(tenant)) => { req.extensions.insert::<TenantKey>(tenant); },
+                Ok(None) => (),
+                Err(msg) => return Ok(Response::with((status::Unauthorized, msg))),
+            }
+            self.0.handle(req)
+        }
+    }
+
+    #[test]
+    fn test_tenants() {
+        use super::UserAccount;
+        use super::currency::FAKE_CURRENCY;
+
+        let tenant = |id: &str, token: &str, transfers_to: &[&str]| Tenant {
+            id: id.to_owned(),
+            token: token.to_owned(),
+            currencies: vec!["USD".to_owned()],
+            transfers_to: transfers_to.iter().map(|&to| to.to_owned()).collect(),
+        };
+        let tenants = vec![tenant("acme", "acmetoken-0123456789", &["globex"]),
+                           tenant("globex", "globextoken-0123456789", &[])];
+        assert_eq!(validate(&tenants), Ok(()));
+        assert!(validate(&[tenant("acme", "short", &[])]).is_err());
+        assert!(validate(&[tenant("", "acmetoken-0123456789", &[])]).is_err());
+        assert!(validate(&[tenant("-acme", "acmetoken-0123456789", &[])]).is_err());
+        assert!(validate(&[tenant("acme corp", "acmetoken-0123456789", &[])]).is_err());
+        assert!(validate(&[tenants[0].clone(), tenant("acme", "othertoken-0123456789", &[])]).is_err());
+        assert!(validate(&[tenants[1].clone(), tenant("initech", "globextoken-0123456789", &[])]).is_err());
+        assert!(validate(&tenants[..1]).is_err());
+
+        let (acme, globex, initech) = (Some("acme".to_owned()), Some("globex".to_owned()), Some("initech".to_owned()));
+        assert!(pays(&tenants, &acme, &acme) && pays(&tenants, &None, &None));
+        assert!(pays(&tenants, &acme, &globex));
+        assert!(!pays(&tenants, &globex, &acme));
+        assert!(!pays(&tenants, &initech, &acme));
+        assert!(!pays(&tenants, &acme, &None) && !pays(&tenants, &None, &acme));
+
+        // Once there are tenants the operator needs its own token too
+        let operator = Some("operatortoken-0123456789".to_owned());
+        let who = |tenants: &[Tenant], token: &str, path| {
+            let token = if token.is_empty() { None } else { Some(token.to_owned()) };
+            identify_among(tenants, &operator, token, path)
+        };
+        assert_eq!(who(&tenants, "acmetoken-0123456789", "/transfer"), Ok(acme.clone()));
+        assert_eq!(who(&tenants, "operatortoken-0123456789", "/transfer"), Ok(None));
+        assert_eq!(who(&tenants, "", "/transfer"), Err("tenant or operator token required"));
+        assert_eq!(who(&tenants, "", "/healthz"), Ok(None));
+        assert_eq!(who(&tenants, "acmetoken", "/healthz"), Err("unknown tenant token"));
+        assert_eq!(who(&[], "", "/transfer"), Ok(None));
+        assert_eq!(who(&[], "operatortoken-0123456789", "/transfer"), Ok(None));
+
+        let mut userdb = UserDB::new();
+        let mut ids = vec![];
+        for tenant in &[acme.clone(), acme.clone(), globex.clone(), None] {
+            let mut acct = UserAccount::with_currency(FAKE_CURRENCY);
+            acct.tenant = tenant.clone();
+            ids.push(userdb.addacct(acct).unwrap().to_string());
+        }
+        let found: Vec<_> = ids.iter().map(|id| userdb.lookup(&acme, id)).collect();
+        assert_eq!(found, vec![Some(0), Some(1), None, None]);
+        let found: Vec<_> = ids.iter().map(|id| userdb.lookup(&None, id)).collect();
+        assert_eq!(found, vec![Some(0), Some(1), Some(2), Some(3)]);
+        // No tenants are loaded, so none can pay another
+        assert_eq!(userdb.lookup_payee(0, &ids[1]), Some(1));
+        assert_eq!(userdb.lookup_payee(0, &ids[2]), None);
+        assert_eq!(userdb.lookup_payee(0, &ids[3]), None);
+        assert_eq!(userdb.lookup_payee(3, &ids[0]), None);
+    }
+}
+
+
 mod currency {
-    use super::{FAKE_CHARGE_ACCT, EUR_CHARGE_ACCT, GBP_CHARGE_ACCT, USD_CHARGE_ACCT};
+    use serde::de::{self, Deserialize, Deserializer, Visitor};
+    use serde_json;
+
+    use std::collections::{HashMap, HashSet};
+    use std::fmt;
+    use std::fs::File;
+    use std::i64;
+    use std::io;
+    use std::sync::RwLock;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::{UserAccount, UserDB, UserDBError};
+    use super::audit;
+    use super::events;
+    use super::interest;
 
     /// Minimum base units of currency permitted to be transferred
     pub const MINIMUM_TRANSFER_AMOUNT: i64 = 50;
 
-    /// Global table of currency details
-    static CURRENCIES: &'static [CurrencyDetail] = &[
-        CurrencyDetail {
-            name: "EUR",
-            overdraft_limit: 10,
-            transfer_charge: 1.0,
-            transfer_charge_account: EUR_CHARGE_ACCT,
-        },
-        CurrencyDetail {
-            name: "GBP",
-            overdraft_limit: 10,
-            transfer_charge: 1.0,
-            transfer_charge_account: GBP_CHARGE_ACCT,
-        },
-        CurrencyDetail {
-            name: "USD",
-            overdraft_limit: 5,
-            transfer_charge: 2.0,
-            transfer_charge_account: USD_CHARGE_ACCT,
-        },
-    ];
+    /// Config file listing the currencies to load at startup
+    pub static CURRENCY_CONFIG: &'static str = "currencies.json";
 
     /// Sentinel value for currency, api users cannot touch this
-    pub static FAKE_CURRENCY: CurrencyDetail = CurrencyDetail {
-        name: "FAKE",
-        overdraft_limit: 0,
-        transfer_charge: -1.0,
-        transfer_charge_account: FAKE_CHARGE_ACCT,
-    };
+    pub const FAKE_CURRENCY: CurrencyId = CurrencyId(0);
+
+    lazy_static! {
+        /// Global table of currency details
+        static ref CURRENCIES: RwLock<CurrencyTable> = RwLock::new(CurrencyTable::new());
+    }
 
+    /// Set once `load_currencies` has loaded every configured currency
+    static CURRENCIES_LOADED: AtomicBool = AtomicBool::new(false);
 
-    #[derive(Debug)]
+    /// Stable identifier for a currency, fixed by the currency config
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    pub struct CurrencyId(pub u32);
+
+    /// Most decimal places a currency can have, as 10^18 minor units still
+    /// fit in an i64
+    pub const MAX_EXPONENT: u32 = 18;
+
+    /// How amounts are written in a locale
+    struct Locale {
+        /// Language subtag of the locale, e.g. `de` for `de-DE` and `de-AT`
+        language: &'static str,
+        group_separator: &'static str,
+        decimal_separator: &'static str,
+        /// Whether the symbol follows the amount after a space, rather than
+        /// coming straight before it
+        symbol_after: bool,
+    }
+
+    /// Locales amounts can be formatted for, the first being the default
+    const LOCALES: &'static [Locale] = &[
+        Locale { language: "en", group_separator: ",", decimal_separator: ".", symbol_after: false },
+        Locale { language: "ja", group_separator: ",", decimal_separator: ".", symbol_after: false },
+        Locale { language: "de", group_separator: ".", decimal_separator: ",", symbol_after: true },
+        Locale { language: "fr", group_separator: " ", decimal_separator: ",", symbol_after: true },
+    ];
+
+    fn find_locale(tag: &str) -> Option<&'static Locale> {
+        let language = tag.split('-').next().unwrap();
+        LOCALES.iter().find(|locale| locale.language == language)
+    }
+
+    fn default_locale() -> String {
+        "en".to_owned()
+    }
+
+    /// A currency as written in the currency config
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct CurrencyConfig {
+        /// See CurrencyDetail
+        pub id: CurrencyId,
+        /// See CurrencyDetail
+        pub name: String,
+        /// See CurrencyDetail
+        pub overdraft_limit: u64,
+        /// See CurrencyDetail
+        pub transfer_charge: f64,
+        /// See CurrencyDetail. Currencies added before this was configurable
+        /// count in whole units.
+        #[serde(default)]
+        pub exponent: u32,
+        /// See CurrencyDetail
+        #[serde(default)]
+        pub symbol: String,
+        /// See CurrencyDetail
+        #[serde(default = "default_locale")]
+        pub locale: String,
+    }
+    impl CurrencyConfig {
+        /// This config with how amounts are written taken from `detail`,
+        /// to compare the fields which change what amounts mean
+        fn without_presentation(&self, detail: &CurrencyDetail) -> CurrencyConfig {
+            CurrencyConfig {
+                exponent: detail.exponent,
+                symbol: detail.symbol.clone(),
+                locale: detail.locale.clone(),
+                ..self.clone()
+            }
+        }
+        /// Check the currency makes sense by itself, ignoring other currencies
+        fn validate(&self) -> Result<(), CurrencyError> {
+            if self.id == FAKE_CURRENCY {
+                return Err(CurrencyError("currency id is reserved"))
+            }
+            if self.name.is_empty() || !self.name.bytes().all(|b| b'A' <= b && b <= b'Z') {
+                return Err(CurrencyError("currency name must be uppercase letters"))
+            }
+            if self.overdraft_limit > i64::MAX as u64 {
+                return Err(CurrencyError("overdraft limit too large"))
+            }
+            // Written this way round so NaN is rejected too
+            if !(self.transfer_charge >= 0.0 && self.transfer_charge <= 100.0) {
+                return Err(CurrencyError("transfer charge must be a percentage"))
+            }
+            if self.exponent > MAX_EXPONENT {
+                return Err(CurrencyError("exponent too large"))
+            }
+            if self.symbol.chars().any(|c| c.is_numeric() || c.is_whitespace()) {
+                return Err(CurrencyError("symbol must not contain digits or spaces"))
+            }
+            if find_locale(&self.locale).is_none() {
+                return Err(CurrencyError("locale not supported"))
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Debug)]
     pub struct CurrencyDetail {
+        /// Stable id of the currency
+        pub id: CurrencyId,
         /// Name used to identify the currency
-        pub name: &'static str,
+        pub name: String,
         /// Overdraft limit for this currency
         pub overdraft_limit: u64,
         /// Percentage charge when transferring money
         pub transfer_charge: f64,
-        /// Account to deposit transfer charges into
+        /// System account to deposit transfer charges into
         pub transfer_charge_account: usize,
+        /// System account holding funds for transfers awaiting review
+        pub review_hold_account: usize,
+        /// Yearly interest rate on positive balances, in basis points
+        pub interest_rate_bps: u32,
+        /// System account interest is paid from, provisioned when a rate is
+        /// first set
+        pub interest_expense_account: Option<usize>,
+        /// Decimal places of the major unit, e.g. 2 for USD (counted in
+        /// cents) or 0 for JPY. Amounts are always in minor units, this only
+        /// changes how they're written and read as decimals.
+        pub exponent: u32,
+        /// Written with formatted amounts, or if empty, the name is
+        pub symbol: String,
+        /// Locale tag, e.g. `en-US`, deciding how formatted amounts are
+        /// written - only the language matters
+        pub locale: String,
+    }
+    impl CurrencyDetail {
+        /// Charge for transferring `amount`, rounded up to the next base unit
+        pub fn transfer_charge_for(&self, amount: i64) -> i64 {
+            f64::ceil(self.transfer_charge/100.0 * amount as f64) as i64
+        }
+        /// The config this currency was added with
+        pub fn config(&self) -> CurrencyConfig {
+            CurrencyConfig {
+                id: self.id,
+                name: self.name.clone(),
+                overdraft_limit: self.overdraft_limit,
+                transfer_charge: self.transfer_charge,
+                exponent: self.exponent,
+                symbol: self.symbol.clone(),
+                locale: self.locale.clone(),
+            }
+        }
+        /// Sign, whole major units and the minor units left over
+        fn split(&self, amount: i64) -> (&'static str, u64, u64) {
+            let scale = 10u64.pow(self.exponent);
+            let sign = if amount < 0 { "-" } else { "" };
+            let magnitude = amount.unsigned_abs();
+            (sign, magnitude / scale, magnitude % scale)
+        }
+        /// `amount` minor units as a plain decimal of major units, e.g.
+        /// `-1234.50`, for formats which want a number
+        pub fn decimal(&self, amount: i64) -> String {
+            match self.split(amount) {
+                (sign, major, _) if self.exponent == 0 => format!("{}{}", sign, major),
+                (sign, major, minor) => format!("{}{}.{:0width$}", sign, major, minor, width = self.exponent as usize),
+            }
+        }
+        /// `amount` minor units written for people in the currency's locale,
+        /// e.g. `-$1,234.50` or `-1.234,50 €`
+        pub fn format(&self, amount: i64) -> String {
+            let locale = find_locale(&self.locale).unwrap_or(&LOCALES[0]);
+            let (sign, major, minor) = self.split(amount);
+            let digits = major.to_string();
+            let mut number = String::new();
+            for (i, digit) in digits.chars().enumerate() {
+                if i > 0 && (digits.len() - i) % 3 == 0 {
+                    number.push_str(locale.group_separator);
+                }
+                number.push(digit);
+            }
+            if self.exponent > 0 {
+                number += &format!("{}{:0width$}", locale.decimal_separator, minor, width = self.exponent as usize);
+            }
+            match (&self.symbol[..], locale.symbol_after) {
+                ("", false) => format!("{}{} {}", sign, self.name, number),
+                (symbol, false) => format!("{}{}{}", sign, symbol, number),
+                ("", true) => format!("{}{} {}", sign, number, self.name),
+                (symbol, true) => format!("{}{} {}", sign, number, symbol),
+            }
+        }
+        /// Read `text`, a decimal of major units such as `12.50`, as minor
+        /// units. It can't have more decimal places than the currency, so
+        /// nothing is ever rounded.
+        pub fn parse_decimal(&self, text: &str) -> Result<i64, &'static str> {
+            let (major, minor) = match text.find('.') {
+                Some(pos) => (&text[..pos], &text[pos + 1..]),
+                None => (text, ""),
+            };
+            let is_digits = |part: &str| part.bytes().all(|b| b'0' <= b && b <= b'9');
+            if major.is_empty() || !is_digits(major) || !is_digits(minor) || text.ends_with('.') {
+                return Err("amount must be a decimal like 12.50")
+            }
+            if minor.len() > self.exponent as usize {
+                return Err("amount has more decimal places than the currency")
+            }
+            let scale = 10i64.pow(self.exponent);
+            let minor_scale = 10i64.pow(self.exponent - minor.len() as u32);
+            let minor = if minor.is_empty() { 0 } else { minor.parse::<i64>().unwrap() * minor_scale };
+            major.parse::<i64>().ok()
+                .and_then(|major| major.checked_mul(scale))
+                .and_then(|major| major.checked_add(minor))
+                .filter(|&amount| amount < i64::MAX)
+                .ok_or("amount too large")
+        }
+    }
+
+    /// An amount in a request - either a number of minor units, as amounts
+    /// have always been given, or a string with a decimal of major units
+    /// such as `"12.50"`. Which currency it's in depends on the request.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Amount {
+        Minor(u64),
+        Decimal(String),
+    }
+    impl Amount {
+        /// The amount in minor units of the currency `detail`
+        pub fn minor_units(&self, detail: &CurrencyDetail) -> Result<i64, &'static str> {
+            match *self {
+                Amount::Minor(amount) if amount < i64::MAX as u64 => Ok(amount as i64),
+                Amount::Minor(_) => Err("amount too large"),
+                Amount::Decimal(ref text) => detail.parse_decimal(text),
+            }
+        }
+    }
+    impl Deserialize for Amount {
+        fn deserialize<D: Deserializer>(deserializer: D) -> Result<Amount, D::Error> {
+            struct AmountVisitor;
+            impl Visitor for AmountVisitor {
+                type Value = Amount;
+                fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                    formatter.write_str("a number of minor units or a decimal string")
+                }
+                fn visit_u64<E: de::Error>(self, amount: u64) -> Result<Amount, E> {
+                    Ok(Amount::Minor(amount))
+                }
+                fn visit_str<E: de::Error>(self, text: &str) -> Result<Amount, E> {
+                    Ok(Amount::Decimal(text.to_owned()))
+                }
+            }
+            deserializer.deserialize(AmountVisitor)
+        }
     }
 
-    /// Currencies are always static, so just compare the pointers
+    /// Currency ids are unique, so just compare those
     impl PartialEq for CurrencyDetail {
         fn eq(&self, other: &Self) -> bool {
-            self as *const _ == other as *const _
+            self.id == other.id
         }
     }
     impl Eq for CurrencyDetail {}
 
-    pub fn lookup_currency(currency_name: &str) -> Option<&'static CurrencyDetail> {
-        CURRENCIES.iter().find(|cur| currency_name == cur.name)
+    #[derive(Debug)]
+    pub struct CurrencyError(pub &'static str);
+    impl From<io::Error> for CurrencyError {
+        fn from(_: io::Error) -> CurrencyError {
+            CurrencyError("cannot read currency config")
+        }
+    }
+    impl From<serde_json::Error> for CurrencyError {
+        fn from(_: serde_json::Error) -> CurrencyError {
+            CurrencyError("invalid currency config")
+        }
+    }
+    impl From<events::EventError> for CurrencyError {
+        fn from(_: events::EventError) -> CurrencyError {
+            CurrencyError("could not write the event log")
+        }
+    }
+    impl From<UserDBError> for CurrencyError {
+        fn from(_: UserDBError) -> CurrencyError {
+            CurrencyError("could not write the event log")
+        }
+    }
+
+    pub struct CurrencyTable {
+        currencies: Vec<CurrencyDetail>,
+        /// Transfer charge account of each tenant in each of its currencies,
+        /// used instead of the currency's for transfers from its accounts
+        tenant_charge_accounts: HashMap<(String, CurrencyId), usize>,
+        /// Charge accounts no longer taking transfer charges
+        disabled_charge_accounts: HashSet<usize>,
+    }
+    impl CurrencyTable {
+        pub fn new() -> CurrencyTable {
+            CurrencyTable {
+                currencies: vec![],
+                tenant_charge_accounts: HashMap::new(),
+                disabled_charge_accounts: HashSet::new(),
+            }
+        }
+        /// Add a currency, provisioning new system accounts in `userdb` to
+        /// receive its transfer charges (separate by currency for tax
+        /// reasons) and hold transfers under review. Returns the index of the
+        /// charge account.
+        fn add(&mut self, userdb: &mut UserDB, config: CurrencyConfig) -> Result<usize, CurrencyError> {
+            config.validate()?;
+            if self.currencies.iter().any(|cur| cur.id == config.id || cur.name == config.name) {
+                return Err(CurrencyError("currency already exists"))
+            }
+            let charge_acct = userdb.addsysacct(UserAccount::with_currency(config.id))?;
+            let hold_acct = userdb.addsysacct(UserAccount::with_currency(config.id))?;
+            events::record(userdb, events::Change::AddCurrency {
+                config: config.clone(),
+                charge_account: charge_acct,
+                hold_account: hold_acct,
+            })?;
+            self.restore(config, charge_acct, hold_acct)?;
+            Ok(charge_acct)
+        }
+        /// Add a currency whose system accounts already exist, without
+        /// recording an event - for replaying the event log
+        pub fn restore(&mut self, config: CurrencyConfig, charge_acct: usize,
+                       hold_acct: usize) -> Result<(), CurrencyError> {
+            config.validate()?;
+            if self.currencies.iter().any(|cur| cur.id == config.id || cur.name == config.name) {
+                return Err(CurrencyError("currency already exists"))
+            }
+            self.currencies.push(CurrencyDetail {
+                id: config.id,
+                name: config.name,
+                overdraft_limit: config.overdraft_limit,
+                transfer_charge: config.transfer_charge,
+                transfer_charge_account: charge_acct,
+                review_hold_account: hold_acct,
+                interest_rate_bps: 0,
+                interest_expense_account: None,
+                exponent: config.exponent,
+                symbol: config.symbol,
+                locale: config.locale,
+            });
+            Ok(())
+        }
+        /// Set the interest rate of a currency, provisioning a system account
+        /// to pay interest from the first time
+        fn set_interest_rate(&mut self, userdb: &mut UserDB, id: CurrencyId,
+                             rate_bps: u32) -> Result<(), CurrencyError> {
+            if rate_bps > interest::MAX_RATE_BPS {
+                return Err(CurrencyError("interest rate too high"))
+            }
+            let expense_acct = match self.get(id) {
+                Some(detail) => detail.interest_expense_account,
+                None => return Err(CurrencyError("currency does not exist")),
+            };
+            let expense_acct = match expense_acct {
+                Some(acct) => acct,
+                None => userdb.addsysacct(UserAccount::with_currency(id))?,
+            };
+            events::record(userdb, events::Change::SetCurrencyInterest {
+                currency: id,
+                rate_bps: rate_bps,
+                expense_account: expense_acct,
+            })?;
+            self.restore_interest_rate(id, rate_bps, expense_acct)
+        }
+        /// Change how a currency's amounts are written to match `config`,
+        /// which must be the same currency otherwise
+        fn set_presentation(&mut self, userdb: &UserDB, config: CurrencyConfig) -> Result<(), CurrencyError> {
+            let presented = self.presented(config.id, config.exponent, config.symbol.clone(), config.locale.clone())?;
+            events::record(userdb, events::Change::SetCurrencyPresentation {
+                currency: config.id,
+                exponent: config.exponent,
+                symbol: config.symbol,
+                locale: config.locale,
+            })?;
+            self.present(presented);
+            Ok(())
+        }
+        /// `set_presentation`, without recording an event
+        pub fn restore_presentation(&mut self, id: CurrencyId, exponent: u32, symbol: String,
+                                    locale: String) -> Result<(), CurrencyError> {
+            let presented = self.presented(id, exponent, symbol, locale)?;
+            self.present(presented);
+            Ok(())
+        }
+        /// The config of currency `id` with its amounts written as `exponent`,
+        /// `symbol` and `locale` say, checked it's valid
+        fn presented(&self, id: CurrencyId, exponent: u32, symbol: String,
+                     locale: String) -> Result<CurrencyConfig, CurrencyError> {
+            let detail = match self.get(id) {
+                Some(detail) => detail,
+                None => return Err(CurrencyError("currency does not exist")),
+            };
+            let config = CurrencyConfig {
+                exponent: exponent,
+                symbol: symbol,
+                locale: locale,
+                ..detail.config()
+            };
+            config.validate()?;
+            Ok(config)
+        }
+        fn present(&mut self, config: CurrencyConfig) {
+            if let Some(detail) = self.currencies.iter_mut().find(|cur| cur.id == config.id) {
+                detail.exponent = config.exponent;
+                detail.symbol = config.symbol;
+                detail.locale = config.locale;
+            }
+        }
+        /// `set_interest_rate` with an existing expense account, without
+        /// recording an event
+        pub fn restore_interest_rate(&mut self, id: CurrencyId, rate_bps: u32,
+                                     expense_acct: usize) -> Result<(), CurrencyError> {
+            match self.currencies.iter_mut().find(|cur| cur.id == id) {
+                Some(detail) => {
+                    detail.interest_rate_bps = rate_bps;
+                    detail.interest_expense_account = Some(expense_acct);
+                    Ok(())
+                },
+                None => Err(CurrencyError("currency does not exist")),
+            }
+        }
+        /// Provision a system account to receive the transfer charges of
+        /// `tenant`'s accounts in currency `id`, kept apart from other
+        /// tenants' and the operator's. Returns its index.
+        fn add_tenant_charge_account(&mut self, userdb: &mut UserDB, tenant: &str,
+                                     id: CurrencyId) -> Result<usize, CurrencyError> {
+            if self.get(id).is_none() {
+                return Err(CurrencyError("currency does not exist"))
+            }
+            if self.tenant_charge_accounts.contains_key(&(tenant.to_owned(), id)) {
+                return Err(CurrencyError("tenant already has a charge account for the currency"))
+            }
+            let charge_acct = userdb.addsysacct(UserAccount::with_currency(id))?;
+            events::record(userdb, events::Change::AddTenantChargeAccount {
+                tenant: tenant.to_owned(),
+                currency: id,
+                charge_account: charge_acct,
+            })?;
+            self.restore_tenant_charge_account(tenant.to_owned(), id, charge_acct)?;
+            Ok(charge_acct)
+        }
+        /// `add_tenant_charge_account` with an existing account, without
+        /// recording an event
+        pub fn restore_tenant_charge_account(&mut self, tenant: String, id: CurrencyId,
+                                             charge_acct: usize) -> Result<(), CurrencyError> {
+            if self.get(id).is_none() {
+                return Err(CurrencyError("currency does not exist"))
+            }
+            if self.tenant_charge_accounts.insert((tenant, id), charge_acct).is_some() {
+                return Err(CurrencyError("tenant already has a charge account for the currency"))
+            }
+            Ok(())
+        }
+        /// Stop taking transfer charges into charge account `charge_acct`, or
+        /// start again. Transfers whose charge would go to a disabled charge
+        /// account are made free of charge.
+        fn set_charge_account_enabled(&mut self, userdb: &mut UserDB, charge_acct: usize,
+                                      enabled: bool) -> Result<(), CurrencyError> {
+            if !self.is_charge_account(charge_acct) {
+                return Err(CurrencyError("not a charge account"))
+            }
+            events::record(userdb, events::Change::SetChargeAccountEnabled {
+                charge_account: charge_acct,
+                enabled: enabled,
+            })?;
+            self.restore_charge_account_enabled(charge_acct, enabled)
+        }
+        /// `set_charge_account_enabled` without recording an event
+        pub fn restore_charge_account_enabled(&mut self, charge_acct: usize, enabled: bool) -> Result<(), CurrencyError> {
+            if !self.is_charge_account(charge_acct) {
+                return Err(CurrencyError("not a charge account"))
+            }
+            if enabled {
+                self.disabled_charge_accounts.remove(&charge_acct);
+            } else {
+                self.disabled_charge_accounts.insert(charge_acct);
+            }
+            Ok(())
+        }
+        fn is_charge_account(&self, acct: usize) -> bool {
+            self.currencies.iter().any(|cur| cur.transfer_charge_account == acct) ||
+                self.tenant_charge_accounts.values().any(|&charge_acct| charge_acct == acct)
+        }
+        pub fn details(&self) -> &[CurrencyDetail] {
+            &self.currencies
+        }
+        /// Every tenant charge account, by tenant and currency
+        pub fn tenant_charge_accounts(&self) -> &HashMap<(String, CurrencyId), usize> {
+            &self.tenant_charge_accounts
+        }
+        fn lookup(&self, currency_name: &str) -> Option<&CurrencyDetail> {
+            self.currencies.iter().find(|cur| cur.name == currency_name)
+        }
+        fn get(&self, id: CurrencyId) -> Option<&CurrencyDetail> {
+            self.currencies.iter().find(|cur| cur.id == id)
+        }
+    }
+
+    /// Read the currency config at `path`, checking every currency is valid
+    /// and that no id or name is used twice
+    pub fn read_config(path: &str) -> Result<Vec<CurrencyConfig>, CurrencyError> {
+        let configs: Vec<CurrencyConfig> = serde_json::from_reader(File::open(path)?)?;
+        let mut ids = HashSet::new();
+        let mut names = HashSet::new();
+        for config in &configs {
+            config.validate()?;
+            if !ids.insert(config.id) {
+                return Err(CurrencyError("duplicate currency id"))
+            }
+            if !names.insert(&config.name) {
+                return Err(CurrencyError("duplicate currency name"))
+            }
+        }
+        Ok(configs)
+    }
+
+    /// Load every currency in the config at `path`, recording each in the
+    /// audit log as loaded by `caller`. Currencies already restored from the
+    /// event log are left alone, but must not have changed.
+    pub fn load_currencies(userdb: &mut UserDB, path: &str, caller: &str) -> Result<(), CurrencyError> {
+        for config in read_config(path)? {
+            println!("Loading currency: {}", config.name);
+            let body = serde_json::to_vec(&config).unwrap();
+            let result = match get_currency(config.id) {
+                Some(ref detail) if detail.config() == config => Ok("already loaded"),
+                Some(ref detail) if detail.config() == config.without_presentation(detail) =>
+                    CURRENCIES.write().unwrap().set_presentation(userdb, config).map(|_| "presentation updated"),
+                Some(_) => Err(CurrencyError("currency differs from the one in the event log")),
+                None => add_currency(userdb, config).map(|_| "loaded"),
+            };
+            let outcome = match result {
+                Ok(outcome) => outcome,
+                Err(CurrencyError(msg)) => msg,
+            };
+            if audit::record(caller, "loadcurrency", &body, outcome).is_err() {
+                return Err(CurrencyError("cannot write audit log"))
+            }
+            result?;
+        }
+        CURRENCIES_LOADED.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn currencies_loaded() -> bool {
+        CURRENCIES_LOADED.load(Ordering::SeqCst)
+    }
+
+    /// Add a currency to the global table, see `CurrencyTable::add`
+    pub fn add_currency(userdb: &mut UserDB, config: CurrencyConfig) -> Result<usize, CurrencyError> {
+        CURRENCIES.write().unwrap().add(userdb, config)
+    }
+
+    pub fn lookup_currency(currency_name: &str) -> Option<CurrencyDetail> {
+        CURRENCIES.read().unwrap().lookup(currency_name).cloned()
+    }
+
+    pub fn get_currency(id: CurrencyId) -> Option<CurrencyDetail> {
+        CURRENCIES.read().unwrap().get(id).cloned()
+    }
+
+    /// `get_currency`, but with transfers charged to `tenant`'s charge
+    /// account for the currency if it has one, for transfers from its
+    /// accounts, and free of charge if that charge account is disabled
+    pub fn tenant_currency(id: CurrencyId, tenant: &Option<String>) -> Option<CurrencyDetail> {
+        let currencies = CURRENCIES.read().unwrap();
+        let charge_acct = tenant.as_ref()
+            .and_then(|tenant| currencies.tenant_charge_accounts.get(&(tenant.clone(), id)).cloned());
+        currencies.get(id).map(|detail| {
+            let charge_acct = charge_acct.unwrap_or(detail.transfer_charge_account);
+            let disabled = currencies.disabled_charge_accounts.contains(&charge_acct);
+            CurrencyDetail {
+                transfer_charge: if disabled { 0.0 } else { detail.transfer_charge },
+                transfer_charge_account: charge_acct,
+                ..detail.clone()
+            }
+        })
+    }
+
+    /// Disable or re-enable the charge account of currency `currency_name`,
+    /// or `tenant`'s charge account in it, see
+    /// `CurrencyTable::set_charge_account_enabled`
+    pub fn set_charge_account_enabled(userdb: &mut UserDB, currency_name: &str, tenant: &Option<String>,
+                                      enabled: bool) -> Result<(), CurrencyError> {
+        let mut currencies = CURRENCIES.write().unwrap();
+        let (id, charge_acct) = match currencies.lookup(currency_name) {
+            Some(detail) => (detail.id, detail.transfer_charge_account),
+            None => return Err(CurrencyError("currency does not exist")),
+        };
+        let charge_acct = match *tenant {
+            Some(ref tenant) => match currencies.tenant_charge_accounts.get(&(tenant.clone(), id)) {
+                Some(&acct) => acct,
+                None => return Err(CurrencyError("tenant has no charge account for the currency")),
+            },
+            None => charge_acct,
+        };
+        currencies.set_charge_account_enabled(userdb, charge_acct, enabled)
+    }
+
+    /// Provision `tenant`'s charge account for currency `id` in the global
+    /// table, unless it has one. Returns whether it was provisioned.
+    pub fn provision_tenant(userdb: &mut UserDB, tenant: &str, id: CurrencyId) -> Result<bool, CurrencyError> {
+        let mut currencies = CURRENCIES.write().unwrap();
+        if currencies.tenant_charge_accounts.contains_key(&(tenant.to_owned(), id)) {
+            return Ok(false)
+        }
+        currencies.add_tenant_charge_account(userdb, tenant, id).map(|_| true)
+    }
+
+    /// Set the interest rate of currency `currency_name`, see
+    /// `CurrencyTable::set_interest_rate`
+    pub fn set_interest_rate(userdb: &mut UserDB, currency_name: &str, rate_bps: u32) -> Result<(), CurrencyError> {
+        let mut currencies = CURRENCIES.write().unwrap();
+        let id = match currencies.lookup(currency_name) {
+            Some(detail) => detail.id,
+            None => return Err(CurrencyError("currency does not exist")),
+        };
+        currencies.set_interest_rate(userdb, id, rate_bps)
+    }
+
+    pub fn all_currencies() -> Vec<CurrencyDetail> {
+        CURRENCIES.read().unwrap().currencies.clone()
+    }
+
+    /// Change the global table in place, for events streamed from the
+    /// leader. The caller must hold the `USERDB` lock.
+    pub fn update_currencies<T, F: FnOnce(&mut CurrencyTable) -> T>(f: F) -> T {
+        f(&mut CURRENCIES.write().unwrap())
+    }
+
+    /// Swap in a table rebuilt from the event log
+    pub fn replace_currencies(table: CurrencyTable) {
+        *CURRENCIES.write().unwrap() = table;
+    }
+
+    /// US dollars charging `transfer_charge` percent into `charge_acct`,
+    /// which also holds transfers under review
+    #[cfg(test)]
+    pub fn test_usd(transfer_charge: f64, charge_acct: usize) -> CurrencyDetail {
+        CurrencyDetail {
+            id: CurrencyId(1),
+            name: "USD".to_owned(),
+            overdraft_limit: 0,
+            transfer_charge: transfer_charge,
+            transfer_charge_account: charge_acct,
+            review_hold_account: charge_acct,
+            interest_rate_bps: 0,
+            interest_expense_account: None,
+            exponent: 2,
+            symbol: "$".to_owned(),
+            locale: "en-US".to_owned(),
+        }
+    }
+
+    #[cfg(test)]
+    fn test_table() -> (CurrencyTable, UserDB) {
+        let mut table = CurrencyTable::new();
+        let mut userdb = UserDB::new();
+        for config in read_config(CURRENCY_CONFIG).unwrap() {
+            table.add(&mut userdb, config).unwrap();
+        }
+        (table, userdb)
     }
 
     #[test]
     fn test_lookup() {
-        assert!(lookup_currency("EUR").is_some());
-        assert!(lookup_currency("FAKE").is_none());
+        let (table, _) = test_table();
+        assert!(table.lookup("EUR").is_some());
+        assert!(table.lookup("FAKE").is_none());
+        assert!(table.get(FAKE_CURRENCY).is_none());
+    }
+
+    #[test]
+    fn test_add_currency() {
+        fn config(id: u32, name: &str, transfer_charge: f64) -> CurrencyConfig {
+            CurrencyConfig {
+                id: CurrencyId(id),
+                name: name.to_owned(),
+                overdraft_limit: 0,
+                transfer_charge: transfer_charge,
+                exponent: 0,
+                symbol: String::new(),
+                locale: default_locale(),
+            }
+        }
+        let (mut table, mut userdb) = test_table();
+        let charge_acct = table.add(&mut userdb, config(100, "JPY", 1.0)).unwrap();
+        let jpy = table.lookup("JPY").unwrap();
+        assert_eq!(jpy.transfer_charge_account, charge_acct);
+        assert_eq!(userdb.get(charge_acct).unwrap().currency, jpy.id);
+        assert_eq!(userdb.get(jpy.review_hold_account).unwrap().currency, jpy.id);
+        assert!(jpy.review_hold_account != charge_acct);
+        assert!(userdb.public_ids.values().all(|&idx| idx != charge_acct));
+        assert!(table.add(&mut userdb, config(100, "CHF", 1.0)).is_err());
+        assert!(table.add(&mut userdb, config(101, "JPY", 1.0)).is_err());
+        assert!(table.add(&mut userdb, config(0, "CHF", 1.0)).is_err());
+        assert!(table.add(&mut userdb, config(101, "CHF", -1.0)).is_err());
+        assert!(table.add(&mut userdb, CurrencyConfig { exponent: MAX_EXPONENT + 1, ..config(101, "CHF", 1.0) }).is_err());
+        assert!(table.add(&mut userdb, CurrencyConfig { locale: "xx-XX".to_owned(), ..config(101, "CHF", 1.0) }).is_err());
+
+        // Logs written before amounts had decimals can take them on later
+        let jpy = table.lookup("JPY").unwrap().clone();
+        let decimals = CurrencyConfig { exponent: 2, symbol: "¥".to_owned(), ..config(100, "JPY", 1.0) };
+        assert_eq!(decimals.without_presentation(&jpy), jpy.config());
+        assert!(config(100, "JPY", 2.0).without_presentation(&jpy) != jpy.config());
+        table.set_presentation(&userdb, decimals.clone()).unwrap();
+        assert_eq!(table.lookup("JPY").unwrap().config(), decimals);
+        assert!(table.restore_presentation(CurrencyId(100), 0, String::new(), "xx-XX".to_owned()).is_err());
+        assert!(table.restore_presentation(CurrencyId(999), 0, String::new(), default_locale()).is_err());
+
+        let tenant_acct = table.add_tenant_charge_account(&mut userdb, "acme", CurrencyId(100)).unwrap();
+        assert!(tenant_acct != charge_acct && userdb.public_ids.values().all(|&idx| idx != tenant_acct));
+        assert_eq!(userdb.get(tenant_acct).unwrap().currency, CurrencyId(100));
+        assert!(table.add_tenant_charge_account(&mut userdb, "acme", CurrencyId(100)).is_err());
+        assert!(table.add_tenant_charge_account(&mut userdb, "acme", CurrencyId(999)).is_err());
+        assert_eq!(table.tenant_charge_accounts().get(&("acme".to_owned(), CurrencyId(100))), Some(&tenant_acct));
+
+        table.set_charge_account_enabled(&mut userdb, tenant_acct, false).unwrap();
+        assert!(table.disabled_charge_accounts.contains(&tenant_acct));
+        assert!(!table.disabled_charge_accounts.contains(&charge_acct));
+        assert!(table.set_charge_account_enabled(&mut userdb, charge_acct + 1, false).is_err());
+        table.set_charge_account_enabled(&mut userdb, tenant_acct, true).unwrap();
+        assert!(table.disabled_charge_accounts.is_empty());
+    }
+
+    #[test]
+    fn test_format() {
+        let (table, _) = test_table();
+        let usd = table.lookup("USD").unwrap();
+        let eur = table.lookup("EUR").unwrap();
+        assert_eq!(usd.format(-123456789), "-$1,234,567.89");
+        assert_eq!(usd.format(5), "$0.05");
+        assert_eq!(usd.decimal(-123456789), "-1234567.89");
+        assert_eq!(eur.format(123450), "1.234,50 €");
+        assert_eq!(eur.format(i64::MIN), "-92.233.720.368.547.758,08 €");
+        let jpy = CurrencyDetail { exponent: 0, symbol: String::new(), locale: "ja-JP".to_owned(),
+                                   name: "JPY".to_owned(), ..usd.clone() };
+        assert_eq!((jpy.format(1234), jpy.decimal(1234)), ("JPY 1,234".to_owned(), "1234".to_owned()));
+
+        assert_eq!(usd.parse_decimal("12.50"), Ok(1250));
+        assert_eq!(usd.parse_decimal("12.5"), Ok(1250));
+        assert_eq!(usd.parse_decimal("12"), Ok(1200));
+        assert_eq!(usd.parse_decimal("0.01"), Ok(1));
+        assert_eq!(usd.parse_decimal("12.505"), Err("amount has more decimal places than the currency"));
+        assert_eq!(jpy.parse_decimal("12.0"), Err("amount has more decimal places than the currency"));
+        for bad in &["", ".5", "12.", "-1", "+1", "1e3", "1,000", " 1"] {
+            assert_eq!(usd.parse_decimal(bad), Err("amount must be a decimal like 12.50"));
+        }
+        assert_eq!(usd.parse_decimal("92233720368547758.07"), Err("amount too large"));
+        assert_eq!(usd.parse_decimal("92233720368547758.06"), Ok(i64::MAX - 1));
+
+        let amounts: Vec<Amount> = serde_json::from_str(r#"[1250, "12.50"]"#).unwrap();
+        assert_eq!(amounts, vec![Amount::Minor(1250), Amount::Decimal("12.50".to_owned())]);
+        assert!(serde_json::from_str::<Amount>("-5").is_err());
+        assert_eq!(amounts[1].minor_units(&jpy), Err("amount has more decimal places than the currency"));
+        assert_eq!(Amount::Minor(i64::MAX as u64).minor_units(&usd), Err("amount too large"));
     }
 }